@@ -0,0 +1,12 @@
+// Compiles the gRPC service definition with a pure-Rust protobuf parser, so
+// building does not need `protoc` installed.
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    println!("cargo:rerun-if-changed=proto/users.proto");
+
+    let descriptors = protox::compile(["proto/users.proto"], ["proto"])?;
+    tonic_build::configure()
+        .build_client(false)
+        .compile_fds(descriptors)?;
+
+    Ok(())
+}
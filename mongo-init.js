@@ -1,5 +1,7 @@
 // MongoDB initialization script for Docker container
-// This script runs when the container first starts
+// This script runs when the container first starts, against a temporary
+// standalone mongod; the replica set (see mongo-replica-set.sh) is initiated
+// afterwards by the container healthcheck
 
 print("Starting MongoDB initialization for simple_api_db...");
 
@@ -0,0 +1,19 @@
+#!/bin/bash
+# Entrypoint of the MongoDB container: run mongod as the only member of replica set rs0
+#
+# The API writes user changes and their outbox entries in one transaction, and
+# transactions need a replica set. With authentication on, replica set members
+# must share a key file; a single member only needs one nobody else can read.
+# The set itself is initiated by the container healthcheck once mongod is up.
+
+set -e
+
+KEY_FILE=/data/configdb/replica-set.key
+
+if [ ! -f "$KEY_FILE" ]; then
+  head -c 756 /dev/urandom | base64 > "$KEY_FILE"
+  chmod 400 "$KEY_FILE"
+  chown mongodb:mongodb "$KEY_FILE"
+fi
+
+exec docker-entrypoint.sh mongod --replSet rs0 --bind_ip_all --keyFile "$KEY_FILE" "$@"
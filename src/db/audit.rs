@@ -0,0 +1,60 @@
+use crate::models::AuditEntry;
+use mongodb::error::Error as MongoError;
+use mongodb::options::IndexOptions;
+use mongodb::{bson::doc, Collection, Database, IndexModel};
+
+/// Collection holding the append-only audit trail
+pub const AUDIT_COLLECTION: &str = "audit_log";
+
+/// Create the indexes used by the history and audit listing endpoints
+pub async fn ensure_audit_indexes(db: &Database) -> Result<(), MongoError> {
+    let collection: Collection<AuditEntry> = db.collection(AUDIT_COLLECTION);
+
+    let indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "resource_id": 1, "timestamp": -1 })
+            .options(
+                IndexOptions::builder()
+                    .name("resource_id_timestamp".to_string())
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "actor": 1, "timestamp": -1 })
+            .options(
+                IndexOptions::builder()
+                    .name("actor_timestamp".to_string())
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "timestamp": -1 })
+            .options(
+                IndexOptions::builder()
+                    .name("timestamp".to_string())
+                    .build(),
+            )
+            .build(),
+    ];
+
+    collection.create_indexes(indexes, None).await?;
+    Ok(())
+}
+
+/// Append an entry to the audit log.
+///
+/// Failures are logged rather than returned so an unavailable audit log
+/// never turns a successful mutation into an error response.
+pub async fn record_audit_entry(db: &Database, entry: &AuditEntry) {
+    let collection: Collection<AuditEntry> = db.collection(AUDIT_COLLECTION);
+
+    if let Err(e) = collection.insert_one(entry, None).await {
+        eprintln!(
+            "Failed to record audit entry for {} {} (request {}): {}",
+            entry.action.as_str(),
+            entry.resource_id,
+            entry.request_id,
+            e
+        );
+    }
+}
@@ -0,0 +1,255 @@
+use crate::db::Outbox;
+use crate::models::{Credential, PasswordResetToken, Session, User};
+use chrono::Utc;
+use futures::stream::TryStreamExt;
+use mongodb::bson::{self, doc, oid::ObjectId, Bson, DateTime, Document};
+use mongodb::error::Error as MongoError;
+use mongodb::options::{Collation, CollationStrength, FindOptions, IndexOptions, UpdateOptions};
+use mongodb::{Collection, Database, IndexModel};
+use sha2::{Digest, Sha256};
+use std::time::Duration;
+
+/// Collection holding password hashes, keyed by user ID
+pub const CREDENTIALS_COLLECTION: &str = "credentials";
+
+/// Collection holding signed-in sessions
+pub const SESSIONS_COLLECTION: &str = "sessions";
+
+/// Collection holding issued password reset tokens
+pub const PASSWORD_RESET_TOKENS_COLLECTION: &str = "password_reset_tokens";
+
+/// Most users looked up for one email address; addresses are not unique
+const MAX_USERS_PER_EMAIL: i64 = 10;
+
+/// Create the TTL indexes removing expired sessions and reset tokens, and the
+/// indexes finding them by user
+pub async fn ensure_auth_indexes(db: &Database) -> Result<(), MongoError> {
+    for name in [SESSIONS_COLLECTION, PASSWORD_RESET_TOKENS_COLLECTION] {
+        let collection: Collection<Document> = db.collection(name);
+        let indexes = vec![
+            IndexModel::builder()
+                .keys(doc! { "expires_at": 1 })
+                .options(
+                    IndexOptions::builder()
+                        .name("expires_at_ttl".to_string())
+                        .expire_after(Duration::ZERO)
+                        .build(),
+                )
+                .build(),
+            IndexModel::builder()
+                .keys(doc! { "user_id": 1 })
+                .options(IndexOptions::builder().name("user_id".to_string()).build())
+                .build(),
+        ];
+
+        collection.create_indexes(indexes, None).await?;
+    }
+    Ok(())
+}
+
+/// The hex SHA-256 of a session or reset token, which is all that is stored of it
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+/// Get the users with an email address, compared without case
+pub async fn find_users_by_email(db: &Database, email: &str) -> Result<Vec<User>, MongoError> {
+    let collection: Collection<User> = db.collection("users");
+    let options = FindOptions::builder()
+        .collation(
+            Collation::builder()
+                .locale("en")
+                .strength(CollationStrength::Secondary)
+                .build(),
+        )
+        .sort(doc! { "_id": 1 })
+        .limit(MAX_USERS_PER_EMAIL)
+        .build();
+
+    collection
+        .find(doc! { "email": email }, options)
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Get the password hash of a user, if they have set a password
+pub async fn find_credential(
+    db: &Database,
+    user_id: ObjectId,
+) -> Result<Option<Credential>, MongoError> {
+    let collection: Collection<Credential> = db.collection(CREDENTIALS_COLLECTION);
+
+    collection.find_one(doc! { "_id": user_id }, None).await
+}
+
+/// Store a new session
+pub async fn insert_session(db: &Database, session: &Session) -> Result<(), MongoError> {
+    let collection: Collection<Session> = db.collection(SESSIONS_COLLECTION);
+
+    collection.insert_one(session, None).await?;
+    Ok(())
+}
+
+/// Get the session with a token hash, unless it has expired.
+///
+/// The TTL monitor only runs about once a minute, so expired sessions are
+/// filtered out here rather than trusted to be gone.
+pub async fn find_session(db: &Database, token_hash: &str) -> Result<Option<Session>, MongoError> {
+    let collection: Collection<Session> = db.collection(SESSIONS_COLLECTION);
+
+    collection
+        .find_one(
+            doc! { "_id": token_hash, "expires_at": { "$gt": DateTime::now() } },
+            None,
+        )
+        .await
+}
+
+/// Store a new password reset token
+pub async fn insert_password_reset_token(
+    db: &Database,
+    token: &PasswordResetToken,
+) -> Result<(), MongoError> {
+    let collection: Collection<PasswordResetToken> =
+        db.collection(PASSWORD_RESET_TOKENS_COLLECTION);
+
+    collection.insert_one(token, None).await?;
+    Ok(())
+}
+
+/// Record that a reset link is being mailed to `user` at `now`.
+///
+/// Only succeeds while the user still has the request time it was read with,
+/// so of two concurrent requests for the same user only one mails a link.
+pub async fn claim_password_reset(
+    db: &Database,
+    user: &User,
+    now: chrono::DateTime<Utc>,
+) -> Result<bool, MongoError> {
+    let collection: Collection<User> = db.collection("users");
+    let filter = doc! {
+        "_id": user.id,
+        "password_reset_requested_at": requested_at_bson(user.password_reset_requested_at),
+    };
+    let update = doc! { "$set": { "password_reset_requested_at": requested_at_bson(Some(now)) } };
+
+    let result = collection.update_one(filter, update, None).await?;
+    Ok(result.matched_count == 1)
+}
+
+/// Put back the request time a failed reset email replaced, so the user can ask again
+pub async fn release_password_reset(
+    db: &Database,
+    user: &User,
+    now: chrono::DateTime<Utc>,
+) -> Result<(), MongoError> {
+    let collection: Collection<User> = db.collection("users");
+    let filter = doc! {
+        "_id": user.id,
+        "password_reset_requested_at": requested_at_bson(Some(now)),
+    };
+    let update = match user.password_reset_requested_at {
+        Some(previous) => {
+            doc! { "$set": { "password_reset_requested_at": requested_at_bson(Some(previous)) } }
+        }
+        None => doc! { "$unset": { "password_reset_requested_at": "" } },
+    };
+
+    collection.update_one(filter, update, None).await?;
+    Ok(())
+}
+
+/// A request time as stored on the user, or null to match a user without one
+fn requested_at_bson(requested_at: Option<chrono::DateTime<Utc>>) -> Bson {
+    requested_at
+        .and_then(|requested_at| bson::to_bson(&requested_at).ok())
+        .unwrap_or(Bson::Null)
+}
+
+/// Redeem a password reset token, setting the user's new password hash.
+///
+/// In one transaction the token and every other unused token of the user are
+/// marked used, the password hash is replaced and all of the user's sessions are
+/// deleted. Returns the user ID, or `None` for a token that is unknown, expired
+/// or already used, or whose user no longer exists.
+pub async fn redeem_password_reset_token(
+    db: &Database,
+    outbox: &Outbox,
+    token_hash: &str,
+    password_hash: &str,
+) -> Result<Option<ObjectId>, MongoError> {
+    let tokens: Collection<PasswordResetToken> = db.collection(PASSWORD_RESET_TOKENS_COLLECTION);
+    let users: Collection<Document> = db.collection("users");
+    let credentials: Collection<Credential> = db.collection(CREDENTIALS_COLLECTION);
+    let sessions: Collection<Session> = db.collection(SESSIONS_COLLECTION);
+
+    let now = DateTime::now();
+    let mut session = outbox.begin_without_entry().await?;
+
+    let token = tokens
+        .find_one_and_update_with_session(
+            doc! {
+                "_id": token_hash,
+                "used_at": null,
+                "expires_at": { "$gt": now },
+            },
+            doc! { "$set": { "used_at": now } },
+            None,
+            &mut session,
+        )
+        .await?;
+    let Some(token) = token else {
+        session.abort_transaction().await?;
+        return Ok(None);
+    };
+    let user_id = token.user_id;
+
+    if users
+        .count_documents_with_session(doc! { "_id": user_id }, None, &mut session)
+        .await?
+        == 0
+    {
+        session.abort_transaction().await?;
+        return Ok(None);
+    }
+
+    tokens
+        .update_many_with_session(
+            doc! { "user_id": user_id, "used_at": null },
+            doc! { "$set": { "used_at": now } },
+            None,
+            &mut session,
+        )
+        .await?;
+    credentials
+        .update_one_with_session(
+            doc! { "_id": user_id },
+            doc! { "$set": { "password_hash": password_hash, "updated_at": now } },
+            UpdateOptions::builder().upsert(true).build(),
+            &mut session,
+        )
+        .await?;
+    sessions
+        .delete_many_with_session(doc! { "user_id": user_id }, None, &mut session)
+        .await?;
+
+    session.commit_transaction().await?;
+    Ok(Some(user_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hash_token() {
+        let hash = hash_token("secret");
+
+        assert_eq!(
+            hash,
+            "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b"
+        );
+        assert_ne!(hash_token("secret2"), hash);
+    }
+}
@@ -0,0 +1,148 @@
+use futures::TryStreamExt;
+use mongodb::bson::{doc, oid::ObjectId, Bson, DateTime as BsonDateTime, Document};
+use mongodb::error::Error as MongoError;
+use mongodb::gridfs::{FilesCollectionDocument, GridFsBucket};
+use mongodb::options::{GridFsBucketOptions, GridFsFindOptions, GridFsUploadOptions, IndexOptions};
+use mongodb::{Collection, Database, IndexModel};
+
+/// GridFS bucket holding avatar images, in `avatars.files` and `avatars.chunks`
+pub const AVATAR_BUCKET: &str = "avatars";
+
+/// One stored rendition of a user's avatar
+#[derive(Debug, Clone)]
+pub struct AvatarFile {
+    /// Width and height in pixels
+    pub size: u32,
+    pub content_type: &'static str,
+    pub data: Vec<u8>,
+}
+
+pub fn avatar_bucket(db: &Database) -> GridFsBucket {
+    db.gridfs_bucket(
+        GridFsBucketOptions::builder()
+            .bucket_name(AVATAR_BUCKET.to_string())
+            .build(),
+    )
+}
+
+/// Index avatar files by user and size for lookups and cleanup
+pub async fn ensure_avatar_indexes(db: &Database) -> Result<(), MongoError> {
+    let collection: Collection<Document> = db.collection(&format!("{}.files", AVATAR_BUCKET));
+
+    let index = IndexModel::builder()
+        .keys(doc! { "metadata.user_id": 1, "metadata.size": 1, "uploadDate": -1 })
+        .options(
+            IndexOptions::builder()
+                .name("user_id_size_upload_date".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_index(index, None).await?;
+    Ok(())
+}
+
+/// Store a new set of avatar files for a user, replacing older ones.
+///
+/// Files of uploads that started later are kept, so when two uploads race the
+/// newer one wins. If any file fails to upload, the ones already written are
+/// removed again and the previous avatar stays in place.
+pub async fn store_avatar(
+    db: &Database,
+    user_id: ObjectId,
+    files: &[AvatarFile],
+) -> Result<(), MongoError> {
+    let bucket = avatar_bucket(db);
+    let upload_id = ObjectId::new();
+    let started_at = BsonDateTime::now();
+    let mut uploaded = Vec::new();
+
+    for file in files {
+        let options = GridFsUploadOptions::builder()
+            .metadata(doc! {
+                "user_id": user_id,
+                "size": file.size as i64,
+                "content_type": file.content_type,
+                "upload_id": upload_id,
+            })
+            .build();
+        let result = bucket
+            .upload_from_futures_0_3_reader(
+                format!("{}-{}", user_id.to_hex(), file.size),
+                file.data.as_slice(),
+                options,
+            )
+            .await;
+
+        match result {
+            Ok(id) => uploaded.push(id),
+            Err(e) => {
+                for id in uploaded {
+                    let _ = bucket.delete(Bson::ObjectId(id)).await;
+                }
+                return Err(e);
+            }
+        }
+    }
+
+    delete_files(
+        &bucket,
+        doc! {
+            "metadata.user_id": user_id,
+            "metadata.upload_id": { "$ne": upload_id },
+            "uploadDate": { "$lt": started_at },
+        },
+    )
+    .await?;
+    Ok(())
+}
+
+/// Find the newest file of a user's avatar in the given size
+pub async fn find_avatar(
+    db: &Database,
+    user_id: ObjectId,
+    size: u32,
+) -> Result<Option<FilesCollectionDocument>, MongoError> {
+    let options = GridFsFindOptions::builder()
+        .sort(doc! { "uploadDate": -1 })
+        .limit(1)
+        .build();
+
+    avatar_bucket(db)
+        .find(
+            doc! { "metadata.user_id": user_id, "metadata.size": size as i64 },
+            options,
+        )
+        .await?
+        .try_next()
+        .await
+}
+
+/// Read the contents of an avatar file
+pub async fn read_avatar(
+    db: &Database,
+    file: &FilesCollectionDocument,
+) -> Result<Vec<u8>, MongoError> {
+    let mut data = Vec::with_capacity(file.length as usize);
+    avatar_bucket(db)
+        .download_to_futures_0_3_writer(file.id.clone(), &mut data)
+        .await?;
+    Ok(data)
+}
+
+/// Delete every avatar file of a user, returning how many were removed
+pub async fn delete_avatars(db: &Database, user_id: ObjectId) -> Result<u64, MongoError> {
+    delete_files(&avatar_bucket(db), doc! { "metadata.user_id": user_id }).await
+}
+
+async fn delete_files(bucket: &GridFsBucket, filter: Document) -> Result<u64, MongoError> {
+    let files: Vec<FilesCollectionDocument> =
+        bucket.find(filter, None).await?.try_collect().await?;
+
+    let mut deleted = 0;
+    for file in files {
+        bucket.delete(file.id).await?;
+        deleted += 1;
+    }
+    Ok(deleted)
+}
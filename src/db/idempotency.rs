@@ -0,0 +1,128 @@
+use crate::models::IdempotencyRecord;
+use mongodb::error::{Error as MongoError, ErrorKind, WriteFailure};
+use mongodb::options::IndexOptions;
+use mongodb::{bson::doc, Collection, Database, IndexModel};
+use std::env;
+use std::time::Duration;
+
+/// Collection holding stored idempotent responses
+pub const IDEMPOTENCY_COLLECTION: &str = "idempotency_keys";
+
+/// Default lifetime of a stored idempotent response (24 hours)
+const DEFAULT_IDEMPOTENCY_TTL_SECONDS: u64 = 86_400;
+
+/// Default time after which an unfinished claim on a key can be taken over
+const DEFAULT_IDEMPOTENCY_LEASE_SECONDS: u64 = 30;
+
+/// MongoDB error code for a unique index violation
+const DUPLICATE_KEY_ERROR_CODE: i32 = 11000;
+
+/// Get the idempotency record lifetime from `IDEMPOTENCY_TTL_SECONDS` or use the default
+pub fn idempotency_ttl() -> Duration {
+    let seconds = env::var("IDEMPOTENCY_TTL_SECONDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_IDEMPOTENCY_TTL_SECONDS);
+
+    Duration::from_secs(seconds)
+}
+
+/// Get how long a request may hold an unfinished key from `IDEMPOTENCY_LEASE_SECONDS`
+/// or use the default
+pub fn idempotency_lease() -> Duration {
+    let seconds = env::var("IDEMPOTENCY_LEASE_SECONDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_IDEMPOTENCY_LEASE_SECONDS);
+
+    Duration::from_secs(seconds)
+}
+
+/// Create the TTL index that expires idempotency records after `idempotency_ttl()`
+pub async fn ensure_idempotency_indexes(db: &Database) -> Result<(), MongoError> {
+    let collection: Collection<IdempotencyRecord> = db.collection(IDEMPOTENCY_COLLECTION);
+
+    let index = IndexModel::builder()
+        .keys(doc! { "created_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("created_at_ttl".to_string())
+                .expire_after(idempotency_ttl())
+                .build(),
+        )
+        .build();
+
+    collection.create_index(index, None).await?;
+    Ok(())
+}
+
+/// Check whether an error was caused by inserting a document whose `_id` already exists
+pub fn is_duplicate_key_error(error: &MongoError) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error))
+            if write_error.code == DUPLICATE_KEY_ERROR_CODE
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idempotency_ttl_from_env() {
+        unsafe {
+            env::remove_var("IDEMPOTENCY_TTL_SECONDS");
+        }
+        assert_eq!(
+            idempotency_ttl(),
+            Duration::from_secs(DEFAULT_IDEMPOTENCY_TTL_SECONDS)
+        );
+
+        unsafe {
+            env::set_var("IDEMPOTENCY_TTL_SECONDS", "600");
+        }
+        assert_eq!(idempotency_ttl(), Duration::from_secs(600));
+
+        // Invalid values fall back to the default
+        unsafe {
+            env::set_var("IDEMPOTENCY_TTL_SECONDS", "not-a-number");
+        }
+        assert_eq!(
+            idempotency_ttl(),
+            Duration::from_secs(DEFAULT_IDEMPOTENCY_TTL_SECONDS)
+        );
+
+        // Clean up
+        unsafe {
+            env::remove_var("IDEMPOTENCY_TTL_SECONDS");
+        }
+    }
+
+    #[test]
+    fn test_idempotency_lease_from_env() {
+        unsafe {
+            env::remove_var("IDEMPOTENCY_LEASE_SECONDS");
+        }
+        assert_eq!(
+            idempotency_lease(),
+            Duration::from_secs(DEFAULT_IDEMPOTENCY_LEASE_SECONDS)
+        );
+
+        unsafe {
+            env::set_var("IDEMPOTENCY_LEASE_SECONDS", "5");
+        }
+        assert_eq!(idempotency_lease(), Duration::from_secs(5));
+
+        // Clean up
+        unsafe {
+            env::remove_var("IDEMPOTENCY_LEASE_SECONDS");
+        }
+    }
+
+    #[test]
+    fn test_non_write_error_is_not_duplicate_key() {
+        let error = MongoError::custom("not a write error");
+        assert!(!is_duplicate_key_error(&error));
+    }
+}
@@ -9,27 +9,70 @@ const DEFAULT_DATABASE_NAME: &str = "simple_api_db";
 
 /// Establishes a connection to MongoDB and returns the database instance
 pub async fn connect_to_database() -> Result<Database, Box<dyn std::error::Error>> {
+    let client = connect_to_client().await?;
+    Ok(database_for_client(&client))
+}
+
+/// Connects to the MongoDB deployment named by `MONGODB_URI`
+pub async fn connect_to_client() -> Result<Client, Box<dyn std::error::Error>> {
     // Get MongoDB connection string from environment variables
     let mongodb_uri = env::var("MONGODB_URI").unwrap_or_else(|_| DEFAULT_MONGODB_URI.to_string());
 
-    // Get database name from environment variables
-    let db_name = env::var("DATABASE_NAME").unwrap_or_else(|_| DEFAULT_DATABASE_NAME.to_string());
-
     // Connect to MongoDB
     let client = Client::with_uri_str(mongodb_uri).await?;
 
+    Ok(client)
+}
+
+/// Returns the database named by `DATABASE_NAME` on an existing connection
+pub fn database_for_client(client: &Client) -> Database {
+    // Get database name from environment variables
+    let db_name = env::var("DATABASE_NAME").unwrap_or_else(|_| DEFAULT_DATABASE_NAME.to_string());
+
     // Get the database instance
     let database = client.database(&db_name);
 
     println!("Connected to MongoDB database: {}", db_name);
 
-    Ok(database)
+    database
 }
 
 /// Seed data module for populating the database with mock data
 pub mod seed;
 pub use seed::*;
 
+/// Storage for replayable responses keyed by `Idempotency-Key`
+pub mod idempotency;
+pub use idempotency::*;
+
+/// Append-only audit trail of user mutations
+pub mod audit;
+pub use audit::*;
+
+/// Webhook subscriptions and their delivery queue
+pub mod webhooks;
+pub use webhooks::*;
+
+/// Events written alongside user changes and relayed to publishing sinks
+pub mod outbox;
+pub use outbox::*;
+
+/// Text index for user search
+pub mod search;
+pub use search::*;
+
+/// Status index and backfill of users stored before statuses
+pub mod status;
+pub use status::*;
+
+/// Passwords, sessions and password reset tokens
+pub mod auth;
+pub use auth::*;
+
+/// Avatar images stored in GridFS
+pub mod avatars;
+pub use avatars::*;
+
 #[cfg(test)]
 mod tests {
     use super::*;
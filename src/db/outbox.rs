@@ -0,0 +1,282 @@
+use crate::models::OutboxEntry;
+use mongodb::bson::{doc, oid::ObjectId, DateTime, Document};
+use mongodb::error::{Error as MongoError, TRANSIENT_TRANSACTION_ERROR};
+use mongodb::options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument};
+use mongodb::{Client, ClientSession, Collection, Database, IndexModel};
+use std::env;
+use std::time::Duration;
+
+/// Collection holding events waiting to be relayed
+pub const OUTBOX_COLLECTION: &str = "outbox";
+
+/// Default time entries are kept once every sink has published them (7 days)
+const DEFAULT_OUTBOX_RETENTION_SECONDS: u64 = 604_800;
+
+/// TTL index on `occurred_at` created by earlier versions, which expired unpublished entries
+const LEGACY_TTL_INDEX: &str = "occurred_at_ttl";
+
+/// Get the outbox retention from `OUTBOX_RETENTION_SECONDS` or use the default
+pub fn outbox_retention() -> Duration {
+    let seconds = env::var("OUTBOX_RETENTION_SECONDS")
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(DEFAULT_OUTBOX_RETENTION_SECONDS);
+
+    Duration::from_secs(seconds)
+}
+
+/// Create the index ordering entries for the relay, and the TTL index removing published ones.
+///
+/// The TTL index is on `published_at`, which is only set once every sink has
+/// published the entry, so an entry a sink is still retrying never expires.
+pub async fn ensure_outbox_indexes(db: &Database) -> Result<(), MongoError> {
+    let collection: Collection<OutboxEntry> = db.collection(OUTBOX_COLLECTION);
+
+    // Missing on new deployments
+    let _ = collection.drop_index(LEGACY_TTL_INDEX, None).await;
+
+    let order = IndexModel::builder()
+        .keys(doc! { "occurred_at": 1, "_id": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("occurred_at_order".to_string())
+                .build(),
+        )
+        .build();
+    let ttl = IndexModel::builder()
+        .keys(doc! { "published_at": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("published_at_ttl".to_string())
+                .expire_after(outbox_retention())
+                .build(),
+        )
+        .build();
+
+    collection.create_indexes([order, ttl], None).await?;
+    Ok(())
+}
+
+/// Starts the transactions in which user changes and their outbox entries are written
+pub struct Outbox {
+    client: Client,
+}
+
+impl Outbox {
+    /// Use `client` for outbox transactions, refusing deployments that cannot run them.
+    ///
+    /// Writing the change and its entry one after the other would lose the event
+    /// on a crash in between, so a standalone server is an error, not a fallback.
+    pub async fn connect(client: Client) -> Result<Self, Box<dyn std::error::Error>> {
+        let reply = client
+            .database("admin")
+            .run_command(doc! { "hello": 1 }, None)
+            .await?;
+        if !supports_transactions(&reply) {
+            return Err("MongoDB must run as a replica set or sharded cluster, \
+                 outbox entries are written in the same transaction as user changes"
+                .into());
+        }
+
+        Ok(Outbox { client })
+    }
+
+    /// An outbox over `client` without checking the deployment, for tests
+    #[cfg(test)]
+    pub fn new(client: Client) -> Self {
+        Outbox { client }
+    }
+
+    /// Begin the transaction of a user change
+    pub async fn begin(&self, db: &Database) -> Result<OutboxTransaction, MongoError> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+
+        Ok(OutboxTransaction {
+            session,
+            collection: db.collection(OUTBOX_COLLECTION),
+        })
+    }
+
+    /// Begin a transaction for writes that publish no event, such as a password change
+    pub async fn begin_without_entry(&self) -> Result<ClientSession, MongoError> {
+        let mut session = self.client.start_session(None).await?;
+        session.start_transaction(None).await?;
+        Ok(session)
+    }
+}
+
+/// Whether a `hello` reply comes from a replica set member or a `mongos`
+fn supports_transactions(hello: &Document) -> bool {
+    hello.contains_key("setName") || hello.get_str("msg").ok() == Some("isdbgrid")
+}
+
+/// A user change in progress; dropping it without committing aborts the transaction
+pub struct OutboxTransaction {
+    session: ClientSession,
+    collection: Collection<OutboxEntry>,
+}
+
+impl OutboxTransaction {
+    /// The session every write belonging to the change must use
+    pub fn session(&mut self) -> &mut ClientSession {
+        &mut self.session
+    }
+
+    /// Add the outbox entry describing the change and commit both
+    pub async fn commit(mut self, entry: &OutboxEntry) -> Result<(), MongoError> {
+        self.collection
+            .insert_one_with_session(entry, None, &mut self.session)
+            .await?;
+        self.session.commit_transaction().await
+    }
+}
+
+/// Check whether a write failed because it conflicted with a concurrent transaction
+pub fn is_transient_transaction_error(error: &MongoError) -> bool {
+    error.contains_label(TRANSIENT_TRANSACTION_ERROR)
+}
+
+/// Claim the oldest entry a sink has not published yet.
+///
+/// The claim is a per-sink lease, so several relays can run side by side
+/// without publishing an entry to the same sink twice at the same time.
+pub async fn claim_outbox_entry(
+    db: &Database,
+    sink: &str,
+    lease: Duration,
+) -> Result<Option<OutboxEntry>, MongoError> {
+    let collection: Collection<OutboxEntry> = db.collection(OUTBOX_COLLECTION);
+    let now = DateTime::now();
+    let lease_field = format!("leases.{}", sink);
+
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! { "occurred_at": 1, "_id": 1 })
+        .return_document(ReturnDocument::After)
+        .build();
+
+    collection
+        .find_one_and_update(
+            doc! {
+                "published_to": { "$ne": sink },
+                "$or": [
+                    { lease_field.as_str(): { "$exists": false } },
+                    { lease_field.as_str(): { "$lte": now } },
+                ],
+            },
+            doc! { "$set": { lease_field.as_str(): later(now, lease) } },
+            options,
+        )
+        .await
+}
+
+/// Record that a sink has published an entry, and when all of `sinks` have, that it is done
+pub async fn mark_outbox_published(
+    db: &Database,
+    id: ObjectId,
+    sink: &str,
+    sinks: &[String],
+) -> Result<(), MongoError> {
+    let collection: Collection<OutboxEntry> = db.collection(OUTBOX_COLLECTION);
+
+    collection
+        .update_one(
+            doc! { "_id": id },
+            doc! {
+                "$addToSet": { "published_to": sink },
+                "$unset": { format!("leases.{}", sink): "" },
+            },
+            None,
+        )
+        .await?;
+    collection
+        .update_one(
+            doc! {
+                "_id": id,
+                "published_to": { "$all": sinks },
+                "published_at": { "$exists": false },
+            },
+            doc! { "$set": { "published_at": DateTime::now() } },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
+
+/// Keep a failed entry away from a sink until `retry_after` has passed
+pub async fn postpone_outbox_entry(
+    db: &Database,
+    id: ObjectId,
+    sink: &str,
+    retry_after: Duration,
+) -> Result<(), MongoError> {
+    let collection: Collection<OutboxEntry> = db.collection(OUTBOX_COLLECTION);
+
+    collection
+        .update_one(
+            doc! { "_id": id },
+            doc! { "$set": { format!("leases.{}", sink): later(DateTime::now(), retry_after) } },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
+
+fn later(from: DateTime, duration: Duration) -> DateTime {
+    DateTime::from_millis(from.timestamp_millis() + duration.as_millis() as i64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_outbox_retention_from_env() {
+        unsafe {
+            env::remove_var("OUTBOX_RETENTION_SECONDS");
+        }
+        assert_eq!(
+            outbox_retention(),
+            Duration::from_secs(DEFAULT_OUTBOX_RETENTION_SECONDS)
+        );
+
+        unsafe {
+            env::set_var("OUTBOX_RETENTION_SECONDS", "3600");
+        }
+        assert_eq!(outbox_retention(), Duration::from_secs(3600));
+
+        // Clean up
+        unsafe {
+            env::remove_var("OUTBOX_RETENTION_SECONDS");
+        }
+    }
+
+    #[test]
+    fn test_later_adds_duration() {
+        let now = DateTime::from_millis(1_000);
+
+        assert_eq!(
+            later(now, Duration::from_secs(2)),
+            DateTime::from_millis(3_000)
+        );
+    }
+
+    #[test]
+    fn test_supports_transactions() {
+        assert!(supports_transactions(
+            &doc! { "isWritablePrimary": true, "setName": "rs0" }
+        ));
+        assert!(supports_transactions(
+            &doc! { "isWritablePrimary": true, "msg": "isdbgrid" }
+        ));
+        assert!(!supports_transactions(&doc! { "isWritablePrimary": true }));
+    }
+
+    #[test]
+    fn test_plain_error_is_not_transient() {
+        let error = MongoError::custom("not a transaction error");
+        assert!(!is_transient_transaction_error(&error));
+    }
+}
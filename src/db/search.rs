@@ -0,0 +1,29 @@
+use crate::models::User;
+use mongodb::error::Error as MongoError;
+use mongodb::options::IndexOptions;
+use mongodb::{bson::doc, Collection, Database, IndexModel};
+
+/// Name of the text index behind `GET /users/search`
+pub const USER_TEXT_INDEX: &str = "user_text";
+
+/// Create the text index over user names and emails.
+///
+/// Text indexes are case- and diacritic-insensitive. The language is `none`
+/// so names are matched as written rather than stemmed as English words.
+pub async fn ensure_user_search_indexes(db: &Database) -> Result<(), MongoError> {
+    let collection: Collection<User> = db.collection("users");
+
+    let index = IndexModel::builder()
+        .keys(doc! { "name": "text", "email": "text" })
+        .options(
+            IndexOptions::builder()
+                .name(USER_TEXT_INDEX.to_string())
+                .weights(doc! { "name": 3, "email": 1 })
+                .default_language("none".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_index(index, None).await?;
+    Ok(())
+}
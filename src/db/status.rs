@@ -0,0 +1,39 @@
+use crate::models::{User, UserStatus};
+use mongodb::error::Error as MongoError;
+use mongodb::options::IndexOptions;
+use mongodb::{bson::doc, Collection, Database, IndexModel};
+
+/// Give users stored before statuses existed an explicit `active` status and
+/// index the field for `status` filters.
+///
+/// They already read as active; storing it lets filters and status transitions,
+/// which match on the stored value, see them too.
+pub async fn ensure_user_status(db: &Database) -> Result<(), MongoError> {
+    let collection: Collection<User> = db.collection("users");
+
+    let backfilled = collection
+        .update_many(
+            doc! { "status": { "$exists": false } },
+            doc! { "$set": { "status": UserStatus::Active.as_str() } },
+            None,
+        )
+        .await?;
+    if backfilled.modified_count > 0 {
+        println!(
+            "Marked {} users stored without a status as active",
+            backfilled.modified_count
+        );
+    }
+
+    let index = IndexModel::builder()
+        .keys(doc! { "status": 1 })
+        .options(
+            IndexOptions::builder()
+                .name("user_status".to_string())
+                .build(),
+        )
+        .build();
+
+    collection.create_index(index, None).await?;
+    Ok(())
+}
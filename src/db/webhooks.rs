@@ -0,0 +1,130 @@
+use crate::db::is_duplicate_key_error;
+use crate::models::{DeliveryAttempt, DeliveryStatus, WebhookDelivery, WebhookSubscription};
+use futures::stream::TryStreamExt;
+use mongodb::bson::{self, doc, DateTime};
+use mongodb::error::Error as MongoError;
+use mongodb::options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument};
+use mongodb::{Collection, Database, IndexModel};
+use std::time::Duration;
+
+/// Collection holding webhook subscriptions
+pub const WEBHOOK_SUBSCRIPTIONS_COLLECTION: &str = "webhook_subscriptions";
+
+/// Collection holding queued, delivered and dead-lettered webhook deliveries
+pub const WEBHOOK_DELIVERIES_COLLECTION: &str = "webhook_deliveries";
+
+/// Create the indexes used by the delivery worker and the delivery log endpoints
+pub async fn ensure_webhook_indexes(db: &Database) -> Result<(), MongoError> {
+    let deliveries: Collection<WebhookDelivery> = db.collection(WEBHOOK_DELIVERIES_COLLECTION);
+
+    let indexes = vec![
+        IndexModel::builder()
+            .keys(doc! { "status": 1, "next_attempt_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .name("status_next_attempt_at".to_string())
+                    .build(),
+            )
+            .build(),
+        IndexModel::builder()
+            .keys(doc! { "subscription_id": 1, "created_at": -1 })
+            .options(
+                IndexOptions::builder()
+                    .name("subscription_id_created_at".to_string())
+                    .build(),
+            )
+            .build(),
+    ];
+
+    deliveries.create_indexes(indexes, None).await?;
+    Ok(())
+}
+
+/// Get the active subscriptions that want events of the given type
+pub async fn find_webhook_subscribers(
+    db: &Database,
+    event_type: &str,
+) -> Result<Vec<WebhookSubscription>, MongoError> {
+    let collection: Collection<WebhookSubscription> =
+        db.collection(WEBHOOK_SUBSCRIPTIONS_COLLECTION);
+
+    collection
+        .find(doc! { "active": true, "events": event_type }, None)
+        .await?
+        .try_collect()
+        .await
+}
+
+/// Queue a delivery, returning false if the same delivery was already queued
+pub async fn enqueue_webhook_delivery(
+    db: &Database,
+    delivery: &WebhookDelivery,
+) -> Result<bool, MongoError> {
+    let collection: Collection<WebhookDelivery> = db.collection(WEBHOOK_DELIVERIES_COLLECTION);
+
+    match collection.insert_one(delivery, None).await {
+        Ok(_) => Ok(true),
+        Err(e) if is_duplicate_key_error(&e) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+/// Claim the oldest pending delivery that is due.
+///
+/// The claim pushes `next_attempt_at` forward by `lease`, so other workers
+/// skip the delivery while it is in flight and pick it up again if this one
+/// dies before recording the outcome.
+pub async fn claim_due_webhook_delivery(
+    db: &Database,
+    lease: Duration,
+) -> Result<Option<WebhookDelivery>, MongoError> {
+    let collection: Collection<WebhookDelivery> = db.collection(WEBHOOK_DELIVERIES_COLLECTION);
+    let now = DateTime::now();
+    let lease_until = DateTime::from_millis(now.timestamp_millis() + lease.as_millis() as i64);
+
+    let options = FindOneAndUpdateOptions::builder()
+        .sort(doc! { "next_attempt_at": 1 })
+        .return_document(ReturnDocument::After)
+        .build();
+
+    collection
+        .find_one_and_update(
+            doc! {
+                "status": DeliveryStatus::Pending.as_str(),
+                "next_attempt_at": { "$lte": now },
+            },
+            doc! { "$set": { "next_attempt_at": lease_until } },
+            options,
+        )
+        .await
+}
+
+/// Store the outcome of an attempt and move the delivery to its next state
+pub async fn record_webhook_attempt(
+    db: &Database,
+    delivery_id: &str,
+    attempt: &DeliveryAttempt,
+    status: DeliveryStatus,
+    next_attempt_at: DateTime,
+) -> Result<(), MongoError> {
+    let collection: Collection<WebhookDelivery> = db.collection(WEBHOOK_DELIVERIES_COLLECTION);
+    let attempt = bson::to_bson(attempt)?;
+
+    collection
+        .update_one(
+            doc! { "_id": delivery_id },
+            doc! {
+                "$set": {
+                    "status": status.as_str(),
+                    "next_attempt_at": next_attempt_at,
+                    "updated_at": DateTime::now(),
+                },
+                "$inc": { "attempts": 1 },
+                "$push": { "attempt_log": attempt },
+            },
+            None,
+        )
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,550 @@
+//! In-process feed of user change events
+//!
+//! Every change is kept in a bounded replay buffer so clients can resume from
+//! the last event they saw. The feed is filled by a MongoDB change stream when
+//! the server supports one (replica sets), and by the handlers themselves
+//! otherwise. Events from a change stream are identified by their resume token,
+//! so a client whose last event has left the buffer, or who reconnects to
+//! another instance or after a restart, can still resume from MongoDB.
+
+use futures::stream::{Stream, StreamExt};
+use mongodb::bson::{self, doc, oid::ObjectId, Bson};
+use mongodb::change_stream::event::{ChangeStreamEvent, OperationType, ResumeToken};
+use mongodb::change_stream::ChangeStream;
+use mongodb::error::{Error as MongoError, ErrorKind};
+use mongodb::options::{ChangeStreamOptions, FullDocumentBeforeChangeType, FullDocumentType};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::env;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast;
+use utoipa::ToSchema;
+
+use crate::handlers::users::UserResponse;
+use crate::models::User;
+
+/// Default number of events kept for `Last-Event-ID` resumption
+const DEFAULT_REPLAY_SIZE: usize = 1000;
+
+/// Prefix of IDs given to events published in-process, which cannot be resumed
+/// from MongoDB
+const LOCAL_EVENT_ID_PREFIX: &str = "local-";
+
+/// First wait before reopening a failed change stream, doubled after every failed attempt
+const CHANGE_STREAM_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Longest wait between attempts to reopen a failed change stream
+const MAX_CHANGE_STREAM_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// Server error code for a resume token that has left the oplog
+const CHANGE_STREAM_HISTORY_LOST: i32 = 286;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UserEventKind {
+    Created,
+    Updated,
+    Deleted,
+}
+
+impl UserEventKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserEventKind::Created => "created",
+            UserEventKind::Updated => "updated",
+            UserEventKind::Deleted => "deleted",
+        }
+    }
+}
+
+/// A change to a single user
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+pub struct UserEvent {
+    /// The change stream resume token, or `local-<instance>-<sequence>` for events
+    /// published in-process
+    pub id: String,
+    #[serde(rename = "type")]
+    pub kind: UserEventKind,
+    pub user_id: String,
+    /// The user after the change, or before it for deletions when known
+    pub user: Option<UserResponse>,
+    pub timestamp: String,
+}
+
+struct FeedState {
+    next_sequence: u64,
+    recent: VecDeque<UserEvent>,
+}
+
+/// What a subscriber missed since its `Last-Event-ID`
+#[derive(Debug)]
+pub enum Replay {
+    /// The buffered events after the given ID, empty when no ID was given
+    Missed(Vec<UserEvent>),
+    /// The ID is no longer (or never was) in the buffer
+    Unknown,
+}
+
+/// Broadcast channel plus replay buffer shared by all event subscribers
+pub struct UserEventBus {
+    sender: broadcast::Sender<UserEvent>,
+    state: Mutex<FeedState>,
+    replay_size: usize,
+    /// Distinguishes in-process IDs from those of earlier runs and other instances
+    instance_id: String,
+    /// False while a change stream is feeding the bus
+    local_publishing: AtomicBool,
+}
+
+impl Default for UserEventBus {
+    fn default() -> Self {
+        UserEventBus::new(DEFAULT_REPLAY_SIZE)
+    }
+}
+
+impl UserEventBus {
+    pub fn new(replay_size: usize) -> Self {
+        let (sender, _) = broadcast::channel(replay_size.max(1));
+        UserEventBus {
+            sender,
+            state: Mutex::new(FeedState {
+                next_sequence: 1,
+                recent: VecDeque::with_capacity(replay_size),
+            }),
+            replay_size,
+            instance_id: ObjectId::new().to_hex(),
+            local_publishing: AtomicBool::new(true),
+        }
+    }
+
+    /// Create a bus sized from `USER_EVENTS_REPLAY_SIZE` or the default
+    pub fn from_env() -> Self {
+        let replay_size = env::var("USER_EVENTS_REPLAY_SIZE")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_REPLAY_SIZE);
+
+        UserEventBus::new(replay_size)
+    }
+
+    /// Assign the next in-process ID to a change and deliver it to subscribers
+    pub fn publish(
+        &self,
+        kind: UserEventKind,
+        user_id: String,
+        user: Option<UserResponse>,
+    ) -> UserEvent {
+        self.publish_with_id(None, kind, user_id, user)
+    }
+
+    /// Deliver a change to subscribers under `id`, or the next in-process ID when `None`
+    fn publish_with_id(
+        &self,
+        id: Option<String>,
+        kind: UserEventKind,
+        user_id: String,
+        user: Option<UserResponse>,
+    ) -> UserEvent {
+        let mut state = self.state.lock().unwrap();
+
+        let id = id.unwrap_or_else(|| {
+            let sequence = state.next_sequence;
+            state.next_sequence += 1;
+            format!("{}{}-{}", LOCAL_EVENT_ID_PREFIX, self.instance_id, sequence)
+        });
+        let event = UserEvent {
+            id,
+            kind,
+            user_id,
+            user,
+            timestamp: chrono::Utc::now().to_rfc3339(),
+        };
+
+        if self.replay_size > 0 {
+            if state.recent.len() == self.replay_size {
+                state.recent.pop_front();
+            }
+            state.recent.push_back(event.clone());
+        }
+
+        // Sending while holding the lock keeps replay and live delivery in the same order
+        let _ = self.sender.send(event.clone());
+        event
+    }
+
+    /// Publish a change made by this process, unless a change stream already reports it
+    pub fn publish_local(&self, kind: UserEventKind, user: &User) {
+        if self.local_publishing.load(Ordering::SeqCst) {
+            let user_id = user.id.map(|id| id.to_hex()).unwrap_or_default();
+            self.publish(kind, user_id, Some(UserResponse::from(user.clone())));
+        }
+    }
+
+    /// Subscribe to live events, returning the buffered events after `last_event_id` first
+    pub fn subscribe(
+        &self,
+        last_event_id: Option<&str>,
+    ) -> (Replay, broadcast::Receiver<UserEvent>) {
+        let state = self.state.lock().unwrap();
+        let receiver = self.sender.subscribe();
+
+        let replay = match last_event_id {
+            Some(last_event_id) => {
+                match state
+                    .recent
+                    .iter()
+                    .position(|event| event.id == last_event_id)
+                {
+                    Some(position) => {
+                        Replay::Missed(state.recent.iter().skip(position + 1).cloned().collect())
+                    }
+                    None => Replay::Unknown,
+                }
+            }
+            None => Replay::Missed(Vec::new()),
+        };
+
+        (replay, receiver)
+    }
+
+    fn set_local_publishing(&self, enabled: bool) {
+        self.local_publishing.store(enabled, Ordering::SeqCst);
+    }
+}
+
+/// Feed the bus from a change stream on the users collection when the server supports it.
+///
+/// Standalone servers reject change streams, in which case the handlers keep
+/// publishing their own changes. A stream that fails later is reopened from its
+/// last resume token, so the changes made meanwhile are published once it is back.
+pub async fn start_change_stream_feed(db: Arc<Database>, bus: Arc<UserEventBus>) {
+    let collection: Collection<User> = db.collection("users");
+    let change_stream = match collection.watch(None, change_stream_options(None)).await {
+        Ok(change_stream) => change_stream,
+        Err(e) => {
+            println!(
+                "Change streams unavailable ({}), publishing user events in-process",
+                e
+            );
+            return;
+        }
+    };
+
+    println!("Publishing user events from MongoDB change stream");
+    bus.set_local_publishing(false);
+
+    tokio::spawn(follow_change_stream(collection, change_stream, bus));
+}
+
+/// Publish every change from `change_stream`, reopening it whenever it fails or ends
+async fn follow_change_stream(
+    collection: Collection<User>,
+    mut change_stream: ChangeStream<ChangeStreamEvent<User>>,
+    bus: Arc<UserEventBus>,
+) {
+    let mut resume_after = None;
+    loop {
+        while let Some(result) = change_stream.next().await {
+            match result {
+                Ok(change) => {
+                    if let Some((id, kind, user_id, user)) = event_from_change(change) {
+                        bus.publish_with_id(Some(id), kind, user_id, user);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("User change stream failed, reopening it: {}", e);
+                    break;
+                }
+            }
+        }
+
+        // Includes changes that were not published, such as other operation types
+        resume_after = change_stream.resume_token().or(resume_after);
+        change_stream = reopen_change_stream(&collection, resume_after.clone()).await;
+    }
+}
+
+/// Open the change stream after `resume_after`, retrying with backoff until it opens.
+///
+/// When the token has left the oplog the changes since are lost, and the stream
+/// is opened from the present instead.
+async fn reopen_change_stream(
+    collection: &Collection<User>,
+    mut resume_after: Option<ResumeToken>,
+) -> ChangeStream<ChangeStreamEvent<User>> {
+    let mut delay = CHANGE_STREAM_RETRY_DELAY;
+    loop {
+        tokio::time::sleep(delay).await;
+        match collection
+            .watch(None, change_stream_options(resume_after.clone()))
+            .await
+        {
+            Ok(change_stream) => {
+                println!("User change stream reopened");
+                return change_stream;
+            }
+            Err(e) if resume_after.is_some() && is_history_lost(&e) => {
+                eprintln!(
+                    "User change stream cannot resume, changes since its last event are lost: {}",
+                    e
+                );
+                resume_after = None;
+            }
+            Err(e) => {
+                delay = next_retry_delay(delay);
+                eprintln!(
+                    "Failed to reopen the user change stream, retrying in {}s: {}",
+                    delay.as_secs(),
+                    e
+                );
+            }
+        }
+    }
+}
+
+/// The wait after `delay` before the next attempt to reopen a change stream
+fn next_retry_delay(delay: Duration) -> Duration {
+    (delay * 2).min(MAX_CHANGE_STREAM_RETRY_DELAY)
+}
+
+/// Whether the server refused a resume token because its history is gone
+fn is_history_lost(error: &MongoError) -> bool {
+    matches!(
+        error.kind.as_ref(),
+        ErrorKind::Command(command_error) if command_error.code == CHANGE_STREAM_HISTORY_LOST
+    )
+}
+
+/// Stream the changes after the event with ID `last_event_id` straight from MongoDB.
+///
+/// Used when the ID has left the replay buffer. Fails for in-process IDs, and when
+/// the server no longer has the history the resume token points into. A later
+/// failure of the change stream is its last item.
+pub async fn resume_change_stream(
+    db: &Database,
+    last_event_id: &str,
+) -> Result<impl Stream<Item = Result<UserEvent, MongoError>>, MongoError> {
+    let token = resume_token(last_event_id)
+        .ok_or_else(|| MongoError::custom("event ID is not a resume token"))?;
+    let collection: Collection<User> = db.collection("users");
+    let change_stream = collection
+        .watch(None, change_stream_options(Some(token)))
+        .await?;
+
+    Ok(change_stream
+        .scan(false, |failed, result| {
+            let item = (!*failed).then_some(result);
+            *failed = matches!(item, Some(Err(_)));
+            futures::future::ready(item)
+        })
+        .filter_map(|result| async move {
+            match result {
+                Ok(change) => {
+                    let (id, kind, user_id, user) = event_from_change(change)?;
+                    Some(Ok(UserEvent {
+                        id,
+                        kind,
+                        user_id,
+                        user,
+                        timestamp: chrono::Utc::now().to_rfc3339(),
+                    }))
+                }
+                Err(e) => Some(Err(e)),
+            }
+        }))
+}
+
+fn change_stream_options(start_after: Option<ResumeToken>) -> ChangeStreamOptions {
+    ChangeStreamOptions::builder()
+        .full_document(Some(FullDocumentType::UpdateLookup))
+        .full_document_before_change(Some(FullDocumentBeforeChangeType::WhenAvailable))
+        .start_after(start_after)
+        .build()
+}
+
+/// The event ID for a resume token, which is its `_data` string
+fn event_id(token: &ResumeToken) -> Option<String> {
+    match bson::to_bson(token).ok()? {
+        Bson::Document(document) => document.get_str("_data").ok().map(str::to_string),
+        _ => None,
+    }
+}
+
+/// The resume token an event ID came from, `None` for in-process IDs
+fn resume_token(event_id: &str) -> Option<ResumeToken> {
+    if event_id.is_empty() || event_id.starts_with(LOCAL_EVENT_ID_PREFIX) {
+        return None;
+    }
+    bson::from_document(doc! { "_data": event_id }).ok()
+}
+
+/// Translate a change stream event into the bus representation
+fn event_from_change(
+    change: ChangeStreamEvent<User>,
+) -> Option<(String, UserEventKind, String, Option<UserResponse>)> {
+    let kind = match change.operation_type {
+        OperationType::Insert => UserEventKind::Created,
+        OperationType::Update | OperationType::Replace => UserEventKind::Updated,
+        OperationType::Delete => UserEventKind::Deleted,
+        _ => return None,
+    };
+    let id = event_id(&change.id)?;
+
+    let user_id = change
+        .document_key
+        .as_ref()
+        .and_then(|key| key.get_object_id("_id").ok())
+        .map(|id| id.to_hex())
+        .unwrap_or_default();
+
+    let user = match kind {
+        UserEventKind::Deleted => change.full_document_before_change,
+        _ => change.full_document,
+    };
+
+    Some((id, kind, user_id, user.map(UserResponse::from)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::oid::ObjectId;
+
+    fn test_user() -> User {
+        User::with_id(
+            ObjectId::new(),
+            "Event User".to_string(),
+            "event@example.com".to_string(),
+            chrono::Utc::now(),
+        )
+    }
+
+    fn missed_users(replay: Replay) -> Vec<String> {
+        match replay {
+            Replay::Missed(missed) => missed.into_iter().map(|event| event.user_id).collect(),
+            Replay::Unknown => panic!("expected buffered events"),
+        }
+    }
+
+    #[test]
+    fn test_publish_assigns_instance_scoped_ids() {
+        let bus = UserEventBus::new(10);
+
+        let first = bus.publish(UserEventKind::Created, "a".to_string(), None);
+        let second = bus.publish(UserEventKind::Updated, "a".to_string(), None);
+
+        assert_eq!(first.id, format!("local-{}-1", bus.instance_id));
+        assert_eq!(second.id, format!("local-{}-2", bus.instance_id));
+
+        // Another instance, or this one after a restart, never reuses an ID
+        let other = UserEventBus::new(10);
+        let other_first = other.publish(UserEventKind::Created, "a".to_string(), None);
+        assert_ne!(first.id, other_first.id);
+    }
+
+    #[test]
+    fn test_subscribe_replays_events_after_last_id() {
+        let bus = UserEventBus::new(10);
+        let first = bus.publish(UserEventKind::Created, "a".to_string(), None);
+        bus.publish(UserEventKind::Created, "b".to_string(), None);
+        bus.publish(UserEventKind::Created, "c".to_string(), None);
+
+        let (replay, _receiver) = bus.subscribe(Some(&first.id));
+        assert_eq!(missed_users(replay), vec!["b", "c"]);
+
+        let (replay, _receiver) = bus.subscribe(None);
+        assert!(missed_users(replay).is_empty());
+    }
+
+    #[test]
+    fn test_replay_buffer_is_bounded() {
+        let bus = UserEventBus::new(2);
+        let first = bus.publish(UserEventKind::Created, "a".to_string(), None);
+        let mut ids = Vec::new();
+        for user_id in ["b", "c", "d"] {
+            ids.push(
+                bus.publish(UserEventKind::Created, user_id.to_string(), None)
+                    .id,
+            );
+        }
+
+        let (replay, _receiver) = bus.subscribe(Some(&ids[1]));
+        assert_eq!(missed_users(replay), vec!["d"]);
+
+        // The first event has left the buffer, so the client has to resync
+        let (replay, _receiver) = bus.subscribe(Some(&first.id));
+        assert!(matches!(replay, Replay::Unknown));
+    }
+
+    #[test]
+    fn test_unknown_last_event_id() {
+        let bus = UserEventBus::new(10);
+        bus.publish(UserEventKind::Created, "a".to_string(), None);
+
+        // A sequence number from before the ID format changed, or from a restarted instance
+        let (replay, _receiver) = bus.subscribe(Some("1"));
+        assert!(matches!(replay, Replay::Unknown));
+    }
+
+    #[test]
+    fn test_change_stream_retry_delay_doubles_up_to_the_limit() {
+        assert_eq!(
+            next_retry_delay(CHANGE_STREAM_RETRY_DELAY),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            next_retry_delay(Duration::from_secs(40)),
+            MAX_CHANGE_STREAM_RETRY_DELAY
+        );
+        assert!(!is_history_lost(&MongoError::custom("connection closed")));
+    }
+
+    #[test]
+    fn test_resume_token_round_trip() {
+        let token = resume_token("8263F0A1B2000000012B0229296E04").unwrap();
+        assert_eq!(
+            event_id(&token).as_deref(),
+            Some("8263F0A1B2000000012B0229296E04")
+        );
+
+        assert!(resume_token("local-abc-1").is_none());
+        assert!(resume_token("").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_live_events_reach_subscribers() {
+        let bus = UserEventBus::new(10);
+        let (_, mut receiver) = bus.subscribe(None);
+
+        bus.publish_local(UserEventKind::Created, &test_user());
+
+        let event = receiver.recv().await.unwrap();
+        assert_eq!(event.kind, UserEventKind::Created);
+        assert_eq!(event.user.unwrap().name, "Event User");
+    }
+
+    #[test]
+    fn test_publish_local_is_skipped_while_change_stream_feeds_bus() {
+        let bus = UserEventBus::new(10);
+        bus.set_local_publishing(false);
+
+        bus.publish_local(UserEventKind::Created, &test_user());
+
+        let (replay, _receiver) = bus.subscribe(None);
+        assert!(missed_users(replay).is_empty());
+        assert!(bus.state.lock().unwrap().recent.is_empty());
+    }
+
+    #[test]
+    fn test_user_event_serialization() {
+        let bus = UserEventBus::new(10);
+        let event = bus.publish(UserEventKind::Deleted, "abc".to_string(), None);
+
+        let json_str = serde_json::to_string(&event).unwrap();
+        assert!(json_str.contains("\"type\":\"deleted\""));
+        assert!(json_str.contains("\"user_id\":\"abc\""));
+    }
+}
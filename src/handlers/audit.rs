@@ -0,0 +1,414 @@
+use chrono::DateTime;
+use futures::stream::StreamExt;
+use mongodb::bson::{self, doc, oid::ObjectId, Document};
+use mongodb::options::FindOptions;
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use crate::db::AUDIT_COLLECTION;
+use crate::handlers::pagination::{PageResponse, Pagination};
+use crate::handlers::users::{ErrorResponse, UserResponse};
+use crate::models::{AuditAction, AuditEntry, User};
+use crate::tls::ClientIdentity;
+
+/// Actor recorded when the request is not authenticated
+const ANONYMOUS_ACTOR: &str = "anonymous";
+
+/// Who performed a mutation and which request it belonged to
+#[derive(Debug, Clone)]
+pub struct AuditContext {
+    /// The authenticated caller, or `anonymous`
+    pub actor: String,
+    /// Who the caller says it acts for, from `X-Actor`; recorded as given, never verified
+    pub claimed_actor: Option<String>,
+    pub request_id: String,
+}
+
+impl AuditContext {
+    /// Build an unauthenticated context from the `X-Actor` and `X-Request-Id` headers,
+    /// generating a request ID if missing
+    pub fn new(claimed_actor: Option<String>, request_id: Option<String>) -> Self {
+        AuditContext {
+            actor: ANONYMOUS_ACTOR.to_string(),
+            claimed_actor: claimed_actor
+                .map(|actor| actor.trim().to_string())
+                .filter(|actor| !actor.is_empty()),
+            request_id: request_id
+                .map(|request_id| request_id.trim().to_string())
+                .filter(|request_id| !request_id.is_empty())
+                .unwrap_or_else(|| ObjectId::new().to_hex()),
+        }
+    }
+
+    /// Record `principal`, when the caller authenticated as one, as the actor
+    pub fn authenticated_as(mut self, principal: Option<String>) -> Self {
+        if let Some(principal) = principal {
+            self.actor = principal;
+        }
+        self
+    }
+
+    /// Create an audit entry for a user mutation performed in this context
+    pub fn entry(
+        &self,
+        action: AuditAction,
+        before: Option<&User>,
+        after: Option<&User>,
+    ) -> AuditEntry {
+        let mut entry = AuditEntry::for_user(
+            self.actor.clone(),
+            self.request_id.clone(),
+            action,
+            before,
+            after,
+        );
+        entry.claimed_actor = self.claimed_actor.clone();
+        entry
+    }
+}
+
+/// Extract the audit context from the client certificate and the request headers
+pub fn with_audit_context() -> impl Filter<Extract = (AuditContext,), Error = Rejection> + Clone {
+    warp::ext::optional::<ClientIdentity>()
+        .and(warp::header::optional::<String>("x-actor"))
+        .and(warp::header::optional::<String>("x-request-id"))
+        .map(
+            |identity: Option<ClientIdentity>, claimed_actor, request_id| {
+                AuditContext::new(claimed_actor, request_id)
+                    .authenticated_as(identity.map(|identity| identity.0))
+            },
+        )
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct AuditEntryResponse {
+    pub id: String,
+    pub actor: String,
+    pub claimed_actor: Option<String>,
+    pub request_id: String,
+    pub action: AuditAction,
+    pub resource_id: String,
+    pub before: Option<UserResponse>,
+    pub after: Option<UserResponse>,
+    pub changed_fields: Vec<String>,
+    pub timestamp: String,
+}
+
+impl From<AuditEntry> for AuditEntryResponse {
+    fn from(entry: AuditEntry) -> Self {
+        let snapshot = |document: Option<Document>| {
+            document
+                .and_then(|document| bson::from_document::<User>(document).ok())
+                .map(UserResponse::from)
+        };
+
+        AuditEntryResponse {
+            id: entry.id.map(|id| id.to_hex()).unwrap_or_default(),
+            actor: entry.actor,
+            claimed_actor: entry.claimed_actor,
+            request_id: entry.request_id,
+            action: entry.action,
+            resource_id: entry.resource_id,
+            before: snapshot(entry.before),
+            after: snapshot(entry.after),
+            changed_fields: entry.changed_fields,
+            timestamp: entry.timestamp.try_to_rfc3339_string().unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    pub actor: Option<String>,
+    pub claimed_actor: Option<String>,
+    pub action: Option<String>,
+    pub resource_id: Option<String>,
+    pub request_id: Option<String>,
+    /// Inclusive lower bound, RFC 3339
+    pub from: Option<String>,
+    /// Exclusive upper bound, RFC 3339
+    pub to: Option<String>,
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+}
+
+impl AuditQuery {
+    /// Translate the query parameters into a MongoDB filter
+    pub fn to_filter(&self) -> Result<Document, String> {
+        let mut filter = doc! {};
+
+        if let Some(actor) = &self.actor {
+            filter.insert("actor", actor);
+        }
+        if let Some(claimed_actor) = &self.claimed_actor {
+            filter.insert("claimed_actor", claimed_actor);
+        }
+        if let Some(action) = &self.action {
+            let action = match action.as_str() {
+                "create" => AuditAction::Create,
+                "update" => AuditAction::Update,
+                "delete" => AuditAction::Delete,
+                other => return Err(format!("Unknown action '{}'", other)),
+            };
+            filter.insert("action", action.as_str());
+        }
+        if let Some(resource_id) = &self.resource_id {
+            filter.insert("resource_id", resource_id);
+        }
+        if let Some(request_id) = &self.request_id {
+            filter.insert("request_id", request_id);
+        }
+
+        let mut timestamp = doc! {};
+        if let Some(from) = &self.from {
+            timestamp.insert("$gte", parse_timestamp("from", from)?);
+        }
+        if let Some(to) = &self.to {
+            timestamp.insert("$lt", parse_timestamp("to", to)?);
+        }
+        if !timestamp.is_empty() {
+            filter.insert("timestamp", timestamp);
+        }
+
+        Ok(filter)
+    }
+}
+
+fn parse_timestamp(name: &str, value: &str) -> Result<bson::DateTime, String> {
+    DateTime::parse_from_rfc3339(value)
+        .map(|timestamp| bson::DateTime::from_millis(timestamp.timestamp_millis()))
+        .map_err(|_| format!("'{}' must be an RFC 3339 timestamp", name))
+}
+
+/// Get the audit history of a single user, newest first
+#[utoipa::path(
+    get,
+    path = "/users/{id}/history",
+    tag = "audit",
+    params(("id" = String, Path, description = "User ID"), HistoryQuery),
+    responses(
+        (status = 200, description = "Audit entries of the user", body = PageResponse<AuditEntryResponse>),
+        (status = 400, description = "Invalid user ID", body = ErrorResponse)
+    )
+)]
+pub async fn get_user_history(
+    id: String,
+    query: HistoryQuery,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    if ObjectId::parse_str(&id).is_err() {
+        let error_response = ErrorResponse {
+            error: "invalid_id".to_string(),
+            message: "Invalid user ID format".to_string(),
+        };
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&error_response),
+            StatusCode::BAD_REQUEST,
+        ));
+    }
+
+    let pagination = Pagination::new(query.page, query.per_page);
+    Ok(list_audit_entries(&db, doc! { "resource_id": id }, pagination).await)
+}
+
+/// List audit entries matching the query filters, newest first
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "audit",
+    params(AuditQuery),
+    responses(
+        (status = 200, description = "Matching audit entries", body = PageResponse<AuditEntryResponse>),
+        (status = 400, description = "Invalid filter", body = ErrorResponse)
+    )
+)]
+pub async fn get_audit_log(query: AuditQuery, db: Arc<Database>) -> Result<impl Reply, Rejection> {
+    let filter = match query.to_filter() {
+        Ok(filter) => filter,
+        Err(message) => {
+            let error_response = ErrorResponse {
+                error: "validation_error".to_string(),
+                message,
+            };
+            return Ok(warp::reply::with_status(
+                warp::reply::json(&error_response),
+                StatusCode::BAD_REQUEST,
+            ));
+        }
+    };
+
+    let pagination = Pagination::new(query.page, query.per_page);
+    Ok(list_audit_entries(&db, filter, pagination).await)
+}
+
+async fn list_audit_entries(
+    db: &Database,
+    filter: Document,
+    pagination: Pagination,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let collection: Collection<AuditEntry> = db.collection(AUDIT_COLLECTION);
+
+    let total = match collection.count_documents(filter.clone(), None).await {
+        Ok(total) => total,
+        Err(_) => return audit_database_error(),
+    };
+
+    let options = FindOptions::builder()
+        .sort(doc! { "timestamp": -1, "_id": -1 })
+        .skip(pagination.skip())
+        .limit(pagination.per_page as i64)
+        .build();
+
+    match collection.find(filter, options).await {
+        Ok(mut cursor) => {
+            let mut entries = Vec::new();
+
+            while let Some(result) = cursor.next().await {
+                match result {
+                    Ok(entry) => entries.push(AuditEntryResponse::from(entry)),
+                    Err(_) => return audit_database_error(),
+                }
+            }
+
+            warp::reply::with_status(
+                warp::reply::json(&PageResponse::new(entries, pagination, total)),
+                StatusCode::OK,
+            )
+        }
+        Err(_) => audit_database_error(),
+    }
+}
+
+fn audit_database_error() -> warp::reply::WithStatus<warp::reply::Json> {
+    let error_response = ErrorResponse {
+        error: "database_error".to_string(),
+        message: "Failed to fetch audit log from database".to_string(),
+    };
+    warp::reply::with_status(
+        warp::reply::json(&error_response),
+        StatusCode::INTERNAL_SERVER_ERROR,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    #[test]
+    fn test_audit_context_defaults() {
+        let context = AuditContext::new(None, Some("  ".to_string()));
+
+        assert_eq!(context.actor, ANONYMOUS_ACTOR);
+        assert!(context.claimed_actor.is_none());
+        // A blank request ID is replaced by a generated one
+        assert_eq!(context.request_id.len(), 24);
+    }
+
+    #[test]
+    fn test_audit_context_from_headers() {
+        let context = AuditContext::new(
+            Some("admin@example.com".to_string()),
+            Some("req-42".to_string()),
+        );
+
+        // The header is only a claim
+        assert_eq!(context.actor, ANONYMOUS_ACTOR);
+        assert_eq!(context.claimed_actor.as_deref(), Some("admin@example.com"));
+        assert_eq!(context.request_id, "req-42");
+
+        let context = context.authenticated_as(Some("CN=support-tool".to_string()));
+        assert_eq!(context.actor, "CN=support-tool");
+        assert_eq!(context.claimed_actor.as_deref(), Some("admin@example.com"));
+    }
+
+    #[tokio::test]
+    async fn test_with_audit_context_filter() {
+        let context = warp::test::request()
+            .header("x-actor", "support")
+            .header("x-request-id", "req-1")
+            .filter(&with_audit_context())
+            .await
+            .unwrap();
+
+        assert_eq!(context.actor, ANONYMOUS_ACTOR);
+        assert_eq!(context.claimed_actor.as_deref(), Some("support"));
+        assert_eq!(context.request_id, "req-1");
+
+        let context = warp::test::request()
+            .header("x-actor", "support")
+            .extension(ClientIdentity("CN=client".to_string()))
+            .filter(&with_audit_context())
+            .await
+            .unwrap();
+        assert_eq!(context.actor, "CN=client");
+        assert_eq!(context.claimed_actor.as_deref(), Some("support"));
+    }
+
+    #[test]
+    fn test_audit_query_to_filter() {
+        let query = AuditQuery {
+            actor: Some("admin".to_string()),
+            action: Some("update".to_string()),
+            from: Some("2024-01-01T00:00:00Z".to_string()),
+            ..Default::default()
+        };
+
+        let filter = query.to_filter().unwrap();
+        assert_eq!(filter.get_str("actor").unwrap(), "admin");
+        assert_eq!(filter.get_str("action").unwrap(), "update");
+        assert!(filter
+            .get_document("timestamp")
+            .unwrap()
+            .contains_key("$gte"));
+    }
+
+    #[test]
+    fn test_audit_query_rejects_invalid_values() {
+        let bad_action = AuditQuery {
+            action: Some("rename".to_string()),
+            ..Default::default()
+        };
+        assert!(bad_action.to_filter().is_err());
+
+        let bad_date = AuditQuery {
+            to: Some("yesterday".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            bad_date.to_filter().unwrap_err(),
+            "'to' must be an RFC 3339 timestamp"
+        );
+    }
+
+    #[test]
+    fn test_audit_entry_response_from_entry() {
+        let user = User::with_id(
+            ObjectId::new(),
+            "History User".to_string(),
+            "history@example.com".to_string(),
+            Utc::now(),
+        );
+        let entry = AuditContext::new(Some("admin".to_string()), None)
+            .authenticated_as(Some("CN=admin-console".to_string()))
+            .entry(AuditAction::Create, None, Some(&user));
+
+        let response = AuditEntryResponse::from(entry);
+        assert_eq!(response.actor, "CN=admin-console");
+        assert_eq!(response.claimed_actor.as_deref(), Some("admin"));
+        assert_eq!(response.resource_id, user.id.unwrap().to_hex());
+        assert!(response.before.is_none());
+        assert_eq!(response.after.unwrap().name, "History User");
+    }
+}
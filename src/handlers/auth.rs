@@ -0,0 +1,843 @@
+//! Passwords and sessions: `POST /auth/login` opens a session,
+//! `GET /auth/session` reads it back, `POST /auth/password-reset` mails a
+//! single-use reset link and `POST /auth/password-reset/confirm` sets a new password
+//!
+//! Passwords are hashed with Argon2id. Session and reset tokens are random, and
+//! only their SHA-256 hashes are stored. A user without a password sets their
+//! first one through a reset.
+
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use chrono::{DateTime, Utc};
+use mongodb::Database;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::env;
+use std::sync::{Arc, OnceLock};
+use std::time::Duration;
+use utoipa::ToSchema;
+use warp::http::{header, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+use crate::db::{
+    claim_password_reset, find_credential, find_session, find_users_by_email, hash_token,
+    insert_password_reset_token, insert_session, redeem_password_reset_token,
+    release_password_reset, Outbox,
+};
+use crate::handlers::idempotency::{request_fingerprint, with_idempotency};
+use crate::handlers::negotiation::Format;
+use crate::handlers::users::{find_user, ErrorResponse, UserResponse};
+use crate::handlers::users_v2::UserV2Response;
+use crate::mailer::{Email, Mailer};
+use crate::models::{PasswordResetToken, Session, User, UserStatus};
+
+/// Default lifetime of a session
+const DEFAULT_SESSION_TTL_SECONDS: u64 = 86400;
+
+/// Default lifetime of a password reset link
+const DEFAULT_PASSWORD_RESET_TTL_SECONDS: u64 = 3600;
+
+/// Default shortest time between two reset links mailed to the same user
+const DEFAULT_PASSWORD_RESET_INTERVAL_SECONDS: u64 = 60;
+
+/// Default page the reset link points at, which posts the new password to
+/// `/auth/password-reset/confirm`
+const DEFAULT_PASSWORD_RESET_URL: &str = "http://localhost:3030/reset-password";
+
+/// Shortest password accepted, in characters
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Longest password accepted, in characters
+pub const MAX_PASSWORD_LENGTH: usize = 128;
+
+/// Random bytes in a session or reset token
+const TOKEN_BYTES: usize = 32;
+
+/// Why signing in, reading a session or resetting a password failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthError {
+    /// Unknown email, wrong password, no password set, or a suspended or archived user
+    InvalidCredentials,
+    /// Missing, unknown or expired session token
+    Unauthorized,
+    /// Unknown, expired or already used reset token
+    InvalidToken,
+    Validation(Cow<'static, str>),
+    Database(&'static str),
+    /// Hashing a password failed
+    Internal,
+}
+
+impl AuthError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AuthError::InvalidCredentials => "invalid_credentials",
+            AuthError::Unauthorized => "unauthorized",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::Validation(_) => "validation_error",
+            AuthError::Database(_) => "database_error",
+            AuthError::Internal => "internal_error",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AuthError::InvalidCredentials | AuthError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AuthError::InvalidToken | AuthError::Validation(_) => StatusCode::BAD_REQUEST,
+            AuthError::Database(_) | AuthError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            AuthError::InvalidCredentials => "The email or password is incorrect",
+            AuthError::Unauthorized => "A valid session token is required",
+            AuthError::InvalidToken => {
+                "The password reset link is invalid, expired or already used"
+            }
+            AuthError::Validation(message) => message,
+            AuthError::Database(message) => message,
+            AuthError::Internal => "Failed to process the password",
+        }
+    }
+
+    fn reply(&self, format: Format) -> Response {
+        let error_response = ErrorResponse {
+            error: self.code().to_string(),
+            message: self.message().to_string(),
+        };
+        let response =
+            warp::reply::with_status(format.reply("error", &error_response), self.status());
+        match self {
+            AuthError::Unauthorized => {
+                warp::reply::with_header(response, header::WWW_AUTHENTICATE, "Bearer")
+                    .into_response()
+            }
+            _ => response.into_response(),
+        }
+    }
+}
+
+/// Session and reset link lifetimes, how often reset links may be mailed, and
+/// the mailer they go out through
+pub struct AuthConfig {
+    session_ttl: Duration,
+    reset_ttl: Duration,
+    reset_interval: Duration,
+    reset_url: String,
+    mailer: Arc<dyn Mailer>,
+}
+
+impl AuthConfig {
+    pub fn new(
+        session_ttl: Duration,
+        reset_ttl: Duration,
+        reset_interval: Duration,
+        reset_url: String,
+        mailer: Arc<dyn Mailer>,
+    ) -> Self {
+        AuthConfig {
+            session_ttl,
+            reset_ttl,
+            reset_interval,
+            reset_url,
+            mailer,
+        }
+    }
+
+    /// Configure from `SESSION_TTL_SECONDS`, `PASSWORD_RESET_TTL_SECONDS`,
+    /// `PASSWORD_RESET_INTERVAL_SECONDS` and `PASSWORD_RESET_URL`
+    pub fn from_env(mailer: Arc<dyn Mailer>) -> Self {
+        fn seconds_or(name: &str, default: u64) -> Duration {
+            let seconds = env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|seconds| *seconds > 0)
+                .unwrap_or(default);
+            Duration::from_secs(seconds)
+        }
+
+        AuthConfig::new(
+            seconds_or("SESSION_TTL_SECONDS", DEFAULT_SESSION_TTL_SECONDS),
+            seconds_or(
+                "PASSWORD_RESET_TTL_SECONDS",
+                DEFAULT_PASSWORD_RESET_TTL_SECONDS,
+            ),
+            seconds_or(
+                "PASSWORD_RESET_INTERVAL_SECONDS",
+                DEFAULT_PASSWORD_RESET_INTERVAL_SECONDS,
+            ),
+            env::var("PASSWORD_RESET_URL")
+                .unwrap_or_else(|_| DEFAULT_PASSWORD_RESET_URL.to_string()),
+            mailer,
+        )
+    }
+
+    /// Whether a reset link may be mailed to a user last sent one at `requested_at`
+    fn reset_allowed(&self, requested_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        let Some(requested_at) = requested_at else {
+            return true;
+        };
+        (now - requested_at).to_std().unwrap_or_default() >= self.reset_interval
+    }
+
+    /// The message carrying a reset link for `user`
+    fn reset_email(&self, user: &User, token: &str, expires_at: DateTime<Utc>) -> Email {
+        Email {
+            to: user.email.clone(),
+            subject: "Reset your password".to_string(),
+            body: format!(
+                "Hi {},\n\nSomeone asked to reset the password for {}. Open this link to choose a new one:\n\n\
+                 {}?token={}\n\n\
+                 The link works once and expires at {}. If you did not ask, you can ignore this email; \
+                 your password has not changed.\n",
+                user.name,
+                user.email,
+                self.reset_url,
+                token,
+                expires_at.to_rfc3339()
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct LoginRequest {
+    pub email: String,
+    pub password: String,
+}
+
+/// A new session; `token` is only returned here
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct SessionResponse {
+    /// Sent back as `Authorization: Bearer <token>`
+    pub token: String,
+    pub user_id: String,
+    /// RFC 3339
+    pub expires_at: String,
+}
+
+/// A new session in v2, with camelCase fields; `token` is only returned here
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionV2Response {
+    /// Sent back as `Authorization: Bearer <token>`
+    pub token: String,
+    pub user_id: String,
+    /// RFC 3339
+    pub expires_at: String,
+}
+
+impl From<SessionResponse> for SessionV2Response {
+    fn from(session: SessionResponse) -> Self {
+        SessionV2Response {
+            token: session.token,
+            user_id: session.user_id,
+            expires_at: session.expires_at,
+        }
+    }
+}
+
+/// The session a bearer token belongs to
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct CurrentSession {
+    pub user: UserResponse,
+    /// RFC 3339
+    pub expires_at: String,
+}
+
+/// The session a bearer token belongs to in v2, with camelCase fields
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct CurrentSessionV2 {
+    pub user: UserV2Response,
+    /// RFC 3339
+    pub expires_at: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct PasswordResetRequest {
+    pub email: String,
+}
+
+#[derive(Deserialize, Debug, ToSchema)]
+pub struct PasswordResetConfirmRequest {
+    /// The token from the reset link
+    pub token: String,
+    pub password: String,
+}
+
+/// Check the length of a new password
+pub fn validate_password(password: &str) -> Result<(), AuthError> {
+    let length = password.chars().count();
+    if length < MIN_PASSWORD_LENGTH {
+        return Err(AuthError::Validation(
+            format!(
+                "Password must be at least {} characters",
+                MIN_PASSWORD_LENGTH
+            )
+            .into(),
+        ));
+    }
+    if length > MAX_PASSWORD_LENGTH {
+        return Err(AuthError::Validation(
+            format!(
+                "Password must be at most {} characters",
+                MAX_PASSWORD_LENGTH
+            )
+            .into(),
+        ));
+    }
+    Ok(())
+}
+
+/// A random session or reset token
+fn new_token() -> String {
+    let mut token = [0u8; TOKEN_BYTES];
+    rand::thread_rng().fill_bytes(&mut token);
+    hex::encode(token)
+}
+
+/// Hash a password with Argon2id and a random salt, in PHC string format
+fn hash_password(password: &str) -> Result<String, AuthError> {
+    let mut salt = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let salt = SaltString::encode_b64(&salt).map_err(|_| AuthError::Internal)?;
+
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|_| AuthError::Internal)
+}
+
+/// Check a password against a stored hash; a malformed hash matches nothing
+fn verify_password(hash: &str, password: &str) -> bool {
+    PasswordHash::new(hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    })
+}
+
+/// A hash checked when no user can sign in with an email, so unknown addresses
+/// take as long to refuse as wrong passwords
+fn dummy_hash() -> &'static str {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    DUMMY_HASH.get_or_init(|| hash_password("not a real password").unwrap_or_default())
+}
+
+/// Run Argon2 off the async worker threads
+async fn blocking<T, F>(work: F) -> Result<T, AuthError>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|_| AuthError::Internal)
+}
+
+/// The token of an `Authorization: Bearer <token>` header
+fn bearer_token(authorization: Option<&str>) -> Option<&str> {
+    authorization
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::trim)
+        .filter(|token| !token.is_empty())
+}
+
+/// Sign in with an email and password
+///
+/// Suspended and archived users, and users who have not set a password yet,
+/// are refused like a wrong password.
+#[utoipa::path(
+    post,
+    path = "/auth/login",
+    tag = "auth",
+    request_body = LoginRequest,
+    responses(
+        (status = 201, description = "Session opened", body = SessionResponse),
+        (status = 401, description = "Wrong email or password", body = ErrorResponse)
+    )
+)]
+pub async fn login(
+    request: LoginRequest,
+    format: Format,
+    config: Arc<AuthConfig>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match sign_in(request, &config, &db).await {
+        Ok(session) => Ok(warp::reply::with_status(
+            format.reply("session", &session),
+            StatusCode::CREATED,
+        )
+        .into_response()),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+/// Sign in with an email and password
+///
+/// Suspended and archived users, and users who have not set a password yet,
+/// are refused like a wrong password.
+#[utoipa::path(
+    post,
+    path = "/v2/auth/login",
+    tag = "auth",
+    request_body = LoginRequest,
+    responses(
+        (status = 201, description = "Session opened", body = SessionV2Response),
+        (status = 401, description = "Wrong email or password", body = ErrorResponse)
+    )
+)]
+pub async fn login_v2(
+    request: LoginRequest,
+    format: Format,
+    config: Arc<AuthConfig>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match sign_in(request, &config, &db).await {
+        Ok(session) => Ok(warp::reply::with_status(
+            format.reply("session", &SessionV2Response::from(session)),
+            StatusCode::CREATED,
+        )
+        .into_response()),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+async fn sign_in(
+    request: LoginRequest,
+    config: &AuthConfig,
+    db: &Database,
+) -> Result<SessionResponse, AuthError> {
+    let users = find_users_by_email(db, request.email.trim())
+        .await
+        .map_err(|_| AuthError::Database("Failed to look up the user"))?;
+
+    let mut candidates = Vec::new();
+    for user in users {
+        let (Some(user_id), UserStatus::Pending | UserStatus::Active) = (user.id, user.status)
+        else {
+            continue;
+        };
+        if let Some(credential) = find_credential(db, user_id)
+            .await
+            .map_err(|_| AuthError::Database("Failed to look up the user"))?
+        {
+            candidates.push((user_id, credential.password_hash));
+        }
+    }
+
+    let password = request.password;
+    let user_id = blocking(move || {
+        if candidates.is_empty() {
+            verify_password(dummy_hash(), &password);
+            return None;
+        }
+        candidates
+            .into_iter()
+            .find(|(_, hash)| verify_password(hash, &password))
+            .map(|(user_id, _)| user_id)
+    })
+    .await?
+    .ok_or(AuthError::InvalidCredentials)?;
+
+    let token = new_token();
+    let session = Session::new(hash_token(&token), user_id, config.session_ttl);
+    insert_session(db, &session)
+        .await
+        .map_err(|_| AuthError::Database("Failed to open the session"))?;
+
+    Ok(SessionResponse {
+        token,
+        user_id: user_id.to_hex(),
+        expires_at: session
+            .expires_at
+            .try_to_rfc3339_string()
+            .unwrap_or_default(),
+    })
+}
+
+/// Read the session of a bearer token
+#[utoipa::path(
+    get,
+    path = "/auth/session",
+    tag = "auth",
+    params(("Authorization" = String, Header, description = "`Bearer <token>` from `POST /auth/login`")),
+    responses(
+        (status = 200, description = "The signed-in user", body = CurrentSession),
+        (status = 401, description = "Missing, unknown or expired token", body = ErrorResponse)
+    )
+)]
+pub async fn get_session(
+    authorization: Option<String>,
+    format: Format,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match current_session(authorization.as_deref(), &db).await {
+        Ok((user, expires_at)) => Ok(warp::reply::with_status(
+            format.reply(
+                "session",
+                &CurrentSession {
+                    user: UserResponse::from(user),
+                    expires_at,
+                },
+            ),
+            StatusCode::OK,
+        )
+        .into_response()),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+/// Read the session of a bearer token
+#[utoipa::path(
+    get,
+    path = "/v2/auth/session",
+    tag = "auth",
+    params(("Authorization" = String, Header, description = "`Bearer <token>` from `POST /v2/auth/login`")),
+    responses(
+        (status = 200, description = "The signed-in user", body = CurrentSessionV2),
+        (status = 401, description = "Missing, unknown or expired token", body = ErrorResponse)
+    )
+)]
+pub async fn get_session_v2(
+    authorization: Option<String>,
+    format: Format,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match current_session(authorization.as_deref(), &db).await {
+        Ok((user, expires_at)) => Ok(warp::reply::with_status(
+            format.reply(
+                "session",
+                &CurrentSessionV2 {
+                    user: UserV2Response::from(user),
+                    expires_at,
+                },
+            ),
+            StatusCode::OK,
+        )
+        .into_response()),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+/// The user a bearer token's session belongs to, and when the session expires
+async fn current_session(
+    authorization: Option<&str>,
+    db: &Database,
+) -> Result<(User, String), AuthError> {
+    let token = bearer_token(authorization).ok_or(AuthError::Unauthorized)?;
+    let session = find_session(db, &hash_token(token))
+        .await
+        .map_err(|_| AuthError::Database("Failed to look up the session"))?
+        .ok_or(AuthError::Unauthorized)?;
+    // A session outliving its user authenticates nobody
+    let user = find_user(db, session.user_id)
+        .await
+        .map_err(|_| AuthError::Database("Failed to look up the session"))?
+        .ok_or(AuthError::Unauthorized)?;
+
+    let expires_at = session
+        .expires_at
+        .try_to_rfc3339_string()
+        .unwrap_or_default();
+    Ok((user, expires_at))
+}
+
+/// Mail a password reset link
+///
+/// Always answers 202, whether or not a user has the email, and mails the link
+/// after answering so the response time does not tell either.
+#[utoipa::path(
+    post,
+    path = "/auth/password-reset",
+    tag = "auth",
+    request_body = PasswordResetRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe")
+    ),
+    responses(
+        (status = 202, description = "A link is mailed if a user has the email"),
+        (status = 409, description = "A request with the same key is in progress", body = ErrorResponse),
+        (status = 422, description = "The key was used with a different request", body = ErrorResponse)
+    )
+)]
+pub async fn request_password_reset(
+    idempotency_key: Option<String>,
+    request: PasswordResetRequest,
+    raw_body: Bytes,
+    config: Arc<AuthConfig>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let fingerprint = request_fingerprint("POST", "/auth/password-reset", &raw_body);
+
+    with_idempotency(
+        db.clone(),
+        idempotency_key,
+        "POST /auth/password-reset",
+        fingerprint,
+        start_password_reset(request, config, db),
+    )
+    .await
+}
+
+async fn start_password_reset(
+    request: PasswordResetRequest,
+    config: Arc<AuthConfig>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    tokio::spawn(async move {
+        send_reset_links(request.email.trim(), &config, &db).await;
+    });
+    Ok(StatusCode::ACCEPTED.into_response())
+}
+
+/// Mail a fresh token to every user with the email, skipping users mailed one
+/// within the reset interval
+async fn send_reset_links(email: &str, config: &AuthConfig, db: &Database) {
+    let users = match find_users_by_email(db, email).await {
+        Ok(users) => users,
+        Err(e) => {
+            eprintln!("Failed to look up users for a password reset: {}", e);
+            return;
+        }
+    };
+
+    for user in users {
+        let Some(user_id) = user.id else { continue };
+        let now = Utc::now();
+        if !config.reset_allowed(user.password_reset_requested_at, now) {
+            continue;
+        }
+        match claim_password_reset(db, &user, now).await {
+            Ok(true) => {}
+            // A concurrent request for the same user got there first
+            Ok(false) => continue,
+            Err(e) => {
+                eprintln!(
+                    "Failed to record a password reset request for user {}: {}",
+                    user_id, e
+                );
+                continue;
+            }
+        }
+
+        let token = new_token();
+        let record = PasswordResetToken::new(hash_token(&token), user_id, config.reset_ttl);
+        if let Err(e) = insert_password_reset_token(db, &record).await {
+            eprintln!(
+                "Failed to store a password reset token for user {}: {}",
+                user_id, e
+            );
+            let _ = release_password_reset(db, &user, now).await;
+            continue;
+        }
+
+        let email = config.reset_email(
+            &user,
+            &token,
+            DateTime::<Utc>::from_timestamp_millis(record.expires_at.timestamp_millis())
+                .unwrap_or_default(),
+        );
+        if let Err(e) = config.mailer.send(&email).await {
+            eprintln!(
+                "Failed to send password reset email to user {}: {}",
+                user_id, e
+            );
+            let _ = release_password_reset(db, &user, now).await;
+        }
+    }
+}
+
+/// Set a new password with a token from a reset link
+///
+/// The token and any other unused ones for the user stop working, and every
+/// session of the user is closed.
+#[utoipa::path(
+    post,
+    path = "/auth/password-reset/confirm",
+    tag = "auth",
+    request_body = PasswordResetConfirmRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe")
+    ),
+    responses(
+        (status = 204, description = "Password changed; existing sessions are closed"),
+        (status = 400, description = "Invalid, expired or used token, or an unacceptable password", body = ErrorResponse),
+        (status = 409, description = "A request with the same key is in progress", body = ErrorResponse),
+        (status = 422, description = "The key was used with a different request", body = ErrorResponse)
+    )
+)]
+pub async fn confirm_password_reset(
+    idempotency_key: Option<String>,
+    request: PasswordResetConfirmRequest,
+    raw_body: Bytes,
+    format: Format,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let fingerprint = request_fingerprint("POST", "/auth/password-reset/confirm", &raw_body);
+
+    with_idempotency(
+        db.clone(),
+        idempotency_key,
+        "POST /auth/password-reset/confirm",
+        fingerprint,
+        confirm_reset(request, format, outbox, db),
+    )
+    .await
+}
+
+async fn confirm_reset(
+    request: PasswordResetConfirmRequest,
+    format: Format,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match reset_password(request, &outbox, &db).await {
+        Ok(()) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+async fn reset_password(
+    request: PasswordResetConfirmRequest,
+    outbox: &Outbox,
+    db: &Database,
+) -> Result<(), AuthError> {
+    validate_password(&request.password)?;
+    let password = request.password;
+    let password_hash = blocking(move || hash_password(&password)).await??;
+
+    match redeem_password_reset_token(
+        db,
+        outbox,
+        &hash_token(request.token.trim()),
+        &password_hash,
+    )
+    .await
+    {
+        Ok(Some(_)) => Ok(()),
+        Ok(None) => Err(AuthError::InvalidToken),
+        Err(_) => Err(AuthError::Database("Failed to reset the password")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::MemoryMailer;
+    use mongodb::bson::oid::ObjectId;
+
+    fn config(mailer: Arc<MemoryMailer>) -> AuthConfig {
+        AuthConfig::new(
+            Duration::from_secs(60),
+            Duration::from_secs(3600),
+            Duration::from_secs(60),
+            "https://app.example.com/reset".to_string(),
+            mailer,
+        )
+    }
+
+    #[test]
+    fn test_reset_links_are_spaced_out() {
+        let config = config(Arc::new(MemoryMailer::default()));
+        let now = Utc::now();
+
+        assert!(config.reset_allowed(None, now));
+        assert!(!config.reset_allowed(Some(now), now));
+        assert!(!config.reset_allowed(Some(now - chrono::Duration::seconds(59)), now));
+        assert!(config.reset_allowed(Some(now - chrono::Duration::seconds(60)), now));
+    }
+
+    #[test]
+    fn test_password_hash_round_trip() {
+        let hash = hash_password("correct horse").unwrap();
+
+        assert!(hash.starts_with("$argon2id$"));
+        assert!(verify_password(&hash, "correct horse"));
+        assert!(!verify_password(&hash, "battery staple"));
+        assert!(!verify_password("not a hash", "correct horse"));
+        // Salted, so the same password hashes differently each time
+        assert_ne!(hash_password("correct horse").unwrap(), hash);
+        assert!(!verify_password(dummy_hash(), ""));
+    }
+
+    #[test]
+    fn test_password_length() {
+        assert_eq!(validate_password("12345678"), Ok(()));
+        assert_eq!(validate_password(&"x".repeat(MAX_PASSWORD_LENGTH)), Ok(()));
+
+        let short = validate_password("1234567").unwrap_err();
+        assert_eq!(short.code(), "validation_error");
+        assert_eq!(short.message(), "Password must be at least 8 characters");
+        let long = validate_password(&"x".repeat(MAX_PASSWORD_LENGTH + 1)).unwrap_err();
+        assert_eq!(long.message(), "Password must be at most 128 characters");
+        // Characters, not bytes
+        assert_eq!(validate_password("ééééééé").unwrap_err(), short);
+    }
+
+    #[test]
+    fn test_tokens_are_random() {
+        let token = new_token();
+
+        assert_eq!(token.len(), TOKEN_BYTES * 2);
+        assert_ne!(new_token(), token);
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        assert_eq!(bearer_token(Some("Bearer abc")), Some("abc"));
+        assert_eq!(bearer_token(Some("Basic abc")), None);
+        assert_eq!(bearer_token(Some("Bearer ")), None);
+        assert_eq!(bearer_token(None), None);
+    }
+
+    #[tokio::test]
+    async fn test_reset_email_carries_the_token() {
+        let mailer = Arc::new(MemoryMailer::default());
+        let config = config(mailer.clone());
+        let user = User::with_id(
+            ObjectId::new(),
+            "Ada".to_string(),
+            "ada@example.com".to_string(),
+            Utc::now(),
+        );
+        let token = new_token();
+
+        config
+            .mailer
+            .send(&config.reset_email(&user, &token, Utc::now()))
+            .await
+            .unwrap();
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "ada@example.com");
+        assert!(sent[0]
+            .body
+            .lines()
+            .any(|line| line == format!("https://app.example.com/reset?token={}", token)));
+    }
+
+    #[test]
+    fn test_error_statuses() {
+        assert_eq!(
+            AuthError::InvalidCredentials.status(),
+            StatusCode::UNAUTHORIZED
+        );
+        assert_eq!(AuthError::Unauthorized.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(AuthError::InvalidToken.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(
+            AuthError::Unauthorized
+                .reply(Format::Json)
+                .headers()
+                .get(header::WWW_AUTHENTICATE)
+                .unwrap(),
+            "Bearer"
+        );
+    }
+}
@@ -0,0 +1,623 @@
+//! User avatars: `PUT /users/{id}/avatar` uploads an image as multipart form
+//! data and `GET /users/{id}/avatar?size=` serves it.
+//!
+//! The image type is detected from its bytes, whatever the upload claims. Every
+//! upload is decoded and re-encoded into square thumbnails of the standard sizes,
+//! so what is served never carries the uploaded file's metadata. The files live
+//! in GridFS and are removed by an outbox sink when the user is deleted.
+
+use chrono::{DateTime, Utc};
+use futures::TryStreamExt;
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageError, ImageFormat, ImageReader, Limits};
+use mongodb::bson::Bson;
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::env;
+use std::io::Cursor;
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+use warp::http::{header, StatusCode};
+use warp::multipart::{FormData, Part};
+use warp::reply::Response;
+use warp::{Buf, Rejection, Reply};
+
+use crate::db::{delete_avatars, find_avatar, read_avatar, store_avatar, AvatarFile};
+use crate::handlers::conditional::if_none_match_tag;
+use crate::handlers::negotiation::Format;
+use crate::handlers::users::{find_user, parse_user_id, ErrorResponse, UserError};
+
+/// Widths and heights of the stored thumbnails, in pixels
+pub const AVATAR_SIZES: [u32; 4] = [64, 128, 256, 512];
+
+/// Size served when `size` is not given
+const DEFAULT_AVATAR_SIZE: u32 = 256;
+
+/// Uploads wider or taller than this are refused before being decoded
+const MAX_AVATAR_DIMENSION: u32 = 4096;
+
+/// JPEG quality of thumbnails of JPEG uploads
+const AVATAR_JPEG_QUALITY: u8 = 85;
+
+/// Default time clients and proxies may keep an avatar before revalidating
+const DEFAULT_AVATAR_CACHE_SECONDS: u64 = 3600;
+
+/// Multipart field carrying the image
+const AVATAR_FIELD: &str = "avatar";
+
+/// Why uploading or serving an avatar failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AvatarError {
+    /// No `avatar` field in the form
+    MissingImage,
+    TooLarge,
+    /// Not a PNG, JPEG, GIF or WebP image
+    UnsupportedType,
+    /// The bytes could not be decoded as the detected type
+    InvalidImage,
+    /// Wider or taller than `MAX_AVATAR_DIMENSION`
+    TooManyPixels,
+    InvalidSize,
+    NotFound,
+    User(UserError),
+}
+
+impl From<UserError> for AvatarError {
+    fn from(error: UserError) -> Self {
+        AvatarError::User(error)
+    }
+}
+
+impl AvatarError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            AvatarError::MissingImage | AvatarError::InvalidSize => "validation_error",
+            AvatarError::TooLarge => "payload_too_large",
+            AvatarError::UnsupportedType => "unsupported_media_type",
+            AvatarError::InvalidImage | AvatarError::TooManyPixels => "invalid_image",
+            AvatarError::NotFound => "not_found",
+            AvatarError::User(error) => error.code(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AvatarError::MissingImage
+            | AvatarError::InvalidSize
+            | AvatarError::InvalidImage
+            | AvatarError::TooManyPixels => StatusCode::BAD_REQUEST,
+            AvatarError::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            AvatarError::UnsupportedType => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            AvatarError::NotFound => StatusCode::NOT_FOUND,
+            AvatarError::User(error) => error.status(),
+        }
+    }
+
+    pub fn message(&self) -> Cow<'_, str> {
+        match self {
+            AvatarError::MissingImage => {
+                "Send the image in a multipart field named 'avatar'".into()
+            }
+            AvatarError::TooLarge => "The image is too large".into(),
+            AvatarError::UnsupportedType => "Avatars must be PNG, JPEG, GIF or WebP images".into(),
+            AvatarError::InvalidImage => "The image could not be decoded".into(),
+            AvatarError::TooManyPixels => format!(
+                "Avatars may be at most {} pixels wide and high",
+                MAX_AVATAR_DIMENSION
+            )
+            .into(),
+            AvatarError::InvalidSize => {
+                let (last, rest) = AVATAR_SIZES.split_last().expect("sizes are not empty");
+                let rest: Vec<String> = rest.iter().map(u32::to_string).collect();
+                format!("size must be one of {} or {}", rest.join(", "), last).into()
+            }
+            AvatarError::NotFound => "Avatar not found".into(),
+            AvatarError::User(error) => error.message().into(),
+        }
+    }
+
+    fn reply(&self, format: Format) -> Response {
+        let error_response = ErrorResponse {
+            error: self.code().to_string(),
+            message: self.message().to_string(),
+        };
+        warp::reply::with_status(format.reply("error", &error_response), self.status())
+            .into_response()
+    }
+}
+
+/// How long avatars may be cached, from `AVATAR_CACHE_SECONDS`
+#[derive(Debug, Clone, Copy)]
+pub struct AvatarConfig {
+    pub cache_seconds: u64,
+}
+
+impl AvatarConfig {
+    pub fn from_env() -> Self {
+        AvatarConfig {
+            cache_seconds: env::var("AVATAR_CACHE_SECONDS")
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(DEFAULT_AVATAR_CACHE_SECONDS),
+        }
+    }
+}
+
+/// Multipart form for `PUT /users/{id}/avatar`
+#[derive(Debug, ToSchema)]
+#[allow(dead_code)]
+pub struct AvatarUpload {
+    /// PNG, JPEG, GIF or WebP image; GIFs keep their first frame
+    #[schema(value_type = String, format = Binary)]
+    pub avatar: Vec<u8>,
+}
+
+/// The stored avatar of a user
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Avatar {
+    pub user_id: String,
+    /// Type of the stored thumbnails
+    pub content_type: String,
+    /// Sizes `GET /users/{id}/avatar?size=` can serve
+    pub sizes: Vec<u32>,
+    pub updated_at: String,
+}
+
+/// The stored avatar of a user in v2, with camelCase fields
+#[derive(Debug, Serialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct AvatarV2 {
+    pub user_id: String,
+    /// Type of the stored thumbnails
+    pub content_type: String,
+    /// Sizes `GET /users/{id}/avatar?size=` can serve
+    pub sizes: Vec<u32>,
+    pub updated_at: String,
+}
+
+impl From<Avatar> for AvatarV2 {
+    fn from(avatar: Avatar) -> Self {
+        AvatarV2 {
+            user_id: avatar.user_id,
+            content_type: avatar.content_type,
+            sizes: avatar.sizes,
+            updated_at: avatar.updated_at,
+        }
+    }
+}
+
+/// Query parameters for `GET /users/{id}/avatar`
+#[derive(Debug, Default, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AvatarQuery {
+    /// Width and height in pixels: 64, 128, 256 (default) or 512
+    pub size: Option<u32>,
+}
+
+impl AvatarQuery {
+    fn size(&self) -> Result<u32, AvatarError> {
+        match self.size {
+            None => Ok(DEFAULT_AVATAR_SIZE),
+            Some(size) if AVATAR_SIZES.contains(&size) => Ok(size),
+            Some(_) => Err(AvatarError::InvalidSize),
+        }
+    }
+}
+
+/// Read the `avatar` field of a form, refusing forms over `limit` bytes in total
+async fn read_avatar_field(form: FormData, limit: u64) -> Result<Vec<u8>, AvatarError> {
+    let mut form = Box::pin(form);
+    let mut read = 0u64;
+    let mut avatar = None;
+
+    while let Some(part) = form
+        .try_next()
+        .await
+        .map_err(|_| AvatarError::InvalidImage)?
+    {
+        let is_avatar = part.name() == AVATAR_FIELD && avatar.is_none();
+        let data = read_part(part, limit, &mut read).await?;
+        if is_avatar {
+            avatar = Some(data);
+        }
+    }
+
+    avatar
+        .filter(|data| !data.is_empty())
+        .ok_or(AvatarError::MissingImage)
+}
+
+async fn read_part(part: Part, limit: u64, read: &mut u64) -> Result<Vec<u8>, AvatarError> {
+    let mut stream = Box::pin(part.stream());
+    let mut data = Vec::new();
+
+    while let Some(mut chunk) = stream
+        .try_next()
+        .await
+        .map_err(|_| AvatarError::InvalidImage)?
+    {
+        *read += chunk.remaining() as u64;
+        if *read > limit {
+            return Err(AvatarError::TooLarge);
+        }
+        while chunk.has_remaining() {
+            let bytes = chunk.chunk();
+            data.extend_from_slice(bytes);
+            let len = bytes.len();
+            chunk.advance(len);
+        }
+    }
+
+    Ok(data)
+}
+
+/// Decode an uploaded image and render the thumbnails of every standard size.
+///
+/// JPEG uploads give JPEG thumbnails; everything else becomes PNG so
+/// transparency survives.
+pub fn render_avatar(data: &[u8]) -> Result<Vec<AvatarFile>, AvatarError> {
+    let format = image::guess_format(data).map_err(|_| AvatarError::UnsupportedType)?;
+    if !matches!(
+        format,
+        ImageFormat::Png | ImageFormat::Jpeg | ImageFormat::Gif | ImageFormat::WebP
+    ) {
+        return Err(AvatarError::UnsupportedType);
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_AVATAR_DIMENSION);
+    limits.max_image_height = Some(MAX_AVATAR_DIMENSION);
+    let mut reader = ImageReader::with_format(Cursor::new(data), format);
+    reader.limits(limits);
+    let image = reader.decode().map_err(|e| match e {
+        ImageError::Limits(_) => AvatarError::TooManyPixels,
+        _ => AvatarError::InvalidImage,
+    })?;
+
+    AVATAR_SIZES
+        .iter()
+        .map(|&size| {
+            let thumbnail = image.resize_to_fill(size, size, FilterType::Lanczos3);
+            let mut encoded = Cursor::new(Vec::new());
+            let (content_type, result) = if format == ImageFormat::Jpeg {
+                let encoder = JpegEncoder::new_with_quality(&mut encoded, AVATAR_JPEG_QUALITY);
+                let thumbnail = DynamicImage::ImageRgb8(thumbnail.to_rgb8());
+                ("image/jpeg", thumbnail.write_with_encoder(encoder))
+            } else {
+                (
+                    "image/png",
+                    thumbnail.write_to(&mut encoded, ImageFormat::Png),
+                )
+            };
+            result.map_err(|_| AvatarError::InvalidImage)?;
+
+            Ok(AvatarFile {
+                size,
+                content_type,
+                data: encoded.into_inner(),
+            })
+        })
+        .collect()
+}
+
+/// Upload a user's avatar
+#[utoipa::path(
+    put,
+    path = "/users/{id}/avatar",
+    tag = "users",
+    params(("id" = String, Path, description = "User ID")),
+    request_body(content = AvatarUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Avatar stored", body = Avatar),
+        (status = 400, description = "Invalid user ID, missing or undecodable image", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 413, description = "The upload is too large", body = ErrorResponse),
+        (status = 415, description = "Not a PNG, JPEG, GIF or WebP image", body = ErrorResponse)
+    )
+)]
+pub async fn upload_avatar(
+    id: String,
+    form: FormData,
+    limit: u64,
+    format: Format,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match replace_avatar(&id, form, limit, &db).await {
+        Ok(avatar) => Ok(
+            warp::reply::with_status(format.reply("avatar", &avatar), StatusCode::OK)
+                .into_response(),
+        ),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+/// Upload a user's avatar
+#[utoipa::path(
+    put,
+    path = "/v2/users/{id}/avatar",
+    tag = "users",
+    params(("id" = String, Path, description = "User ID")),
+    request_body(content = AvatarUpload, content_type = "multipart/form-data"),
+    responses(
+        (status = 200, description = "Avatar stored", body = AvatarV2),
+        (status = 400, description = "Invalid user ID, missing or undecodable image", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 413, description = "The upload is too large", body = ErrorResponse),
+        (status = 415, description = "Not a PNG, JPEG, GIF or WebP image", body = ErrorResponse)
+    )
+)]
+pub async fn upload_avatar_v2(
+    id: String,
+    form: FormData,
+    limit: u64,
+    format: Format,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match replace_avatar(&id, form, limit, &db).await {
+        Ok(avatar) => Ok(warp::reply::with_status(
+            format.reply("avatar", &AvatarV2::from(avatar)),
+            StatusCode::OK,
+        )
+        .into_response()),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+async fn replace_avatar(
+    id: &str,
+    form: FormData,
+    limit: u64,
+    db: &Database,
+) -> Result<Avatar, AvatarError> {
+    let object_id = parse_user_id(id)?;
+    if find_user(db, object_id).await?.is_none() {
+        return Err(UserError::NotFound.into());
+    }
+
+    let data = read_avatar_field(form, limit).await?;
+    let files = tokio::task::spawn_blocking(move || render_avatar(&data))
+        .await
+        .map_err(|_| AvatarError::InvalidImage)??;
+
+    store_avatar(db, object_id, &files)
+        .await
+        .map_err(|_| UserError::Database("Failed to store avatar"))?;
+
+    // The user may have been deleted while the image was processed, after
+    // cleanup of their avatars already ran
+    if find_user(db, object_id).await?.is_none() {
+        let _ = delete_avatars(db, object_id).await;
+        return Err(UserError::NotFound.into());
+    }
+
+    Ok(Avatar {
+        user_id: object_id.to_hex(),
+        content_type: files[0].content_type.to_string(),
+        sizes: AVATAR_SIZES.to_vec(),
+        updated_at: Utc::now().to_rfc3339(),
+    })
+}
+
+/// Serve a user's avatar
+#[utoipa::path(
+    get,
+    path = "/users/{id}/avatar",
+    tag = "users",
+    params(
+        ("id" = String, Path, description = "User ID"),
+        AvatarQuery,
+        ("If-None-Match" = Option<String>, Header, description = "Answer 304 when the avatar is unchanged")
+    ),
+    responses(
+        (status = 200, description = "The image, with ETag, Last-Modified and Cache-Control", content_type = "image/png"),
+        (status = 304, description = "The avatar is unchanged"),
+        (status = 400, description = "Invalid user ID or size", body = ErrorResponse),
+        (status = 404, description = "The user has no avatar", body = ErrorResponse)
+    )
+)]
+pub async fn get_avatar(
+    id: String,
+    query: AvatarQuery,
+    if_none_match: Option<String>,
+    format: Format,
+    config: AvatarConfig,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match avatar_response(&id, &query, if_none_match.as_deref(), config, &db).await {
+        Ok(response) => Ok(response),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+async fn avatar_response(
+    id: &str,
+    query: &AvatarQuery,
+    if_none_match: Option<&str>,
+    config: AvatarConfig,
+    db: &Database,
+) -> Result<Response, AvatarError> {
+    let object_id = parse_user_id(id)?;
+    let size = query.size()?;
+    let file = find_avatar(db, object_id, size)
+        .await
+        .map_err(|_| UserError::Database("Failed to fetch avatar from database"))?
+        .ok_or(AvatarError::NotFound)?;
+
+    // Every upload writes new files, so the file ID identifies the image
+    let etag = match &file.id {
+        Bson::ObjectId(id) => format!("\"{}\"", id.to_hex()),
+        other => format!("\"{}\"", other),
+    };
+    let cache_control = format!("public, max-age={}", config.cache_seconds);
+
+    if if_none_match.is_some_and(|header| if_none_match_tag(header, &etag)) {
+        let mut response = StatusCode::NOT_MODIFIED.into_response();
+        let headers = response.headers_mut();
+        headers.insert(header::ETAG, etag.parse().unwrap());
+        headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
+        return Ok(response);
+    }
+
+    let content_type = file
+        .metadata
+        .as_ref()
+        .and_then(|metadata| metadata.get_str("content_type").ok())
+        .unwrap_or("application/octet-stream")
+        .to_string();
+    let last_modified = DateTime::<Utc>::from_timestamp_millis(file.upload_date.timestamp_millis())
+        .unwrap_or_default()
+        .format("%a, %d %b %Y %H:%M:%S GMT")
+        .to_string();
+    let data = read_avatar(db, &file)
+        .await
+        .map_err(|_| UserError::Database("Failed to read avatar from database"))?;
+
+    let mut response = Response::new(data.into());
+    let headers = response.headers_mut();
+    headers.insert(header::CONTENT_TYPE, content_type.parse().unwrap());
+    headers.insert(header::ETAG, etag.parse().unwrap());
+    headers.insert(header::LAST_MODIFIED, last_modified.parse().unwrap());
+    headers.insert(header::CACHE_CONTROL, cache_control.parse().unwrap());
+    Ok(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgba};
+
+    fn png(width: u32, height: u32) -> Vec<u8> {
+        let image = ImageBuffer::from_pixel(width, height, Rgba([200u8, 10, 10, 128]));
+        let mut encoded = Cursor::new(Vec::new());
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut encoded, ImageFormat::Png)
+            .unwrap();
+        encoded.into_inner()
+    }
+
+    fn multipart(field: &str, data: &[u8]) -> Vec<u8> {
+        let mut body = format!(
+            "--BOUNDARY\r\ncontent-disposition: form-data; name=\"{}\"; filename=\"a.png\"\r\n\
+             content-type: image/png\r\n\r\n",
+            field
+        )
+        .into_bytes();
+        body.extend_from_slice(data);
+        body.extend_from_slice(b"\r\n--BOUNDARY--\r\n");
+        body
+    }
+
+    async fn form(body: Vec<u8>) -> FormData {
+        warp::test::request()
+            .method("PUT")
+            .header("content-type", "multipart/form-data; boundary=BOUNDARY")
+            .body(body)
+            .filter(&warp::multipart::form().max_length(None))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_error_messages_follow_limits() {
+        assert_eq!(
+            AvatarError::TooManyPixels.message(),
+            format!(
+                "Avatars may be at most {} pixels wide and high",
+                MAX_AVATAR_DIMENSION
+            )
+        );
+        assert_eq!(
+            AvatarError::InvalidSize.message(),
+            "size must be one of 64, 128, 256 or 512"
+        );
+    }
+
+    #[test]
+    fn test_render_avatar_sizes() {
+        let files = render_avatar(&png(300, 200)).unwrap();
+
+        assert_eq!(
+            files.iter().map(|file| file.size).collect::<Vec<_>>(),
+            AVATAR_SIZES
+        );
+        for file in &files {
+            assert_eq!(file.content_type, "image/png");
+            let thumbnail = image::load_from_memory(&file.data).unwrap();
+            assert_eq!(
+                (thumbnail.width(), thumbnail.height()),
+                (file.size, file.size)
+            );
+            // Transparency is kept
+            assert!(thumbnail.color().has_alpha());
+        }
+    }
+
+    #[test]
+    fn test_render_avatar_keeps_jpeg() {
+        let image =
+            DynamicImage::ImageRgba8(ImageBuffer::from_pixel(100, 100, Rgba([1, 2, 3, 255])));
+        let mut jpeg = Cursor::new(Vec::new());
+        image
+            .to_rgb8()
+            .write_to(&mut jpeg, ImageFormat::Jpeg)
+            .unwrap();
+
+        let files = render_avatar(jpeg.get_ref()).unwrap();
+        assert!(files.iter().all(|file| file.content_type == "image/jpeg"));
+        assert_eq!(
+            image::guess_format(&files[0].data).unwrap(),
+            ImageFormat::Jpeg
+        );
+    }
+
+    #[test]
+    fn test_render_avatar_rejections() {
+        // Type comes from the bytes, not the name or declared type
+        assert_eq!(
+            render_avatar(b"<svg xmlns=\"http://www.w3.org/2000/svg\"/>").unwrap_err(),
+            AvatarError::UnsupportedType
+        );
+        let mut truncated = png(10, 10);
+        truncated.truncate(40);
+        assert_eq!(
+            render_avatar(&truncated).unwrap_err(),
+            AvatarError::InvalidImage
+        );
+        assert_eq!(
+            render_avatar(&png(MAX_AVATAR_DIMENSION + 1, 1)).unwrap_err(),
+            AvatarError::TooManyPixels
+        );
+    }
+
+    #[test]
+    fn test_avatar_size_query() {
+        assert_eq!(AvatarQuery::default().size(), Ok(DEFAULT_AVATAR_SIZE));
+        assert_eq!(AvatarQuery { size: Some(64) }.size(), Ok(64));
+        assert_eq!(
+            AvatarQuery { size: Some(100) }.size(),
+            Err(AvatarError::InvalidSize)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_read_avatar_field() {
+        let image = png(8, 8);
+        let data = read_avatar_field(form(multipart("avatar", &image)).await, 1024)
+            .await
+            .unwrap();
+        assert_eq!(data, image);
+
+        let error = read_avatar_field(form(multipart("picture", &image)).await, 1024)
+            .await
+            .unwrap_err();
+        assert_eq!(error, AvatarError::MissingImage);
+
+        let error = read_avatar_field(form(multipart("avatar", &image)).await, 10)
+            .await
+            .unwrap_err();
+        assert_eq!(error, AvatarError::TooLarge);
+        assert_eq!(error.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
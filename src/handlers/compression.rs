@@ -0,0 +1,503 @@
+//! Compressing responses by `Accept-Encoding`, and reading request bodies
+//! that may be compressed, up to a size limit
+
+use futures::stream::{Stream, TryStreamExt};
+use serde::de::DeserializeOwned;
+use std::convert::Infallible;
+use std::env;
+use std::io::{Read, Write};
+use warp::http::{header, HeaderValue, StatusCode};
+use warp::hyper::body::{Body, Bytes, HttpBody};
+use warp::reply::Response;
+use warp::{Buf, Filter, Rejection, Reply};
+
+use crate::handlers::negotiation::{Format, InvalidBody};
+
+/// Default limit for user request bodies
+const DEFAULT_USERS_BODY_LIMIT: u64 = 16 * 1024;
+
+/// Default limit for webhook request bodies
+const DEFAULT_WEBHOOKS_BODY_LIMIT: u64 = 16 * 1024;
+
+/// Default limit for GraphQL request bodies, which carry whole documents
+const DEFAULT_GRAPHQL_BODY_LIMIT: u64 = 64 * 1024;
+
+/// Default limit for avatar uploads, multipart framing included
+const DEFAULT_AVATARS_BODY_LIMIT: u64 = 5 * 1024 * 1024;
+
+/// Responses smaller than this are not worth compressing
+const MIN_COMPRESS_BYTES: usize = 1024;
+
+/// A content coding the API can compress with and decompress
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Brotli,
+    Zstd,
+    Gzip,
+}
+
+/// The request body, before or after decompression, is over the route's limit
+#[derive(Debug)]
+pub struct PayloadTooLarge;
+
+impl warp::reject::Reject for PayloadTooLarge {}
+
+/// The request body has a `Content-Encoding` the API cannot decompress
+#[derive(Debug)]
+pub struct UnsupportedContentEncoding;
+
+impl warp::reject::Reject for UnsupportedContentEncoding {}
+
+/// The request body is not valid data in its `Content-Encoding`
+#[derive(Debug)]
+pub struct InvalidContentEncoding;
+
+impl warp::reject::Reject for InvalidContentEncoding {}
+
+impl Encoding {
+    /// In order of preference when the client accepts several equally
+    const PREFERRED: [Encoding; 3] = [Encoding::Brotli, Encoding::Zstd, Encoding::Gzip];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Encoding::Brotli => "br",
+            Encoding::Zstd => "zstd",
+            Encoding::Gzip => "gzip",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Encoding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "br" => Some(Encoding::Brotli),
+            "zstd" => Some(Encoding::Zstd),
+            "gzip" | "x-gzip" => Some(Encoding::Gzip),
+            _ => None,
+        }
+    }
+
+    /// Choose the response encoding from an `Accept-Encoding` header, honouring q-values.
+    ///
+    /// `None` means the response goes out uncompressed.
+    pub fn negotiate(accept_encoding: &str) -> Option<Encoding> {
+        let codings: Vec<(&str, f32)> = accept_encoding
+            .split(',')
+            .map(|coding| {
+                let mut parts = coding.split(';').map(str::trim);
+                let name = parts.next().unwrap_or_default();
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (name, quality)
+            })
+            .collect();
+
+        let quality = |encoding: Encoding| {
+            let named = codings
+                .iter()
+                .find(|(name, _)| Encoding::from_name(name) == Some(encoding));
+            let wildcard = codings.iter().find(|(name, _)| *name == "*");
+            named.or(wildcard).map_or(0.0, |(_, quality)| *quality)
+        };
+
+        let mut best: Option<(Encoding, f32)> = None;
+        for encoding in Encoding::PREFERRED {
+            let quality = quality(encoding);
+            if quality > 0.0 && best.is_none_or(|(_, best)| quality > best) {
+                best = Some((encoding, quality));
+            }
+        }
+        best.map(|(encoding, _)| encoding)
+    }
+
+    pub fn compress(&self, data: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Encoding::Brotli => {
+                let mut writer = brotli::CompressorWriter::new(Vec::new(), 4096, 5, 22);
+                writer.write_all(data)?;
+                Ok(writer.into_inner())
+            }
+            Encoding::Zstd => zstd::encode_all(data, 3),
+            Encoding::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    /// Decompress `data`, refusing to inflate it past `limit` bytes
+    pub fn decompress(&self, data: &[u8], limit: u64) -> Result<Vec<u8>, Rejection> {
+        let reader: Box<dyn Read + '_> = match self {
+            Encoding::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+            Encoding::Zstd => Box::new(
+                zstd::stream::read::Decoder::new(data)
+                    .map_err(|_| warp::reject::custom(InvalidContentEncoding))?,
+            ),
+            Encoding::Gzip => Box::new(flate2::read::GzDecoder::new(data)),
+        };
+
+        // One byte past the limit is enough to know it was exceeded
+        let mut decompressed = Vec::new();
+        reader
+            .take(limit + 1)
+            .read_to_end(&mut decompressed)
+            .map_err(|_| warp::reject::custom(InvalidContentEncoding))?;
+
+        if decompressed.len() as u64 > limit {
+            return Err(warp::reject::custom(PayloadTooLarge));
+        }
+        Ok(decompressed)
+    }
+}
+
+/// Request body size limits for each group of routes
+#[derive(Debug, Clone, Copy)]
+pub struct BodyLimits {
+    pub users: u64,
+    pub webhooks: u64,
+    pub graphql: u64,
+    pub avatars: u64,
+}
+
+impl BodyLimits {
+    /// Read `USERS_BODY_LIMIT_BYTES`, `WEBHOOKS_BODY_LIMIT_BYTES`, `GRAPHQL_BODY_LIMIT_BYTES`
+    /// and `AVATARS_BODY_LIMIT_BYTES`
+    pub fn from_env() -> Self {
+        fn env_or(name: &str, default: u64) -> u64 {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+
+        BodyLimits {
+            users: env_or("USERS_BODY_LIMIT_BYTES", DEFAULT_USERS_BODY_LIMIT),
+            webhooks: env_or("WEBHOOKS_BODY_LIMIT_BYTES", DEFAULT_WEBHOOKS_BODY_LIMIT),
+            graphql: env_or("GRAPHQL_BODY_LIMIT_BYTES", DEFAULT_GRAPHQL_BODY_LIMIT),
+            avatars: env_or("AVATARS_BODY_LIMIT_BYTES", DEFAULT_AVATARS_BODY_LIMIT),
+        }
+    }
+}
+
+/// Read a stream of body chunks, rejecting once more than `limit` bytes have arrived
+async fn read_limited<S, B>(body: S, limit: u64) -> Result<Vec<u8>, Rejection>
+where
+    S: Stream<Item = Result<B, warp::Error>>,
+    B: Buf,
+{
+    let mut body = Box::pin(body);
+    let mut bytes = Vec::new();
+
+    while let Some(mut chunk) = body
+        .try_next()
+        .await
+        .map_err(|_| warp::reject::custom(InvalidContentEncoding))?
+    {
+        if (bytes.len() + chunk.remaining()) as u64 > limit {
+            return Err(warp::reject::custom(PayloadTooLarge));
+        }
+        while chunk.has_remaining() {
+            let part = chunk.chunk();
+            bytes.extend_from_slice(part);
+            let read = part.len();
+            chunk.advance(read);
+        }
+    }
+
+    Ok(bytes)
+}
+
+/// Extract the request body, decompressed by `Content-Encoding`.
+///
+/// Rejects with `PayloadTooLarge` (413) when the body is over `limit` bytes
+/// as sent or once decompressed, and with `UnsupportedContentEncoding` (415)
+/// for codings other than gzip, br and zstd.
+pub fn limited_body(limit: u64) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::stream())
+        .and_then(
+            move |length: Option<u64>, content_encoding: Option<String>, body| async move {
+                // Refuse early when the client says up front that the body is too large
+                if length.is_some_and(|length| length > limit) {
+                    return Err(warp::reject::custom(PayloadTooLarge));
+                }
+
+                let encoding = match content_encoding.as_deref().map(str::trim) {
+                    None | Some("") | Some("identity") => None,
+                    Some(name) => Some(
+                        Encoding::from_name(name)
+                            .ok_or_else(|| warp::reject::custom(UnsupportedContentEncoding))?,
+                    ),
+                };
+
+                let body = read_limited(body, limit).await?;
+                match encoding {
+                    None => Ok(Bytes::from(body)),
+                    Some(encoding) => encoding.decompress(&body, limit).map(Bytes::from),
+                }
+            },
+        )
+}
+
+/// Decode a JSON request body of at most `limit` bytes, rejecting with `InvalidBody` (400)
+pub fn json_body<T>(limit: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    json_body_with_bytes(limit).map(|body: T, _raw: Bytes| body)
+}
+
+/// Like `json_body`, but also extract the raw bytes the body was decoded from
+pub fn json_body_with_bytes<T>(
+    limit: u64,
+) -> impl Filter<Extract = (T, Bytes), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    limited_body(limit)
+        .and_then(|body: Bytes| async move {
+            serde_json::from_slice(&body)
+                .map(|decoded| (decoded, body))
+                .map_err(|_| warp::reject::custom(InvalidBody(Format::Json)))
+        })
+        .untuple_one()
+}
+
+/// Like `json_body`, but an empty body reads as `T::default()`
+pub fn optional_json_body<T>(limit: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Default + Send,
+{
+    limited_body(limit).and_then(|body: Bytes| async move {
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(T::default());
+        }
+        serde_json::from_slice(&body).map_err(|_| warp::reject::custom(InvalidBody(Format::Json)))
+    })
+}
+
+/// Whether a response should be left as it is
+fn skip_compression(response: &Response) -> bool {
+    let status = response.status();
+    if status == StatusCode::SWITCHING_PROTOCOLS
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+    {
+        return true;
+    }
+
+    let headers = response.headers();
+    if headers.contains_key(header::CONTENT_ENCODING) {
+        return true;
+    }
+
+    // Streamed lists have no known length, and buffering them to compress would
+    // hold the whole list in memory again
+    if HttpBody::size_hint(response.body()).exact().is_none() {
+        return true;
+    }
+
+    // Event streams never end, so they cannot be buffered to compress, and
+    // images are compressed already
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|content_type| {
+            content_type.starts_with("text/event-stream") || content_type.starts_with("image/")
+        })
+}
+
+/// Compress a reply in the encoding the client prefers, when it is large enough to be worth it
+pub async fn compress_reply(
+    accept_encoding: Option<String>,
+    reply: impl Reply,
+) -> Result<Response, Infallible> {
+    let response = reply.into_response();
+    if skip_compression(&response) {
+        return Ok(response);
+    }
+
+    let (mut parts, body) = response.into_parts();
+    parts
+        .headers
+        .append(header::VARY, HeaderValue::from_static("accept-encoding"));
+
+    let body = match warp::hyper::body::to_bytes(body).await {
+        Ok(body) => body,
+        Err(_) => return Ok(Response::from_parts(parts, Body::empty())),
+    };
+
+    let encoding = accept_encoding.as_deref().and_then(Encoding::negotiate);
+    let compressed = match encoding {
+        Some(encoding) if body.len() >= MIN_COMPRESS_BYTES => encoding
+            .compress(&body)
+            .ok()
+            .map(|compressed| (encoding, compressed)),
+        _ => None,
+    };
+
+    match compressed {
+        Some((encoding, compressed)) => {
+            parts.headers.insert(
+                header::CONTENT_ENCODING,
+                HeaderValue::from_static(encoding.name()),
+            );
+            parts.headers.remove(header::CONTENT_LENGTH);
+            Ok(Response::from_parts(parts, Body::from(compressed)))
+        }
+        None => Ok(Response::from_parts(parts, Body::from(body))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::users::UpdateUserRequest;
+
+    fn large_json() -> String {
+        serde_json::to_string(&vec!["user@example.com"; 200]).unwrap()
+    }
+
+    #[test]
+    fn test_negotiate_prefers_highest_quality() {
+        assert_eq!(Encoding::negotiate("gzip"), Some(Encoding::Gzip));
+        assert_eq!(
+            Encoding::negotiate("gzip;q=1.0, br;q=0.5"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(
+            Encoding::negotiate("gzip, deflate, br, zstd"),
+            Some(Encoding::Brotli)
+        );
+        assert_eq!(Encoding::negotiate("*"), Some(Encoding::Brotli));
+        assert_eq!(Encoding::negotiate("*, br;q=0"), Some(Encoding::Zstd));
+        assert_eq!(Encoding::negotiate("identity"), None);
+        assert_eq!(Encoding::negotiate("deflate"), None);
+    }
+
+    #[test]
+    fn test_round_trip_every_encoding() {
+        let data = large_json();
+        for encoding in Encoding::PREFERRED {
+            let compressed = encoding.compress(data.as_bytes()).unwrap();
+            assert!(compressed.len() < data.len());
+
+            let decompressed = encoding.decompress(&compressed, 1 << 20).unwrap();
+            assert_eq!(decompressed, data.as_bytes());
+        }
+    }
+
+    #[test]
+    fn test_decompress_stops_at_limit() {
+        let bomb = Encoding::Gzip.compress(&vec![0; 1 << 20]).unwrap();
+
+        let rejection = Encoding::Gzip.decompress(&bomb, 1024).unwrap_err();
+        assert!(rejection.find::<PayloadTooLarge>().is_some());
+
+        let rejection = Encoding::Zstd.decompress(b"not zstd", 1024).unwrap_err();
+        assert!(rejection.find::<InvalidContentEncoding>().is_some());
+    }
+
+    #[test]
+    fn test_body_limits_from_env() {
+        unsafe {
+            env::remove_var("USERS_BODY_LIMIT_BYTES");
+            env::set_var("GRAPHQL_BODY_LIMIT_BYTES", "1000");
+        }
+
+        let limits = BodyLimits::from_env();
+        assert_eq!(limits.users, DEFAULT_USERS_BODY_LIMIT);
+        assert_eq!(limits.graphql, 1000);
+
+        // Clean up
+        unsafe {
+            env::remove_var("GRAPHQL_BODY_LIMIT_BYTES");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_limited_body_rejects_large_bodies() {
+        let rejection = warp::test::request()
+            .body(vec![b'a'; 100])
+            .filter(&limited_body(10))
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<PayloadTooLarge>().is_some());
+
+        let rejection = warp::test::request()
+            .header("content-encoding", "deflate")
+            .body("{}")
+            .filter(&limited_body(10))
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<UnsupportedContentEncoding>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_json_body_decompresses() {
+        let body = Encoding::Gzip.compress(br#"{"name":"Jane"}"#).unwrap();
+
+        let request: UpdateUserRequest = warp::test::request()
+            .header("content-encoding", "gzip")
+            .body(body)
+            .filter(&json_body(1024))
+            .await
+            .unwrap();
+        assert_eq!(request.name.as_deref(), Some("Jane"));
+
+        let rejection = warp::test::request()
+            .body("invalid json")
+            .filter(&json_body::<UpdateUserRequest>(1024))
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<InvalidBody>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_compress_reply_large_json() {
+        let reply = warp::reply::with_header(large_json(), "content-type", "application/json");
+
+        let response = compress_reply(Some("gzip".to_string()), reply)
+            .await
+            .unwrap();
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert_eq!(response.headers()[header::VARY], "accept-encoding");
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let body = Encoding::Gzip.decompress(&body, 1 << 20).unwrap();
+        assert_eq!(body, large_json().as_bytes());
+    }
+
+    #[tokio::test]
+    async fn test_compress_reply_leaves_small_and_streaming_responses() {
+        let response = compress_reply(Some("gzip".to_string()), "ok")
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+
+        let reply = warp::reply::with_header(large_json(), "content-type", "text/event-stream");
+        let response = compress_reply(Some("gzip".to_string()), reply)
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+        assert!(response.headers().get(header::VARY).is_none());
+
+        let reply = warp::reply::with_header(large_json(), "content-type", "image/png");
+        let response = compress_reply(Some("gzip".to_string()), reply)
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+
+        let chunks = futures::stream::iter(vec![Ok::<_, Infallible>(large_json())]);
+        let streamed = Response::new(Body::wrap_stream(chunks));
+        let response = compress_reply(Some("gzip".to_string()), streamed)
+            .await
+            .unwrap();
+        assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    }
+}
@@ -0,0 +1,98 @@
+//! Helpers for `ETag`, `If-Match` and `If-None-Match` handling
+
+/// Build the weak entity tag for a record version.
+///
+/// Weak because one version is served in several formats, field selections
+/// and content encodings, which are equivalent but not byte-for-byte equal.
+pub fn entity_tag(version: i64) -> String {
+    format!("W/\"{}\"", version)
+}
+
+/// Parse the versions listed in an `If-Match` header.
+///
+/// Returns `None` for `*` (any current version matches). The tags name a
+/// stored version rather than the bytes of one representation, so weak tags,
+/// the only kind sent, are accepted; malformed tags are skipped.
+pub fn if_match_versions(header: &str) -> Option<Vec<i64>> {
+    if header.trim() == "*" {
+        return None;
+    }
+
+    Some(
+        header
+            .split(',')
+            .map(|tag| tag.trim().trim_start_matches("W/"))
+            .filter_map(parse_tag_version)
+            .collect(),
+    )
+}
+
+/// Check whether an `If-None-Match` header matches the current version, comparing weakly
+pub fn if_none_match_matches(header: &str, version: i64) -> bool {
+    if header.trim() == "*" {
+        return true;
+    }
+
+    header
+        .split(',')
+        .map(|tag| tag.trim().trim_start_matches("W/"))
+        .filter_map(parse_tag_version)
+        .any(|tag_version| tag_version == version)
+}
+
+/// Check whether an `If-None-Match` header lists `tag`, a quoted entity tag
+pub fn if_none_match_tag(header: &str, tag: &str) -> bool {
+    header.trim() == "*"
+        || header
+            .split(',')
+            .map(|listed| listed.trim().trim_start_matches("W/"))
+            .any(|listed| listed == tag)
+}
+
+fn parse_tag_version(tag: &str) -> Option<i64> {
+    tag.strip_prefix('"')?.strip_suffix('"')?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entity_tag_format() {
+        assert_eq!(entity_tag(3), "W/\"3\"");
+    }
+
+    #[test]
+    fn test_if_match_versions() {
+        assert_eq!(if_match_versions("\"3\""), Some(vec![3]));
+        assert_eq!(if_match_versions("\"1\", \"2\""), Some(vec![1, 2]));
+        assert_eq!(if_match_versions("*"), None);
+    }
+
+    #[test]
+    fn test_if_match_accepts_weak_and_ignores_malformed_tags() {
+        assert_eq!(if_match_versions("W/\"3\""), Some(vec![3]));
+        assert_eq!(if_match_versions(&entity_tag(4)), Some(vec![4]));
+        assert_eq!(if_match_versions("3, \"abc\""), Some(vec![]));
+    }
+
+    #[test]
+    fn test_if_none_match_matches() {
+        assert!(if_none_match_matches("\"3\"", 3));
+        assert!(if_none_match_matches("W/\"3\"", 3));
+        assert!(if_none_match_matches(&entity_tag(3), 3));
+        assert!(if_none_match_matches("\"1\", \"3\"", 3));
+        assert!(if_none_match_matches("*", 3));
+        assert!(!if_none_match_matches("\"2\"", 3));
+        assert!(!if_none_match_matches("garbage", 3));
+    }
+
+    #[test]
+    fn test_if_none_match_tag() {
+        assert!(if_none_match_tag("\"abc\"", "\"abc\""));
+        assert!(if_none_match_tag("\"x\", W/\"abc\"", "\"abc\""));
+        assert!(if_none_match_tag("*", "\"abc\""));
+        assert!(!if_none_match_tag("\"abd\"", "\"abc\""));
+        assert!(!if_none_match_tag("abc", "\"abc\""));
+    }
+}
@@ -0,0 +1,66 @@
+use crate::openapi::ApiDoc;
+use utoipa::OpenApi;
+use warp::{Rejection, Reply};
+
+/// Redoc page rendering the spec served at `/openapi.json`
+const DOCS_PAGE: &str = r#"<!DOCTYPE html>
+<html>
+  <head>
+    <title>Rust Simple API</title>
+    <meta charset="utf-8"/>
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+  </head>
+  <body>
+    <redoc spec-url="/openapi.json"></redoc>
+    <script src="https://cdn.redoc.ly/redoc/latest/bundles/redoc.standalone.js"></script>
+  </body>
+</html>
+"#;
+
+/// Lets the page load Redoc and its fonts, which the API-wide policy forbids
+const DOCS_PAGE_CSP: &str = "default-src 'self'; script-src 'self' https://cdn.redoc.ly; \
+    style-src 'self' 'unsafe-inline' https://fonts.googleapis.com; font-src https://fonts.gstatic.com; \
+    img-src 'self' data: https:; worker-src blob:; frame-ancestors 'none'";
+
+/// Serve the OpenAPI document
+pub async fn openapi_spec() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&ApiDoc::openapi()))
+}
+
+/// Serve the API reference page
+pub async fn docs() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::with_header(
+        warp::reply::html(DOCS_PAGE),
+        "content-security-policy",
+        DOCS_PAGE_CSP,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::StatusCode;
+
+    #[tokio::test]
+    async fn test_openapi_spec_is_json() {
+        let response = openapi_spec().await.unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let spec: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(spec["paths"]["/users"].is_object());
+    }
+
+    #[tokio::test]
+    async fn test_docs_page_points_at_spec() {
+        let response = docs().await.unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("/openapi.json"));
+    }
+}
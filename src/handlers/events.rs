@@ -0,0 +1,186 @@
+use futures::stream::{self, BoxStream, StreamExt};
+use mongodb::Database;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use warp::sse::Event;
+use warp::{Rejection, Reply};
+
+use crate::events::{resume_change_stream, Replay, UserEvent, UserEventBus};
+
+/// Stream user changes as Server-Sent Events.
+///
+/// When `Last-Event-ID` is sent, buffered events after that ID are replayed
+/// before live events. An ID that has left the buffer is resumed from the
+/// MongoDB change stream; when that is not possible either, a `reset` event
+/// tells the client to reload the users before following live events. A
+/// resumed change stream that fails ends with an `error` event, and a
+/// subscriber that falls too far behind is disconnected; either can reconnect
+/// and resume from its last event.
+#[utoipa::path(
+    get,
+    path = "/users/events",
+    tag = "events",
+    params(("Last-Event-ID" = Option<String>, Header, description = "Resume after this event")),
+    responses((status = 200, description = "Stream of user events, one JSON `UserEvent` per message. \
+        A `reset` event means the events after `Last-Event-ID` cannot be replayed and the client \
+        should reload the users it tracks. An `error` event ends the stream when the change stream \
+        it was resumed from fails; reconnect with the last `Last-Event-ID`.",
+        content_type = "text/event-stream", body = UserEvent))
+)]
+pub async fn user_events(
+    last_event_id: Option<String>,
+    events: Arc<UserEventBus>,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    let last_event_id = last_event_id
+        .map(|value| value.trim().to_string())
+        .filter(|value| !value.is_empty());
+    let (replay, receiver) = events.subscribe(last_event_id.as_deref());
+
+    let event_stream: BoxStream<'static, Event> = match replay {
+        Replay::Missed(missed) => stream::iter(missed)
+            .chain(live_events(receiver))
+            .map(|event| sse_event(&event))
+            .boxed(),
+        Replay::Unknown => {
+            let resumed = match last_event_id.as_deref() {
+                Some(last_event_id) => resume_change_stream(&db, last_event_id).await.ok(),
+                None => None,
+            };
+            match resumed {
+                Some(resumed) => resumed
+                    .map(|result| match result {
+                        Ok(event) => sse_event(&event),
+                        Err(e) => {
+                            eprintln!("Resumed user change stream failed: {}", e);
+                            stream_error_event()
+                        }
+                    })
+                    .boxed(),
+                None => stream::once(async { reset_event() })
+                    .chain(live_events(receiver).map(|event| sse_event(&event)))
+                    .boxed(),
+            }
+        }
+    };
+
+    Ok(warp::sse::reply(
+        warp::sse::keep_alive().stream(event_stream.map(Ok::<_, Infallible>)),
+    ))
+}
+
+/// Events from the bus until the subscriber lags behind or the bus closes
+fn live_events(receiver: broadcast::Receiver<UserEvent>) -> impl futures::Stream<Item = UserEvent> {
+    stream::unfold(receiver, |mut receiver| async move {
+        match receiver.recv().await {
+            Ok(event) => Some((event, receiver)),
+            Err(broadcast::error::RecvError::Lagged(_))
+            | Err(broadcast::error::RecvError::Closed) => None,
+        }
+    })
+}
+
+/// Convert a user event into an SSE frame named after the change type
+pub fn sse_event(event: &UserEvent) -> Event {
+    Event::default()
+        .id(event.id.clone())
+        .event(event.kind.as_str())
+        .data(serde_json::to_string(event).unwrap_or_default())
+}
+
+/// Tell a resuming client that the events it missed are gone and it should resync
+pub fn reset_event() -> Event {
+    Event::default()
+        .event("reset")
+        .data(r#"{"reason":"last_event_id_unavailable"}"#)
+}
+
+/// Tell a client that the change stream its events came from failed, ending the stream
+pub fn stream_error_event() -> Event {
+    Event::default()
+        .event("error")
+        .data(r#"{"reason":"change_stream_failed"}"#)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::UserEventKind;
+    use warp::http::StatusCode;
+
+    async fn test_db() -> Arc<Database> {
+        // The database is only used to resume IDs that are not resume tokens of a live server
+        let client = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap();
+        Arc::new(client.database("simple_api_db"))
+    }
+
+    #[test]
+    fn test_sse_event_format() {
+        let bus = UserEventBus::new(10);
+        let event = bus.publish(UserEventKind::Created, "abc".to_string(), None);
+
+        let frame = sse_event(&event).to_string();
+        assert!(frame.contains("event:created"));
+        assert!(frame.contains(&format!("id:{}", event.id)));
+        assert!(frame.contains("\"user_id\":\"abc\""));
+    }
+
+    #[test]
+    fn test_stream_error_event_format() {
+        let frame = stream_error_event().to_string();
+
+        assert!(frame.contains("event:error"));
+        assert!(frame.contains("change_stream_failed"));
+        assert!(!frame.contains("id:"));
+    }
+
+    #[tokio::test]
+    async fn test_user_events_replays_after_last_event_id() {
+        let bus = Arc::new(UserEventBus::new(10));
+        let first = bus.publish(UserEventKind::Created, "first".to_string(), None);
+        bus.publish(UserEventKind::Updated, "second".to_string(), None);
+
+        let reply = user_events(Some(first.id), bus.clone(), test_db().await)
+            .await
+            .unwrap();
+        let response = reply.into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "text/event-stream"
+        );
+
+        // Close the channel so the stream ends after the replayed events
+        drop(bus);
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body_str = String::from_utf8(body_bytes.to_vec()).unwrap();
+
+        assert!(body_str.contains("\"user_id\":\"second\""));
+        assert!(!body_str.contains("\"user_id\":\"first\""));
+    }
+
+    #[tokio::test]
+    async fn test_user_events_resets_unknown_last_event_id() {
+        let bus = Arc::new(UserEventBus::new(10));
+        bus.publish(UserEventKind::Created, "first".to_string(), None);
+
+        let reply = user_events(
+            Some("local-gone-7".to_string()),
+            bus.clone(),
+            test_db().await,
+        )
+        .await
+        .unwrap();
+        let response = reply.into_response();
+
+        drop(bus);
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        let body_str = String::from_utf8(body_bytes.to_vec()).unwrap();
+
+        assert!(body_str.starts_with("event:reset"));
+        assert!(!body_str.contains("\"user_id\":\"first\""));
+    }
+}
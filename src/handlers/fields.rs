@@ -0,0 +1,359 @@
+//! Sparse fieldsets: `fields=` picks which response fields are read and sent,
+//! `expand=` asks for related data to be embedded
+
+use chrono::{DateTime, Utc};
+use mongodb::bson::{self, doc, oid::ObjectId, Document};
+use serde::ser::SerializeStruct;
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::BTreeMap;
+use utoipa::IntoParams;
+
+use crate::models::{User, UserStatus};
+
+/// A response field and the document key it is read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResponseField {
+    pub name: &'static str,
+    pub key: &'static str,
+}
+
+/// Related data that can be embedded with `expand=`. Nothing is related to a user yet.
+const EXPANSIONS: &[&str] = &[];
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FieldsQuery {
+    /// Comma-separated response fields to return, e.g. `id,name`; all fields when missing
+    pub fields: Option<String>,
+    /// Comma-separated related data to embed
+    pub expand: Option<String>,
+}
+
+/// Why `fields` or `expand` was refused
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldsError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl FieldsQuery {
+    /// Validate the parameters against the fields of a response type
+    pub fn fieldset(&self, available: &'static [ResponseField]) -> Result<Fieldset, FieldsError> {
+        let mut fields = None;
+        if let Some(names) = self
+            .fields
+            .as_deref()
+            .filter(|names| !names.trim().is_empty())
+        {
+            let mut selected = Vec::new();
+            for name in names.split(',').map(str::trim) {
+                let field = available
+                    .iter()
+                    .find(|field| field.name == name)
+                    .ok_or_else(|| FieldsError {
+                        code: "invalid_fields",
+                        message: format!(
+                            "Unknown field '{}', expected one of {}",
+                            name,
+                            names_of(available)
+                        ),
+                    })?;
+                if !selected.contains(field) {
+                    selected.push(*field);
+                }
+            }
+            // Keep the order of the response type, so CSV columns are stable
+            selected.sort_by_key(|field| available.iter().position(|other| other == field));
+            fields = Some(selected);
+        }
+
+        let mut expand = Vec::new();
+        if let Some(names) = self
+            .expand
+            .as_deref()
+            .filter(|names| !names.trim().is_empty())
+        {
+            for name in names.split(',').map(str::trim) {
+                let expansion = EXPANSIONS
+                    .iter()
+                    .find(|expansion| **expansion == name)
+                    .ok_or_else(|| FieldsError {
+                        code: "invalid_expand",
+                        message: if EXPANSIONS.is_empty() {
+                            format!(
+                                "Unknown expansion '{}', users have no related data to expand yet",
+                                name
+                            )
+                        } else {
+                            format!(
+                                "Unknown expansion '{}', expected one of {}",
+                                name,
+                                EXPANSIONS.join(", ")
+                            )
+                        },
+                    })?;
+                if !expand.contains(expansion) {
+                    expand.push(*expansion);
+                }
+            }
+        }
+
+        Ok(Fieldset { fields, expand })
+    }
+}
+
+fn names_of(fields: &[ResponseField]) -> String {
+    fields
+        .iter()
+        .map(|field| field.name)
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The validated `fields` and `expand` of a request
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Fieldset {
+    /// `None` selects every field
+    fields: Option<Vec<ResponseField>>,
+    expand: Vec<&'static str>,
+}
+
+impl Fieldset {
+    /// Whether `expand` asked for the named related data
+    #[allow(dead_code)]
+    pub fn expands(&self, name: &str) -> bool {
+        self.expand.contains(&name)
+    }
+
+    /// The MongoDB projection reading only the selected fields, plus `version` for ETags
+    pub fn projection(&self) -> Option<Document> {
+        let fields = self.fields.as_ref()?;
+        let mut projection = doc! { "version": 1 };
+        if !fields.iter().any(|field| field.key == "_id") {
+            projection.insert("_id", 0);
+        }
+        for field in fields {
+            projection.insert(field.key, 1);
+        }
+        Some(projection)
+    }
+
+    /// Read a user document fetched with `projection()`
+    pub fn read_user(&self, document: Document) -> Result<User, bson::de::Error> {
+        match self.fields {
+            None => bson::from_document(document),
+            Some(_) => bson::from_document::<ProjectedUser>(document).map(User::from),
+        }
+    }
+
+    /// Keep only the selected fields of a serialized response
+    pub fn select<T: Serialize>(&self, response: &T) -> SparseRecord {
+        let value = serde_json::to_value(response).unwrap_or_default();
+        match &self.fields {
+            None => SparseRecord::Whole(value),
+            Some(fields) => SparseRecord::Fields(
+                fields
+                    .iter()
+                    .map(|field| {
+                        (
+                            field.name,
+                            value.get(field.name).cloned().unwrap_or_default(),
+                        )
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+/// A response trimmed to a fieldset, serialized as a struct so every format can encode it
+#[derive(Debug, Clone, PartialEq)]
+pub enum SparseRecord {
+    /// Every field was selected
+    Whole(serde_json::Value),
+    Fields(Vec<(&'static str, serde_json::Value)>),
+}
+
+impl Serialize for SparseRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SparseRecord::Whole(value) => value.serialize(serializer),
+            SparseRecord::Fields(entries) => {
+                let mut record = serializer.serialize_struct("user", entries.len())?;
+                for (name, value) in entries {
+                    record.serialize_field(name, value)?;
+                }
+                record.end()
+            }
+        }
+    }
+}
+
+/// A user read with a projection; fields left out read as defaults, which `select` drops again
+#[derive(Deserialize, Debug)]
+struct ProjectedUser {
+    #[serde(rename = "_id", default)]
+    id: Option<ObjectId>,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    email: String,
+    #[serde(default)]
+    created_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    updated_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    version: i64,
+    #[serde(default)]
+    phone: Option<String>,
+    #[serde(default)]
+    locale: Option<String>,
+    #[serde(default)]
+    timezone: Option<String>,
+    #[serde(default)]
+    avatar_url: Option<String>,
+    #[serde(default)]
+    metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    status: UserStatus,
+    #[serde(default)]
+    status_reason: Option<String>,
+    #[serde(default)]
+    status_changed_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    email_verified_at: Option<DateTime<Utc>>,
+}
+
+impl From<ProjectedUser> for User {
+    fn from(projected: ProjectedUser) -> Self {
+        User {
+            id: projected.id,
+            name: projected.name,
+            email: projected.email,
+            created_at: projected.created_at.unwrap_or_default(),
+            updated_at: projected.updated_at,
+            version: projected.version,
+            phone: projected.phone,
+            locale: projected.locale,
+            timezone: projected.timezone,
+            avatar_url: projected.avatar_url,
+            metadata: projected.metadata,
+            status: projected.status,
+            status_reason: projected.status_reason,
+            status_changed_at: projected.status_changed_at,
+            email_verified_at: projected.email_verified_at,
+            verification_resent_at: None,
+            password_reset_requested_at: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::negotiation::Format;
+    use crate::handlers::users::{UserResponse, USER_FIELDS};
+
+    fn query(fields: &str) -> FieldsQuery {
+        FieldsQuery {
+            fields: Some(fields.to_string()),
+            expand: None,
+        }
+    }
+
+    fn response() -> UserResponse {
+        UserResponse {
+            id: "65a000000000000000000001".to_string(),
+            name: "Ada".to_string(),
+            email: "ada@example.com".to_string(),
+            created_at: "2024-01-01T00:00:00+00:00".to_string(),
+            version: 3,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_fieldset_validation() {
+        let fieldset = query(" name, id,name").fieldset(USER_FIELDS).unwrap();
+        assert_eq!(
+            fieldset.projection(),
+            Some(doc! { "version": 1, "_id": 1, "name": 1 })
+        );
+
+        let error = query("id,password").fieldset(USER_FIELDS).unwrap_err();
+        assert_eq!(error.code, "invalid_fields");
+        assert!(error.message.starts_with("Unknown field 'password'"));
+
+        assert_eq!(
+            query("").fieldset(USER_FIELDS).unwrap(),
+            Fieldset::default()
+        );
+        assert_eq!(Fieldset::default().projection(), None);
+    }
+
+    #[test]
+    fn test_expand_has_nothing_to_expand_yet() {
+        let query = FieldsQuery {
+            fields: None,
+            expand: Some("history".to_string()),
+        };
+        let error = query.fieldset(USER_FIELDS).unwrap_err();
+        assert_eq!(error.code, "invalid_expand");
+    }
+
+    #[test]
+    fn test_projection_leaves_out_unrequested_id() {
+        let fieldset = query("name").fieldset(USER_FIELDS).unwrap();
+        assert_eq!(
+            fieldset.projection(),
+            Some(doc! { "version": 1, "_id": 0, "name": 1 })
+        );
+    }
+
+    #[test]
+    fn test_select_trims_every_format() {
+        let fieldset = query("name,id").fieldset(USER_FIELDS).unwrap();
+        let record = fieldset.select(&response());
+
+        assert_eq!(
+            serde_json::to_value(&record).unwrap(),
+            serde_json::json!({ "id": "65a000000000000000000001", "name": "Ada" })
+        );
+        let csv = Format::Csv
+            .encode_list("users", std::slice::from_ref(&record))
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "id,name\n65a000000000000000000001,Ada\n"
+        );
+        let xml = Format::Xml.encode("user", &record).unwrap();
+        assert_eq!(
+            String::from_utf8(xml).unwrap(),
+            "<user><id>65a000000000000000000001</id><name>Ada</name></user>"
+        );
+
+        // Without `fields` the response is unchanged
+        let whole = Fieldset::default().select(&response());
+        assert_eq!(
+            serde_json::to_value(&whole).unwrap(),
+            serde_json::to_value(response()).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_projected_user_reads_partial_documents() {
+        let fieldset = query("name").fieldset(USER_FIELDS).unwrap();
+        let user = fieldset
+            .read_user(doc! { "name": "Ada", "version": 2_i64 })
+            .unwrap();
+
+        assert_eq!(user.name, "Ada");
+        assert_eq!(user.version, 2);
+        assert!(user.id.is_none());
+
+        // Whole documents are still read strictly
+        assert!(Fieldset::default()
+            .read_user(doc! { "name": "Ada" })
+            .is_err());
+    }
+}
@@ -0,0 +1,859 @@
+//! The `filter` expression language of `GET /users`
+//!
+//! ```text
+//! expression := and ("or" and)*
+//! and        := unary ("and" unary)*
+//! unary      := "not" unary | "(" expression ")" | comparison
+//! comparison := field operator value | field "in" "(" value ("," value)* ")" | field "exists"
+//! operator   := "=" | "!=" | ">" | ">=" | "<" | "<=" | "contains" | "starts_with" | "ends_with"
+//! ```
+//!
+//! Values are bare words (`2024-01-01`, `42`) or double-quoted strings with `\"`
+//! and `\\` escapes. Fields come from an allow-list and each accepts only the
+//! operators and values that make sense for its type, so a parsed filter is
+//! always a plain BSON query with escaped regexes. Metadata entries are fields
+//! named `metadata.<key>`, e.g. `metadata.plan = pro` or `metadata.tenant exists`,
+//! and `status` takes one of the user statuses, e.g. `status in (active, suspended)`.
+
+use chrono::{DateTime, Duration, NaiveDate, NaiveTime, Timelike, Utc};
+use mongodb::bson::{doc, oid::ObjectId, Bson, Document};
+use serde::Deserialize;
+use std::fmt;
+use utoipa::IntoParams;
+
+use crate::handlers::users::is_metadata_key;
+use crate::models::UserStatus;
+
+/// Longest filter accepted, in characters
+const MAX_FILTER_LENGTH: usize = 1000;
+
+/// Deepest nesting of parentheses and `not`
+const MAX_DEPTH: usize = 16;
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct UserListQuery {
+    /// Filter expression, e.g. `created_at>=2024-01-01 and email ends_with "@acme.com"`
+    pub filter: Option<String>,
+    /// Stream a JSON list as it is read instead of collecting it first; NDJSON always streams
+    #[serde(default)]
+    pub stream: bool,
+}
+
+impl UserListQuery {
+    /// The MongoDB filter for the `filter` parameter, `None` when it is missing or blank
+    pub fn to_filter(&self) -> Result<Option<Document>, FilterError> {
+        match self.filter.as_deref().map(str::trim) {
+            None | Some("") => Ok(None),
+            Some(filter) => parse_user_filter(filter).map(Some),
+        }
+    }
+}
+
+/// Why a filter was refused, and where
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FilterError {
+    pub message: String,
+    /// 1-based character position of the offending token
+    pub position: usize,
+}
+
+impl FilterError {
+    fn new(message: impl Into<String>, position: usize) -> Self {
+        FilterError {
+            message: message.into(),
+            position,
+        }
+    }
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at position {}", self.message, self.position)
+    }
+}
+
+/// User fields a filter may name
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Field<'a> {
+    Id,
+    Name,
+    Email,
+    Phone,
+    Locale,
+    Timezone,
+    AvatarUrl,
+    CreatedAt,
+    UpdatedAt,
+    Version,
+    Status,
+    StatusChangedAt,
+    EmailVerifiedAt,
+    /// A metadata entry, holding the whole `metadata.<key>` name
+    Metadata(&'a str),
+}
+
+/// What a field holds, which decides its operators and how values parse
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    ObjectId,
+    Text,
+    /// Stored as an RFC 3339 string in UTC
+    Time,
+    Integer,
+    /// One of the `UserStatus` names
+    Status,
+}
+
+impl<'a> Field<'a> {
+    const NAMES: &'static str = "id, name, email, phone, locale, timezone, avatar_url, \
+        metadata.<key>, created_at, updated_at, version, status, status_changed_at, \
+        email_verified_at";
+
+    fn parse(name: &'a str) -> Option<Field<'a>> {
+        match name {
+            "id" => Some(Field::Id),
+            "name" => Some(Field::Name),
+            "email" => Some(Field::Email),
+            "phone" => Some(Field::Phone),
+            "locale" => Some(Field::Locale),
+            "timezone" => Some(Field::Timezone),
+            "avatar_url" => Some(Field::AvatarUrl),
+            "created_at" => Some(Field::CreatedAt),
+            "updated_at" => Some(Field::UpdatedAt),
+            "version" => Some(Field::Version),
+            "status" => Some(Field::Status),
+            "status_changed_at" => Some(Field::StatusChangedAt),
+            "email_verified_at" => Some(Field::EmailVerifiedAt),
+            _ => name
+                .strip_prefix("metadata.")
+                .filter(|key| is_metadata_key(key))
+                .map(|_| Field::Metadata(name)),
+        }
+    }
+
+    fn name(&self) -> &'a str {
+        match self {
+            Field::Id => "id",
+            Field::Name => "name",
+            Field::Email => "email",
+            Field::Phone => "phone",
+            Field::Locale => "locale",
+            Field::Timezone => "timezone",
+            Field::AvatarUrl => "avatar_url",
+            Field::CreatedAt => "created_at",
+            Field::UpdatedAt => "updated_at",
+            Field::Version => "version",
+            Field::Status => "status",
+            Field::StatusChangedAt => "status_changed_at",
+            Field::EmailVerifiedAt => "email_verified_at",
+            Field::Metadata(name) => name,
+        }
+    }
+
+    /// Key of the field in the `users` collection
+    fn key(&self) -> &'a str {
+        match self {
+            Field::Id => "_id",
+            other => other.name(),
+        }
+    }
+
+    fn kind(&self) -> Kind {
+        match self {
+            Field::Id => Kind::ObjectId,
+            Field::Name
+            | Field::Email
+            | Field::Phone
+            | Field::Locale
+            | Field::Timezone
+            | Field::AvatarUrl
+            | Field::Metadata(_) => Kind::Text,
+            Field::CreatedAt
+            | Field::UpdatedAt
+            | Field::StatusChangedAt
+            | Field::EmailVerifiedAt => Kind::Time,
+            Field::Version => Kind::Integer,
+            Field::Status => Kind::Status,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Eq,
+    Ne,
+    Gt,
+    Gte,
+    Lt,
+    Lte,
+    Contains,
+    StartsWith,
+    EndsWith,
+    In,
+    Exists,
+}
+
+impl Operator {
+    fn parse(token: &TokenKind) -> Option<Operator> {
+        match token {
+            TokenKind::Symbol(symbol) => match *symbol {
+                "=" | "==" => Some(Operator::Eq),
+                "!=" => Some(Operator::Ne),
+                ">" => Some(Operator::Gt),
+                ">=" => Some(Operator::Gte),
+                "<" => Some(Operator::Lt),
+                "<=" => Some(Operator::Lte),
+                _ => None,
+            },
+            TokenKind::Word(word) => match word.to_ascii_lowercase().as_str() {
+                "contains" => Some(Operator::Contains),
+                "starts_with" => Some(Operator::StartsWith),
+                "ends_with" => Some(Operator::EndsWith),
+                "in" => Some(Operator::In),
+                "exists" => Some(Operator::Exists),
+                _ => None,
+            },
+            TokenKind::Str(_) => None,
+        }
+    }
+
+    fn supports(&self, kind: Kind) -> bool {
+        match self {
+            Operator::Eq | Operator::Ne | Operator::Exists => true,
+            Operator::In => kind != Kind::Time,
+            Operator::Gt | Operator::Gte | Operator::Lt | Operator::Lte => {
+                matches!(kind, Kind::Time | Kind::Integer)
+            }
+            Operator::Contains | Operator::StartsWith | Operator::EndsWith => kind == Kind::Text,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    /// A bare word: field, keyword, number or date
+    Word(String),
+    /// A double-quoted string, unescaped
+    Str(String),
+    /// An operator or punctuation
+    Symbol(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Token {
+    kind: TokenKind,
+    position: usize,
+}
+
+impl Token {
+    /// How the token reads in an error message
+    fn describe(&self) -> String {
+        match &self.kind {
+            TokenKind::Word(word) => format!("'{}'", word),
+            TokenKind::Str(value) => format!("\"{}\"", value),
+            TokenKind::Symbol(symbol) => format!("'{}'", symbol),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        matches!(&self.kind, TokenKind::Word(word) if word.eq_ignore_ascii_case(keyword))
+    }
+
+    fn is_symbol(&self, expected: &str) -> bool {
+        matches!(&self.kind, TokenKind::Symbol(symbol) if *symbol == expected)
+    }
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || "_-:.+@".contains(c)
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, FilterError> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let position = i + 1;
+
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+
+        let next = chars.get(i + 1).copied();
+        let symbol = match (c, next) {
+            ('=', Some('=')) => Some("=="),
+            ('!', Some('=')) => Some("!="),
+            ('>', Some('=')) => Some(">="),
+            ('<', Some('=')) => Some("<="),
+            ('=', _) => Some("="),
+            ('>', _) => Some(">"),
+            ('<', _) => Some("<"),
+            ('(', _) => Some("("),
+            (')', _) => Some(")"),
+            (',', _) => Some(","),
+            _ => None,
+        };
+        if let Some(symbol) = symbol {
+            tokens.push(Token {
+                kind: TokenKind::Symbol(symbol),
+                position,
+            });
+            i += symbol.len();
+            continue;
+        }
+
+        if c == '"' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(FilterError::new("Unterminated string", position)),
+                    Some('"') => break,
+                    Some('\\') => match chars.get(i + 1) {
+                        Some(escaped @ ('"' | '\\')) => {
+                            value.push(*escaped);
+                            i += 2;
+                        }
+                        _ => {
+                            return Err(FilterError::new(
+                                "Invalid escape, only \\\" and \\\\ are allowed",
+                                i + 1,
+                            ))
+                        }
+                    },
+                    Some(other) => {
+                        value.push(*other);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            tokens.push(Token {
+                kind: TokenKind::Str(value),
+                position,
+            });
+            continue;
+        }
+
+        if is_word_char(c) {
+            let start = i;
+            while i < chars.len() && is_word_char(chars[i]) {
+                i += 1;
+            }
+            tokens.push(Token {
+                kind: TokenKind::Word(chars[start..i].iter().collect()),
+                position,
+            });
+            continue;
+        }
+
+        return Err(FilterError::new(
+            format!("Unexpected character '{}'", c),
+            position,
+        ));
+    }
+
+    Ok(tokens)
+}
+
+/// Parse a filter expression into a MongoDB filter on the `users` collection
+pub fn parse_user_filter(input: &str) -> Result<Document, FilterError> {
+    if input.chars().count() > MAX_FILTER_LENGTH {
+        return Err(FilterError::new(
+            format!("Filter is longer than {} characters", MAX_FILTER_LENGTH),
+            MAX_FILTER_LENGTH + 1,
+        ));
+    }
+
+    let tokens = tokenize(input)?;
+    let mut parser = Parser {
+        tokens,
+        index: 0,
+        end: input.chars().count() + 1,
+    };
+
+    let filter = parser.expression(0)?;
+    match parser.peek() {
+        None => Ok(filter),
+        Some(token) => Err(FilterError::new(
+            format!("Expected 'and' or 'or', found {}", token.describe()),
+            token.position,
+        )),
+    }
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    index: usize,
+    /// Position reported for errors at the end of the input
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.index)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.index).cloned();
+        self.index += 1;
+        token
+    }
+
+    /// The next token, or an error saying what was expected instead of the end
+    fn expect(&mut self, expected: &str) -> Result<Token, FilterError> {
+        self.next().ok_or_else(|| {
+            FilterError::new(
+                format!("Expected {}, found end of filter", expected),
+                self.end,
+            )
+        })
+    }
+
+    fn expression(&mut self, depth: usize) -> Result<Document, FilterError> {
+        let mut clauses = vec![self.and(depth)?];
+        while self.peek().is_some_and(|token| token.is_keyword("or")) {
+            self.index += 1;
+            clauses.push(self.and(depth)?);
+        }
+        Ok(combine("$or", clauses))
+    }
+
+    fn and(&mut self, depth: usize) -> Result<Document, FilterError> {
+        let mut clauses = vec![self.unary(depth)?];
+        while self.peek().is_some_and(|token| token.is_keyword("and")) {
+            self.index += 1;
+            clauses.push(self.unary(depth)?);
+        }
+        Ok(combine("$and", clauses))
+    }
+
+    fn unary(&mut self, depth: usize) -> Result<Document, FilterError> {
+        let token = self.expect("a field name")?;
+        if depth >= MAX_DEPTH {
+            return Err(FilterError::new(
+                format!("Filter nests deeper than {} levels", MAX_DEPTH),
+                token.position,
+            ));
+        }
+
+        if token.is_keyword("not") {
+            let inner = self.unary(depth + 1)?;
+            return Ok(doc! { "$nor": [inner] });
+        }
+
+        if token.is_symbol("(") {
+            let inner = self.expression(depth + 1)?;
+            let close = self.expect("')'")?;
+            if !close.is_symbol(")") {
+                return Err(FilterError::new(
+                    format!("Expected ')', found {}", close.describe()),
+                    close.position,
+                ));
+            }
+            return Ok(inner);
+        }
+
+        self.comparison(token)
+    }
+
+    fn comparison(&mut self, token: Token) -> Result<Document, FilterError> {
+        let field = match &token.kind {
+            TokenKind::Word(name) => Field::parse(name).ok_or_else(|| {
+                FilterError::new(
+                    format!("Unknown field '{}', expected one of {}", name, Field::NAMES),
+                    token.position,
+                )
+            })?,
+            _ => {
+                return Err(FilterError::new(
+                    format!("Expected a field name, found {}", token.describe()),
+                    token.position,
+                ))
+            }
+        };
+
+        let operator_token = self.expect(&format!("an operator after '{}'", field.name()))?;
+        let operator = Operator::parse(&operator_token.kind).ok_or_else(|| {
+            FilterError::new(
+                format!(
+                    "Expected an operator after '{}', found {}",
+                    field.name(),
+                    operator_token.describe()
+                ),
+                operator_token.position,
+            )
+        })?;
+        if !operator.supports(field.kind()) {
+            return Err(FilterError::new(
+                format!(
+                    "Operator {} cannot be used with '{}'",
+                    operator_token.describe(),
+                    field.name()
+                ),
+                operator_token.position,
+            ));
+        }
+
+        if operator == Operator::In {
+            let values = self.list(field)?;
+            return Ok(doc! { field.key(): { "$in": values } });
+        }
+        if operator == Operator::Exists {
+            return Ok(doc! { field.key(): { "$exists": true } });
+        }
+
+        let value_token = self.expect("a value")?;
+        match field.kind() {
+            Kind::Time => {
+                let (start, end) = time_range(field, &value_token)?;
+                Ok(time_condition(field, operator, start, end))
+            }
+            _ => {
+                let value = value(field, &value_token)?;
+                Ok(condition(field, operator, value))
+            }
+        }
+    }
+
+    /// The parenthesised, comma-separated values after `in`
+    fn list(&mut self, field: Field) -> Result<Vec<Bson>, FilterError> {
+        let open = self.expect("'('")?;
+        if !open.is_symbol("(") {
+            return Err(FilterError::new(
+                format!("Expected '(' after 'in', found {}", open.describe()),
+                open.position,
+            ));
+        }
+
+        let mut values = Vec::new();
+        loop {
+            let token = self.expect("a value")?;
+            values.push(value(field, &token)?);
+
+            let separator = self.expect("',' or ')'")?;
+            if separator.is_symbol(")") {
+                return Ok(values);
+            }
+            if !separator.is_symbol(",") {
+                return Err(FilterError::new(
+                    format!("Expected ',' or ')', found {}", separator.describe()),
+                    separator.position,
+                ));
+            }
+        }
+    }
+}
+
+/// Join clauses with `$and` or `$or`, leaving a single clause as it is
+fn combine(operator: &str, mut clauses: Vec<Document>) -> Document {
+    if clauses.len() == 1 {
+        clauses.remove(0)
+    } else {
+        doc! { operator: clauses }
+    }
+}
+
+/// The text of a value token, quoted or bare
+fn literal(token: &Token) -> Result<&str, FilterError> {
+    match &token.kind {
+        TokenKind::Word(word) => Ok(word),
+        TokenKind::Str(value) => Ok(value),
+        TokenKind::Symbol(_) => Err(FilterError::new(
+            format!("Expected a value, found {}", token.describe()),
+            token.position,
+        )),
+    }
+}
+
+/// A value for a field of any kind but time
+fn value(field: Field, token: &Token) -> Result<Bson, FilterError> {
+    let text = literal(token)?;
+    let invalid = |expected: &str| {
+        FilterError::new(
+            format!(
+                "Invalid value {} for '{}', expected {}",
+                token.describe(),
+                field.name(),
+                expected
+            ),
+            token.position,
+        )
+    };
+
+    match field.kind() {
+        Kind::ObjectId => ObjectId::parse_str(text)
+            .map(Bson::ObjectId)
+            .map_err(|_| invalid("a user ID")),
+        Kind::Text => Ok(Bson::String(text.to_string())),
+        Kind::Integer => text
+            .parse::<i64>()
+            .map(Bson::Int64)
+            .map_err(|_| invalid("an integer")),
+        Kind::Time => time_range(field, token).map(|(start, _)| Bson::String(start)),
+        Kind::Status => UserStatus::parse(text)
+            .map(|status| Bson::String(status.as_str().to_string()))
+            .ok_or_else(|| invalid("pending, active, suspended or archived")),
+    }
+}
+
+/// The span of stored timestamps a time value covers, as `[start, end)` string bounds.
+///
+/// A date covers the whole day and a timestamp its whole second. Timestamps are
+/// stored as RFC 3339 strings in UTC with optional fractions, so comparing them
+/// against second-precision prefixes orders them correctly.
+fn time_range(field: Field, token: &Token) -> Result<(String, String), FilterError> {
+    let text = literal(token)?;
+
+    let (start, length) = if let Ok(date) = NaiveDate::parse_from_str(text, "%Y-%m-%d") {
+        (date.and_time(NaiveTime::MIN).and_utc(), Duration::days(1))
+    } else if let Ok(time) = DateTime::parse_from_rfc3339(text) {
+        let time = time.with_timezone(&Utc);
+        (
+            time.with_nanosecond(0).unwrap_or(time),
+            Duration::seconds(1),
+        )
+    } else {
+        return Err(FilterError::new(
+            format!(
+                "Invalid value {} for '{}', expected a date (YYYY-MM-DD) or an RFC 3339 timestamp",
+                token.describe(),
+                field.name()
+            ),
+            token.position,
+        ));
+    };
+
+    let bound = |time: DateTime<Utc>| time.format("%Y-%m-%dT%H:%M:%S").to_string();
+    Ok((bound(start), bound(start + length)))
+}
+
+fn time_condition(field: Field, operator: Operator, start: String, end: String) -> Document {
+    let condition = match operator {
+        Operator::Gt => doc! { "$gte": end },
+        Operator::Gte => doc! { "$gte": start },
+        Operator::Lt => doc! { "$lt": start },
+        Operator::Lte => doc! { "$lt": end },
+        Operator::Ne => doc! { "$not": { "$gte": start, "$lt": end } },
+        _ => doc! { "$gte": start, "$lt": end },
+    };
+    doc! { field.key(): condition }
+}
+
+/// Escape a literal for use inside a regex
+fn escape_regex(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if "\\^$.|?*+()[]{}-/#".contains(c) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn condition(field: Field, operator: Operator, value: Bson) -> Document {
+    let text = || value.as_str().map(escape_regex).unwrap_or_default();
+    let condition = match operator {
+        Operator::Eq => return doc! { field.key(): value },
+        Operator::Ne => doc! { "$ne": value },
+        Operator::Gt => doc! { "$gt": value },
+        Operator::Gte => doc! { "$gte": value },
+        Operator::Lt => doc! { "$lt": value },
+        Operator::Lte => doc! { "$lte": value },
+        Operator::Contains => doc! { "$regex": text(), "$options": "i" },
+        Operator::StartsWith => doc! { "$regex": format!("^{}", text()), "$options": "i" },
+        Operator::EndsWith => doc! { "$regex": format!("{}$", text()), "$options": "i" },
+        Operator::In => doc! { "$in": [value] },
+        Operator::Exists => doc! { "$exists": true },
+    };
+    doc! { field.key(): condition }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(input: &str) -> FilterError {
+        parse_user_filter(input).unwrap_err()
+    }
+
+    #[test]
+    fn test_example_from_the_docs() {
+        let filter =
+            parse_user_filter("created_at>=2024-01-01 and email ends_with \"@acme.com\"").unwrap();
+
+        assert_eq!(
+            filter,
+            doc! {
+                "$and": [
+                    { "created_at": { "$gte": "2024-01-01T00:00:00" } },
+                    { "email": { "$regex": "@acme\\.com$", "$options": "i" } },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_precedence_grouping_and_not() {
+        let filter = parse_user_filter(
+            "NOT name = Bob or version > 2 AND (email contains x or email contains y)",
+        )
+        .unwrap();
+
+        assert_eq!(
+            filter,
+            doc! {
+                "$or": [
+                    { "$nor": [{ "name": "Bob" }] },
+                    { "$and": [
+                        { "version": { "$gt": 2_i64 } },
+                        { "$or": [
+                            { "email": { "$regex": "x", "$options": "i" } },
+                            { "email": { "$regex": "y", "$options": "i" } },
+                        ] },
+                    ] },
+                ]
+            }
+        );
+    }
+
+    #[test]
+    fn test_time_bounds() {
+        assert_eq!(
+            parse_user_filter("created_at = 2024-02-29").unwrap(),
+            doc! { "created_at": { "$gte": "2024-02-29T00:00:00", "$lt": "2024-03-01T00:00:00" } }
+        );
+        assert_eq!(
+            parse_user_filter("updated_at > \"2024-01-01T10:00:00.5+02:00\"").unwrap(),
+            doc! { "updated_at": { "$gte": "2024-01-01T08:00:01" } }
+        );
+        assert_eq!(
+            parse_user_filter("created_at != 2024-01-01T00:00:00Z").unwrap(),
+            doc! { "created_at": { "$not": { "$gte": "2024-01-01T00:00:00", "$lt": "2024-01-01T00:00:01" } } }
+        );
+        assert_eq!(
+            parse_user_filter("created_at <= 2024-01-01").unwrap(),
+            doc! { "created_at": { "$lt": "2024-01-02T00:00:00" } }
+        );
+    }
+
+    #[test]
+    fn test_in_and_ids() {
+        let id = ObjectId::new();
+        assert_eq!(
+            parse_user_filter(&format!("id in ({}, {})", id, id)).unwrap(),
+            doc! { "_id": { "$in": [id, id] } }
+        );
+        assert_eq!(
+            parse_user_filter("name != \"Ann \\\"The Hammer\\\"\"").unwrap(),
+            doc! { "name": { "$ne": "Ann \"The Hammer\"" } }
+        );
+    }
+
+    #[test]
+    fn test_profile_and_metadata_fields() {
+        assert_eq!(
+            parse_user_filter("metadata.plan = pro and locale starts_with en").unwrap(),
+            doc! {
+                "$and": [
+                    { "metadata.plan": "pro" },
+                    { "locale": { "$regex": "^en", "$options": "i" } },
+                ]
+            }
+        );
+        assert_eq!(
+            parse_user_filter("not metadata.tenant-id exists").unwrap(),
+            doc! { "$nor": [{ "metadata.tenant-id": { "$exists": true } }] }
+        );
+        assert_eq!(
+            parse_user_filter("timezone in (UTC, \"Europe/London\")").unwrap(),
+            doc! { "timezone": { "$in": ["UTC", "Europe/London"] } }
+        );
+
+        // Keys follow the rules for stored metadata, so no operator can sneak in
+        assert_eq!(error("metadata.$where = x").position, 10);
+        assert_eq!(error("metadata.a.b = x").position, 1);
+        assert_eq!(error("metadata. = x").position, 1);
+        assert_eq!(error("metadata.plan > 3").position, 15);
+    }
+
+    #[test]
+    fn test_status_fields() {
+        assert_eq!(
+            parse_user_filter("status in (active, \"suspended\") and status != archived").unwrap(),
+            doc! {
+                "$and": [
+                    { "status": { "$in": ["active", "suspended"] } },
+                    { "status": { "$ne": "archived" } },
+                ]
+            }
+        );
+        assert_eq!(
+            parse_user_filter("status_changed_at >= 2024-03-01").unwrap(),
+            doc! { "status_changed_at": { "$gte": "2024-03-01T00:00:00" } }
+        );
+
+        assert_eq!(
+            parse_user_filter("not email_verified_at exists").unwrap(),
+            doc! { "$nor": [{ "email_verified_at": { "$exists": true } }] }
+        );
+
+        assert_eq!(error("status = deleted").position, 10);
+        assert_eq!(error("status contains act").position, 8);
+    }
+
+    #[test]
+    fn test_regex_values_are_escaped() {
+        assert_eq!(
+            parse_user_filter("name starts_with \".*(\"").unwrap(),
+            doc! { "name": { "$regex": "^\\.\\*\\(", "$options": "i" } }
+        );
+    }
+
+    #[test]
+    fn test_errors_point_at_the_offending_token() {
+        assert_eq!(
+            error("role = admin"),
+            FilterError::new(
+                format!("Unknown field 'role', expected one of {}", Field::NAMES),
+                1
+            )
+        );
+        assert_eq!(
+            error("name = Bob and version >= two").to_string(),
+            "Invalid value 'two' for 'version', expected an integer at position 27"
+        );
+        assert_eq!(error("created_at contains 2024").position, 12);
+        assert_eq!(error("created_at = 2024-13-01").position, 14);
+        assert_eq!(error("name = Bob email = x").position, 12);
+        assert_eq!(error("(name = Bob").position, 12);
+        assert_eq!(error("name = \"Bob").message, "Unterminated string");
+        assert_eq!(error("name = Bob; drop").position, 11);
+        assert_eq!(error("email $regex x").position, 7);
+        assert_eq!(error("version in (1, 2").position, 17);
+    }
+
+    #[test]
+    fn test_limits() {
+        let deep = format!("{}name = x{}", "(".repeat(20), ")".repeat(20));
+        assert!(error(&deep).message.contains("nests deeper"));
+
+        let long = format!("name = \"{}\"", "x".repeat(MAX_FILTER_LENGTH));
+        assert!(error(&long).message.contains("longer than"));
+    }
+
+    #[test]
+    fn test_blank_filter_is_no_filter() {
+        let query = UserListQuery {
+            filter: Some("  ".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(query.to_filter(), Ok(None));
+        assert_eq!(UserListQuery::default().to_filter(), Ok(None));
+    }
+}
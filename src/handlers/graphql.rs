@@ -0,0 +1,65 @@
+use async_graphql::http::{receive_body, GraphiQLSource, MultipartOptions};
+use async_graphql_warp::{GraphQLBadRequest, GraphQLResponse};
+use futures::io::Cursor;
+use warp::hyper::body::Bytes;
+use warp::{Rejection, Reply};
+
+use crate::graphql::UserSchema;
+use crate::handlers::audit::AuditContext;
+
+/// Lets GraphiQL load from unpkg and run its inline script, which the API-wide policy forbids
+const GRAPHIQL_CSP: &str = "default-src 'self'; script-src 'self' 'unsafe-inline' unpkg.com; \
+    style-src 'self' 'unsafe-inline' unpkg.com; img-src 'self' data:; connect-src 'self' ws: wss:; \
+    frame-ancestors 'none'";
+
+/// Execute a GraphQL request, recording mutations under the request's audit context
+pub async fn graphql_request(
+    content_type: Option<String>,
+    body: Bytes,
+    audit: AuditContext,
+    schema: UserSchema,
+) -> Result<GraphQLResponse, Rejection> {
+    let request = receive_body(content_type, Cursor::new(body), MultipartOptions::default())
+        .await
+        .map_err(|e| warp::reject::custom(GraphQLBadRequest(e)))?;
+
+    Ok(GraphQLResponse::from(
+        schema.execute(request.data(audit)).await,
+    ))
+}
+
+/// Serve the GraphiQL playground, which only exists when `GRAPHIQL_ENABLED` is set
+pub async fn graphiql(enabled: bool) -> Result<impl Reply, Rejection> {
+    if !enabled {
+        return Err(warp::reject::not_found());
+    }
+
+    Ok(warp::reply::with_header(
+        warp::reply::html(GraphiQLSource::build().endpoint("/graphql").finish()),
+        "content-security-policy",
+        GRAPHIQL_CSP,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::http::StatusCode;
+
+    #[tokio::test]
+    async fn test_graphiql_disabled_is_not_found() {
+        let rejection = graphiql(false).await.err().unwrap();
+        assert!(rejection.is_not_found());
+    }
+
+    #[tokio::test]
+    async fn test_graphiql_points_at_endpoint() {
+        let response = graphiql(true).await.unwrap().into_response();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        assert!(String::from_utf8_lossy(&body).contains("/graphql"));
+    }
+}
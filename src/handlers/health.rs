@@ -1,11 +1,12 @@
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
 use warp::{http::StatusCode, Rejection, Reply};
 
 /// Application version constant
 const API_VERSION: &str = "1.0.0";
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
 pub struct HealthResponse {
     pub status: String,
     pub timestamp: DateTime<Utc>,
@@ -22,6 +23,13 @@ pub async fn health_check() -> Result<impl Reply, Rejection> {
     Ok(warp::reply::json(&response))
 }
 
+/// Report that the service is up
+#[utoipa::path(
+    get,
+    path = "/health",
+    tag = "health",
+    responses((status = 200, description = "Service is healthy", body = HealthResponse))
+)]
 pub async fn health_check_with_status() -> Result<impl Reply, Rejection> {
     let response = health_check().await?;
 
@@ -0,0 +1,495 @@
+use mongodb::bson::{doc, spec::BinarySubtype, Binary, Bson, DateTime};
+use mongodb::{Collection, Database};
+use sha2::{Digest, Sha256};
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+use warp::http::{header, HeaderValue, StatusCode};
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+use crate::db::{idempotency_lease, is_duplicate_key_error, IDEMPOTENCY_COLLECTION};
+use crate::handlers::users::ErrorResponse;
+use crate::models::IdempotencyRecord;
+
+/// Maximum accepted length of an `Idempotency-Key` header value
+const MAX_IDEMPOTENCY_KEY_LENGTH: usize = 255;
+
+/// Header added to responses that were replayed from a stored record
+pub const IDEMPOTENT_REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Compute the fingerprint stored alongside an idempotency key
+pub fn request_fingerprint(method: &str, path: &str, body: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(method.as_bytes());
+    hasher.update(b" ");
+    hasher.update(path.as_bytes());
+    hasher.update(b"\n");
+    hasher.update(body);
+    hex::encode(hasher.finalize())
+}
+
+/// Run `handler` at most once per `Idempotency-Key`.
+///
+/// Without a key the handler simply runs. With a key, the first request stores its
+/// response and later requests with the same key and fingerprint get that response
+/// replayed. Reusing a key with a different request body is rejected with 422. While
+/// the handler runs its claim is renewed every third of the lease, so a retry gets 409
+/// for as long as the first request is alive. Only a claim left behind by a request that
+/// died is taken over once its lease runs out.
+pub async fn with_idempotency<F, R>(
+    db: Arc<Database>,
+    idempotency_key: Option<String>,
+    scope: &str,
+    fingerprint: String,
+    handler: F,
+) -> Result<Response, Rejection>
+where
+    F: Future<Output = Result<R, Rejection>>,
+    R: Reply,
+{
+    let key = match idempotency_key {
+        Some(key) => key.trim().to_string(),
+        None => return handler.await.map(Reply::into_response),
+    };
+
+    if key.is_empty() || key.len() > MAX_IDEMPOTENCY_KEY_LENGTH {
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "invalid_idempotency_key",
+            "Idempotency-Key must be between 1 and 255 characters",
+        ));
+    }
+
+    let collection: Collection<IdempotencyRecord> = db.collection(IDEMPOTENCY_COLLECTION);
+    let record_id = format!("{}:{}", scope, key);
+
+    // Claim the key before running the handler so concurrent retries cannot both execute it
+    let lease = idempotency_lease();
+    let placeholder = IdempotencyRecord::pending(record_id.clone(), fingerprint.clone());
+    let mut claimed_at = placeholder.created_at;
+    match collection.insert_one(&placeholder, None).await {
+        Ok(_) => {}
+        Err(e) if is_duplicate_key_error(&e) => {
+            let existing = match collection.find_one(doc! { "_id": &record_id }, None).await {
+                Ok(Some(existing)) => existing,
+                // The record expired between the insert and the lookup
+                Ok(None) => {
+                    return Ok(error_reply(
+                        StatusCode::CONFLICT,
+                        "idempotency_conflict",
+                        "Request with this Idempotency-Key is being processed, retry later",
+                    ))
+                }
+                Err(_) => {
+                    return Ok(error_reply(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "database_error",
+                        "Failed to read idempotency record",
+                    ))
+                }
+            };
+
+            if existing.fingerprint != fingerprint
+                || !claim_expired(&existing, lease, DateTime::now())
+            {
+                return Ok(stored_response(&existing, &fingerprint));
+            }
+
+            // The request holding the claim stopped renewing it; take it over, matching
+            // on the old claim so only one retry wins
+            claimed_at = DateTime::now();
+            let reclaim = collection
+                .update_one(
+                    doc! {
+                        "_id": &record_id,
+                        "status_code": Bson::Null,
+                        "claimed_at": existing.claimed_at,
+                    },
+                    doc! { "$set": { "claimed_at": claimed_at } },
+                    None,
+                )
+                .await;
+            match reclaim {
+                Ok(result) if result.modified_count == 1 => {}
+                Ok(_) => return Ok(stored_response(&existing, &fingerprint)),
+                Err(_) => {
+                    return Ok(error_reply(
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "database_error",
+                        "Failed to store idempotency record",
+                    ))
+                }
+            }
+        }
+        Err(_) => {
+            return Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "database_error",
+                "Failed to store idempotency record",
+            ));
+        }
+    }
+
+    let (result, claim) = renewing_claim(&collection, &record_id, claimed_at, lease, handler).await;
+    // Every write from here on only touches the record while this request still owns it
+    let Some(claimed_at) = claim else {
+        eprintln!(
+            "Lost the claim on idempotency key {} while handling it",
+            record_id
+        );
+        return result.map(Reply::into_response);
+    };
+    let owned = doc! {
+        "_id": &record_id,
+        "status_code": Bson::Null,
+        "claimed_at": claimed_at,
+    };
+
+    let response = match result {
+        Ok(reply) => reply.into_response(),
+        Err(rejection) => {
+            let _ = collection.delete_one(owned, None).await;
+            return Err(rejection);
+        }
+    };
+
+    let (parts, body) = response.into_parts();
+    let body_bytes = match warp::hyper::body::to_bytes(body).await {
+        Ok(bytes) => bytes,
+        Err(_) => {
+            let _ = collection.delete_one(owned, None).await;
+            return Ok(error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "Failed to read response body",
+            ));
+        }
+    };
+
+    if parts.status.is_server_error() {
+        // Server errors are not stored so the client can retry with the same key
+        let _ = collection.delete_one(owned, None).await;
+    } else {
+        let content_type = parts
+            .headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok());
+        let stored_body = match std::str::from_utf8(&body_bytes) {
+            Ok(text) => doc! { "body": text },
+            Err(_) => doc! {
+                "binary_body": Binary {
+                    subtype: BinarySubtype::Generic,
+                    bytes: body_bytes.to_vec(),
+                },
+            },
+        };
+        let mut fields = doc! {
+            "status_code": parts.status.as_u16() as i32,
+            "content_type": content_type,
+        };
+        fields.extend(stored_body);
+        let update = doc! { "$set": fields };
+        match collection.update_one(owned, update, None).await {
+            Ok(result) if result.matched_count == 1 => {}
+            Ok(_) => eprintln!(
+                "Lost the claim on idempotency key {} before storing its response",
+                record_id
+            ),
+            Err(_) => eprintln!("Failed to store idempotent response for key {}", record_id),
+        }
+    }
+
+    Ok(Response::from_parts(parts, body_bytes.into()))
+}
+
+/// Run `handler` while pushing the claim's `claimed_at` forward every third of `lease`.
+///
+/// Returns the handler's output and the claim time the record holds when it finished,
+/// or `None` if another request took the claim over in the meantime.
+async fn renewing_claim<F: Future>(
+    collection: &Collection<IdempotencyRecord>,
+    record_id: &str,
+    mut claimed_at: DateTime,
+    lease: Duration,
+    handler: F,
+) -> (F::Output, Option<DateTime>) {
+    tokio::pin!(handler);
+    let mut renewal = tokio::time::interval(renewal_period(lease));
+    // The first tick completes at once; the claim was just made
+    renewal.tick().await;
+    let mut owned = true;
+
+    loop {
+        tokio::select! {
+            output = &mut handler => return (output, owned.then_some(claimed_at)),
+            _ = renewal.tick(), if owned => {
+                let renewed_at = DateTime::now();
+                let renewal = collection
+                    .update_one(
+                        doc! {
+                            "_id": record_id,
+                            "status_code": Bson::Null,
+                            "claimed_at": claimed_at,
+                        },
+                        doc! { "$set": { "claimed_at": renewed_at } },
+                        None,
+                    )
+                    .await;
+                match renewal {
+                    Ok(result) if result.matched_count == 1 => claimed_at = renewed_at,
+                    Ok(_) => owned = false,
+                    // Tried again on the next tick, well before the lease runs out
+                    Err(_) => {}
+                }
+            }
+        }
+    }
+}
+
+/// How often a running request renews its claim
+fn renewal_period(lease: Duration) -> Duration {
+    (lease / 3).max(Duration::from_secs(1))
+}
+
+/// Whether a placeholder's claim is older than `lease`, so another request may take it over
+pub fn claim_expired(record: &IdempotencyRecord, lease: Duration, now: DateTime) -> bool {
+    if record.status_code.is_some() {
+        return false;
+    }
+    let claimed_at = record.claimed_at.unwrap_or(record.created_at);
+    let elapsed = now.timestamp_millis() - claimed_at.timestamp_millis();
+    elapsed >= lease.as_millis() as i64
+}
+
+/// Build the response for a repeated key from its stored record
+pub fn stored_response(record: &IdempotencyRecord, fingerprint: &str) -> Response {
+    if record.fingerprint != fingerprint {
+        return error_reply(
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "idempotency_key_reused",
+            "Idempotency-Key was already used with a different request",
+        );
+    }
+
+    let status = match record.status_code.map(StatusCode::from_u16) {
+        Some(Ok(status)) => status,
+        Some(Err(_)) => {
+            return error_reply(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal_error",
+                "Stored idempotent response is invalid",
+            )
+        }
+        None => {
+            return error_reply(
+                StatusCode::CONFLICT,
+                "idempotency_conflict",
+                "Request with this Idempotency-Key is being processed, retry later",
+            )
+        }
+    };
+
+    let body = match &record.binary_body {
+        Some(binary) => binary.bytes.clone().into(),
+        None => record.body.clone().unwrap_or_default().into(),
+    };
+    let mut response = Response::new(body);
+    *response.status_mut() = status;
+
+    if let Some(content_type) = record
+        .content_type
+        .as_deref()
+        .and_then(|value| HeaderValue::from_str(value).ok())
+    {
+        response
+            .headers_mut()
+            .insert(header::CONTENT_TYPE, content_type);
+    }
+    response
+        .headers_mut()
+        .insert(IDEMPOTENT_REPLAYED_HEADER, HeaderValue::from_static("true"));
+
+    response
+}
+
+fn error_reply(status: StatusCode, error: &str, message: &str) -> Response {
+    let error_response = ErrorResponse {
+        error: error.to_string(),
+        message: message.to_string(),
+    };
+    warp::reply::with_status(warp::reply::json(&error_response), status).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completed_record(fingerprint: &str) -> IdempotencyRecord {
+        let mut record =
+            IdempotencyRecord::pending("POST /users:key-1".to_string(), fingerprint.to_string());
+        record.status_code = Some(201);
+        record.content_type = Some("application/json".to_string());
+        record.body = Some(r#"{"id":"abc","name":"Test User"}"#.to_string());
+        record
+    }
+
+    async fn body_string(response: Response) -> String {
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body_bytes.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn test_request_fingerprint_is_deterministic() {
+        let first = request_fingerprint("POST", "/users", br#"{"name":"a"}"#);
+        let second = request_fingerprint("POST", "/users", br#"{"name":"a"}"#);
+
+        assert_eq!(first, second);
+        assert_eq!(first.len(), 64);
+    }
+
+    #[test]
+    fn test_request_fingerprint_differs_by_body_and_path() {
+        let base = request_fingerprint("POST", "/users", br#"{"name":"a"}"#);
+
+        assert_ne!(
+            base,
+            request_fingerprint("POST", "/users", br#"{"name":"b"}"#)
+        );
+        assert_ne!(
+            base,
+            request_fingerprint("POST", "/other", br#"{"name":"a"}"#)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_stored_response_replays_completed_record() {
+        let record = completed_record("hash");
+        let response = stored_response(&record, "hash");
+
+        assert_eq!(response.status(), StatusCode::CREATED);
+        assert_eq!(
+            response.headers().get(IDEMPOTENT_REPLAYED_HEADER).unwrap(),
+            "true"
+        );
+        assert_eq!(
+            response.headers().get(header::CONTENT_TYPE).unwrap(),
+            "application/json"
+        );
+        assert!(body_string(response).await.contains("Test User"));
+    }
+
+    #[tokio::test]
+    async fn test_stored_response_replays_binary_body() {
+        let mut record = completed_record("hash");
+        record.content_type = Some("application/msgpack".to_string());
+        record.body = None;
+        record.binary_body = Some(Binary {
+            subtype: BinarySubtype::Generic,
+            bytes: vec![0x81, 0xa2, 0x69, 0x64, 0xc0],
+        });
+
+        let response = stored_response(&record, "hash");
+        let body_bytes = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(body_bytes.to_vec(), vec![0x81, 0xa2, 0x69, 0x64, 0xc0]);
+    }
+
+    #[tokio::test]
+    async fn test_stored_response_rejects_different_fingerprint() {
+        let record = completed_record("hash");
+        let response = stored_response(&record, "other-hash");
+
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(body_string(response)
+            .await
+            .contains("idempotency_key_reused"));
+    }
+
+    #[test]
+    fn test_claim_expired_after_lease() {
+        let mut record =
+            IdempotencyRecord::pending("POST /users:key-1".to_string(), "hash".to_string());
+        let claimed_at = record.claimed_at.unwrap();
+        let lease = Duration::from_secs(30);
+
+        let soon = DateTime::from_millis(claimed_at.timestamp_millis() + 1_000);
+        let later = DateTime::from_millis(claimed_at.timestamp_millis() + 30_000);
+        assert!(!claim_expired(&record, lease, soon));
+        assert!(claim_expired(&record, lease, later));
+
+        // Completed records are never claimed again
+        record.status_code = Some(201);
+        assert!(!claim_expired(&record, lease, later));
+    }
+
+    #[test]
+    fn test_claims_are_renewed_well_within_the_lease() {
+        assert_eq!(
+            renewal_period(Duration::from_secs(30)),
+            Duration::from_secs(10)
+        );
+        // Never zero, which a timer cannot tick at
+        assert_eq!(renewal_period(Duration::ZERO), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn test_claim_expired_falls_back_to_created_at() {
+        let mut record =
+            IdempotencyRecord::pending("POST /users:key-1".to_string(), "hash".to_string());
+        record.claimed_at = None;
+        let later = DateTime::from_millis(record.created_at.timestamp_millis() + 60_000);
+
+        assert!(claim_expired(&record, Duration::from_secs(30), later));
+    }
+
+    #[tokio::test]
+    async fn test_stored_response_for_pending_record() {
+        let record =
+            IdempotencyRecord::pending("POST /users:key-1".to_string(), "hash".to_string());
+        let response = stored_response(&record, "hash");
+
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        assert!(body_string(response).await.contains("idempotency_conflict"));
+    }
+
+    #[tokio::test]
+    async fn test_with_idempotency_without_key_runs_handler() {
+        // The database is never touched when no key is supplied
+        let client = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap();
+        let db = Arc::new(client.database("simple_api_db"));
+
+        let handler =
+            async { Ok::<_, Rejection>(warp::reply::with_status("ok", StatusCode::ACCEPTED)) };
+        let response = with_idempotency(db, None, "POST /users", "hash".to_string(), handler)
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn test_with_idempotency_rejects_blank_key() {
+        let client = mongodb::Client::with_uri_str("mongodb://localhost:27017")
+            .await
+            .unwrap();
+        let db = Arc::new(client.database("simple_api_db"));
+
+        let handler = async { Ok::<_, Rejection>(warp::reply()) };
+        let response = with_idempotency(
+            db,
+            Some("   ".to_string()),
+            "POST /users",
+            "hash".to_string(),
+            handler,
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(body_string(response)
+            .await
+            .contains("invalid_idempotency_key"));
+    }
+}
@@ -1,5 +1,47 @@
+pub mod audit;
+pub mod auth;
+pub mod avatars;
+pub mod compression;
+pub mod conditional;
+pub mod docs;
+pub mod events;
+pub mod fields;
+pub mod filter;
+pub mod graphql;
 pub mod health;
+pub mod idempotency;
+pub mod negotiation;
+pub mod pagination;
+pub mod search;
+pub mod security;
+pub mod stats;
+pub mod status;
+pub mod streaming;
 pub mod users;
+pub mod users_v2;
+pub mod verification;
+pub mod versioning;
+pub mod webhooks;
+pub mod ws;
 
+pub use audit::*;
+pub use auth::*;
+pub use avatars::*;
+pub use compression::*;
+pub use docs::*;
+pub use events::*;
+pub use fields::*;
+pub use filter::*;
+pub use graphql::*;
 pub use health::*;
+pub use negotiation::*;
+pub use search::*;
+pub use security::*;
+pub use stats::*;
+pub use status::*;
 pub use users::*;
+pub use users_v2::*;
+pub use verification::*;
+pub use versioning::*;
+pub use webhooks::*;
+pub use ws::*;
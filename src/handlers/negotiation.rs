@@ -0,0 +1,508 @@
+//! Picking response formats from `Accept` and decoding request bodies by `Content-Type`
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use warp::http::{header, HeaderValue, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+use crate::handlers::compression::limited_body;
+
+/// A representation of a resource that clients can send or ask for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    MessagePack,
+    Cbor,
+    /// Only for lists, one row per item
+    Csv,
+    Xml,
+    /// Only for lists, one JSON document per line, streamed as it is read
+    Ndjson,
+}
+
+/// The `Accept` header asked only for formats this endpoint cannot produce
+#[derive(Debug)]
+pub struct NotAcceptable;
+
+impl warp::reject::Reject for NotAcceptable {}
+
+/// The request body has a `Content-Type` this endpoint cannot read
+#[derive(Debug)]
+pub struct UnsupportedMediaType;
+
+impl warp::reject::Reject for UnsupportedMediaType {}
+
+/// The request body could not be decoded in the format its `Content-Type` names
+#[derive(Debug)]
+pub struct InvalidBody(pub Format);
+
+impl warp::reject::Reject for InvalidBody {}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Json => "application/json",
+            Format::MessagePack => "application/msgpack",
+            Format::Cbor => "application/cbor",
+            Format::Csv => "text/csv",
+            Format::Xml => "application/xml",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// Whether the format can only represent lists
+    pub fn list_only(&self) -> bool {
+        matches!(self, Format::Csv | Format::Ndjson)
+    }
+
+    fn from_media_type(media_type: &str) -> Option<Format> {
+        match media_type {
+            "application/json" => Some(Format::Json),
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Format::MessagePack)
+            }
+            "application/cbor" => Some(Format::Cbor),
+            "text/csv" => Some(Format::Csv),
+            "application/xml" | "text/xml" => Some(Format::Xml),
+            "application/x-ndjson" | "application/ndjson" => Some(Format::Ndjson),
+            _ => None,
+        }
+    }
+
+    /// Choose the reply format from an `Accept` header, honouring q-values.
+    ///
+    /// A missing header or a wildcard means JSON. CSV is only offered for
+    /// lists, like NDJSON. `None` means nothing acceptable can be produced.
+    pub fn negotiate(accept: Option<&str>, list: bool) -> Option<Format> {
+        let accept = match accept.map(str::trim) {
+            None | Some("") => return Some(Format::Json),
+            Some(accept) => accept,
+        };
+
+        let mut ranges: Vec<(&str, f32)> = accept
+            .split(',')
+            .map(|range| {
+                let mut parts = range.split(';').map(str::trim);
+                let media_type = parts.next().unwrap_or_default();
+                let quality = parts
+                    .find_map(|param| param.strip_prefix("q="))
+                    .and_then(|q| q.parse().ok())
+                    .unwrap_or(1.0);
+                (media_type, quality)
+            })
+            .filter(|(_, quality)| *quality > 0.0)
+            .collect();
+        // Stable, so equally preferred ranges keep the client's order
+        ranges.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        ranges.into_iter().find_map(|(media_type, _)| {
+            match media_type.to_ascii_lowercase().as_str() {
+                "*/*" | "application/*" => Some(Format::Json),
+                "text/*" if list => Some(Format::Csv),
+                "text/*" => Some(Format::Xml),
+                other => {
+                    Format::from_media_type(other).filter(|format| list || !format.list_only())
+                }
+            }
+        })
+    }
+
+    /// The format of a request body; a missing `Content-Type` means JSON
+    pub fn from_content_type(content_type: Option<&str>) -> Option<Format> {
+        let media_type = match content_type {
+            None => return Some(Format::Json),
+            Some(content_type) => content_type
+                .split(';')
+                .next()
+                .unwrap_or_default()
+                .trim()
+                .to_ascii_lowercase(),
+        };
+
+        Format::from_media_type(&media_type).filter(|format| !format.list_only())
+    }
+
+    /// Serialize a single value; `root` names the XML element
+    pub fn encode<T: Serialize>(&self, root: &str, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Format::Json => serde_json::to_vec(value).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::to_vec_named(value).map_err(|e| e.to_string()),
+            Format::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::into_writer(value, &mut bytes).map_err(|e| e.to_string())?;
+                Ok(bytes)
+            }
+            Format::Csv => encode_csv(std::slice::from_ref(value)),
+            Format::Xml => quick_xml::se::to_string_with_root(root, value)
+                .map(String::into_bytes)
+                .map_err(|e| e.to_string()),
+            Format::Ndjson => {
+                let mut line = serde_json::to_vec(value).map_err(|e| e.to_string())?;
+                line.push(b'\n');
+                Ok(line)
+            }
+        }
+    }
+
+    /// Serialize a list; in XML every item is an `<item>` inside `root`
+    pub fn encode_list<T: Serialize>(&self, root: &str, items: &[T]) -> Result<Vec<u8>, String> {
+        #[derive(Serialize)]
+        struct XmlList<'a, T> {
+            item: &'a [T],
+        }
+
+        match self {
+            Format::Csv => encode_csv(items),
+            Format::Xml => self.encode(root, &XmlList { item: items }),
+            Format::Ndjson => items.iter().try_fold(Vec::new(), |mut lines, item| {
+                lines.extend(self.encode(root, item)?);
+                Ok(lines)
+            }),
+            _ => self.encode(root, &items),
+        }
+    }
+
+    /// Deserialize a request body
+    pub fn decode<T: DeserializeOwned>(&self, body: &[u8]) -> Result<T, String> {
+        match self {
+            Format::Json => serde_json::from_slice(body).map_err(|e| e.to_string()),
+            Format::MessagePack => rmp_serde::from_slice(body).map_err(|e| e.to_string()),
+            Format::Cbor => ciborium::from_reader(body).map_err(|e| e.to_string()),
+            Format::Csv => Err("CSV request bodies are not supported".to_string()),
+            Format::Ndjson => Err("NDJSON request bodies are not supported".to_string()),
+            Format::Xml => {
+                let text = std::str::from_utf8(body).map_err(|e| e.to_string())?;
+                quick_xml::de::from_str(text).map_err(|e| e.to_string())
+            }
+        }
+    }
+
+    /// Reply with a single value in this format
+    pub fn reply<T: Serialize>(&self, root: &str, value: &T) -> Response {
+        self.bytes_reply(self.encode(root, value))
+    }
+
+    /// Reply with a list in this format
+    pub fn reply_list<T: Serialize>(&self, root: &str, items: &[T]) -> Response {
+        self.bytes_reply(self.encode_list(root, items))
+    }
+
+    fn bytes_reply(&self, encoded: Result<Vec<u8>, String>) -> Response {
+        let mut response = match encoded {
+            Ok(bytes) => {
+                let mut response = Response::new(bytes.into());
+                response.headers_mut().insert(
+                    header::CONTENT_TYPE,
+                    HeaderValue::from_static(self.content_type()),
+                );
+                response
+            }
+            Err(_) => warp::reply::with_status(
+                warp::reply::json(&serde_json::json!({
+                    "error": "internal_error",
+                    "message": "Failed to encode response",
+                })),
+                StatusCode::INTERNAL_SERVER_ERROR,
+            )
+            .into_response(),
+        };
+        response
+            .headers_mut()
+            .insert(header::VARY, HeaderValue::from_static("accept"));
+        response
+    }
+}
+
+/// Write one row per item, with the keys of the first item as the header.
+///
+/// Items go through `serde_json::Value` so records held as JSON objects encode
+/// like structs; missing values are empty and nested values are written as JSON.
+fn encode_csv<T: Serialize>(items: &[T]) -> Result<Vec<u8>, String> {
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for (index, item) in items.iter().enumerate() {
+        let record = match serde_json::to_value(item).map_err(|e| e.to_string())? {
+            serde_json::Value::Object(record) => record,
+            _ => return Err("CSV rows must be records".to_string()),
+        };
+        // `preserve_order` keeps the keys in field declaration order
+        if index == 0 {
+            writer
+                .write_record(record.keys())
+                .map_err(|e| e.to_string())?;
+        }
+        writer
+            .write_record(record.values().map(csv_field))
+            .map_err(|e| e.to_string())?;
+    }
+    writer.into_inner().map_err(|e| e.to_string())
+}
+
+/// Characters that make spreadsheets read a cell as a formula
+const CSV_FORMULA_PREFIXES: [char; 6] = ['=', '+', '-', '@', '\t', '\r'];
+
+fn csv_field(value: &serde_json::Value) -> String {
+    let text = match value {
+        serde_json::Value::Null => String::new(),
+        serde_json::Value::String(text) => text.clone(),
+        other => other.to_string(),
+    };
+
+    // A leading quote keeps a user's name from running as a formula when the export is opened
+    if text.starts_with(CSV_FORMULA_PREFIXES) {
+        format!("'{}", text)
+    } else {
+        text
+    }
+}
+
+/// Extract the reply format from `Accept`, rejecting with `NotAcceptable` (406)
+pub fn with_format(list: bool) -> impl Filter<Extract = (Format,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("accept").and_then(move |accept: Option<String>| async move {
+        Format::negotiate(accept.as_deref(), list)
+            .ok_or_else(|| warp::reject::custom(NotAcceptable))
+    })
+}
+
+/// Decode a request body of at most `limit` bytes by `Content-Type`, rejecting with
+/// `UnsupportedMediaType` (415) or `InvalidBody` (400)
+pub fn negotiated_body<T>(limit: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    negotiated_body_with_bytes(limit).map(|body: T, _raw: Bytes| body)
+}
+
+/// Like `negotiated_body`, but also extract the raw bytes the body was decoded from
+pub fn negotiated_body_with_bytes<T>(
+    limit: u64,
+) -> impl Filter<Extract = (T, Bytes), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    warp::header::optional::<String>("content-type")
+        .and(limited_body(limit))
+        .and_then(|content_type: Option<String>, body: Bytes| async move {
+            let format = Format::from_content_type(content_type.as_deref())
+                .ok_or_else(|| warp::reject::custom(UnsupportedMediaType))?;
+            format
+                .decode(&body)
+                .map(|decoded| (decoded, body))
+                .map_err(|_| warp::reject::custom(InvalidBody(format)))
+        })
+        .untuple_one()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::users::{CreateUserRequest, UserResponse};
+
+    fn test_user() -> UserResponse {
+        UserResponse {
+            id: "abc".to_string(),
+            name: "Test User".to_string(),
+            email: "test@example.com".to_string(),
+            created_at: "2024-01-01T00:00:00+00:00".to_string(),
+            version: 1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_negotiate_defaults_to_json() {
+        assert_eq!(Format::negotiate(None, false), Some(Format::Json));
+        assert_eq!(Format::negotiate(Some("*/*"), false), Some(Format::Json));
+        assert_eq!(
+            Format::negotiate(Some("text/html, */*;q=0.8"), false),
+            Some(Format::Json)
+        );
+    }
+
+    #[test]
+    fn test_negotiate_honours_quality() {
+        assert_eq!(
+            Format::negotiate(Some("application/json;q=0.5, application/msgpack"), false),
+            Some(Format::MessagePack)
+        );
+        assert_eq!(
+            Format::negotiate(Some("application/cbor;q=0, application/xml"), false),
+            Some(Format::Xml)
+        );
+    }
+
+    #[test]
+    fn test_csv_is_only_offered_for_lists() {
+        assert_eq!(Format::negotiate(Some("text/csv"), true), Some(Format::Csv));
+        assert_eq!(Format::negotiate(Some("text/csv"), false), None);
+        assert_eq!(Format::negotiate(Some("image/png"), true), None);
+    }
+
+    #[test]
+    fn test_ndjson_is_only_offered_for_lists() {
+        assert_eq!(
+            Format::negotiate(Some("application/x-ndjson"), true),
+            Some(Format::Ndjson)
+        );
+        assert_eq!(Format::negotiate(Some("application/ndjson"), false), None);
+        assert_eq!(
+            Format::from_content_type(Some("application/x-ndjson")),
+            None
+        );
+
+        let lines = Format::Ndjson
+            .encode_list("users", &[test_user(), test_user()])
+            .unwrap();
+        let lines = String::from_utf8(lines).unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        assert!(lines.ends_with("}\n"));
+    }
+
+    #[test]
+    fn test_from_content_type() {
+        assert_eq!(Format::from_content_type(None), Some(Format::Json));
+        assert_eq!(
+            Format::from_content_type(Some("application/json; charset=utf-8")),
+            Some(Format::Json)
+        );
+        assert_eq!(
+            Format::from_content_type(Some("application/x-msgpack")),
+            Some(Format::MessagePack)
+        );
+        assert_eq!(Format::from_content_type(Some("text/csv")), None);
+        assert_eq!(Format::from_content_type(Some("text/plain")), None);
+    }
+
+    #[test]
+    fn test_binary_formats_round_trip() {
+        for format in [Format::Json, Format::MessagePack, Format::Cbor] {
+            let encoded = format.encode("user", &test_user()).unwrap();
+            let decoded: UserResponse = format.decode(&encoded).unwrap();
+            assert_eq!(decoded.email, "test@example.com");
+        }
+    }
+
+    #[test]
+    fn test_xml_uses_root_and_item_elements() {
+        let single = String::from_utf8(Format::Xml.encode("user", &test_user()).unwrap()).unwrap();
+        assert!(single.starts_with("<user>"));
+        assert!(single.contains("<email>test@example.com</email>"));
+
+        let list =
+            String::from_utf8(Format::Xml.encode_list("users", &[test_user()]).unwrap()).unwrap();
+        assert!(list.starts_with("<users><item>"));
+
+        let request: CreateUserRequest = Format::Xml
+            .decode(b"<user><name>Jane</name><email>jane@example.com</email></user>")
+            .unwrap();
+        assert_eq!(request.name, "Jane");
+    }
+
+    #[test]
+    fn test_csv_has_header_and_row_per_item() {
+        let csv = Format::Csv
+            .encode_list("users", &[test_user(), test_user()])
+            .unwrap();
+        let csv = String::from_utf8(csv).unwrap();
+
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[0],
+            "id,name,email,created_at,version,phone,locale,timezone,avatar_url,metadata,status,\
+             status_reason,status_changed_at,email_verified_at"
+        );
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn test_csv_escapes_formulas() {
+        let mut user = test_user();
+        user.name = "=HYPERLINK(\"http://evil.example\")".to_string();
+        user.email = "@sum@example.com".to_string();
+        user.phone = Some("+441234567890".to_string());
+        user.locale = Some("-en".to_string());
+        user.timezone = Some("\tUTC".to_string());
+
+        let csv = String::from_utf8(Format::Csv.encode_list("users", &[user]).unwrap()).unwrap();
+        let row = csv.lines().nth(1).unwrap();
+        assert!(row.starts_with(
+            "abc,\"'=HYPERLINK(\"\"http://evil.example\"\")\",'@sum@example.com,\
+             2024-01-01T00:00:00+00:00,1,'+441234567890,'-en,'\tUTC,"
+        ));
+    }
+
+    #[test]
+    fn test_csv_writes_metadata_as_json() {
+        let mut user = test_user();
+        user.locale = Some("en-GB".to_string());
+        user.metadata.insert("plan".to_string(), "pro".to_string());
+
+        let csv = String::from_utf8(Format::Csv.encode_list("users", &[user]).unwrap()).unwrap();
+        assert!(csv
+            .lines()
+            .nth(1)
+            .unwrap()
+            .ends_with(",en-GB,,,\"{\"\"plan\"\":\"\"pro\"\"}\",active,,,"));
+    }
+
+    #[test]
+    fn test_csv_of_json_records_keeps_field_order() {
+        let records = [
+            serde_json::json!({ "id": "abc", "name": "Test User", "email": "test@example.com", "created_at": "2024-01-01T00:00:00+00:00", "version": 1 }),
+            serde_json::json!({ "id": "def", "name": null, "email": "a,b", "created_at": "", "version": 2 }),
+        ];
+        let csv = String::from_utf8(Format::Csv.encode_list("users", &records).unwrap()).unwrap();
+
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "id,name,email,created_at,version");
+        assert_eq!(lines[2], "def,,\"a,b\",,2");
+    }
+
+    #[tokio::test]
+    async fn test_with_format_rejects_unacceptable() {
+        let rejection = warp::test::request()
+            .header("accept", "text/csv")
+            .filter(&with_format(false))
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<NotAcceptable>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_body_by_content_type() {
+        let body = rmp_serde::to_vec_named(&serde_json::json!({
+            "name": "Jane",
+            "email": "jane@example.com",
+        }))
+        .unwrap();
+        let request: CreateUserRequest = warp::test::request()
+            .header("content-type", "application/msgpack")
+            .body(body)
+            .filter(&negotiated_body(1024))
+            .await
+            .unwrap();
+        assert_eq!(request.email, "jane@example.com");
+
+        let rejection = warp::test::request()
+            .header("content-type", "text/plain")
+            .body("name=Jane")
+            .filter(&negotiated_body::<CreateUserRequest>(1024))
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<UnsupportedMediaType>().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_negotiated_body_with_bytes_keeps_raw_body() {
+        let body = r#"{ "email": "jane@example.com", "name": "Jane" }"#;
+        let (request, raw): (CreateUserRequest, Bytes) = warp::test::request()
+            .header("content-type", "application/json")
+            .body(body)
+            .filter(&negotiated_body_with_bytes(1024))
+            .await
+            .unwrap();
+        assert_eq!(request.name, "Jane");
+        assert_eq!(raw, body.as_bytes());
+    }
+}
@@ -0,0 +1,89 @@
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Page size used when the client does not ask for one
+pub const DEFAULT_PER_PAGE: u64 = 20;
+
+/// Largest page size a client may request
+pub const MAX_PER_PAGE: u64 = 100;
+
+/// A validated page request (1-based page number)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pagination {
+    pub page: u64,
+    pub per_page: u64,
+}
+
+impl Pagination {
+    /// Build from optional query values, clamping them into the allowed range
+    pub fn new(page: Option<u64>, per_page: Option<u64>) -> Self {
+        Pagination {
+            page: page.unwrap_or(1).max(1),
+            per_page: per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE),
+        }
+    }
+
+    /// Number of documents to skip before this page
+    pub fn skip(&self) -> u64 {
+        (self.page - 1) * self.per_page
+    }
+}
+
+/// A page of results together with the total number of matches
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct PageResponse<T> {
+    pub items: Vec<T>,
+    pub page: u64,
+    pub per_page: u64,
+    pub total: u64,
+}
+
+impl<T> PageResponse<T> {
+    pub fn new(items: Vec<T>, pagination: Pagination, total: u64) -> Self {
+        PageResponse {
+            items,
+            page: pagination.page,
+            per_page: pagination.per_page,
+            total,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pagination_defaults() {
+        let pagination = Pagination::new(None, None);
+
+        assert_eq!(pagination.page, 1);
+        assert_eq!(pagination.per_page, DEFAULT_PER_PAGE);
+        assert_eq!(pagination.skip(), 0);
+    }
+
+    #[test]
+    fn test_pagination_clamps_values() {
+        assert_eq!(
+            Pagination::new(Some(0), Some(0)),
+            Pagination::new(Some(1), Some(1))
+        );
+        assert_eq!(Pagination::new(None, Some(10_000)).per_page, MAX_PER_PAGE);
+    }
+
+    #[test]
+    fn test_pagination_skip() {
+        assert_eq!(Pagination::new(Some(3), Some(25)).skip(), 50);
+    }
+
+    #[test]
+    fn test_page_response_serialization() {
+        let response = PageResponse::new(vec!["a", "b"], Pagination::new(Some(2), Some(2)), 5);
+
+        let json_str = serde_json::to_string(&response).unwrap();
+        assert_eq!(
+            json_str,
+            r#"{"items":["a","b"],"page":2,"per_page":2,"total":5}"#
+        );
+    }
+}
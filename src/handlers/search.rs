@@ -0,0 +1,658 @@
+//! Full-text user search with an accent- and typo-tolerant fallback
+//!
+//! Queries go to the `user_text` index first. When it finds nothing, usually
+//! because the query is a prefix or has a typo, a regex over the folded letters
+//! of each term finds near matches instead, which are scored here.
+
+use futures::stream::StreamExt;
+use mongodb::bson::{self, doc, Document};
+use mongodb::options::FindOptions;
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+use warp::{http::StatusCode, Rejection, Reply};
+
+use crate::handlers::pagination::{PageResponse, Pagination};
+use crate::handlers::users::{ErrorResponse, UserResponse};
+use crate::handlers::users_v2::UserV2Response;
+use crate::models::User;
+
+/// Longest query accepted, in characters
+const MAX_QUERY_LENGTH: usize = 100;
+
+/// Most users the fallback scores in memory
+const FUZZY_CANDIDATE_LIMIT: i64 = 500;
+
+/// Shorter terms only match as written, since one typo in them matches almost anything
+const MIN_FUZZY_TERM_LENGTH: usize = 4;
+
+/// Longer terms only match as written, which keeps the fallback regex small
+const MAX_FUZZY_TERM_LENGTH: usize = 32;
+
+/// Weights of the two fields, as in the text index
+const NAME_WEIGHT: f64 = 3.0;
+const EMAIL_WEIGHT: f64 = 1.0;
+
+/// Score of a term found with one typo, relative to one found as written
+const TYPO_SCORE: f64 = 0.5;
+
+/// Accented letters and the letter they fold to
+const ACCENTS: [(char, &str); 16] = [
+    ('a', "àáâãäåāăą"),
+    ('c', "çćĉċč"),
+    ('d', "ďđ"),
+    ('e', "èéêëēĕėęě"),
+    ('g', "ĝğġģ"),
+    ('i', "ìíîïĩīĭįı"),
+    ('l', "ĺļľŀł"),
+    ('n', "ñńņň"),
+    ('o', "òóôõöøōŏő"),
+    ('r', "ŕŗř"),
+    ('s', "śŝşš"),
+    ('t', "ţťŧ"),
+    ('u', "ùúûüũūŭůűų"),
+    ('w', "ŵ"),
+    ('y', "ýÿŷ"),
+    ('z', "źżž"),
+];
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct SearchQuery {
+    /// Words to look for in names and emails
+    pub q: Option<String>,
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+}
+
+/// How a search hit was found
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum MatchKind {
+    /// By the text index, scored by MongoDB
+    Text,
+    /// By the prefix and typo fallback, scored by the API
+    Fuzzy,
+}
+
+/// Field values with the matched parts wrapped in `<em>`, HTML-escaped otherwise
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, ToSchema)]
+pub struct SearchHighlights {
+    pub name: Option<String>,
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct UserSearchHit {
+    pub user: UserResponse,
+    pub score: f64,
+    pub matched_by: MatchKind,
+    pub highlights: SearchHighlights,
+}
+
+/// A search hit in v2, with camelCase fields
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSearchHitV2 {
+    pub user: UserV2Response,
+    pub score: f64,
+    pub matched_by: MatchKind,
+    pub highlights: SearchHighlights,
+}
+
+/// A page of v2 search hits, in the `data` envelope v2 lists use
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserSearchV2Response {
+    pub data: Vec<UserSearchHitV2>,
+    pub page: u64,
+    pub per_page: u64,
+    pub total: u64,
+}
+
+/// A matching user before it is rendered for an API version
+struct SearchMatch {
+    user: User,
+    score: f64,
+    matched_by: MatchKind,
+    highlights: SearchHighlights,
+}
+
+impl From<SearchMatch> for UserSearchHit {
+    fn from(found: SearchMatch) -> Self {
+        UserSearchHit {
+            user: UserResponse::from(found.user),
+            score: found.score,
+            matched_by: found.matched_by,
+            highlights: found.highlights,
+        }
+    }
+}
+
+impl From<SearchMatch> for UserSearchHitV2 {
+    fn from(found: SearchMatch) -> Self {
+        UserSearchHitV2 {
+            user: UserV2Response::from(found.user),
+            score: found.score,
+            matched_by: found.matched_by,
+            highlights: found.highlights,
+        }
+    }
+}
+
+impl From<PageResponse<SearchMatch>> for UserSearchV2Response {
+    fn from(page: PageResponse<SearchMatch>) -> Self {
+        UserSearchV2Response {
+            data: page.items.into_iter().map(UserSearchHitV2::from).collect(),
+            page: page.page,
+            per_page: page.per_page,
+            total: page.total,
+        }
+    }
+}
+
+/// Lowercase a character and strip its accent
+fn fold_char(c: char) -> char {
+    let lower = c.to_lowercase().next().unwrap_or(c);
+    ACCENTS
+        .iter()
+        .find(|(_, variants)| variants.contains(lower))
+        .map(|(base, _)| *base)
+        .unwrap_or(lower)
+}
+
+/// Fold every character, keeping one folded character per original one
+fn fold(text: &str) -> Vec<char> {
+    text.chars().map(fold_char).collect()
+}
+
+/// Words of the query without the quotes and negation the text index would interpret
+fn query_words(q: &str) -> Vec<String> {
+    q.split_whitespace()
+        .map(|word| word.trim_matches('"').trim_start_matches('-'))
+        .filter(|word| !word.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// The folded, distinct terms of the query words
+fn terms(words: &[String]) -> Vec<Vec<char>> {
+    let mut terms: Vec<Vec<char>> = Vec::new();
+    for word in words {
+        let term = fold(word);
+        if !terms.contains(&term) {
+            terms.push(term);
+        }
+    }
+    terms
+}
+
+/// A term followed by its one-typo spellings, where `None` stands for any character
+fn variants(term: &[char]) -> Vec<Vec<Option<char>>> {
+    let exact: Vec<Option<char>> = term.iter().copied().map(Some).collect();
+    if !(MIN_FUZZY_TERM_LENGTH..=MAX_FUZZY_TERM_LENGTH).contains(&term.len()) {
+        return vec![exact];
+    }
+
+    let mut typos = Vec::new();
+    for i in 0..exact.len() {
+        // A wrong character
+        let mut substituted = exact.clone();
+        substituted[i] = None;
+        typos.push(substituted);
+
+        // An extra character
+        let mut deleted = exact.clone();
+        deleted.remove(i);
+        typos.push(deleted);
+
+        // A missing character
+        if i > 0 {
+            let mut inserted = exact.clone();
+            inserted.insert(i, None);
+            typos.push(inserted);
+        }
+    }
+    typos.sort();
+    typos.dedup();
+    typos.retain(|typo| typo != &exact);
+
+    let mut variants = vec![exact];
+    variants.extend(typos);
+    variants
+}
+
+/// Regex for one folded character that also matches its accented and uppercase forms
+fn char_pattern(c: char) -> String {
+    match ACCENTS.iter().find(|(base, _)| *base == c) {
+        Some((base, accented)) => {
+            let mut class = String::from("[");
+            class.push(*base);
+            for variant in accented.chars() {
+                class.push(variant);
+                class.extend(variant.to_uppercase());
+            }
+            class.push(']');
+            class
+        }
+        None if "\\^$.|?*+()[]{}-/#".contains(c) => format!("\\{}", c),
+        None => c.to_string(),
+    }
+}
+
+/// Regex matching a term, or any of its one-typo spellings, anywhere in a field
+fn term_pattern(term: &[char]) -> String {
+    let alternatives: Vec<String> = variants(term)
+        .iter()
+        .map(|variant| {
+            variant
+                .iter()
+                .map(|c| c.map(char_pattern).unwrap_or_else(|| ".".to_string()))
+                .collect()
+        })
+        .collect();
+
+    if alternatives.len() == 1 {
+        alternatives.into_iter().next().unwrap()
+    } else {
+        format!("(?:{})", alternatives.join("|"))
+    }
+}
+
+/// Users whose name or email contains every term, allowing one typo in each
+fn fuzzy_filter(terms: &[Vec<char>]) -> Document {
+    let clauses: Vec<Document> = terms
+        .iter()
+        .map(|term| {
+            let pattern = term_pattern(term);
+            doc! {
+                "$or": [
+                    { "name": { "$regex": &pattern, "$options": "i" } },
+                    { "email": { "$regex": &pattern, "$options": "i" } },
+                ]
+            }
+        })
+        .collect();
+
+    doc! { "$and": clauses }
+}
+
+/// Where a variant occurs in a folded field, without overlaps
+fn occurrences(field: &[char], variant: &[Option<char>]) -> Vec<(usize, usize)> {
+    let mut ranges = Vec::new();
+    if variant.is_empty() || variant.len() > field.len() {
+        return ranges;
+    }
+
+    let mut start = 0;
+    while start + variant.len() <= field.len() {
+        let matches = variant
+            .iter()
+            .zip(&field[start..])
+            .all(|(expected, actual)| expected.is_none_or(|expected| expected == *actual));
+        if matches {
+            ranges.push((start, start + variant.len()));
+            start += variant.len();
+        } else {
+            start += 1;
+        }
+    }
+    ranges
+}
+
+/// Where a term occurs in a folded field and how well: 1 as written, `TYPO_SCORE` with a typo
+fn term_matches(field: &[char], term: &[char]) -> (f64, Vec<(usize, usize)>) {
+    let mut variants = variants(term).into_iter();
+
+    if let Some(exact) = variants.next() {
+        let ranges = occurrences(field, &exact);
+        if !ranges.is_empty() {
+            return (1.0, ranges);
+        }
+    }
+    for variant in variants {
+        let ranges = occurrences(field, &variant);
+        if !ranges.is_empty() {
+            return (TYPO_SCORE, ranges);
+        }
+    }
+    (0.0, Vec::new())
+}
+
+/// Relevance of a user to the terms, weighing name matches over email matches
+fn fuzzy_score(user: &User, terms: &[Vec<char>]) -> f64 {
+    let name = fold(&user.name);
+    let email = fold(&user.email);
+
+    terms
+        .iter()
+        .map(|term| {
+            NAME_WEIGHT * term_matches(&name, term).0 + EMAIL_WEIGHT * term_matches(&email, term).0
+        })
+        .sum()
+}
+
+fn escape_html(c: char, out: &mut String) {
+    match c {
+        '&' => out.push_str("&amp;"),
+        '<' => out.push_str("&lt;"),
+        '>' => out.push_str("&gt;"),
+        '"' => out.push_str("&quot;"),
+        '\'' => out.push_str("&#39;"),
+        c => out.push(c),
+    }
+}
+
+/// Wrap the parts of `text` matching any term in `<em>`, or `None` if nothing matches
+fn highlight(text: &str, terms: &[Vec<char>]) -> Option<String> {
+    let folded = fold(text);
+    let mut ranges: Vec<(usize, usize)> = terms
+        .iter()
+        .flat_map(|term| term_matches(&folded, term).1)
+        .collect();
+    if ranges.is_empty() {
+        return None;
+    }
+
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = Vec::new();
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+
+    let mut out = String::new();
+    let mut ranges = merged.into_iter().peekable();
+    for (i, c) in text.chars().enumerate() {
+        if ranges.peek().is_some_and(|(start, _)| *start == i) {
+            out.push_str("<em>");
+        }
+        escape_html(c, &mut out);
+        if ranges.peek().is_some_and(|(_, end)| *end == i + 1) {
+            out.push_str("</em>");
+            ranges.next();
+        }
+    }
+    Some(out)
+}
+
+fn hit(user: User, score: f64, matched_by: MatchKind, terms: &[Vec<char>]) -> SearchMatch {
+    let highlights = SearchHighlights {
+        name: highlight(&user.name, terms),
+        email: highlight(&user.email, terms),
+    };
+
+    SearchMatch {
+        user,
+        score,
+        matched_by,
+        highlights,
+    }
+}
+
+/// Search users by name and email, best matches first
+#[utoipa::path(
+    get,
+    path = "/users/search",
+    tag = "users",
+    params(SearchQuery),
+    responses(
+        (status = 200, description = "Matching users with scores and highlights", body = PageResponse<UserSearchHit>),
+        (status = 400, description = "Missing or too long query", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse)
+    )
+)]
+pub async fn search_users(query: SearchQuery, db: Arc<Database>) -> Result<impl Reply, Rejection> {
+    Ok(match search(&query, &db).await {
+        Ok(page) => {
+            let page = PageResponse {
+                items: page.items.into_iter().map(UserSearchHit::from).collect(),
+                page: page.page,
+                per_page: page.per_page,
+                total: page.total,
+            };
+            warp::reply::with_status(warp::reply::json(&page), StatusCode::OK)
+        }
+        Err(error) => error.reply(),
+    })
+}
+
+/// Search users by name and email, best matches first
+#[utoipa::path(
+    get,
+    path = "/v2/users/search",
+    tag = "users",
+    params(SearchQuery),
+    responses(
+        (status = 200, description = "Matching users with scores and highlights", body = UserSearchV2Response),
+        (status = 400, description = "Missing or too long query", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse)
+    )
+)]
+pub async fn search_users_v2(
+    query: SearchQuery,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    Ok(match search(&query, &db).await {
+        Ok(page) => warp::reply::with_status(
+            warp::reply::json(&UserSearchV2Response::from(page)),
+            StatusCode::OK,
+        ),
+        Err(error) => error.reply(),
+    })
+}
+
+/// Why a search failed
+enum SearchError {
+    Validation(String),
+    Database,
+}
+
+impl SearchError {
+    fn reply(self) -> warp::reply::WithStatus<warp::reply::Json> {
+        let (status, error_response) = match self {
+            SearchError::Validation(message) => (
+                StatusCode::BAD_REQUEST,
+                ErrorResponse {
+                    error: "validation_error".to_string(),
+                    message,
+                },
+            ),
+            SearchError::Database => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                ErrorResponse {
+                    error: "database_error".to_string(),
+                    message: "Failed to search users".to_string(),
+                },
+            ),
+        };
+        warp::reply::with_status(warp::reply::json(&error_response), status)
+    }
+}
+
+/// Check the query, then search the text index and fall back to near matches
+async fn search(
+    query: &SearchQuery,
+    db: &Database,
+) -> Result<PageResponse<SearchMatch>, SearchError> {
+    let q = query.q.as_deref().unwrap_or_default().trim();
+    let words = query_words(q);
+
+    if words.is_empty() {
+        return Err(SearchError::Validation(
+            "Query parameter 'q' is required".to_string(),
+        ));
+    }
+    if q.chars().count() > MAX_QUERY_LENGTH {
+        return Err(SearchError::Validation(format!(
+            "Query parameter 'q' must be at most {} characters",
+            MAX_QUERY_LENGTH
+        )));
+    }
+
+    let pagination = Pagination::new(query.page, query.per_page);
+    let terms = terms(&words);
+
+    match text_search(db, &words, &terms, pagination).await {
+        Ok(page) if page.total > 0 => Ok(page),
+        Ok(_) => fuzzy_search(db, &terms, pagination).await,
+        Err(error) => Err(error),
+    }
+    .map_err(|_| SearchError::Database)
+}
+
+/// Users matching whole words through the text index, by MongoDB's relevance score
+async fn text_search(
+    db: &Database,
+    words: &[String],
+    terms: &[Vec<char>],
+    pagination: Pagination,
+) -> Result<PageResponse<SearchMatch>, mongodb::error::Error> {
+    let collection: Collection<Document> = db.collection("users");
+    let filter = doc! { "$text": { "$search": words.join(" ") } };
+
+    let total = collection.count_documents(filter.clone(), None).await?;
+    if total == 0 {
+        return Ok(PageResponse::new(Vec::new(), pagination, 0));
+    }
+
+    let options = FindOptions::builder()
+        .projection(doc! { "score": { "$meta": "textScore" } })
+        .sort(doc! { "score": { "$meta": "textScore" }, "_id": 1 })
+        .skip(pagination.skip())
+        .limit(pagination.per_page as i64)
+        .build();
+
+    let mut cursor = collection.find(filter, options).await?;
+    let mut hits = Vec::new();
+    while let Some(mut document) = cursor.next().await.transpose()? {
+        let score = document
+            .remove("score")
+            .and_then(|score| score.as_f64())
+            .unwrap_or_default();
+        let user: User = bson::from_document(document)?;
+        hits.push(hit(user, score, MatchKind::Text, terms));
+    }
+
+    Ok(PageResponse::new(hits, pagination, total))
+}
+
+/// Users containing every term, allowing prefixes and one typo per term, scored here
+async fn fuzzy_search(
+    db: &Database,
+    terms: &[Vec<char>],
+    pagination: Pagination,
+) -> Result<PageResponse<SearchMatch>, mongodb::error::Error> {
+    let collection: Collection<User> = db.collection("users");
+    let options = FindOptions::builder().limit(FUZZY_CANDIDATE_LIMIT).build();
+
+    let mut cursor = collection.find(fuzzy_filter(terms), options).await?;
+    let mut scored = Vec::new();
+    while let Some(user) = cursor.next().await.transpose()? {
+        let score = fuzzy_score(&user, terms);
+        scored.push((score, user));
+    }
+
+    scored.sort_by(|(a_score, a), (b_score, b)| {
+        b_score.total_cmp(a_score).then_with(|| a.id.cmp(&b.id))
+    });
+
+    let total = scored.len() as u64;
+    let hits = scored
+        .into_iter()
+        .skip(pagination.skip() as usize)
+        .take(pagination.per_page as usize)
+        .map(|(score, user)| hit(user, score, MatchKind::Fuzzy, terms))
+        .collect();
+
+    Ok(PageResponse::new(hits, pagination, total))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn term(text: &str) -> Vec<char> {
+        fold(text)
+    }
+
+    #[test]
+    fn test_fold_strips_case_and_accents() {
+        assert_eq!(fold("Zoë Łukasz"), fold("zoe lukasz"));
+        assert_eq!(fold("ÉMILE").iter().collect::<String>(), "emile");
+        // One folded character per original keeps highlights aligned
+        assert_eq!(fold("Straße").len(), "Straße".chars().count());
+    }
+
+    #[test]
+    fn test_query_words_drop_operators() {
+        assert_eq!(
+            query_words(" \"ada\"  -lovelace - "),
+            vec!["ada".to_string(), "lovelace".to_string()]
+        );
+        assert_eq!(terms(&query_words("José jose")), vec![term("jose")]);
+    }
+
+    #[test]
+    fn test_short_terms_have_no_typo_variants() {
+        assert_eq!(variants(&term("bob")).len(), 1);
+        assert!(variants(&term("alice")).len() > 1);
+    }
+
+    #[test]
+    fn test_term_pattern_matches_accents_and_escapes() {
+        assert_eq!(term_pattern(&term("a.b")), "[aàÀáÁâÂãÃäÄåÅāĀăĂąĄ]\\.b");
+        let pattern = term_pattern(&term("jose"));
+        assert!(pattern.starts_with("(?:"));
+        assert!(pattern.contains("[eèÈéÉ"));
+        assert!(pattern.contains('.'));
+    }
+
+    #[test]
+    fn test_term_matches_prefixes_and_typos() {
+        let name = fold("Margaret Hamilton");
+
+        assert_eq!(term_matches(&name, &term("marg")).0, 1.0);
+        assert_eq!(term_matches(&name, &term("hamiltn")).0, TYPO_SCORE);
+        assert_eq!(term_matches(&name, &term("hamxlton")).0, TYPO_SCORE);
+        assert_eq!(term_matches(&name, &term("hammilton")).0, TYPO_SCORE);
+        assert_eq!(term_matches(&name, &term("hxmxlton")).0, 0.0);
+    }
+
+    #[test]
+    fn test_fuzzy_score_weighs_name_over_email() {
+        let by_name = User::new_user("Grace Hopper".into(), "admiral@navy.mil".into());
+        let by_email = User::new_user("Admiral".into(), "grace@navy.mil".into());
+        let terms = vec![term("grace")];
+
+        assert_eq!(fuzzy_score(&by_name, &terms), NAME_WEIGHT);
+        assert_eq!(fuzzy_score(&by_email, &terms), EMAIL_WEIGHT);
+    }
+
+    #[test]
+    fn test_highlight_wraps_matches_and_escapes() {
+        let terms = vec![term("zoe"), term("ada")];
+
+        assert_eq!(
+            highlight("Zoë <Adams>", &terms).unwrap(),
+            "<em>Zoë</em> &lt;<em>Ada</em>ms&gt;"
+        );
+        assert_eq!(highlight("Grace", &terms), None);
+
+        // Overlapping matches merge into one
+        let terms = vec![term("ann"), term("anne")];
+        assert_eq!(highlight("Anne", &terms).unwrap(), "<em>Anne</em>");
+    }
+
+    #[test]
+    fn test_fuzzy_filter_requires_every_term() {
+        let filter = fuzzy_filter(&[term("ada"), term("lovelace")]);
+        let clauses = filter.get_array("$and").unwrap();
+
+        assert_eq!(clauses.len(), 2);
+        assert!(clauses[0].as_document().unwrap().get_array("$or").is_ok());
+    }
+}
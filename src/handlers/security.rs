@@ -0,0 +1,367 @@
+//! CORS policy and security headers for every response
+
+use std::env;
+use std::time::Duration;
+use warp::http::header::{self, HeaderMap, HeaderName, HeaderValue};
+use warp::http::{Method, Uri};
+use warp::reply::Response;
+use warp::Reply;
+
+/// Methods browsers may use cross-origin unless `CORS_ALLOWED_METHODS` says otherwise
+const DEFAULT_ALLOWED_METHODS: &str = "GET,POST,PATCH,DELETE,OPTIONS";
+
+/// Request headers the API reads, allowed cross-origin unless `CORS_ALLOWED_HEADERS` says otherwise
+const DEFAULT_ALLOWED_HEADERS: &str = "accept,authorization,content-type,content-encoding,\
+    if-match,if-none-match,idempotency-key,last-event-id,x-actor,x-request-id";
+
+/// Response headers scripts may read
+const EXPOSED_HEADERS: [&str; 3] = ["etag", "location", "x-request-id"];
+
+/// How long browsers may cache a preflight response
+const DEFAULT_CORS_MAX_AGE_SECONDS: u64 = 600;
+
+/// One year, as recommended for HSTS
+const DEFAULT_HSTS_MAX_AGE_SECONDS: u64 = 31_536_000;
+
+/// An API only returns data, so it needs no sources and must not be framed
+const DEFAULT_CONTENT_SECURITY_POLICY: &str = "default-src 'none'; frame-ancestors 'none'";
+
+const DEFAULT_REFERRER_POLICY: &str = "no-referrer";
+
+fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+    env::var(name)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Split a comma-separated list, dropping blanks
+fn list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+/// Whether `APP_ENV` names a production deployment
+fn is_production() -> bool {
+    env::var("APP_ENV").unwrap_or_default() == "production"
+}
+
+/// Whether `origin` is a bare `scheme://host[:port]`, which is all an `Origin` header holds
+fn is_valid_origin(origin: &str) -> bool {
+    match origin.parse::<Uri>() {
+        Ok(uri) => {
+            uri.scheme().is_some()
+                && uri.authority().is_some()
+                && matches!(uri.path(), "" | "/")
+                && uri.query().is_none()
+                && !origin.ends_with('/')
+        }
+        Err(_) => false,
+    }
+}
+
+/// Cross-origin access for browsers
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    /// `None` allows any origin
+    pub origins: Option<Vec<String>>,
+    pub methods: Vec<Method>,
+    pub headers: Vec<HeaderName>,
+    pub credentials: bool,
+    pub max_age: u64,
+}
+
+impl CorsConfig {
+    /// Read `CORS_ALLOWED_ORIGINS`, `CORS_ALLOWED_METHODS`, `CORS_ALLOWED_HEADERS`,
+    /// `CORS_ALLOW_CREDENTIALS` and `CORS_MAX_AGE_SECONDS`.
+    ///
+    /// Without `CORS_ALLOWED_ORIGINS`, any origin is allowed in development and
+    /// none when `APP_ENV=production`. `*` allows any origin explicitly.
+    pub fn from_env() -> Self {
+        let origins = match env::var("CORS_ALLOWED_ORIGINS") {
+            Ok(origins) if origins.trim() == "*" => None,
+            Ok(origins) => Some(list(&origins)),
+            Err(_) if is_production() => Some(Vec::new()),
+            Err(_) => None,
+        };
+        let origins = origins.map(|origins| {
+            origins
+                .into_iter()
+                .filter(|origin| {
+                    let valid = is_valid_origin(origin);
+                    if !valid {
+                        eprintln!("Ignoring invalid CORS origin: {}", origin);
+                    }
+                    valid
+                })
+                .collect()
+        });
+
+        let methods = list(
+            &env::var("CORS_ALLOWED_METHODS").unwrap_or_else(|_| DEFAULT_ALLOWED_METHODS.into()),
+        )
+        .iter()
+        .filter_map(|method| method.to_ascii_uppercase().parse().ok())
+        .collect();
+
+        let headers = list(
+            &env::var("CORS_ALLOWED_HEADERS").unwrap_or_else(|_| DEFAULT_ALLOWED_HEADERS.into()),
+        )
+        .iter()
+        .filter_map(|name| name.parse().ok())
+        .collect();
+
+        let mut credentials = env::var("CORS_ALLOW_CREDENTIALS").unwrap_or_default() == "true";
+        if credentials && origins.is_none() {
+            // Credentials for any origin would let every site act as the user
+            eprintln!("CORS_ALLOW_CREDENTIALS needs explicit CORS_ALLOWED_ORIGINS, ignoring it");
+            credentials = false;
+        }
+
+        CorsConfig {
+            origins,
+            methods,
+            headers,
+            credentials,
+            max_age: env_or("CORS_MAX_AGE_SECONDS", DEFAULT_CORS_MAX_AGE_SECONDS),
+        }
+    }
+
+    /// Build the warp CORS wrapper for this policy
+    pub fn build(&self) -> warp::cors::Builder {
+        let cors = warp::cors()
+            .allow_methods(self.methods.iter().cloned())
+            .allow_headers(self.headers.iter().cloned())
+            .expose_headers(EXPOSED_HEADERS)
+            .allow_credentials(self.credentials)
+            .max_age(Duration::from_secs(self.max_age));
+
+        match &self.origins {
+            None => cors.allow_any_origin(),
+            Some(origins) => cors.allow_origins(origins.iter().map(String::as_str)),
+        }
+    }
+}
+
+/// Headers added to every response that does not set them itself
+#[derive(Debug, Clone)]
+pub struct SecurityHeaders {
+    headers: HeaderMap,
+}
+
+impl SecurityHeaders {
+    /// Read `HSTS_MAX_AGE_SECONDS` (0 leaves HSTS out), `CONTENT_SECURITY_POLICY`
+    /// and `REFERRER_POLICY`
+    pub fn from_env() -> Self {
+        let mut headers = HeaderMap::new();
+
+        let hsts_max_age = env_or("HSTS_MAX_AGE_SECONDS", DEFAULT_HSTS_MAX_AGE_SECONDS);
+        if hsts_max_age > 0 {
+            headers.insert(
+                header::STRICT_TRANSPORT_SECURITY,
+                HeaderValue::from_str(&format!("max-age={}; includeSubDomains", hsts_max_age))
+                    .unwrap(),
+            );
+        }
+
+        headers.insert(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        );
+        headers.insert(header::X_FRAME_OPTIONS, HeaderValue::from_static("DENY"));
+
+        let policy = |name: &str, default: &'static str| {
+            env::var(name)
+                .ok()
+                .and_then(|value| HeaderValue::from_str(&value).ok())
+                .unwrap_or_else(|| HeaderValue::from_static(default))
+        };
+        headers.insert(
+            header::CONTENT_SECURITY_POLICY,
+            policy("CONTENT_SECURITY_POLICY", DEFAULT_CONTENT_SECURITY_POLICY),
+        );
+        headers.insert(
+            header::REFERRER_POLICY,
+            policy("REFERRER_POLICY", DEFAULT_REFERRER_POLICY),
+        );
+
+        SecurityHeaders { headers }
+    }
+
+    /// Add the headers the reply does not already have, so pages can loosen their own CSP
+    pub fn apply(&self, reply: impl Reply) -> Response {
+        let mut response = reply.into_response();
+        for (name, value) in &self.headers {
+            if !response.headers().contains_key(name) {
+                response.headers_mut().insert(name, value.clone());
+            }
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+
+    const CORS_VARS: [&str; 6] = [
+        "APP_ENV",
+        "CORS_ALLOWED_ORIGINS",
+        "CORS_ALLOWED_METHODS",
+        "CORS_ALLOWED_HEADERS",
+        "CORS_ALLOW_CREDENTIALS",
+        "CORS_MAX_AGE_SECONDS",
+    ];
+
+    const HEADER_VARS: [&str; 3] = [
+        "HSTS_MAX_AGE_SECONDS",
+        "CONTENT_SECURITY_POLICY",
+        "REFERRER_POLICY",
+    ];
+
+    fn clear_env(names: &[&str]) {
+        unsafe {
+            for name in names {
+                env::remove_var(name);
+            }
+        }
+    }
+
+    #[test]
+    fn test_origin_validation() {
+        assert!(is_valid_origin("https://app.example.com"));
+        assert!(is_valid_origin("http://localhost:5173"));
+        assert!(!is_valid_origin("app.example.com"));
+        assert!(!is_valid_origin("https://app.example.com/"));
+        assert!(!is_valid_origin("https://app.example.com/path"));
+    }
+
+    #[test]
+    fn test_cors_config_from_env() {
+        clear_env(&CORS_VARS);
+
+        let config = CorsConfig::from_env();
+        assert!(config.origins.is_none());
+        assert!(config.methods.contains(&Method::PATCH));
+        assert!(config.methods.contains(&Method::DELETE));
+        assert!(config
+            .headers
+            .contains(&HeaderName::from_static("if-match")));
+        assert!(!config.credentials);
+
+        unsafe {
+            env::set_var("APP_ENV", "production");
+        }
+        assert_eq!(CorsConfig::from_env().origins, Some(Vec::new()));
+
+        unsafe {
+            env::set_var(
+                "CORS_ALLOWED_ORIGINS",
+                "https://app.example.com, not-an-origin",
+            );
+            env::set_var("CORS_ALLOW_CREDENTIALS", "true");
+            env::set_var("CORS_MAX_AGE_SECONDS", "60");
+        }
+        let config = CorsConfig::from_env();
+        assert_eq!(
+            config.origins,
+            Some(vec!["https://app.example.com".to_string()])
+        );
+        assert!(config.credentials);
+        assert_eq!(config.max_age, 60);
+
+        // Credentials are never allowed for any origin
+        unsafe {
+            env::set_var("CORS_ALLOWED_ORIGINS", "*");
+        }
+        assert!(!CorsConfig::from_env().credentials);
+
+        // Clean up
+        clear_env(&CORS_VARS);
+    }
+
+    #[tokio::test]
+    async fn test_preflight_allows_patch_from_configured_origin() {
+        let config = CorsConfig {
+            origins: Some(vec!["https://app.example.com".to_string()]),
+            methods: vec![Method::GET, Method::PATCH],
+            headers: vec![HeaderName::from_static("if-match")],
+            credentials: true,
+            max_age: 60,
+        };
+        let routes = warp::any().map(warp::reply).with(config.build());
+
+        let response = warp::test::request()
+            .method("OPTIONS")
+            .header("origin", "https://app.example.com")
+            .header("access-control-request-method", "PATCH")
+            .header("access-control-request-headers", "if-match")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers()["access-control-allow-origin"],
+            "https://app.example.com"
+        );
+        assert_eq!(
+            response.headers()["access-control-allow-credentials"],
+            "true"
+        );
+        assert_eq!(response.headers()["access-control-max-age"], "60");
+
+        let response = warp::test::request()
+            .method("OPTIONS")
+            .header("origin", "https://evil.example.com")
+            .header("access-control-request-method", "PATCH")
+            .reply(&routes)
+            .await;
+        assert_eq!(response.status(), 403);
+    }
+
+    #[test]
+    fn test_security_headers_from_env() {
+        clear_env(&HEADER_VARS);
+        let headers = SecurityHeaders::from_env();
+
+        let response = headers.apply(warp::reply());
+        assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+        assert_eq!(response.headers()["referrer-policy"], "no-referrer");
+        assert_eq!(
+            response.headers()["strict-transport-security"],
+            "max-age=31536000; includeSubDomains"
+        );
+        assert_eq!(
+            response.headers()["content-security-policy"],
+            DEFAULT_CONTENT_SECURITY_POLICY
+        );
+
+        let page = warp::reply::with_header(
+            warp::reply(),
+            "content-security-policy",
+            "default-src 'self'",
+        );
+        let response = headers.apply(page);
+        assert_eq!(
+            response.headers()["content-security-policy"],
+            "default-src 'self'"
+        );
+
+        unsafe {
+            env::set_var("HSTS_MAX_AGE_SECONDS", "0");
+        }
+
+        let response = SecurityHeaders::from_env().apply(warp::reply());
+        assert!(response
+            .headers()
+            .get("strict-transport-security")
+            .is_none());
+
+        // Clean up
+        clear_env(&HEADER_VARS);
+    }
+}
@@ -0,0 +1,695 @@
+//! User statistics computed with aggregation pipelines and cached for a short while
+//!
+//! `created_at` is stored as an RFC 3339 string, so date ranges compare against
+//! second-precision prefixes and signups are bucketed by the date part of the string.
+
+use chrono::{DateTime, Duration as ChronoDuration, NaiveDate, NaiveTime, Utc};
+use futures::stream::TryStreamExt;
+use mongodb::bson::{doc, Document};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use utoipa::{IntoParams, ToSchema};
+use warp::{http::StatusCode, Rejection, Reply};
+
+use crate::db::AUDIT_COLLECTION;
+use crate::handlers::users::ErrorResponse;
+
+/// How long computed statistics are served from the cache by default
+const DEFAULT_USER_STATS_CACHE_SECONDS: u64 = 60;
+
+/// Most distinct queries kept in the cache at once
+const MAX_CACHED_QUERIES: usize = 256;
+
+/// Length of the range when `from` is not given
+const DEFAULT_RANGE_DAYS: i64 = 30;
+
+/// Longest range accepted, about ten years
+const MAX_RANGE_DAYS: i64 = 3660;
+
+/// Email domains listed by default, and at most
+const DEFAULT_TOP_DOMAINS: u32 = 10;
+const MAX_TOP_DOMAINS: u32 = 100;
+
+/// The prefix of a stored `created_at` compared against range bounds
+const BOUND_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct StatsQuery {
+    /// Start of the signup range, a date or RFC 3339 timestamp; 30 days before `to` when missing
+    pub from: Option<String>,
+    /// End of the signup range, a date (inclusive) or RFC 3339 timestamp; today when missing
+    pub to: Option<String>,
+    /// Signup bucket size: `day` (default), `week` or `month`
+    pub interval: Option<String>,
+    /// Number of email domains to list, 1 to 100 (default 10)
+    pub top: Option<u32>,
+}
+
+/// Size of the signup buckets
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq, Hash, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum StatsInterval {
+    Day,
+    Week,
+    Month,
+}
+
+impl StatsInterval {
+    fn parse(value: Option<&str>) -> Result<Self, String> {
+        match value.map(str::trim).unwrap_or("day") {
+            "day" => Ok(StatsInterval::Day),
+            "week" => Ok(StatsInterval::Week),
+            "month" => Ok(StatsInterval::Month),
+            other => Err(format!(
+                "Unknown interval '{}', expected one of day, week, month",
+                other
+            )),
+        }
+    }
+
+    /// The bucket a day falls in: `2024-03-05`, ISO week `2024-W10` or `2024-03`
+    fn label(&self, date: NaiveDate) -> String {
+        match self {
+            StatsInterval::Day => date.format("%Y-%m-%d").to_string(),
+            StatsInterval::Week => date.format("%G-W%V").to_string(),
+            StatsInterval::Month => date.format("%Y-%m").to_string(),
+        }
+    }
+
+    /// The same label computed in the pipeline from a stored `created_at`
+    fn group_key(&self) -> Document {
+        match self {
+            StatsInterval::Day => doc! { "$substrBytes": ["$created_at", 0, 10] },
+            StatsInterval::Month => doc! { "$substrBytes": ["$created_at", 0, 7] },
+            StatsInterval::Week => doc! {
+                "$dateToString": {
+                    "format": "%G-W%V",
+                    "date": {
+                        "$dateFromString": {
+                            "dateString": { "$substrBytes": ["$created_at", 0, 10] },
+                            "format": "%Y-%m-%d",
+                        }
+                    },
+                }
+            },
+        }
+    }
+}
+
+/// A validated stats request; also the cache key
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct StatsRequest {
+    from: DateTime<Utc>,
+    /// Exclusive
+    to: DateTime<Utc>,
+    interval: StatsInterval,
+    top: u32,
+}
+
+impl StatsQuery {
+    fn validate(&self, now: DateTime<Utc>) -> Result<StatsRequest, String> {
+        let interval = StatsInterval::parse(self.interval.as_deref())?;
+
+        let top = self.top.unwrap_or(DEFAULT_TOP_DOMAINS);
+        if !(1..=MAX_TOP_DOMAINS).contains(&top) {
+            return Err(format!(
+                "Parameter 'top' must be between 1 and {}",
+                MAX_TOP_DOMAINS
+            ));
+        }
+
+        let to = match self.to.as_deref() {
+            Some(value) => parse_bound("to", value, true)?,
+            None => start_of_day(now.date_naive() + ChronoDuration::days(1)),
+        };
+        let from = match self.from.as_deref() {
+            Some(value) => parse_bound("from", value, false)?,
+            None => to - ChronoDuration::days(DEFAULT_RANGE_DAYS),
+        };
+
+        if from >= to {
+            return Err("Parameter 'from' must be before 'to'".to_string());
+        }
+        if to - from > ChronoDuration::days(MAX_RANGE_DAYS) {
+            return Err(format!(
+                "The range may span at most {} days",
+                MAX_RANGE_DAYS
+            ));
+        }
+
+        Ok(StatsRequest {
+            from,
+            to,
+            interval,
+            top,
+        })
+    }
+}
+
+/// Parse a range bound, truncated to the second; a date `to` covers the whole day
+fn parse_bound(name: &str, value: &str, end: bool) -> Result<DateTime<Utc>, String> {
+    let value = value.trim();
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        let date = if end {
+            date.succ_opt().unwrap_or(date)
+        } else {
+            date
+        };
+        return Ok(start_of_day(date));
+    }
+
+    DateTime::parse_from_rfc3339(value)
+        .map(|time| {
+            let time = time.with_timezone(&Utc);
+            time - ChronoDuration::nanoseconds(time.timestamp_subsec_nanos() as i64)
+        })
+        .map_err(|_| {
+            format!(
+                "Parameter '{}' must be a date (YYYY-MM-DD) or an RFC 3339 timestamp",
+                name
+            )
+        })
+}
+
+fn start_of_day(date: NaiveDate) -> DateTime<Utc> {
+    date.and_time(NaiveTime::MIN).and_utc()
+}
+
+/// Signups in one bucket
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct SignupBucket {
+    /// `2024-03-05`, `2024-W10` or `2024-03`, depending on the interval
+    pub period: String,
+    pub count: u64,
+}
+
+/// Users with an email address at one domain
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct DomainCount {
+    pub domain: String,
+    pub count: u64,
+}
+
+/// Users ever created, still present and deleted
+#[derive(Serialize, Debug, Clone, PartialEq, Eq, ToSchema)]
+pub struct UserTotals {
+    /// Active and deleted users together
+    pub total: u64,
+    pub active: u64,
+    /// Distinct users with a `delete` entry in the audit log
+    pub deleted: u64,
+}
+
+#[derive(Serialize, Debug, Clone, ToSchema)]
+pub struct UserStats {
+    pub totals: UserTotals,
+    /// Start of the signup range
+    pub from: String,
+    /// End of the signup range, exclusive
+    pub to: String,
+    pub interval: StatsInterval,
+    /// Users created in the range
+    pub signups_in_range: u64,
+    /// Signups per period of the range, including periods without any
+    pub signups: Vec<SignupBucket>,
+    /// Most common email domains among active users
+    pub top_domains: Vec<DomainCount>,
+    /// When these statistics were computed; they may be served from the cache until it expires
+    pub generated_at: String,
+}
+
+/// User statistics in v2, with camelCase fields
+#[derive(Serialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserStatsV2 {
+    pub totals: UserTotals,
+    /// Start of the signup range
+    pub from: String,
+    /// End of the signup range, exclusive
+    pub to: String,
+    pub interval: StatsInterval,
+    /// Users created in the range
+    pub signups_in_range: u64,
+    /// Signups per period of the range, including periods without any
+    pub signups: Vec<SignupBucket>,
+    /// Most common email domains among active users
+    pub top_domains: Vec<DomainCount>,
+    /// When these statistics were computed; they may be served from the cache until it expires
+    pub generated_at: String,
+}
+
+impl From<UserStats> for UserStatsV2 {
+    fn from(stats: UserStats) -> Self {
+        UserStatsV2 {
+            totals: stats.totals,
+            from: stats.from,
+            to: stats.to,
+            interval: stats.interval,
+            signups_in_range: stats.signups_in_range,
+            signups: stats.signups,
+            top_domains: stats.top_domains,
+            generated_at: stats.generated_at,
+        }
+    }
+}
+
+/// Computed statistics kept for `USER_STATS_CACHE_SECONDS`, keyed by the validated query
+#[derive(Debug, Clone)]
+pub struct StatsCache {
+    ttl: Duration,
+    entries: Arc<Mutex<HashMap<StatsRequest, (Instant, UserStats)>>>,
+}
+
+impl StatsCache {
+    pub fn new(ttl: Duration) -> Self {
+        StatsCache {
+            ttl,
+            entries: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Read the cache lifetime from `USER_STATS_CACHE_SECONDS`; `0` disables caching
+    pub fn from_env() -> Self {
+        let seconds = env::var("USER_STATS_CACHE_SECONDS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_USER_STATS_CACHE_SECONDS);
+
+        StatsCache::new(Duration::from_secs(seconds))
+    }
+
+    fn get(&self, request: &StatsRequest) -> Option<UserStats> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries
+            .get(request)
+            .filter(|(stored, _)| stored.elapsed() < self.ttl)
+            .map(|(_, stats)| stats.clone())
+    }
+
+    fn insert(&self, request: StatsRequest, stats: UserStats) {
+        if self.ttl.is_zero() {
+            return;
+        }
+        let mut entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.retain(|_, (stored, _)| stored.elapsed() < self.ttl);
+        if entries.len() >= MAX_CACHED_QUERIES {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (stored, _))| *stored)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(request, (Instant::now(), stats));
+    }
+}
+
+/// Get user statistics for a signup range
+#[utoipa::path(
+    get,
+    path = "/users/stats",
+    tag = "users",
+    params(StatsQuery),
+    responses(
+        (status = 200, description = "User counts, signups per period and top email domains", body = UserStats),
+        (status = 400, description = "Invalid range, interval or top", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse)
+    )
+)]
+pub async fn get_user_stats(
+    query: StatsQuery,
+    cache: StatsCache,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    Ok(match user_stats(query, &cache, &db).await {
+        Ok(stats) => warp::reply::with_status(warp::reply::json(&stats), StatusCode::OK),
+        Err((status, error_response)) => {
+            warp::reply::with_status(warp::reply::json(&error_response), status)
+        }
+    })
+}
+
+/// Get user statistics for a signup range
+#[utoipa::path(
+    get,
+    path = "/v2/users/stats",
+    tag = "users",
+    params(StatsQuery),
+    responses(
+        (status = 200, description = "User counts, signups per period and top email domains", body = UserStatsV2),
+        (status = 400, description = "Invalid range, interval or top", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse)
+    )
+)]
+pub async fn get_user_stats_v2(
+    query: StatsQuery,
+    cache: StatsCache,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    Ok(match user_stats(query, &cache, &db).await {
+        Ok(stats) => {
+            warp::reply::with_status(warp::reply::json(&UserStatsV2::from(stats)), StatusCode::OK)
+        }
+        Err((status, error_response)) => {
+            warp::reply::with_status(warp::reply::json(&error_response), status)
+        }
+    })
+}
+
+/// Validate the query, then answer from the cache or compute and cache the statistics
+async fn user_stats(
+    query: StatsQuery,
+    cache: &StatsCache,
+    db: &Database,
+) -> Result<UserStats, (StatusCode, ErrorResponse)> {
+    let request = query.validate(Utc::now()).map_err(|message| {
+        (
+            StatusCode::BAD_REQUEST,
+            ErrorResponse {
+                error: "validation_error".to_string(),
+                message,
+            },
+        )
+    })?;
+
+    if let Some(stats) = cache.get(&request) {
+        return Ok(stats);
+    }
+
+    let stats = compute_stats(db, &request).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorResponse {
+                error: "database_error".to_string(),
+                message: "Failed to compute user statistics".to_string(),
+            },
+        )
+    })?;
+    cache.insert(request, stats.clone());
+    Ok(stats)
+}
+
+async fn compute_stats(
+    db: &Database,
+    request: &StatsRequest,
+) -> Result<UserStats, mongodb::error::Error> {
+    let users: Collection<Document> = db.collection("users");
+    let audit: Collection<Document> = db.collection(AUDIT_COLLECTION);
+
+    let (active, deleted, signups, top_domains) = tokio::try_join!(
+        users.count_documents(doc! {}, None),
+        count_deleted(&audit),
+        count_signups(&users, request),
+        count_domains(&users, request.top),
+    )?;
+
+    let signups = fill_buckets(request, signups);
+    Ok(UserStats {
+        totals: UserTotals {
+            total: active + deleted,
+            active,
+            deleted,
+        },
+        from: request.from.to_rfc3339(),
+        to: request.to.to_rfc3339(),
+        interval: request.interval,
+        signups_in_range: signups.iter().map(|bucket| bucket.count).sum(),
+        signups,
+        top_domains,
+        generated_at: Utc::now().to_rfc3339(),
+    })
+}
+
+/// Run a pipeline whose results are `{_id: <label>, count: <n>}`
+async fn grouped_counts(
+    collection: &Collection<Document>,
+    pipeline: Vec<Document>,
+) -> Result<Vec<(String, u64)>, mongodb::error::Error> {
+    let results: Vec<Document> = collection
+        .aggregate(pipeline, None)
+        .await?
+        .try_collect()
+        .await?;
+    Ok(results
+        .iter()
+        .map(|result| {
+            let label = result.get_str("_id").unwrap_or_default().to_string();
+            (label, count_of(result))
+        })
+        .collect())
+}
+
+/// `$sum` and `$count` give an int32 or int64 depending on the size
+fn count_of(result: &Document) -> u64 {
+    result
+        .get_i64("count")
+        .or_else(|_| result.get_i32("count").map(i64::from))
+        .unwrap_or_default()
+        .max(0) as u64
+}
+
+async fn count_deleted(audit: &Collection<Document>) -> Result<u64, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! { "$match": { "action": "delete" } },
+        doc! { "$group": { "_id": "$resource_id" } },
+        doc! { "$count": "count" },
+    ];
+    let results: Vec<Document> = audit.aggregate(pipeline, None).await?.try_collect().await?;
+    Ok(results.first().map(count_of).unwrap_or_default())
+}
+
+async fn count_signups(
+    users: &Collection<Document>,
+    request: &StatsRequest,
+) -> Result<Vec<(String, u64)>, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! { "$match": { "created_at": {
+            "$gte": request.from.format(BOUND_FORMAT).to_string(),
+            "$lt": request.to.format(BOUND_FORMAT).to_string(),
+        } } },
+        doc! { "$group": { "_id": request.interval.group_key(), "count": { "$sum": 1 } } },
+    ];
+    grouped_counts(users, pipeline).await
+}
+
+async fn count_domains(
+    users: &Collection<Document>,
+    top: u32,
+) -> Result<Vec<DomainCount>, mongodb::error::Error> {
+    let pipeline = vec![
+        doc! { "$project": { "domain": {
+            "$toLower": { "$arrayElemAt": [{ "$split": ["$email", "@"] }, -1] }
+        } } },
+        doc! { "$group": { "_id": "$domain", "count": { "$sum": 1 } } },
+        doc! { "$sort": { "count": -1, "_id": 1 } },
+        doc! { "$limit": top as i64 },
+    ];
+    Ok(grouped_counts(users, pipeline)
+        .await?
+        .into_iter()
+        .map(|(domain, count)| DomainCount { domain, count })
+        .collect())
+}
+
+/// Every bucket of the range in order, with zero for buckets without signups
+fn fill_buckets(request: &StatsRequest, counts: Vec<(String, u64)>) -> Vec<SignupBucket> {
+    let counts: HashMap<String, u64> = counts.into_iter().collect();
+    let last = (request.to - ChronoDuration::seconds(1)).date_naive();
+
+    let mut buckets: Vec<SignupBucket> = Vec::new();
+    let mut date = request.from.date_naive();
+    while date <= last {
+        let period = request.interval.label(date);
+        if buckets.last().map(|bucket| &bucket.period) != Some(&period) {
+            buckets.push(SignupBucket {
+                count: counts.get(&period).copied().unwrap_or_default(),
+                period,
+            });
+        }
+        match date.succ_opt() {
+            Some(next) => date = next,
+            None => break,
+        }
+    }
+    buckets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 3, 15, 12, 30, 0).unwrap()
+    }
+
+    fn query(from: Option<&str>, to: Option<&str>, interval: Option<&str>) -> StatsQuery {
+        StatsQuery {
+            from: from.map(str::to_string),
+            to: to.map(str::to_string),
+            interval: interval.map(str::to_string),
+            top: None,
+        }
+    }
+
+    fn request(from: &str, to: &str, interval: &str) -> StatsRequest {
+        query(Some(from), Some(to), Some(interval))
+            .validate(now())
+            .unwrap()
+    }
+
+    fn stats() -> UserStats {
+        UserStats {
+            totals: UserTotals {
+                total: 3,
+                active: 2,
+                deleted: 1,
+            },
+            from: String::new(),
+            to: String::new(),
+            interval: StatsInterval::Day,
+            signups_in_range: 0,
+            signups: Vec::new(),
+            top_domains: Vec::new(),
+            generated_at: String::new(),
+        }
+    }
+
+    #[test]
+    fn test_default_range_is_last_30_days() {
+        let request = StatsQuery::default().validate(now()).unwrap();
+        assert_eq!(
+            request.to,
+            Utc.with_ymd_and_hms(2024, 3, 16, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            request.from,
+            Utc.with_ymd_and_hms(2024, 2, 15, 0, 0, 0).unwrap()
+        );
+        assert_eq!(request.interval, StatsInterval::Day);
+        assert_eq!(request.top, DEFAULT_TOP_DOMAINS);
+    }
+
+    #[test]
+    fn test_range_bounds() {
+        // A date `to` covers the whole day; timestamps are truncated to the second
+        let request = request("2024-01-01", "2024-01-31", "day");
+        assert_eq!(
+            request.to,
+            Utc.with_ymd_and_hms(2024, 2, 1, 0, 0, 0).unwrap()
+        );
+
+        let request = query(Some("2024-01-01T10:00:00.750+02:00"), None, None)
+            .validate(now())
+            .unwrap();
+        assert_eq!(
+            request.from,
+            Utc.with_ymd_and_hms(2024, 1, 1, 8, 0, 0).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_invalid_queries() {
+        let invalid = [
+            query(Some("yesterday"), None, None),
+            query(Some("2024-02-01"), Some("2024-01-01"), None),
+            query(Some("2000-01-01"), Some("2024-01-01"), None),
+            query(None, None, Some("year")),
+        ];
+        for query in invalid {
+            assert!(query.validate(now()).is_err(), "{:?}", query);
+        }
+
+        let too_many = StatsQuery {
+            top: Some(MAX_TOP_DOMAINS + 1),
+            ..StatsQuery::default()
+        };
+        assert!(too_many.validate(now()).is_err());
+    }
+
+    #[test]
+    fn test_labels() {
+        let date = NaiveDate::from_ymd_opt(2021, 1, 3).unwrap();
+        assert_eq!(StatsInterval::Day.label(date), "2021-01-03");
+        // 3 January 2021 is a Sunday in the last ISO week of 2020
+        assert_eq!(StatsInterval::Week.label(date), "2020-W53");
+        assert_eq!(StatsInterval::Month.label(date), "2021-01");
+    }
+
+    #[test]
+    fn test_fill_buckets_zero_fills_gaps() {
+        let days = fill_buckets(
+            &request("2024-02-27", "2024-03-02", "day"),
+            vec![("2024-02-29".to_string(), 4)],
+        );
+        let days: Vec<(&str, u64)> = days
+            .iter()
+            .map(|bucket| (bucket.period.as_str(), bucket.count))
+            .collect();
+        assert_eq!(
+            days,
+            vec![
+                ("2024-02-27", 0),
+                ("2024-02-28", 0),
+                ("2024-02-29", 4),
+                ("2024-03-01", 0),
+                ("2024-03-02", 0),
+            ]
+        );
+
+        let weeks = fill_buckets(&request("2024-01-01", "2024-01-31", "week"), Vec::new());
+        assert_eq!(weeks.len(), 5);
+        assert_eq!(weeks[0].period, "2024-W01");
+
+        let months = fill_buckets(
+            &request("2023-12-15", "2024-02-10", "month"),
+            vec![("2024-01".to_string(), 7)],
+        );
+        let months: Vec<(&str, u64)> = months
+            .iter()
+            .map(|bucket| (bucket.period.as_str(), bucket.count))
+            .collect();
+        assert_eq!(months, vec![("2023-12", 0), ("2024-01", 7), ("2024-02", 0)]);
+    }
+
+    #[test]
+    fn test_v2_stats_use_camel_case() {
+        let json = serde_json::to_value(UserStatsV2::from(stats())).unwrap();
+
+        assert_eq!(json["totals"]["total"], 3);
+        assert!(json.get("signupsInRange").is_some());
+        assert!(json.get("topDomains").is_some());
+        assert!(json.get("generatedAt").is_some());
+        assert!(json.get("signups_in_range").is_none());
+    }
+
+    #[test]
+    fn test_cache_expiry_and_disabling() {
+        let key = request("2024-01-01", "2024-01-31", "day");
+
+        let cache = StatsCache::new(Duration::from_secs(60));
+        assert!(cache.get(&key).is_none());
+        cache.insert(key.clone(), stats());
+        assert_eq!(cache.get(&key).unwrap().totals.total, 3);
+
+        // Another range is another entry
+        assert!(cache
+            .get(&request("2024-01-01", "2024-01-30", "day"))
+            .is_none());
+
+        let disabled = StatsCache::new(Duration::ZERO);
+        disabled.insert(key.clone(), stats());
+        assert!(disabled.get(&key).is_none());
+
+        let expiring = StatsCache::new(Duration::from_millis(10));
+        expiring.insert(key.clone(), stats());
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(expiring.get(&key).is_none());
+    }
+}
@@ -0,0 +1,496 @@
+//! Status transitions of a user: `POST /users/{id}/activate`, `/suspend` and `/archive`
+//!
+//! Each transition is checked against the state machine of `UserStatus`, then
+//! written only if the user is still in a status it may leave, so two racing
+//! transitions cannot both apply. The reason and time of the change are stored
+//! on the user, and every change is kept in the audit log.
+
+use chrono::Utc;
+use mongodb::bson::{self, doc, oid::ObjectId, Bson};
+use mongodb::Database;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::ToSchema;
+use warp::http::{header, StatusCode};
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+use crate::db::{is_transient_transaction_error, record_audit_entry, Outbox};
+use crate::events::{UserEventBus, UserEventKind};
+use crate::handlers::audit::AuditContext;
+use crate::handlers::conditional::{entity_tag, if_match_versions};
+use crate::handlers::negotiation::Format;
+use crate::handlers::users::{
+    apply_user_update, find_user, parse_user_id, version_filter, ErrorResponse, UserError,
+    UserResponse,
+};
+use crate::handlers::users_v2::{error_reply, UserV2Response};
+use crate::models::{AuditAction, User, UserStatus};
+
+/// Longest reason accepted, in characters
+const MAX_REASON_LENGTH: usize = 500;
+
+/// Optional body of a status transition
+#[derive(Serialize, Deserialize, Debug, Default, ToSchema)]
+#[serde(deny_unknown_fields)]
+pub struct StatusChangeRequest {
+    /// Why the status changes, e.g. `Chargeback on invoice 1042`; kept on the user and in the audit log
+    pub reason: Option<String>,
+}
+
+impl StatusChangeRequest {
+    fn reason(&self) -> Result<Option<String>, UserError> {
+        let reason = self
+            .reason
+            .as_deref()
+            .map(str::trim)
+            .filter(|reason| !reason.is_empty());
+        if reason.is_some_and(|reason| reason.chars().count() > MAX_REASON_LENGTH) {
+            return Err(UserError::Validation(
+                format!("Reason may be at most {} characters", MAX_REASON_LENGTH).into(),
+            ));
+        }
+        Ok(reason.map(str::to_string))
+    }
+}
+
+/// Why a user in `from` cannot move to `target`
+fn transition_error(from: UserStatus, target: UserStatus) -> UserError {
+    UserError::InvalidTransition(match (from, target) {
+        (UserStatus::Archived, _) => "Archived users cannot change status",
+        (_, UserStatus::Active) => "Only pending or suspended users can be activated",
+        (_, UserStatus::Suspended) => "Only active users can be suspended",
+        _ => "The user cannot move to this status",
+    })
+}
+
+/// Move a user to `target`, only at one of `versions` when given
+#[allow(clippy::too_many_arguments)]
+pub(crate) async fn change_user_status(
+    object_id: ObjectId,
+    versions: Option<Vec<i64>>,
+    target: UserStatus,
+    request: &StatusChangeRequest,
+    audit: &AuditContext,
+    events: &UserEventBus,
+    outbox: &Outbox,
+    db: &Database,
+) -> Result<User, UserError> {
+    let reason = request.reason()?;
+
+    let current = find_user(db, object_id).await?.ok_or(UserError::NotFound)?;
+    if !current.status.can_become(target) {
+        return Err(transition_error(current.status, target));
+    }
+
+    let sources: Vec<Bson> = UserStatus::sources(target)
+        .iter()
+        .map(|status| Bson::String(status.as_str().to_string()))
+        .collect();
+    let mut filter = version_filter(object_id, versions);
+    filter.insert("status", doc! { "$in": sources });
+
+    let now = Utc::now();
+    let mut set = doc! {
+        "status": target.as_str(),
+        "status_changed_at": bson::to_bson(&now).unwrap_or_default(),
+        "updated_at": bson::to_bson(&now).unwrap_or_default(),
+    };
+    let mut update = doc! { "$inc": { "version": 1_i64 } };
+    match &reason {
+        Some(reason) => {
+            set.insert("status_reason", reason);
+        }
+        None => {
+            update.insert("$unset", doc! { "status_reason": "" });
+        }
+    }
+    update.insert("$set", set);
+
+    match apply_user_update(db, outbox, filter, update).await {
+        Ok(Some((before, user))) => {
+            record_audit_entry(
+                db,
+                &audit.entry(AuditAction::Update, Some(&before), Some(&user)),
+            )
+            .await;
+            events.publish_local(UserEventKind::Updated, &user);
+
+            Ok(user)
+        }
+        // The user changed since it was read: gone, moved to another status, or a new version
+        Ok(None) => match find_user(db, object_id).await? {
+            None => Err(UserError::NotFound),
+            Some(user) if !user.status.can_become(target) => {
+                Err(transition_error(user.status, target))
+            }
+            Some(_) => Err(UserError::PreconditionFailed),
+        },
+        Err(e) if is_transient_transaction_error(&e) => Err(UserError::WriteConflict),
+        Err(_) => Err(UserError::Database("Failed to change user status")),
+    }
+}
+
+/// Answer a transition with the user in the response type of the API version
+fn transition_reply<R: Serialize>(
+    format: Format,
+    result: Result<User, UserError>,
+    response: impl FnOnce(User) -> R,
+) -> Response {
+    match result {
+        Ok(user) => {
+            let etag = entity_tag(user.version);
+            warp::reply::with_header(
+                warp::reply::with_status(format.reply("user", &response(user)), StatusCode::OK),
+                header::ETAG,
+                etag,
+            )
+            .into_response()
+        }
+        Err(error) => error_reply(format, error),
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn transition(
+    target: UserStatus,
+    id: String,
+    if_match: Option<String>,
+    request: StatusChangeRequest,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<User, UserError> {
+    let object_id = parse_user_id(&id)?;
+    change_user_status(
+        object_id,
+        if_match.as_deref().and_then(if_match_versions),
+        target,
+        &request,
+        &audit,
+        &events,
+        &outbox,
+        &db,
+    )
+    .await
+}
+
+/// Activate a pending or suspended user
+#[utoipa::path(
+    post,
+    path = "/users/{id}/activate",
+    tag = "users",
+    request_body(content = Option<StatusChangeRequest>, description = "Optional reason"),
+    params(
+        ("id" = String, Path, description = "User ID"),
+        ("If-Match" = Option<String>, Header, description = "Only change the user if it still has this ETag"),
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 200, description = "User activated", body = UserResponse,
+            headers(("ETag" = String, description = "New version of the user"))),
+        (status = 400, description = "Invalid ID or reason", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "The user's status does not allow the transition, or a concurrent write", body = ErrorResponse),
+        (status = 412, description = "The user has a different ETag", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn activate_user(
+    id: String,
+    if_match: Option<String>,
+    request: StatusChangeRequest,
+    format: Format,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let result = transition(
+        UserStatus::Active,
+        id,
+        if_match,
+        request,
+        audit,
+        events,
+        outbox,
+        db,
+    )
+    .await;
+    Ok(transition_reply(format, result, UserResponse::from))
+}
+
+/// Suspend an active user
+#[utoipa::path(
+    post,
+    path = "/users/{id}/suspend",
+    tag = "users",
+    request_body(content = Option<StatusChangeRequest>, description = "Optional reason"),
+    params(
+        ("id" = String, Path, description = "User ID"),
+        ("If-Match" = Option<String>, Header, description = "Only change the user if it still has this ETag"),
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 200, description = "User suspended", body = UserResponse,
+            headers(("ETag" = String, description = "New version of the user"))),
+        (status = 400, description = "Invalid ID or reason", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "The user's status does not allow the transition, or a concurrent write", body = ErrorResponse),
+        (status = 412, description = "The user has a different ETag", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn suspend_user(
+    id: String,
+    if_match: Option<String>,
+    request: StatusChangeRequest,
+    format: Format,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let result = transition(
+        UserStatus::Suspended,
+        id,
+        if_match,
+        request,
+        audit,
+        events,
+        outbox,
+        db,
+    )
+    .await;
+    Ok(transition_reply(format, result, UserResponse::from))
+}
+
+/// Archive a user; archived users keep their data but cannot change status again
+#[utoipa::path(
+    post,
+    path = "/users/{id}/archive",
+    tag = "users",
+    request_body(content = Option<StatusChangeRequest>, description = "Optional reason"),
+    params(
+        ("id" = String, Path, description = "User ID"),
+        ("If-Match" = Option<String>, Header, description = "Only change the user if it still has this ETag"),
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 200, description = "User archived", body = UserResponse,
+            headers(("ETag" = String, description = "New version of the user"))),
+        (status = 400, description = "Invalid ID or reason", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "The user is already archived, or a concurrent write", body = ErrorResponse),
+        (status = 412, description = "The user has a different ETag", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn archive_user(
+    id: String,
+    if_match: Option<String>,
+    request: StatusChangeRequest,
+    format: Format,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let result = transition(
+        UserStatus::Archived,
+        id,
+        if_match,
+        request,
+        audit,
+        events,
+        outbox,
+        db,
+    )
+    .await;
+    Ok(transition_reply(format, result, UserResponse::from))
+}
+
+/// Activate a pending or suspended user
+#[utoipa::path(
+    post,
+    path = "/v2/users/{id}/activate",
+    tag = "users",
+    request_body(content = Option<StatusChangeRequest>, description = "Optional reason"),
+    params(
+        ("id" = String, Path, description = "User ID"),
+        ("If-Match" = Option<String>, Header, description = "Only change the user if it still has this ETag"),
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 200, description = "User activated", body = UserV2Response,
+            headers(("ETag" = String, description = "New version of the user"))),
+        (status = 400, description = "Invalid ID or reason", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "The user's status does not allow the transition, or a concurrent write", body = ErrorResponse),
+        (status = 412, description = "The user has a different ETag", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn activate_user_v2(
+    id: String,
+    if_match: Option<String>,
+    request: StatusChangeRequest,
+    format: Format,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let result = transition(
+        UserStatus::Active,
+        id,
+        if_match,
+        request,
+        audit,
+        events,
+        outbox,
+        db,
+    )
+    .await;
+    Ok(transition_reply(format, result, UserV2Response::from))
+}
+
+/// Suspend an active user
+#[utoipa::path(
+    post,
+    path = "/v2/users/{id}/suspend",
+    tag = "users",
+    request_body(content = Option<StatusChangeRequest>, description = "Optional reason"),
+    params(
+        ("id" = String, Path, description = "User ID"),
+        ("If-Match" = Option<String>, Header, description = "Only change the user if it still has this ETag"),
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 200, description = "User suspended", body = UserV2Response,
+            headers(("ETag" = String, description = "New version of the user"))),
+        (status = 400, description = "Invalid ID or reason", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "The user's status does not allow the transition, or a concurrent write", body = ErrorResponse),
+        (status = 412, description = "The user has a different ETag", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn suspend_user_v2(
+    id: String,
+    if_match: Option<String>,
+    request: StatusChangeRequest,
+    format: Format,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let result = transition(
+        UserStatus::Suspended,
+        id,
+        if_match,
+        request,
+        audit,
+        events,
+        outbox,
+        db,
+    )
+    .await;
+    Ok(transition_reply(format, result, UserV2Response::from))
+}
+
+/// Archive a user; archived users keep their data but cannot change status again
+#[utoipa::path(
+    post,
+    path = "/v2/users/{id}/archive",
+    tag = "users",
+    request_body(content = Option<StatusChangeRequest>, description = "Optional reason"),
+    params(
+        ("id" = String, Path, description = "User ID"),
+        ("If-Match" = Option<String>, Header, description = "Only change the user if it still has this ETag"),
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 200, description = "User archived", body = UserV2Response,
+            headers(("ETag" = String, description = "New version of the user"))),
+        (status = 400, description = "Invalid ID or reason", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "The user is already archived, or a concurrent write", body = ErrorResponse),
+        (status = 412, description = "The user has a different ETag", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn archive_user_v2(
+    id: String,
+    if_match: Option<String>,
+    request: StatusChangeRequest,
+    format: Format,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let result = transition(
+        UserStatus::Archived,
+        id,
+        if_match,
+        request,
+        audit,
+        events,
+        outbox,
+        db,
+    )
+    .await;
+    Ok(transition_reply(format, result, UserV2Response::from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reason_is_trimmed_and_limited() {
+        let request = |reason: &str| StatusChangeRequest {
+            reason: Some(reason.to_string()),
+        };
+
+        assert_eq!(
+            request("  Chargeback  ").reason(),
+            Ok(Some("Chargeback".to_string()))
+        );
+        assert_eq!(request(" ").reason(), Ok(None));
+        assert_eq!(StatusChangeRequest::default().reason(), Ok(None));
+        assert_eq!(
+            request(&"x".repeat(MAX_REASON_LENGTH + 1))
+                .reason()
+                .unwrap_err()
+                .message(),
+            format!("Reason may be at most {} characters", MAX_REASON_LENGTH)
+        );
+    }
+
+    #[test]
+    fn test_transition_errors() {
+        let error = transition_error(UserStatus::Pending, UserStatus::Suspended);
+        assert_eq!(error.code(), "invalid_transition");
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+        assert_eq!(error.message(), "Only active users can be suspended");
+
+        assert_eq!(
+            transition_error(UserStatus::Archived, UserStatus::Active).message(),
+            "Archived users cannot change status"
+        );
+        assert_eq!(
+            transition_error(UserStatus::Active, UserStatus::Active).message(),
+            "Only pending or suspended users can be activated"
+        );
+    }
+}
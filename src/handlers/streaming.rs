@@ -0,0 +1,244 @@
+//! Streaming large lists straight from a cursor instead of collecting them first
+
+use futures::stream::{self, Stream, StreamExt};
+use serde::Serialize;
+use std::convert::Infallible;
+use warp::http::{header, HeaderValue};
+use warp::hyper::body::{Body, Bytes};
+use warp::reply::Response;
+
+use crate::handlers::negotiation::Format;
+use crate::handlers::users::ErrorResponse;
+
+/// Most items written in one chunk; fewer when the cursor has fewer ready
+const CHUNK_ITEMS: usize = 100;
+
+/// How the items of a streamed list are put together
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListFraming {
+    /// One JSON document per line
+    Ndjson,
+    /// A JSON array
+    JsonArray,
+    /// `{"data": [...], "count": n}`, with the count written after the items
+    JsonEnvelope,
+}
+
+impl ListFraming {
+    /// The framing for a list reply, or `None` when it should be collected and encoded whole.
+    ///
+    /// NDJSON always streams; JSON streams when the client asked with `stream=true`.
+    pub fn for_reply(format: Format, stream: bool, envelope: bool) -> Option<ListFraming> {
+        match format {
+            Format::Ndjson => Some(ListFraming::Ndjson),
+            Format::Json if stream && envelope => Some(ListFraming::JsonEnvelope),
+            Format::Json if stream => Some(ListFraming::JsonArray),
+            _ => None,
+        }
+    }
+
+    fn content_type(&self) -> &'static str {
+        match self {
+            ListFraming::Ndjson => Format::Ndjson.content_type(),
+            ListFraming::JsonArray | ListFraming::JsonEnvelope => Format::Json.content_type(),
+        }
+    }
+
+    fn open(&self) -> &'static [u8] {
+        match self {
+            ListFraming::Ndjson => b"",
+            ListFraming::JsonArray => b"[",
+            ListFraming::JsonEnvelope => b"{\"data\":[",
+        }
+    }
+
+    fn item(&self, index: usize, item: &[u8], out: &mut Vec<u8>) {
+        if index > 0 && *self != ListFraming::Ndjson {
+            out.push(b',');
+        }
+        out.extend_from_slice(item);
+        if *self == ListFraming::Ndjson {
+            out.push(b'\n');
+        }
+    }
+
+    /// End the list, with `error` as its last record if the cursor failed
+    fn close(&self, count: usize, error: Option<&[u8]>, out: &mut Vec<u8>) {
+        match (self, error) {
+            (ListFraming::Ndjson, Some(error)) => self.item(count, error, out),
+            (ListFraming::Ndjson, None) => {}
+            (ListFraming::JsonArray, error) => {
+                if let Some(error) = error {
+                    self.item(count, error, out);
+                }
+                out.push(b']');
+            }
+            (ListFraming::JsonEnvelope, None) => {
+                out.extend(format!("],\"count\":{}}}", count).into_bytes())
+            }
+            (ListFraming::JsonEnvelope, Some(error)) => {
+                out.extend(format!("],\"count\":{},\"error\":", count).into_bytes());
+                out.extend_from_slice(error);
+                out.push(b'}');
+            }
+        }
+    }
+}
+
+struct ListState<S> {
+    items: S,
+    framing: ListFraming,
+    count: usize,
+    started: bool,
+    done: bool,
+}
+
+/// Stream `items` as a `200 OK` list, reading the next chunk only when the client took the last.
+///
+/// The status is sent before the first item, so an item that fails ends the list
+/// with a trailing `database_error` record instead.
+pub fn stream_list<S, T>(items: S, framing: ListFraming) -> Response
+where
+    S: Stream<Item = Result<T, ()>> + Send + 'static,
+    T: Serialize + Send + 'static,
+{
+    let state = ListState {
+        items: Box::pin(items.ready_chunks(CHUNK_ITEMS)),
+        framing,
+        count: 0,
+        started: false,
+        done: false,
+    };
+
+    let chunks = stream::unfold(state, |mut state| async move {
+        if state.done {
+            return None;
+        }
+
+        let mut chunk = Vec::new();
+        if !state.started {
+            state.started = true;
+            chunk.extend_from_slice(state.framing.open());
+        }
+
+        match state.items.next().await {
+            Some(items) => {
+                for item in items {
+                    match item.map(|item| serde_json::to_vec(&item)) {
+                        Ok(Ok(item)) => {
+                            state.framing.item(state.count, &item, &mut chunk);
+                            state.count += 1;
+                        }
+                        _ => {
+                            state
+                                .framing
+                                .close(state.count, Some(&error_record()), &mut chunk);
+                            state.done = true;
+                            break;
+                        }
+                    }
+                }
+            }
+            None => {
+                state.framing.close(state.count, None, &mut chunk);
+                state.done = true;
+            }
+        }
+
+        Some((Ok::<_, Infallible>(Bytes::from(chunk)), state))
+    });
+
+    let mut response = Response::new(Body::wrap_stream(chunks));
+    let headers = response.headers_mut();
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static(framing.content_type()),
+    );
+    headers.insert(header::VARY, HeaderValue::from_static("accept"));
+    response
+}
+
+fn error_record() -> Vec<u8> {
+    serde_json::to_vec(&ErrorResponse {
+        error: "database_error".to_string(),
+        message: "Reading users from the database failed midway".to_string(),
+    })
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::{json, Value};
+
+    async fn body(response: Response) -> String {
+        let bytes = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        String::from_utf8(bytes.to_vec()).unwrap()
+    }
+
+    fn items(count: usize, fail: bool) -> impl Stream<Item = Result<Value, ()>> {
+        let items = (0..count).map(|i| Ok(json!({ "id": i })));
+        let failure = fail.then_some(Err(()));
+        stream::iter(items.chain(failure))
+    }
+
+    #[test]
+    fn test_framing_for_reply() {
+        assert_eq!(
+            ListFraming::for_reply(Format::Ndjson, false, true),
+            Some(ListFraming::Ndjson)
+        );
+        assert_eq!(ListFraming::for_reply(Format::Json, false, false), None);
+        assert_eq!(
+            ListFraming::for_reply(Format::Json, true, true),
+            Some(ListFraming::JsonEnvelope)
+        );
+        assert_eq!(ListFraming::for_reply(Format::Csv, true, false), None);
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_lines() {
+        let response = stream_list(items(3, false), ListFraming::Ndjson);
+        assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+        assert_eq!(body(response).await, "{\"id\":0}\n{\"id\":1}\n{\"id\":2}\n");
+
+        let response = stream_list(items(0, false), ListFraming::Ndjson);
+        assert_eq!(body(response).await, "");
+    }
+
+    #[tokio::test]
+    async fn test_json_array_and_envelope_are_valid_json() {
+        let array = body(stream_list(items(250, false), ListFraming::JsonArray)).await;
+        let array: Value = serde_json::from_str(&array).unwrap();
+        assert_eq!(array.as_array().unwrap().len(), 250);
+
+        let empty = body(stream_list(items(0, false), ListFraming::JsonArray)).await;
+        assert_eq!(empty, "[]");
+
+        let envelope = body(stream_list(items(2, false), ListFraming::JsonEnvelope)).await;
+        let envelope: Value = serde_json::from_str(&envelope).unwrap();
+        assert_eq!(
+            envelope,
+            json!({ "data": [{ "id": 0 }, { "id": 1 }], "count": 2 })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_failure_ends_with_error_record() {
+        let lines = body(stream_list(items(2, true), ListFraming::Ndjson)).await;
+        let last: Value = serde_json::from_str(lines.lines().last().unwrap()).unwrap();
+        assert_eq!(lines.lines().count(), 3);
+        assert_eq!(last["error"], "database_error");
+
+        let array = body(stream_list(items(1, true), ListFraming::JsonArray)).await;
+        let array: Value = serde_json::from_str(&array).unwrap();
+        assert_eq!(array[1]["error"], "database_error");
+
+        let envelope = body(stream_list(items(1, true), ListFraming::JsonEnvelope)).await;
+        let envelope: Value = serde_json::from_str(&envelope).unwrap();
+        assert_eq!(envelope["count"], 1);
+        assert_eq!(envelope["error"]["error"], "database_error");
+    }
+}
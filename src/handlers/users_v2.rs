@@ -0,0 +1,627 @@
+//! Version 2 of the user resource
+//!
+//! v2 answers with camelCase fields, includes `updatedAt`, wraps lists in a
+//! `{ "data", "count" }` envelope and refuses request fields it does not
+//! know. Validation, storage, audit entries and events are shared with v1.
+
+use futures::stream::{StreamExt, TryStreamExt};
+use mongodb::bson::{doc, Document};
+use mongodb::options::FindOptions;
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use utoipa::ToSchema;
+use warp::http::{header, StatusCode};
+use warp::hyper::body::Bytes;
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+use crate::db::Outbox;
+use crate::events::UserEventBus;
+use crate::handlers::audit::AuditContext;
+use crate::handlers::conditional::{entity_tag, if_match_versions, if_none_match_matches};
+use crate::handlers::fields::{FieldsError, FieldsQuery, ResponseField, SparseRecord};
+use crate::handlers::filter::UserListQuery;
+use crate::handlers::idempotency::{request_fingerprint, with_idempotency};
+use crate::handlers::negotiation::Format;
+use crate::handlers::streaming::{stream_list, ListFraming};
+use crate::handlers::users::{
+    create_user_record, delete_user_record, find_user_fields, parse_user_id, update_user_record,
+    CreateUserRequest, ErrorResponse, UpdateUserRequest, UserError,
+};
+use crate::models::{User, UserStatus};
+
+#[derive(Serialize, Deserialize, Debug, Clone, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserV2Response {
+    pub id: String,
+    pub name: String,
+    pub email: String,
+    pub created_at: String,
+    pub updated_at: Option<String>,
+    pub version: i64,
+    pub phone: Option<String>,
+    pub locale: Option<String>,
+    pub timezone: Option<String>,
+    pub avatar_url: Option<String>,
+    pub metadata: BTreeMap<String, String>,
+    pub status: UserStatus,
+    pub status_reason: Option<String>,
+    pub status_changed_at: Option<String>,
+    pub email_verified_at: Option<String>,
+}
+
+impl From<User> for UserV2Response {
+    fn from(user: User) -> Self {
+        UserV2Response {
+            id: user.id.map(|id| id.to_hex()).unwrap_or_default(),
+            name: user.name,
+            email: user.email,
+            created_at: user.created_at.to_rfc3339(),
+            updated_at: user.updated_at.map(|updated_at| updated_at.to_rfc3339()),
+            version: user.version,
+            phone: user.phone,
+            locale: user.locale,
+            timezone: user.timezone,
+            avatar_url: user.avatar_url,
+            metadata: user.metadata,
+            status: user.status,
+            status_reason: user.status_reason,
+            status_changed_at: user
+                .status_changed_at
+                .map(|changed_at| changed_at.to_rfc3339()),
+            email_verified_at: user
+                .email_verified_at
+                .map(|verified_at| verified_at.to_rfc3339()),
+        }
+    }
+}
+
+/// Fields of `UserV2Response` that `fields=` can select
+pub const USER_FIELDS_V2: &[ResponseField] = &[
+    ResponseField {
+        name: "id",
+        key: "_id",
+    },
+    ResponseField {
+        name: "name",
+        key: "name",
+    },
+    ResponseField {
+        name: "email",
+        key: "email",
+    },
+    ResponseField {
+        name: "createdAt",
+        key: "created_at",
+    },
+    ResponseField {
+        name: "updatedAt",
+        key: "updated_at",
+    },
+    ResponseField {
+        name: "version",
+        key: "version",
+    },
+    ResponseField {
+        name: "phone",
+        key: "phone",
+    },
+    ResponseField {
+        name: "locale",
+        key: "locale",
+    },
+    ResponseField {
+        name: "timezone",
+        key: "timezone",
+    },
+    ResponseField {
+        name: "avatarUrl",
+        key: "avatar_url",
+    },
+    ResponseField {
+        name: "metadata",
+        key: "metadata",
+    },
+    ResponseField {
+        name: "status",
+        key: "status",
+    },
+    ResponseField {
+        name: "statusReason",
+        key: "status_reason",
+    },
+    ResponseField {
+        name: "statusChangedAt",
+        key: "status_changed_at",
+    },
+    ResponseField {
+        name: "emailVerifiedAt",
+        key: "email_verified_at",
+    },
+];
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct UserListV2Response {
+    pub data: Vec<UserV2Response>,
+    pub count: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct CreateUserV2Request {
+    pub name: String,
+    pub email: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+}
+
+impl From<CreateUserV2Request> for CreateUserRequest {
+    fn from(request: CreateUserV2Request) -> Self {
+        CreateUserRequest {
+            name: request.name,
+            email: request.email,
+            phone: request.phone,
+            locale: request.locale,
+            timezone: request.timezone,
+            avatar_url: request.avatar_url,
+            metadata: request.metadata,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, ToSchema)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+pub struct UpdateUserV2Request {
+    pub name: Option<String>,
+    pub email: Option<String>,
+    /// A blank value removes the phone number, as for the other profile fields
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// Replaces all metadata; an empty object removes it
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<BTreeMap<String, String>>,
+}
+
+impl From<UpdateUserV2Request> for UpdateUserRequest {
+    fn from(request: UpdateUserV2Request) -> Self {
+        UpdateUserRequest {
+            name: request.name,
+            email: request.email,
+            phone: request.phone,
+            locale: request.locale,
+            timezone: request.timezone,
+            avatar_url: request.avatar_url,
+            metadata: request.metadata,
+        }
+    }
+}
+
+/// Answer with the REST error for a failed user operation
+pub(crate) fn error_reply(format: Format, error: UserError) -> Response {
+    let error_response = ErrorResponse {
+        error: error.code().to_string(),
+        message: error.message().to_string(),
+    };
+    warp::reply::with_status(format.reply("error", &error_response), error.status()).into_response()
+}
+
+fn fields_error_reply(format: Format, error: FieldsError) -> Response {
+    let error_response = ErrorResponse {
+        error: error.code.to_string(),
+        message: error.message,
+    };
+    warp::reply::with_status(
+        format.reply("error", &error_response),
+        StatusCode::BAD_REQUEST,
+    )
+    .into_response()
+}
+
+/// Answer with a user and its ETag
+fn user_reply(format: Format, user: User, status: StatusCode) -> Response {
+    let etag = entity_tag(user.version);
+    warp::reply::with_header(
+        warp::reply::with_status(format.reply("user", &UserV2Response::from(user)), status),
+        header::ETAG,
+        etag,
+    )
+    .into_response()
+}
+
+/// Get all users, oldest first
+#[utoipa::path(
+    get,
+    path = "/v2/users",
+    tag = "users",
+    params(UserListQuery, FieldsQuery),
+    responses(
+        (status = 200, description = "All users matching the filter", body = UserListV2Response),
+        (status = 400, description = "Invalid filter, fields or expand", body = ErrorResponse),
+        (status = 500, description = "Database error", body = ErrorResponse)
+    )
+)]
+pub async fn get_all_users_v2(
+    query: UserListQuery,
+    fields: FieldsQuery,
+    format: Format,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let filter = match query.to_filter() {
+        Ok(filter) => filter,
+        Err(error) => {
+            let error_response = ErrorResponse {
+                error: "invalid_filter".to_string(),
+                message: error.to_string(),
+            };
+            return Ok(warp::reply::with_status(
+                format.reply("error", &error_response),
+                StatusCode::BAD_REQUEST,
+            )
+            .into_response());
+        }
+    };
+    let fieldset = match fields.fieldset(USER_FIELDS_V2) {
+        Ok(fieldset) => fieldset,
+        Err(error) => return Ok(fields_error_reply(format, error)),
+    };
+
+    let collection: Collection<Document> = db.collection("users");
+    let options = FindOptions::builder()
+        .projection(fieldset.projection())
+        .sort(doc! { "_id": 1 })
+        .build();
+
+    let cursor = match collection.find(filter, options).await {
+        Ok(cursor) => cursor,
+        Err(_) => {
+            return Ok(error_reply(
+                format,
+                UserError::Database("Failed to fetch users from database"),
+            ))
+        }
+    };
+
+    // NDJSON has no room for an envelope either, so it streams one line per user
+    if let Some(framing) = ListFraming::for_reply(format, query.stream, true) {
+        let users = cursor.map(move |result| {
+            let user = result
+                .ok()
+                .and_then(|document| fieldset.read_user(document).ok());
+            user.map(|user| fieldset.select(&UserV2Response::from(user)))
+                .ok_or(())
+        });
+        return Ok(stream_list(users, framing));
+    }
+
+    let documents: Vec<Document> = match cursor.try_collect().await {
+        Ok(documents) => documents,
+        Err(_) => {
+            return Ok(error_reply(
+                format,
+                UserError::Database("Error processing user data"),
+            ))
+        }
+    };
+    let users: Vec<User> = match documents
+        .into_iter()
+        .map(|document| fieldset.read_user(document))
+        .collect()
+    {
+        Ok(users) => users,
+        Err(_) => {
+            return Ok(error_reply(
+                format,
+                UserError::Database("Error processing user data"),
+            ))
+        }
+    };
+    let data: Vec<SparseRecord> = users
+        .into_iter()
+        .map(|user| fieldset.select(&UserV2Response::from(user)))
+        .collect();
+
+    // CSV has no room for an envelope, so it stays one row per user
+    if format == Format::Csv {
+        return Ok(format.reply_list("users", &data));
+    }
+    let count = data.len();
+    // The `UserListV2Response` envelope around users trimmed to the fieldset
+    #[derive(Serialize)]
+    struct SparseList {
+        data: Vec<SparseRecord>,
+        count: usize,
+    }
+    Ok(format.reply("users", &SparseList { data, count }))
+}
+
+/// Get a user by ID, answering `304 Not Modified` when `If-None-Match` matches its ETag
+#[utoipa::path(
+    get,
+    path = "/v2/users/{id}",
+    tag = "users",
+    params(
+        ("id" = String, Path, description = "User ID"),
+        ("If-None-Match" = Option<String>, Header, description = "ETag of a cached copy"),
+        FieldsQuery
+    ),
+    responses(
+        (status = 200, description = "The user", body = UserV2Response,
+            headers(("ETag" = String, description = "Current version of the user"))),
+        (status = 304, description = "The cached copy is current"),
+        (status = 400, description = "Invalid user ID, fields or expand", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse)
+    )
+)]
+pub async fn get_user_by_id_v2(
+    id: String,
+    if_none_match: Option<String>,
+    fields: FieldsQuery,
+    format: Format,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let fieldset = match fields.fieldset(USER_FIELDS_V2) {
+        Ok(fieldset) => fieldset,
+        Err(error) => return Ok(fields_error_reply(format, error)),
+    };
+    let object_id = match parse_user_id(&id) {
+        Ok(object_id) => object_id,
+        Err(e) => return Ok(error_reply(format, e)),
+    };
+
+    match find_user_fields(&db, object_id, &fieldset).await {
+        Ok(Some(user)) => {
+            if if_none_match
+                .as_deref()
+                .is_some_and(|header| if_none_match_matches(header, user.version))
+            {
+                return Ok(warp::reply::with_header(
+                    StatusCode::NOT_MODIFIED,
+                    header::ETAG,
+                    entity_tag(user.version),
+                )
+                .into_response());
+            }
+            let etag = entity_tag(user.version);
+            let user_response = fieldset.select(&UserV2Response::from(user));
+            Ok(warp::reply::with_header(
+                warp::reply::with_status(format.reply("user", &user_response), StatusCode::OK),
+                header::ETAG,
+                etag,
+            )
+            .into_response())
+        }
+        Ok(None) => Ok(error_reply(format, UserError::NotFound)),
+        Err(e) => Ok(error_reply(format, e)),
+    }
+}
+
+/// Create a new user, replaying the stored response when the `Idempotency-Key` is repeated
+#[utoipa::path(
+    post,
+    path = "/v2/users",
+    tag = "users",
+    request_body = CreateUserV2Request,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe"),
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 201, description = "User created", body = UserV2Response),
+        (status = 400, description = "Validation error or unknown field", body = ErrorResponse),
+        (status = 413, description = "Request body is too large", body = ErrorResponse),
+        (status = 409, description = "A request with the same key is in progress", body = ErrorResponse),
+        (status = 422, description = "The key was used with a different request", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn create_user_v2(
+    idempotency_key: Option<String>,
+    create_user_req: CreateUserV2Request,
+    raw_body: Bytes,
+    format: Format,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let fingerprint = request_fingerprint("POST", "/v2/users", &raw_body);
+
+    let create = async {
+        let reply =
+            match create_user_record(create_user_req.into(), &audit, &events, &outbox, &db).await {
+                Ok(user) => user_reply(format, user, StatusCode::CREATED),
+                Err(e) => error_reply(format, e),
+            };
+        Ok::<_, Rejection>(reply)
+    };
+
+    with_idempotency(
+        db.clone(),
+        idempotency_key,
+        "POST /v2/users",
+        fingerprint,
+        create,
+    )
+    .await
+}
+
+/// Update a user's name and/or email, honouring `If-Match` for optimistic concurrency
+#[utoipa::path(
+    patch,
+    path = "/v2/users/{id}",
+    tag = "users",
+    request_body = UpdateUserV2Request,
+    params(
+        ("id" = String, Path, description = "User ID"),
+        ("If-Match" = Option<String>, Header, description = "Only update if the user still has this ETag"),
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 200, description = "User updated", body = UserV2Response,
+            headers(("ETag" = String, description = "New version of the user"))),
+        (status = 400, description = "Invalid ID, validation error or unknown field", body = ErrorResponse),
+        (status = 413, description = "Request body is too large", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "Concurrent write, retry", body = ErrorResponse),
+        (status = 412, description = "The user has a different ETag", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn update_user_v2(
+    id: String,
+    if_match: Option<String>,
+    update_user_req: UpdateUserV2Request,
+    format: Format,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let object_id = match parse_user_id(&id) {
+        Ok(object_id) => object_id,
+        Err(e) => return Ok(error_reply(format, e)),
+    };
+
+    match update_user_record(
+        object_id,
+        if_match.as_deref().and_then(if_match_versions),
+        &update_user_req.into(),
+        &audit,
+        &events,
+        &outbox,
+        &db,
+    )
+    .await
+    {
+        Ok(user) => Ok(user_reply(format, user, StatusCode::OK)),
+        Err(e) => Ok(error_reply(format, e)),
+    }
+}
+
+/// Delete a user, honouring `If-Match` for optimistic concurrency
+#[utoipa::path(
+    delete,
+    path = "/v2/users/{id}",
+    tag = "users",
+    params(
+        ("id" = String, Path, description = "User ID"),
+        ("If-Match" = Option<String>, Header, description = "Only delete if the user still has this ETag"),
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 204, description = "User deleted"),
+        (status = 400, description = "Invalid user ID", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "Concurrent write, retry", body = ErrorResponse),
+        (status = 412, description = "The user has a different ETag", body = ErrorResponse)
+    )
+)]
+pub async fn delete_user_v2(
+    id: String,
+    if_match: Option<String>,
+    format: Format,
+    audit: AuditContext,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    let object_id = match parse_user_id(&id) {
+        Ok(object_id) => object_id,
+        Err(e) => return Ok(error_reply(format, e)),
+    };
+
+    match delete_user_record(
+        object_id,
+        if_match.as_deref().and_then(if_match_versions),
+        &audit,
+        &events,
+        &outbox,
+        &db,
+    )
+    .await
+    {
+        Ok(_) => Ok(StatusCode::NO_CONTENT.into_response()),
+        Err(e) => Ok(error_reply(format, e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use mongodb::bson::oid::ObjectId;
+
+    fn test_user() -> User {
+        let mut user = User::new_user("Jane".to_string(), "jane@example.com".to_string());
+        user.id = Some(ObjectId::new());
+        user
+    }
+
+    #[test]
+    fn test_response_uses_camel_case() {
+        let json = serde_json::to_value(UserV2Response::from(test_user())).unwrap();
+
+        assert!(json["createdAt"].is_string());
+        assert!(json["updatedAt"].is_string());
+        assert!(json.get("created_at").is_none());
+    }
+
+    #[test]
+    fn test_requests_refuse_unknown_fields() {
+        let result: Result<CreateUserV2Request, _> =
+            serde_json::from_str(r#"{"name":"Jane","email":"jane@example.com","role":"admin"}"#);
+        assert!(result.is_err());
+
+        let request: UpdateUserV2Request = serde_json::from_str(r#"{"name":"Jane"}"#).unwrap();
+        let request = UpdateUserRequest::from(request);
+        assert_eq!(request.name.as_deref(), Some("Jane"));
+        assert!(request.email.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_user_reply_has_etag() {
+        let mut user = test_user();
+        user.updated_at = Some(Utc::now());
+        user.version = 3;
+
+        let response = user_reply(Format::Json, user, StatusCode::OK);
+        assert_eq!(response.headers()[header::ETAG], "W/\"3\"");
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["version"], 3);
+    }
+
+    #[tokio::test]
+    async fn test_error_reply_status() {
+        let response = error_reply(Format::Json, UserError::WriteConflict);
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+
+        let body = warp::hyper::body::to_bytes(response.into_body())
+            .await
+            .unwrap();
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(json["error"], "write_conflict");
+    }
+}
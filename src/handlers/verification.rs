@@ -0,0 +1,710 @@
+//! Email verification: a signed link mailed when a user is created,
+//! `GET /verify-email?token=` to confirm it and
+//! `POST /users/{id}/verify-email/resend` to mail a new one
+//!
+//! Tokens are `<user id>.<expiry>.<signature>`, signed with HMAC-SHA256 over the
+//! user ID, expiry and email address. Nothing is stored per token: changing the
+//! email invalidates every link sent to the old address.
+
+use chrono::{DateTime, Duration as ChronoDuration, Utc};
+use hmac::{Hmac, Mac};
+use mongodb::bson::{self, doc, oid::ObjectId, Bson};
+use mongodb::{Collection, Database};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+use warp::http::{header, StatusCode};
+use warp::reply::Response;
+use warp::{Rejection, Reply};
+
+use crate::db::{is_transient_transaction_error, record_audit_entry, Outbox};
+use crate::events::{UserEventBus, UserEventKind};
+use crate::handlers::audit::AuditContext;
+use crate::handlers::negotiation::Format;
+use crate::handlers::users::{
+    apply_user_update, find_user, parse_user_id, version_filter, ErrorResponse, UserError,
+};
+use crate::mailer::{Email, Mailer};
+use crate::models::{AuditAction, User, UserStatus};
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Default lifetime of a verification link
+const DEFAULT_EMAIL_VERIFICATION_TTL_SECONDS: i64 = 86400;
+
+/// Default page the link points at; the API's own endpoint
+const DEFAULT_EMAIL_VERIFICATION_URL: &str = "http://localhost:3030/verify-email";
+
+/// Default shortest time between two resends for the same user
+const DEFAULT_EMAIL_VERIFICATION_RESEND_SECONDS: u64 = 60;
+
+/// Why verifying or resending failed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum VerificationError {
+    /// Malformed, badly signed, or for an address the user no longer has
+    InvalidToken,
+    ExpiredToken,
+    AlreadyVerified,
+    /// A link was resent recently; retry after this many seconds
+    TooSoon(u64),
+    /// The mailer could not send the link
+    Mail,
+    User(UserError),
+}
+
+impl From<UserError> for VerificationError {
+    fn from(error: UserError) -> Self {
+        VerificationError::User(error)
+    }
+}
+
+impl VerificationError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            VerificationError::InvalidToken => "invalid_token",
+            VerificationError::ExpiredToken => "expired_token",
+            VerificationError::AlreadyVerified => "already_verified",
+            VerificationError::TooSoon(_) => "too_many_requests",
+            VerificationError::Mail => "mail_error",
+            VerificationError::User(error) => error.code(),
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            VerificationError::InvalidToken | VerificationError::ExpiredToken => {
+                StatusCode::BAD_REQUEST
+            }
+            VerificationError::AlreadyVerified => StatusCode::CONFLICT,
+            VerificationError::TooSoon(_) => StatusCode::TOO_MANY_REQUESTS,
+            VerificationError::Mail => StatusCode::BAD_GATEWAY,
+            VerificationError::User(error) => error.status(),
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            VerificationError::InvalidToken => "The verification link is invalid",
+            VerificationError::ExpiredToken => {
+                "The verification link has expired, request a new one"
+            }
+            VerificationError::AlreadyVerified => "The email address is already verified",
+            VerificationError::TooSoon(_) => {
+                "A verification email was sent recently, wait before asking again"
+            }
+            VerificationError::Mail => "Failed to send the verification email",
+            VerificationError::User(error) => error.message(),
+        }
+    }
+
+    fn reply(&self, format: Format) -> Response {
+        let error_response = ErrorResponse {
+            error: self.code().to_string(),
+            message: self.message().to_string(),
+        };
+        let response =
+            warp::reply::with_status(format.reply("error", &error_response), self.status());
+        match self {
+            VerificationError::TooSoon(seconds) => {
+                warp::reply::with_header(response, header::RETRY_AFTER, seconds.to_string())
+                    .into_response()
+            }
+            _ => response.into_response(),
+        }
+    }
+}
+
+/// Signs verification links and mails them
+pub struct EmailVerifier {
+    secret: Vec<u8>,
+    ttl: ChronoDuration,
+    url: String,
+    resend_interval: Duration,
+    mailer: Arc<dyn Mailer>,
+}
+
+impl EmailVerifier {
+    pub fn new(
+        secret: Vec<u8>,
+        ttl: ChronoDuration,
+        url: String,
+        resend_interval: Duration,
+        mailer: Arc<dyn Mailer>,
+    ) -> Self {
+        EmailVerifier {
+            secret,
+            ttl,
+            url,
+            resend_interval,
+            mailer,
+        }
+    }
+
+    /// Configure from `EMAIL_VERIFICATION_SECRET`, `EMAIL_VERIFICATION_TTL_SECONDS`,
+    /// `EMAIL_VERIFICATION_URL` and `EMAIL_VERIFICATION_RESEND_SECONDS`
+    pub fn from_env(mailer: Arc<dyn Mailer>) -> Self {
+        let secret = match env::var("EMAIL_VERIFICATION_SECRET") {
+            Ok(secret) if !secret.trim().is_empty() => secret.into_bytes(),
+            _ => {
+                println!(
+                    "EMAIL_VERIFICATION_SECRET is not set, verification links stop working on restart"
+                );
+                let mut secret = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut secret);
+                secret
+            }
+        };
+        let ttl = env::var("EMAIL_VERIFICATION_TTL_SECONDS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|seconds| *seconds > 0)
+            .unwrap_or(DEFAULT_EMAIL_VERIFICATION_TTL_SECONDS);
+        let resend = env::var("EMAIL_VERIFICATION_RESEND_SECONDS")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(DEFAULT_EMAIL_VERIFICATION_RESEND_SECONDS);
+
+        EmailVerifier::new(
+            secret,
+            ChronoDuration::seconds(ttl),
+            env::var("EMAIL_VERIFICATION_URL")
+                .unwrap_or_else(|_| DEFAULT_EMAIL_VERIFICATION_URL.to_string()),
+            Duration::from_secs(resend),
+            mailer,
+        )
+    }
+
+    /// How long a link stays valid
+    pub fn link_lifetime(&self) -> ChronoDuration {
+        self.ttl
+    }
+
+    fn mac(&self, user_id: ObjectId, expires_at: i64, email: &str) -> HmacSha256 {
+        let mut mac =
+            HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts keys of any length");
+        mac.update(
+            format!(
+                "{}.{}.{}",
+                user_id.to_hex(),
+                expires_at,
+                email.to_lowercase()
+            )
+            .as_bytes(),
+        );
+        mac
+    }
+
+    /// A token for `email`, valid until `expires_at` (Unix seconds)
+    pub fn token(&self, user_id: ObjectId, email: &str, expires_at: i64) -> String {
+        let signature = self.mac(user_id, expires_at, email).finalize().into_bytes();
+        format!(
+            "{}.{}.{}",
+            user_id.to_hex(),
+            expires_at,
+            hex::encode(signature)
+        )
+    }
+
+    /// The user a well-formed token names, before its signature is checked
+    fn token_user(token: &str) -> Result<ObjectId, VerificationError> {
+        token
+            .split('.')
+            .next()
+            .and_then(|id| ObjectId::parse_str(id).ok())
+            .ok_or(VerificationError::InvalidToken)
+    }
+
+    /// Check a token against the user's current email
+    fn check(&self, token: &str, email: &str, now: DateTime<Utc>) -> Result<(), VerificationError> {
+        let mut parts = token.split('.');
+        let (Some(user_id), Some(expires_at), Some(signature), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(VerificationError::InvalidToken);
+        };
+        let user_id = ObjectId::parse_str(user_id).map_err(|_| VerificationError::InvalidToken)?;
+        let expires_at: i64 = expires_at
+            .parse()
+            .map_err(|_| VerificationError::InvalidToken)?;
+        let signature = hex::decode(signature).map_err(|_| VerificationError::InvalidToken)?;
+
+        self.mac(user_id, expires_at, email)
+            .verify_slice(&signature)
+            .map_err(|_| VerificationError::InvalidToken)?;
+        if expires_at < now.timestamp() {
+            return Err(VerificationError::ExpiredToken);
+        }
+        Ok(())
+    }
+
+    /// The message carrying a link for `user`
+    fn email(&self, user: &User, now: DateTime<Utc>) -> Email {
+        let user_id = user.id.unwrap_or_default();
+        let expires_at = now + self.ttl;
+        let link = format!(
+            "{}?token={}",
+            self.url,
+            self.token(user_id, &user.email, expires_at.timestamp())
+        );
+
+        Email {
+            to: user.email.clone(),
+            subject: "Confirm your email address".to_string(),
+            body: format!(
+                "Hi {},\n\nPlease confirm that {} is your email address by opening this link:\n\n{}\n\n\
+                 The link expires at {}. If you did not sign up, you can ignore this email.\n",
+                user.name,
+                user.email,
+                link,
+                expires_at.to_rfc3339()
+            ),
+        }
+    }
+
+    /// Mail `user` a new link
+    pub async fn send_link(&self, user: &User) -> Result<(), String> {
+        self.mailer.send(&self.email(user, Utc::now())).await
+    }
+
+    /// Seconds left before a link may be resent after one was resent at `resent_at`
+    fn resend_wait(&self, resent_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> Option<u64> {
+        let elapsed = (now - resent_at?).to_std().unwrap_or_default();
+        let wait = self.resend_interval.checked_sub(elapsed)?;
+        (!wait.is_zero()).then(|| wait.as_secs().max(1))
+    }
+
+    /// Claim the right to resend to a user by recording the time on them, or say how
+    /// long to wait. Only one of two concurrent requests can replace the old time.
+    async fn claim_resend(
+        &self,
+        db: &Database,
+        user: &User,
+        now: DateTime<Utc>,
+    ) -> Result<(), VerificationError> {
+        if let Some(wait) = self.resend_wait(user.verification_resent_at, now) {
+            return Err(VerificationError::TooSoon(wait));
+        }
+
+        let collection: Collection<User> = db.collection("users");
+        let filter = doc! {
+            "_id": user.id,
+            "verification_resent_at": resent_at_bson(user.verification_resent_at),
+        };
+        let update = doc! { "$set": { "verification_resent_at": resent_at_bson(Some(now)) } };
+        match collection.update_one(filter, update, None).await {
+            Ok(result) if result.matched_count == 1 => Ok(()),
+            Ok(_) => Err(VerificationError::TooSoon(
+                self.resend_interval.as_secs().max(1),
+            )),
+            Err(_) => Err(UserError::Database("Failed to record the resend").into()),
+        }
+    }
+
+    /// Put back the time a failed resend replaced, so the user can ask again
+    async fn release_resend(&self, db: &Database, user: &User, now: DateTime<Utc>) {
+        let collection: Collection<User> = db.collection("users");
+        let filter = doc! {
+            "_id": user.id,
+            "verification_resent_at": resent_at_bson(Some(now)),
+        };
+        let update = match user.verification_resent_at {
+            Some(previous) => {
+                doc! { "$set": { "verification_resent_at": resent_at_bson(Some(previous)) } }
+            }
+            None => doc! { "$unset": { "verification_resent_at": "" } },
+        };
+        if let Err(e) = collection.update_one(filter, update, None).await {
+            eprintln!("Failed to release the verification resend: {}", e);
+        }
+    }
+}
+
+/// `verification_resent_at` as stored, so filters compare like for like
+fn resent_at_bson(resent_at: Option<DateTime<Utc>>) -> Bson {
+    resent_at
+        .and_then(|resent_at| bson::to_bson(&resent_at).ok())
+        .unwrap_or(Bson::Null)
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct VerifyEmailQuery {
+    /// The token from the verification link
+    #[serde(default)]
+    pub token: String,
+}
+
+/// A confirmed email address
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct EmailVerification {
+    pub user_id: String,
+    pub email: String,
+    /// RFC 3339
+    pub email_verified_at: String,
+    /// Unchanged by verifying; pending users are activated with `POST /users/{id}/activate`
+    pub status: UserStatus,
+}
+
+impl From<User> for EmailVerification {
+    fn from(user: User) -> Self {
+        EmailVerification {
+            user_id: user.id.map(|id| id.to_hex()).unwrap_or_default(),
+            email: user.email,
+            email_verified_at: user
+                .email_verified_at
+                .map(|verified_at| verified_at.to_rfc3339())
+                .unwrap_or_default(),
+            status: user.status,
+        }
+    }
+}
+
+/// A confirmed email address in v2, with camelCase fields
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct EmailVerificationV2 {
+    pub user_id: String,
+    pub email: String,
+    /// RFC 3339
+    pub email_verified_at: String,
+    /// Unchanged by verifying; pending users are activated with `POST /v2/users/{id}/activate`
+    pub status: UserStatus,
+}
+
+impl From<User> for EmailVerificationV2 {
+    fn from(user: User) -> Self {
+        let verification = EmailVerification::from(user);
+        EmailVerificationV2 {
+            user_id: verification.user_id,
+            email: verification.email,
+            email_verified_at: verification.email_verified_at,
+            status: verification.status,
+        }
+    }
+}
+
+/// Mark the email a token was issued for as verified.
+///
+/// The user's status is left alone: links are opened with a GET, which mail
+/// scanners and link previews also send. Verifying again with a valid token
+/// returns the user unchanged.
+pub(crate) async fn confirm_email(
+    token: &str,
+    verifier: &EmailVerifier,
+    audit: &AuditContext,
+    events: &UserEventBus,
+    outbox: &Outbox,
+    db: &Database,
+) -> Result<User, VerificationError> {
+    let now = Utc::now();
+    let object_id = EmailVerifier::token_user(token)?;
+    // A link for a deleted user is as good as a forged one
+    let current = find_user(db, object_id)
+        .await?
+        .ok_or(VerificationError::InvalidToken)?;
+    verifier.check(token, &current.email, now)?;
+    if current.email_verified_at.is_some() {
+        return Ok(current);
+    }
+
+    // Only while the user still has the address and version that were checked
+    let mut filter = version_filter(object_id, Some(vec![current.version]));
+    filter.insert("email", &current.email);
+
+    let update = doc! {
+        "$set": {
+            "email_verified_at": bson::to_bson(&now).unwrap_or_default(),
+            "updated_at": bson::to_bson(&now).unwrap_or_default(),
+        },
+        "$inc": { "version": 1_i64 },
+    };
+
+    match apply_user_update(db, outbox, filter, update).await {
+        Ok(Some((before, user))) => {
+            record_audit_entry(
+                db,
+                &audit.entry(AuditAction::Update, Some(&before), Some(&user)),
+            )
+            .await;
+            events.publish_local(UserEventKind::Updated, &user);
+
+            Ok(user)
+        }
+        Ok(None) => Err(UserError::WriteConflict.into()),
+        Err(e) if is_transient_transaction_error(&e) => Err(UserError::WriteConflict.into()),
+        Err(_) => Err(UserError::Database("Failed to verify email").into()),
+    }
+}
+
+/// Confirm an email address from a verification link
+///
+/// Only sets `email_verified_at`; the user's status does not change.
+#[utoipa::path(
+    get,
+    path = "/verify-email",
+    tag = "users",
+    params(
+        VerifyEmailQuery,
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 200, description = "Email verified; the user's status is unchanged", body = EmailVerification),
+        (status = 400, description = "Invalid or expired token", body = ErrorResponse),
+        (status = 409, description = "Concurrent write, retry", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_email(
+    query: VerifyEmailQuery,
+    format: Format,
+    audit: AuditContext,
+    verifier: Arc<EmailVerifier>,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match confirm_email(&query.token, &verifier, &audit, &events, &outbox, &db).await {
+        Ok(user) => Ok(warp::reply::with_status(
+            format.reply("verification", &EmailVerification::from(user)),
+            StatusCode::OK,
+        )
+        .into_response()),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+/// Confirm an email address from a verification link
+///
+/// Only sets `emailVerifiedAt`; the user's status does not change.
+#[utoipa::path(
+    get,
+    path = "/v2/verify-email",
+    tag = "users",
+    params(
+        VerifyEmailQuery,
+        ("X-Actor" = Option<String>, Header, description = "Recorded in the audit log as the claimed actor"),
+        ("X-Request-Id" = Option<String>, Header, description = "Recorded in the audit log")
+    ),
+    responses(
+        (status = 200, description = "Email verified; the user's status is unchanged", body = EmailVerificationV2),
+        (status = 400, description = "Invalid or expired token", body = ErrorResponse),
+        (status = 409, description = "Concurrent write, retry", body = ErrorResponse)
+    )
+)]
+#[allow(clippy::too_many_arguments)]
+pub async fn verify_email_v2(
+    query: VerifyEmailQuery,
+    format: Format,
+    audit: AuditContext,
+    verifier: Arc<EmailVerifier>,
+    events: Arc<UserEventBus>,
+    outbox: Arc<Outbox>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match confirm_email(&query.token, &verifier, &audit, &events, &outbox, &db).await {
+        Ok(user) => Ok(warp::reply::with_status(
+            format.reply("verification", &EmailVerificationV2::from(user)),
+            StatusCode::OK,
+        )
+        .into_response()),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+/// Mail a user a new verification link
+#[utoipa::path(
+    post,
+    path = "/users/{id}/verify-email/resend",
+    tag = "users",
+    params(("id" = String, Path, description = "User ID")),
+    responses(
+        (status = 202, description = "Verification email sent"),
+        (status = 400, description = "Invalid user ID", body = ErrorResponse),
+        (status = 404, description = "User not found", body = ErrorResponse),
+        (status = 409, description = "The email is already verified", body = ErrorResponse),
+        (status = 429, description = "A link was sent recently; see Retry-After", body = ErrorResponse),
+        (status = 502, description = "The mailer failed", body = ErrorResponse)
+    )
+)]
+pub async fn resend_verification_email(
+    id: String,
+    format: Format,
+    verifier: Arc<EmailVerifier>,
+    db: Arc<Database>,
+) -> Result<Response, Rejection> {
+    match resend(&id, &verifier, &db).await {
+        Ok(()) => Ok(StatusCode::ACCEPTED.into_response()),
+        Err(error) => Ok(error.reply(format)),
+    }
+}
+
+async fn resend(
+    id: &str,
+    verifier: &EmailVerifier,
+    db: &Database,
+) -> Result<(), VerificationError> {
+    let object_id = parse_user_id(id)?;
+    let user = find_user(db, object_id).await?.ok_or(UserError::NotFound)?;
+    if user.email_verified_at.is_some() {
+        return Err(VerificationError::AlreadyVerified);
+    }
+
+    let now = Utc::now();
+    verifier.claim_resend(db, &user, now).await?;
+    if let Err(e) = verifier.send_link(&user).await {
+        eprintln!("Failed to send verification email to user {}: {}", id, e);
+        verifier.release_resend(db, &user, now).await;
+        return Err(VerificationError::Mail);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mailer::MemoryMailer;
+
+    fn verifier(mailer: Arc<MemoryMailer>) -> EmailVerifier {
+        EmailVerifier::new(
+            b"test secret".to_vec(),
+            ChronoDuration::hours(1),
+            "https://app.example.com/verify".to_string(),
+            Duration::from_secs(60),
+            mailer,
+        )
+    }
+
+    fn user() -> User {
+        User::with_id(
+            ObjectId::new(),
+            "Ada".to_string(),
+            "Ada@Example.com".to_string(),
+            Utc::now(),
+        )
+    }
+
+    #[test]
+    fn test_token_round_trip() {
+        let verifier = verifier(Arc::new(MemoryMailer::default()));
+        let user = user();
+        let now = Utc::now();
+        let token = verifier.token(user.id.unwrap(), &user.email, now.timestamp() + 60);
+
+        assert_eq!(EmailVerifier::token_user(&token), Ok(user.id.unwrap()));
+        assert_eq!(verifier.check(&token, &user.email, now), Ok(()));
+        // Addresses compare without case
+        assert_eq!(verifier.check(&token, "ada@example.com", now), Ok(()));
+    }
+
+    #[test]
+    fn test_token_rejections() {
+        let verifier = verifier(Arc::new(MemoryMailer::default()));
+        let user = user();
+        let user_id = user.id.unwrap();
+        let now = Utc::now();
+        let token = verifier.token(user_id, &user.email, now.timestamp() + 60);
+
+        // Another address, a tampered expiry, another secret, garbage
+        assert_eq!(
+            verifier.check(&token, "eve@example.com", now),
+            Err(VerificationError::InvalidToken)
+        );
+        let extended = token.replacen(
+            &format!(".{}.", now.timestamp() + 60),
+            &format!(".{}.", now.timestamp() + 6000),
+            1,
+        );
+        assert_eq!(
+            verifier.check(&extended, &user.email, now),
+            Err(VerificationError::InvalidToken)
+        );
+        let other = EmailVerifier::new(
+            b"other secret".to_vec(),
+            ChronoDuration::hours(1),
+            String::new(),
+            Duration::ZERO,
+            Arc::new(MemoryMailer::default()),
+        );
+        assert_eq!(
+            other.check(&token, &user.email, now),
+            Err(VerificationError::InvalidToken)
+        );
+        assert_eq!(
+            verifier.check("not-a-token", &user.email, now),
+            Err(VerificationError::InvalidToken)
+        );
+        assert_eq!(
+            EmailVerifier::token_user("zzz.1.00"),
+            Err(VerificationError::InvalidToken)
+        );
+
+        let expired = verifier.token(user_id, &user.email, now.timestamp() - 1);
+        assert_eq!(
+            verifier.check(&expired, &user.email, now),
+            Err(VerificationError::ExpiredToken)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_link_is_mailed_to_the_user() {
+        let mailer = Arc::new(MemoryMailer::default());
+        let verifier = verifier(mailer.clone());
+        let user = user();
+
+        verifier.send_link(&user).await.unwrap();
+        let sent = mailer.sent();
+        assert_eq!(sent.len(), 1);
+        assert_eq!(sent[0].to, "Ada@Example.com");
+
+        let link = sent[0]
+            .body
+            .lines()
+            .find(|line| line.starts_with("https://app.example.com/verify?token="))
+            .unwrap();
+        let token = link.split_once("token=").unwrap().1;
+        assert_eq!(verifier.check(token, &user.email, Utc::now()), Ok(()));
+    }
+
+    #[test]
+    fn test_resends_are_spaced_out() {
+        let verifier = verifier(Arc::new(MemoryMailer::default()));
+        let now = Utc::now();
+
+        assert_eq!(verifier.resend_wait(None, now), None);
+        assert_eq!(
+            verifier.resend_wait(Some(now - ChronoDuration::seconds(20)), now),
+            Some(40)
+        );
+        assert_eq!(
+            verifier.resend_wait(Some(now - ChronoDuration::milliseconds(59_500)), now),
+            Some(1)
+        );
+        assert_eq!(
+            verifier.resend_wait(Some(now - ChronoDuration::seconds(60)), now),
+            None
+        );
+        // A time in the future, e.g. from clock skew between instances, still waits
+        assert_eq!(
+            verifier.resend_wait(Some(now + ChronoDuration::seconds(5)), now),
+            Some(60)
+        );
+
+        assert_eq!(
+            VerificationError::TooSoon(40).status(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[test]
+    fn test_resent_at_is_stored_like_other_dates() {
+        let now = Utc::now();
+
+        assert_eq!(resent_at_bson(None), Bson::Null);
+        assert_eq!(resent_at_bson(Some(now)), bson::to_bson(&now).unwrap());
+    }
+}
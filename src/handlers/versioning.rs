@@ -0,0 +1,182 @@
+//! Choosing the API version by path prefix or `API-Version` header, and
+//! announcing the retirement of old versions
+
+use chrono::{DateTime, Utc};
+use std::env;
+use warp::http::{HeaderValue, StatusCode};
+use warp::reply::Response;
+use warp::{Filter, Rejection, Reply};
+
+/// When v1 was deprecated, unless `API_V1_DEPRECATED_AT` says otherwise
+const DEFAULT_V1_DEPRECATED_AT: &str = "2026-10-18T00:00:00Z";
+
+/// When v1 stops being served, unless `API_V1_SUNSET_AT` says otherwise
+const DEFAULT_V1_SUNSET_AT: &str = "2027-04-18T00:00:00Z";
+
+/// A version of the REST API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiVersion {
+    V1,
+    V2,
+}
+
+/// The `API-Version` header names a version that does not exist
+#[derive(Debug)]
+pub struct UnsupportedApiVersion;
+
+impl warp::reject::Reject for UnsupportedApiVersion {}
+
+impl ApiVersion {
+    /// Parse `2`, `v2` or `V2`
+    pub fn parse(value: &str) -> Option<ApiVersion> {
+        let value = value.trim();
+        let number = value
+            .strip_prefix('v')
+            .or_else(|| value.strip_prefix('V'))
+            .unwrap_or(value);
+
+        match number {
+            "1" => Some(ApiVersion::V1),
+            "2" => Some(ApiVersion::V2),
+            _ => None,
+        }
+    }
+}
+
+/// Match requests without a version prefix that asked for `version` in `API-Version`.
+///
+/// A missing header means v1, which is what these paths served before
+/// versioning. An unknown version rejects with `UnsupportedApiVersion` (400).
+pub fn api_version_header(
+    version: ApiVersion,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("api-version")
+        .and_then(move |header: Option<String>| async move {
+            let requested = match header {
+                None => ApiVersion::V1,
+                Some(header) => ApiVersion::parse(&header)
+                    .ok_or_else(|| warp::reject::custom(UnsupportedApiVersion))?,
+            };
+
+            if requested == version {
+                Ok(())
+            } else {
+                Err(warp::reject::not_found())
+            }
+        })
+        .untuple_one()
+}
+
+/// `Deprecation`, `Sunset` and successor `Link` headers for a retiring version
+#[derive(Debug, Clone)]
+pub struct Deprecation {
+    deprecation: HeaderValue,
+    sunset: HeaderValue,
+    link: HeaderValue,
+}
+
+impl Deprecation {
+    pub fn new(deprecated_at: DateTime<Utc>, sunset_at: DateTime<Utc>, successor: &str) -> Self {
+        Deprecation {
+            // RFC 9745 structured date, RFC 8594 HTTP-date
+            deprecation: HeaderValue::from_str(&format!("@{}", deprecated_at.timestamp())).unwrap(),
+            sunset: HeaderValue::from_str(
+                &sunset_at.format("%a, %d %b %Y %H:%M:%S GMT").to_string(),
+            )
+            .unwrap(),
+            link: HeaderValue::from_str(&format!("<{}>; rel=\"successor-version\"", successor))
+                .unwrap(),
+        }
+    }
+
+    /// Read `API_V1_DEPRECATED_AT` and `API_V1_SUNSET_AT` as RFC 3339 times
+    pub fn v1_from_env() -> Self {
+        fn time_or(name: &str, default: &str) -> DateTime<Utc> {
+            env::var(name)
+                .ok()
+                .and_then(|value| DateTime::parse_from_rfc3339(&value).ok())
+                .unwrap_or_else(|| DateTime::parse_from_rfc3339(default).unwrap())
+                .with_timezone(&Utc)
+        }
+
+        Deprecation::new(
+            time_or("API_V1_DEPRECATED_AT", DEFAULT_V1_DEPRECATED_AT),
+            time_or("API_V1_SUNSET_AT", DEFAULT_V1_SUNSET_AT),
+            "/v2",
+        )
+    }
+
+    /// Mark a reply as coming from the deprecated version
+    pub fn apply(&self, reply: impl Reply) -> Response {
+        let mut response = reply.into_response();
+
+        // Upgrades hand the connection over, so leave their headers alone
+        if response.status() != StatusCode::SWITCHING_PROTOCOLS {
+            let headers = response.headers_mut();
+            headers.insert("deprecation", self.deprecation.clone());
+            headers.insert("sunset", self.sunset.clone());
+            headers.append("link", self.link.clone());
+        }
+        response
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_version() {
+        assert_eq!(ApiVersion::parse("1"), Some(ApiVersion::V1));
+        assert_eq!(ApiVersion::parse(" v2 "), Some(ApiVersion::V2));
+        assert_eq!(ApiVersion::parse("V2"), Some(ApiVersion::V2));
+        assert_eq!(ApiVersion::parse("3"), None);
+        assert_eq!(ApiVersion::parse("2.0"), None);
+    }
+
+    #[tokio::test]
+    async fn test_api_version_header() {
+        let v1 = api_version_header(ApiVersion::V1);
+        let v2 = api_version_header(ApiVersion::V2);
+
+        assert!(warp::test::request().matches(&v1).await);
+        assert!(!warp::test::request().matches(&v2).await);
+        assert!(
+            warp::test::request()
+                .header("api-version", "2")
+                .matches(&v2)
+                .await
+        );
+
+        let rejection = warp::test::request()
+            .header("api-version", "7")
+            .filter(&v1)
+            .await
+            .unwrap_err();
+        assert!(rejection.find::<UnsupportedApiVersion>().is_some());
+    }
+
+    #[test]
+    fn test_deprecation_headers() {
+        let deprecation = Deprecation::new(
+            DateTime::parse_from_rfc3339("2026-10-18T00:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            DateTime::parse_from_rfc3339("2027-04-18T00:00:00Z")
+                .unwrap()
+                .with_timezone(&Utc),
+            "/v2",
+        );
+
+        let response = deprecation.apply(warp::reply());
+        assert_eq!(response.headers()["deprecation"], "@1792281600");
+        assert_eq!(
+            response.headers()["sunset"],
+            "Sun, 18 Apr 2027 00:00:00 GMT"
+        );
+        assert_eq!(
+            response.headers()["link"],
+            "</v2>; rel=\"successor-version\""
+        );
+    }
+}
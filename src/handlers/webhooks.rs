@@ -0,0 +1,615 @@
+use futures::stream::StreamExt;
+use mongodb::bson::{doc, oid::ObjectId, DateTime, Document};
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use mongodb::{Collection, Database};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use utoipa::{IntoParams, ToSchema};
+use warp::hyper::body::Bytes;
+use warp::{http::StatusCode, Rejection, Reply};
+
+use crate::db::{WEBHOOK_DELIVERIES_COLLECTION, WEBHOOK_SUBSCRIPTIONS_COLLECTION};
+use crate::handlers::idempotency::{request_fingerprint, with_idempotency};
+use crate::handlers::pagination::{PageResponse, Pagination};
+use crate::handlers::users::ErrorResponse;
+use crate::models::webhook::WEBHOOK_EVENT_TYPES;
+use crate::models::{DeliveryAttempt, DeliveryStatus, WebhookDelivery, WebhookSubscription};
+use crate::webhooks::{generate_secret, WebhookTargets};
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct CreateWebhookRequest {
+    pub url: String,
+    /// Defaults to every user event type
+    pub events: Option<Vec<String>>,
+    /// Generated when not provided
+    pub secret: Option<String>,
+}
+
+impl CreateWebhookRequest {
+    /// Check the URL and event types, returning the events to subscribe to
+    pub fn validate(&self) -> Result<Vec<String>, String> {
+        match reqwest::Url::parse(self.url.trim()) {
+            Ok(url) if url.scheme() == "http" || url.scheme() == "https" => {}
+            _ => return Err("url must be an absolute http or https URL".to_string()),
+        }
+
+        if matches!(&self.secret, Some(secret) if secret.trim().is_empty()) {
+            return Err("secret must not be blank".to_string());
+        }
+
+        let events = match &self.events {
+            Some(events) if events.is_empty() => return Err("events must not be empty".to_string()),
+            Some(events) => events.clone(),
+            None => WEBHOOK_EVENT_TYPES
+                .iter()
+                .map(|event| event.to_string())
+                .collect(),
+        };
+
+        if let Some(unknown) = events
+            .iter()
+            .find(|event| !WEBHOOK_EVENT_TYPES.contains(&event.as_str()))
+        {
+            return Err(format!(
+                "Unknown event '{}', expected one of {}",
+                unknown,
+                WEBHOOK_EVENT_TYPES.join(", ")
+            ));
+        }
+
+        Ok(events)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct WebhookSubscriptionResponse {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: String,
+    /// Only returned when the subscription is created
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub secret: Option<String>,
+}
+
+impl From<WebhookSubscription> for WebhookSubscriptionResponse {
+    fn from(subscription: WebhookSubscription) -> Self {
+        WebhookSubscriptionResponse {
+            id: subscription.id.map(|id| id.to_hex()).unwrap_or_default(),
+            url: subscription.url,
+            events: subscription.events,
+            active: subscription.active,
+            created_at: subscription
+                .created_at
+                .try_to_rfc3339_string()
+                .unwrap_or_default(),
+            secret: None,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct DeliveryAttemptResponse {
+    pub attempted_at: String,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+}
+
+impl From<DeliveryAttempt> for DeliveryAttemptResponse {
+    fn from(attempt: DeliveryAttempt) -> Self {
+        DeliveryAttemptResponse {
+            attempted_at: attempt
+                .attempted_at
+                .try_to_rfc3339_string()
+                .unwrap_or_default(),
+            status_code: attempt.status_code,
+            error: attempt.error,
+            duration_ms: attempt.duration_ms,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, ToSchema)]
+pub struct WebhookDeliveryResponse {
+    pub id: String,
+    pub subscription_id: String,
+    pub event_id: String,
+    pub event_type: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    /// Only set while the delivery is pending
+    pub next_attempt_at: Option<String>,
+    #[schema(value_type = Object)]
+    pub payload: serde_json::Value,
+    pub attempt_log: Vec<DeliveryAttemptResponse>,
+    pub created_at: String,
+    pub updated_at: String,
+}
+
+impl From<WebhookDelivery> for WebhookDeliveryResponse {
+    fn from(delivery: WebhookDelivery) -> Self {
+        WebhookDeliveryResponse {
+            id: delivery.id,
+            subscription_id: delivery.subscription_id.to_hex(),
+            event_id: delivery.event_id,
+            event_type: delivery.event_type,
+            status: delivery.status,
+            attempts: delivery.attempts,
+            next_attempt_at: match delivery.status {
+                DeliveryStatus::Pending => delivery.next_attempt_at.try_to_rfc3339_string().ok(),
+                _ => None,
+            },
+            payload: serde_json::from_str(&delivery.payload).unwrap_or_default(),
+            attempt_log: delivery
+                .attempt_log
+                .into_iter()
+                .map(DeliveryAttemptResponse::from)
+                .collect(),
+            created_at: delivery
+                .created_at
+                .try_to_rfc3339_string()
+                .unwrap_or_default(),
+            updated_at: delivery
+                .updated_at
+                .try_to_rfc3339_string()
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeliveryQuery {
+    /// One of `pending`, `succeeded` or `dead`
+    pub status: Option<String>,
+    pub page: Option<u64>,
+    pub per_page: Option<u64>,
+}
+
+/// Register a webhook subscription, replaying the stored response when the
+/// `Idempotency-Key` is repeated
+#[utoipa::path(
+    post,
+    path = "/webhooks",
+    tag = "webhooks",
+    request_body = CreateWebhookRequest,
+    params(
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe")
+    ),
+    responses(
+        (status = 201, description = "Subscription created; the secret is only returned here", body = WebhookSubscriptionResponse),
+        (status = 400, description = "Validation error, or the URL resolves to a non-public address", body = ErrorResponse),
+        (status = 409, description = "A request with the same key is in progress", body = ErrorResponse),
+        (status = 413, description = "Request body is too large", body = ErrorResponse),
+        (status = 422, description = "The key was used with a different request", body = ErrorResponse)
+    )
+)]
+pub async fn create_webhook(
+    idempotency_key: Option<String>,
+    req: CreateWebhookRequest,
+    raw_body: Bytes,
+    targets: WebhookTargets,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    let fingerprint = request_fingerprint("POST", "/webhooks", &raw_body);
+
+    with_idempotency(
+        db.clone(),
+        idempotency_key,
+        "POST /webhooks",
+        fingerprint,
+        create_subscription(req, targets, db),
+    )
+    .await
+}
+
+async fn create_subscription(
+    req: CreateWebhookRequest,
+    targets: WebhookTargets,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    let events = match req.validate() {
+        Ok(events) => events,
+        Err(message) => {
+            return Ok(error_reply(
+                StatusCode::BAD_REQUEST,
+                "validation_error",
+                message,
+            ))
+        }
+    };
+
+    // Checked again on every delivery, in case the host resolves elsewhere by then
+    if let Err(message) = targets.check_url(req.url.trim()).await {
+        return Ok(error_reply(
+            StatusCode::BAD_REQUEST,
+            "invalid_target",
+            message,
+        ));
+    }
+
+    let secret = req
+        .secret
+        .map(|secret| secret.trim().to_string())
+        .unwrap_or_else(generate_secret);
+    let mut subscription = WebhookSubscription::new(req.url.trim().to_string(), secret, events);
+
+    let collection: Collection<WebhookSubscription> =
+        db.collection(WEBHOOK_SUBSCRIPTIONS_COLLECTION);
+    match collection.insert_one(&subscription, None).await {
+        Ok(result) => {
+            subscription.id = result.inserted_id.as_object_id();
+            let secret = subscription.secret.clone();
+            let mut response = WebhookSubscriptionResponse::from(subscription);
+            response.secret = Some(secret);
+
+            Ok(warp::reply::with_status(
+                warp::reply::json(&response),
+                StatusCode::CREATED,
+            ))
+        }
+        Err(_) => Ok(database_error("Failed to create webhook subscription")),
+    }
+}
+
+/// List webhook subscriptions, without their secrets
+#[utoipa::path(
+    get,
+    path = "/webhooks",
+    tag = "webhooks",
+    responses((status = 200, description = "All subscriptions", body = [WebhookSubscriptionResponse]))
+)]
+pub async fn list_webhooks(db: Arc<Database>) -> Result<impl Reply, Rejection> {
+    let collection: Collection<WebhookSubscription> =
+        db.collection(WEBHOOK_SUBSCRIPTIONS_COLLECTION);
+
+    match collection.find(None, None).await {
+        Ok(mut cursor) => {
+            let mut subscriptions = Vec::new();
+
+            while let Some(result) = cursor.next().await {
+                match result {
+                    Ok(subscription) => {
+                        subscriptions.push(WebhookSubscriptionResponse::from(subscription))
+                    }
+                    Err(_) => return Ok(database_error("Failed to fetch webhook subscriptions")),
+                }
+            }
+
+            Ok(warp::reply::with_status(
+                warp::reply::json(&subscriptions),
+                StatusCode::OK,
+            ))
+        }
+        Err(_) => Ok(database_error("Failed to fetch webhook subscriptions")),
+    }
+}
+
+/// Remove a subscription; its queued deliveries are dead-lettered by the worker
+#[utoipa::path(
+    delete,
+    path = "/webhooks/{id}",
+    tag = "webhooks",
+    params(("id" = String, Path, description = "Subscription ID")),
+    responses(
+        (status = 204, description = "Subscription removed"),
+        (status = 400, description = "Invalid subscription ID", body = ErrorResponse),
+        (status = 404, description = "Subscription not found", body = ErrorResponse)
+    )
+)]
+pub async fn delete_webhook(id: String, db: Arc<Database>) -> Result<impl Reply, Rejection> {
+    let object_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => return Ok(invalid_id().into_response()),
+    };
+
+    let collection: Collection<WebhookSubscription> =
+        db.collection(WEBHOOK_SUBSCRIPTIONS_COLLECTION);
+    match collection.delete_one(doc! { "_id": object_id }, None).await {
+        Ok(result) if result.deleted_count == 1 => Ok(StatusCode::NO_CONTENT.into_response()),
+        Ok(_) => Ok(subscription_not_found().into_response()),
+        Err(_) => Ok(database_error("Failed to delete webhook subscription").into_response()),
+    }
+}
+
+/// The delivery log of a single subscription, newest first
+#[utoipa::path(
+    get,
+    path = "/webhooks/{id}/deliveries",
+    tag = "webhooks",
+    params(("id" = String, Path, description = "Subscription ID"), DeliveryQuery),
+    responses(
+        (status = 200, description = "Deliveries of the subscription", body = PageResponse<WebhookDeliveryResponse>),
+        (status = 400, description = "Invalid ID or status", body = ErrorResponse)
+    )
+)]
+pub async fn get_webhook_deliveries(
+    id: String,
+    query: DeliveryQuery,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    let object_id = match ObjectId::parse_str(&id) {
+        Ok(oid) => oid,
+        Err(_) => return Ok(invalid_id()),
+    };
+
+    let mut filter = doc! { "subscription_id": object_id };
+    if let Some(status) = &query.status {
+        match parse_status(status) {
+            Ok(status) => filter.insert("status", status.as_str()),
+            Err(message) => {
+                return Ok(error_reply(
+                    StatusCode::BAD_REQUEST,
+                    "validation_error",
+                    message,
+                ))
+            }
+        };
+    }
+
+    let pagination = Pagination::new(query.page, query.per_page);
+    Ok(list_deliveries(&db, filter, pagination).await)
+}
+
+/// Deliveries that ran out of attempts, newest first
+#[utoipa::path(
+    get,
+    path = "/webhooks/dead-letters",
+    tag = "webhooks",
+    params(DeliveryQuery),
+    responses((status = 200, description = "Dead-lettered deliveries", body = PageResponse<WebhookDeliveryResponse>))
+)]
+pub async fn get_dead_letters(
+    query: DeliveryQuery,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    let pagination = Pagination::new(query.page, query.per_page);
+    Ok(list_deliveries(
+        &db,
+        doc! { "status": DeliveryStatus::Dead.as_str() },
+        pagination,
+    )
+    .await)
+}
+
+/// Move a dead-lettered delivery back into the queue with a fresh set of attempts
+#[utoipa::path(
+    post,
+    path = "/webhooks/deliveries/{id}/retry",
+    tag = "webhooks",
+    params(
+        ("id" = String, Path, description = "Delivery ID"),
+        ("Idempotency-Key" = Option<String>, Header, description = "Makes retries of this request safe")
+    ),
+    responses(
+        (status = 200, description = "Delivery queued again", body = WebhookDeliveryResponse),
+        (status = 404, description = "Delivery not found", body = ErrorResponse),
+        (status = 409, description = "Delivery is not dead-lettered, or a request with the same key is in progress", body = ErrorResponse),
+        (status = 422, description = "The key was used with a different request", body = ErrorResponse)
+    )
+)]
+pub async fn retry_webhook_delivery(
+    id: String,
+    idempotency_key: Option<String>,
+    db: Arc<Database>,
+) -> Result<impl Reply, Rejection> {
+    let path = format!("/webhooks/deliveries/{}/retry", id);
+    let fingerprint = request_fingerprint("POST", &path, &[]);
+
+    with_idempotency(
+        db.clone(),
+        idempotency_key,
+        "POST /webhooks/deliveries/retry",
+        fingerprint,
+        requeue_delivery(id, db),
+    )
+    .await
+}
+
+async fn requeue_delivery(id: String, db: Arc<Database>) -> Result<impl Reply, Rejection> {
+    let collection: Collection<WebhookDelivery> = db.collection(WEBHOOK_DELIVERIES_COLLECTION);
+    let options = FindOneAndUpdateOptions::builder()
+        .return_document(ReturnDocument::After)
+        .build();
+
+    let result = collection
+        .find_one_and_update(
+            doc! { "_id": &id, "status": DeliveryStatus::Dead.as_str() },
+            doc! {
+                "$set": {
+                    "status": DeliveryStatus::Pending.as_str(),
+                    "attempts": 0,
+                    "next_attempt_at": DateTime::now(),
+                    "updated_at": DateTime::now(),
+                }
+            },
+            options,
+        )
+        .await;
+
+    match result {
+        Ok(Some(delivery)) => Ok(warp::reply::with_status(
+            warp::reply::json(&WebhookDeliveryResponse::from(delivery)),
+            StatusCode::OK,
+        )),
+        Ok(None) => match collection.find_one(doc! { "_id": &id }, None).await {
+            Ok(Some(_)) => Ok(error_reply(
+                StatusCode::CONFLICT,
+                "delivery_not_dead",
+                "Only dead-lettered deliveries can be retried".to_string(),
+            )),
+            Ok(None) => Ok(error_reply(
+                StatusCode::NOT_FOUND,
+                "not_found",
+                format!("Delivery with ID '{}' not found", id),
+            )),
+            Err(_) => Ok(database_error("Failed to retry webhook delivery")),
+        },
+        Err(_) => Ok(database_error("Failed to retry webhook delivery")),
+    }
+}
+
+fn parse_status(status: &str) -> Result<DeliveryStatus, String> {
+    match status {
+        "pending" => Ok(DeliveryStatus::Pending),
+        "succeeded" => Ok(DeliveryStatus::Succeeded),
+        "dead" => Ok(DeliveryStatus::Dead),
+        other => Err(format!("Unknown delivery status '{}'", other)),
+    }
+}
+
+async fn list_deliveries(
+    db: &Database,
+    filter: Document,
+    pagination: Pagination,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let collection: Collection<WebhookDelivery> = db.collection(WEBHOOK_DELIVERIES_COLLECTION);
+
+    let total = match collection.count_documents(filter.clone(), None).await {
+        Ok(total) => total,
+        Err(_) => return database_error("Failed to fetch webhook deliveries"),
+    };
+
+    let options = FindOptions::builder()
+        .sort(doc! { "created_at": -1, "_id": -1 })
+        .skip(pagination.skip())
+        .limit(pagination.per_page as i64)
+        .build();
+
+    match collection.find(filter, options).await {
+        Ok(mut cursor) => {
+            let mut deliveries = Vec::new();
+
+            while let Some(result) = cursor.next().await {
+                match result {
+                    Ok(delivery) => deliveries.push(WebhookDeliveryResponse::from(delivery)),
+                    Err(_) => return database_error("Failed to fetch webhook deliveries"),
+                }
+            }
+
+            warp::reply::with_status(
+                warp::reply::json(&PageResponse::new(deliveries, pagination, total)),
+                StatusCode::OK,
+            )
+        }
+        Err(_) => database_error("Failed to fetch webhook deliveries"),
+    }
+}
+
+fn error_reply(
+    status: StatusCode,
+    error: &str,
+    message: String,
+) -> warp::reply::WithStatus<warp::reply::Json> {
+    let error_response = ErrorResponse {
+        error: error.to_string(),
+        message,
+    };
+    warp::reply::with_status(warp::reply::json(&error_response), status)
+}
+
+fn invalid_id() -> warp::reply::WithStatus<warp::reply::Json> {
+    error_reply(
+        StatusCode::BAD_REQUEST,
+        "invalid_id",
+        "Invalid webhook ID format".to_string(),
+    )
+}
+
+fn subscription_not_found() -> warp::reply::WithStatus<warp::reply::Json> {
+    error_reply(
+        StatusCode::NOT_FOUND,
+        "not_found",
+        "Webhook subscription not found".to_string(),
+    )
+}
+
+fn database_error(message: &str) -> warp::reply::WithStatus<warp::reply::Json> {
+    error_reply(
+        StatusCode::INTERNAL_SERVER_ERROR,
+        "database_error",
+        message.to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(url: &str, events: Option<Vec<&str>>) -> CreateWebhookRequest {
+        CreateWebhookRequest {
+            url: url.to_string(),
+            events: events.map(|events| events.into_iter().map(str::to_string).collect()),
+            secret: None,
+        }
+    }
+
+    #[test]
+    fn test_create_webhook_request_defaults_to_all_events() {
+        let events = request("https://crm.example.com/hooks", None)
+            .validate()
+            .unwrap();
+
+        assert_eq!(events, WEBHOOK_EVENT_TYPES.to_vec());
+    }
+
+    #[test]
+    fn test_create_webhook_request_validation() {
+        assert!(request("ftp://example.com", None).validate().is_err());
+        assert!(request("not a url", None).validate().is_err());
+        assert!(request("http://localhost:9000", Some(vec![]))
+            .validate()
+            .is_err());
+        assert_eq!(
+            request("http://localhost:9000", Some(vec!["user.renamed"]))
+                .validate()
+                .unwrap_err(),
+            "Unknown event 'user.renamed', expected one of user.created, user.updated, user.deleted"
+        );
+
+        let mut blank_secret = request("http://localhost:9000", None);
+        blank_secret.secret = Some(" ".to_string());
+        assert!(blank_secret.validate().is_err());
+    }
+
+    #[test]
+    fn test_subscription_response_hides_secret() {
+        let mut subscription = WebhookSubscription::new(
+            "http://localhost:9000/hook".to_string(),
+            "secret".to_string(),
+            vec!["user.created".to_string()],
+        );
+        subscription.id = Some(ObjectId::new());
+
+        let json_str =
+            serde_json::to_string(&WebhookSubscriptionResponse::from(subscription)).unwrap();
+        assert!(!json_str.contains("secret"));
+    }
+
+    #[test]
+    fn test_delivery_response_from_delivery() {
+        let mut delivery = WebhookDelivery::pending(
+            "sub:event".to_string(),
+            ObjectId::new(),
+            "event".to_string(),
+            "user.deleted".to_string(),
+            r#"{"type":"user.deleted"}"#.to_string(),
+        );
+
+        let response = WebhookDeliveryResponse::from(delivery.clone());
+        assert_eq!(response.payload["type"], "user.deleted");
+        assert!(response.next_attempt_at.is_some());
+
+        delivery.status = DeliveryStatus::Dead;
+        let response = WebhookDeliveryResponse::from(delivery);
+        assert!(response.next_attempt_at.is_none());
+    }
+
+    #[test]
+    fn test_parse_status() {
+        assert_eq!(parse_status("dead"), Ok(DeliveryStatus::Dead));
+        assert!(parse_status("failed").is_err());
+    }
+}
@@ -0,0 +1,544 @@
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast;
+use tokio::time::Instant;
+use utoipa::IntoParams;
+use warp::http::StatusCode;
+use warp::ws::{Message, WebSocket, Ws};
+use warp::{Rejection, Reply};
+
+use crate::events::{UserEvent, UserEventBus, UserEventKind};
+use crate::handlers::users::ErrorResponse;
+
+/// Interval between server ping frames
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Connections silent for this long are closed
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(90);
+
+/// Connections that cannot accept a frame within this time are closed
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of subscriptions a single connection may hold
+const MAX_SUBSCRIPTIONS: usize = 50;
+
+/// Maximum number of user IDs in a single subscription
+const MAX_USER_IDS_PER_SUBSCRIPTION: usize = 500;
+
+/// Tokens accepted on the WebSocket upgrade request.
+///
+/// Without tokens every connection is refused, unless anonymous access was
+/// explicitly allowed.
+#[derive(Debug, Clone, Default)]
+pub struct WsAuthConfig {
+    tokens: Vec<String>,
+    allow_anonymous: bool,
+}
+
+impl WsAuthConfig {
+    pub fn new(tokens: Vec<String>) -> Self {
+        WsAuthConfig {
+            tokens,
+            allow_anonymous: false,
+        }
+    }
+
+    /// Accept every connection without a token
+    pub fn anonymous() -> Self {
+        WsAuthConfig {
+            tokens: Vec::new(),
+            allow_anonymous: true,
+        }
+    }
+
+    /// Read the comma-separated `WS_AUTH_TOKENS`, or `WS_ALLOW_ANONYMOUS=true` to accept
+    /// connections without a token
+    pub fn from_env() -> Self {
+        if env::var("WS_ALLOW_ANONYMOUS").unwrap_or_default() == "true" {
+            return WsAuthConfig::anonymous();
+        }
+
+        let tokens = env::var("WS_AUTH_TOKENS")
+            .unwrap_or_default()
+            .split(',')
+            .map(str::trim)
+            .filter(|token| !token.is_empty())
+            .map(str::to_string)
+            .collect();
+
+        WsAuthConfig::new(tokens)
+    }
+
+    pub fn allows_anonymous(&self) -> bool {
+        self.allow_anonymous
+    }
+
+    /// Whether any connection can be authorized at all
+    pub fn accepts_connections(&self) -> bool {
+        self.allow_anonymous || !self.tokens.is_empty()
+    }
+
+    /// Check a bearer token from the `Authorization` header or the `access_token` query parameter
+    pub fn is_authorized(&self, authorization: Option<&str>, query_token: Option<&str>) -> bool {
+        if self.allow_anonymous {
+            return true;
+        }
+
+        let token = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .or(query_token)
+            .map(str::trim);
+
+        match token {
+            Some(token) => self
+                .tokens
+                .iter()
+                .any(|accepted| constant_time_eq(accepted.as_bytes(), token.as_bytes())),
+            None => false,
+        }
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Deserialize, Debug, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct WsQuery {
+    pub access_token: Option<String>,
+}
+
+/// What a subscription matches; every field that is set must match
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct SubscriptionFilter {
+    /// Only these users
+    pub user_ids: Option<Vec<String>>,
+    /// Only these change types
+    pub events: Option<Vec<UserEventKind>>,
+    /// Only users whose email ends with this suffix, e.g. `@example.com`
+    pub email_suffix: Option<String>,
+}
+
+impl SubscriptionFilter {
+    pub fn matches(&self, event: &UserEvent) -> bool {
+        if let Some(user_ids) = &self.user_ids {
+            if !user_ids.contains(&event.user_id) {
+                return false;
+            }
+        }
+        if let Some(events) = &self.events {
+            if !events.contains(&event.kind) {
+                return false;
+            }
+        }
+        if let Some(email_suffix) = &self.email_suffix {
+            let email_matches = event.user.as_ref().is_some_and(|user| {
+                user.email
+                    .to_lowercase()
+                    .ends_with(&email_suffix.to_lowercase())
+            });
+            if !email_matches {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// Messages sent by the client
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ClientMessage {
+    Subscribe {
+        id: String,
+        #[serde(flatten)]
+        filter: SubscriptionFilter,
+    },
+    Unsubscribe {
+        id: String,
+    },
+    Ping,
+}
+
+/// Messages sent by the server
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ServerMessage {
+    Subscribed {
+        id: String,
+    },
+    Unsubscribed {
+        id: String,
+    },
+    Event {
+        subscription: String,
+        event: Box<UserEvent>,
+    },
+    /// Events were dropped because the connection could not keep up
+    Lagged {
+        missed: u64,
+    },
+    Pong,
+    Error {
+        message: String,
+    },
+}
+
+impl ServerMessage {
+    fn to_message(&self) -> Message {
+        Message::text(serde_json::to_string(self).unwrap_or_default())
+    }
+}
+
+/// Apply a client message to the connection's subscriptions and build the reply
+pub fn handle_client_message(
+    text: &str,
+    subscriptions: &mut HashMap<String, SubscriptionFilter>,
+) -> ServerMessage {
+    let message: ClientMessage = match serde_json::from_str(text) {
+        Ok(message) => message,
+        Err(e) => {
+            return ServerMessage::Error {
+                message: format!("Invalid message: {}", e),
+            }
+        }
+    };
+
+    match message {
+        ClientMessage::Subscribe { id, filter } => {
+            if id.trim().is_empty() {
+                return ServerMessage::Error {
+                    message: "Subscription id is required".to_string(),
+                };
+            }
+            if filter
+                .user_ids
+                .as_ref()
+                .is_some_and(|user_ids| user_ids.len() > MAX_USER_IDS_PER_SUBSCRIPTION)
+            {
+                return ServerMessage::Error {
+                    message: format!(
+                        "A subscription may list at most {} user IDs",
+                        MAX_USER_IDS_PER_SUBSCRIPTION
+                    ),
+                };
+            }
+            if !subscriptions.contains_key(&id) && subscriptions.len() >= MAX_SUBSCRIPTIONS {
+                return ServerMessage::Error {
+                    message: format!(
+                        "A connection may hold at most {} subscriptions",
+                        MAX_SUBSCRIPTIONS
+                    ),
+                };
+            }
+
+            subscriptions.insert(id.clone(), filter);
+            ServerMessage::Subscribed { id }
+        }
+        ClientMessage::Unsubscribe { id } => {
+            subscriptions.remove(&id);
+            ServerMessage::Unsubscribed { id }
+        }
+        ClientMessage::Ping => ServerMessage::Pong,
+    }
+}
+
+/// Upgrade to a WebSocket connection that delivers user change notifications
+#[utoipa::path(
+    get,
+    path = "/ws",
+    tag = "events",
+    params(
+        ("Authorization" = Option<String>, Header, description = "`Bearer <token>`, one of `WS_AUTH_TOKENS`"),
+        WsQuery
+    ),
+    responses(
+        (status = 101, description = "Switched to the WebSocket protocol"),
+        (status = 401, description = "Missing or invalid token", body = ErrorResponse)
+    )
+)]
+pub async fn user_updates_socket(
+    ws: Ws,
+    authorization: Option<String>,
+    query: WsQuery,
+    auth: WsAuthConfig,
+    events: Arc<UserEventBus>,
+) -> Result<impl Reply, Rejection> {
+    if !auth.is_authorized(authorization.as_deref(), query.access_token.as_deref()) {
+        let error_response = ErrorResponse {
+            error: "unauthorized".to_string(),
+            message: "A valid access token is required".to_string(),
+        };
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&error_response),
+            StatusCode::UNAUTHORIZED,
+        )
+        .into_response());
+    }
+
+    Ok(ws
+        .on_upgrade(move |socket| run_socket(socket, events))
+        .into_response())
+}
+
+async fn run_socket(socket: WebSocket, events: Arc<UserEventBus>) {
+    let (mut sink, mut stream) = socket.split();
+    let (_, mut receiver) = events.subscribe(None);
+    let mut subscriptions: HashMap<String, SubscriptionFilter> = HashMap::new();
+    let mut heartbeat =
+        tokio::time::interval_at(Instant::now() + HEARTBEAT_INTERVAL, HEARTBEAT_INTERVAL);
+    let mut last_seen = Instant::now();
+
+    loop {
+        let outgoing = tokio::select! {
+            incoming = stream.next() => match incoming {
+                Some(Ok(message)) => {
+                    last_seen = Instant::now();
+                    if message.is_close() {
+                        break;
+                    }
+                    match message.to_str() {
+                        Ok(text) => vec![handle_client_message(text, &mut subscriptions).to_message()],
+                        // Pings are answered by the protocol layer; pongs only refresh `last_seen`
+                        Err(_) => Vec::new(),
+                    }
+                }
+                _ => break,
+            },
+            event = receiver.recv() => match event {
+                Ok(event) => subscriptions
+                    .iter()
+                    .filter(|(_, filter)| filter.matches(&event))
+                    .map(|(id, _)| {
+                        ServerMessage::Event {
+                            subscription: id.clone(),
+                            event: Box::new(event.clone()),
+                        }
+                        .to_message()
+                    })
+                    .collect(),
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    vec![ServerMessage::Lagged { missed }.to_message()]
+                }
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            _ = heartbeat.tick() => {
+                if last_seen.elapsed() > HEARTBEAT_TIMEOUT {
+                    break;
+                }
+                vec![Message::ping(Vec::new())]
+            }
+        };
+
+        for message in outgoing {
+            // A client that stops reading is disconnected instead of buffering without bound
+            match tokio::time::timeout(SEND_TIMEOUT, sink.send(message)).await {
+                Ok(Ok(())) => {}
+                _ => return,
+            }
+        }
+    }
+
+    let _ = sink.close().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handlers::users::UserResponse;
+    use warp::Filter;
+
+    fn test_event(kind: UserEventKind, user_id: &str, email: &str) -> UserEvent {
+        UserEvent {
+            id: "local-test-1".to_string(),
+            kind,
+            user_id: user_id.to_string(),
+            user: Some(UserResponse {
+                id: user_id.to_string(),
+                name: "Socket User".to_string(),
+                email: email.to_string(),
+                created_at: "2024-01-01T00:00:00+00:00".to_string(),
+                version: 1,
+                ..Default::default()
+            }),
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_auth_denies_without_tokens() {
+        let auth = WsAuthConfig::default();
+
+        assert!(!auth.accepts_connections());
+        assert!(!auth.is_authorized(None, None));
+        assert!(!auth.is_authorized(Some("Bearer anything"), None));
+    }
+
+    #[test]
+    fn test_auth_allows_anonymous_only_when_opted_in() {
+        let auth = WsAuthConfig::anonymous();
+
+        assert!(auth.allows_anonymous());
+        assert!(auth.accepts_connections());
+        assert!(auth.is_authorized(None, None));
+    }
+
+    #[test]
+    fn test_auth_from_env() {
+        unsafe {
+            env::remove_var("WS_ALLOW_ANONYMOUS");
+            env::remove_var("WS_AUTH_TOKENS");
+        }
+        assert!(!WsAuthConfig::from_env().accepts_connections());
+
+        unsafe {
+            env::set_var("WS_AUTH_TOKENS", "one, two");
+        }
+        let auth = WsAuthConfig::from_env();
+        assert!(auth.is_authorized(Some("Bearer two"), None));
+        assert!(!auth.is_authorized(None, None));
+
+        unsafe {
+            env::set_var("WS_ALLOW_ANONYMOUS", "true");
+        }
+        assert!(WsAuthConfig::from_env().is_authorized(None, None));
+
+        // Clean up
+        unsafe {
+            env::remove_var("WS_ALLOW_ANONYMOUS");
+            env::remove_var("WS_AUTH_TOKENS");
+        }
+    }
+
+    #[test]
+    fn test_auth_accepts_header_or_query_token() {
+        let auth = WsAuthConfig::new(vec!["secret".to_string()]);
+
+        assert!(auth.is_authorized(Some("Bearer secret"), None));
+        assert!(auth.is_authorized(None, Some("secret")));
+        assert!(!auth.is_authorized(Some("Bearer wrong"), None));
+        assert!(!auth.is_authorized(Some("secret"), None));
+        assert!(!auth.is_authorized(None, None));
+    }
+
+    #[test]
+    fn test_subscription_filter_matches() {
+        let event = test_event(UserEventKind::Updated, "abc", "user@Example.com");
+
+        assert!(SubscriptionFilter::default().matches(&event));
+
+        let by_id = SubscriptionFilter {
+            user_ids: Some(vec!["abc".to_string()]),
+            ..Default::default()
+        };
+        assert!(by_id.matches(&event));
+
+        let by_kind = SubscriptionFilter {
+            events: Some(vec![UserEventKind::Created]),
+            ..Default::default()
+        };
+        assert!(!by_kind.matches(&event));
+
+        let by_email = SubscriptionFilter {
+            email_suffix: Some("@example.com".to_string()),
+            ..Default::default()
+        };
+        assert!(by_email.matches(&event));
+    }
+
+    #[test]
+    fn test_handle_subscribe_and_unsubscribe() {
+        let mut subscriptions = HashMap::new();
+
+        let reply = handle_client_message(
+            r#"{"type":"subscribe","id":"sub-1","user_ids":["abc"],"events":["deleted"]}"#,
+            &mut subscriptions,
+        );
+        assert!(matches!(reply, ServerMessage::Subscribed { ref id } if id == "sub-1"));
+        assert_eq!(
+            subscriptions["sub-1"].events,
+            Some(vec![UserEventKind::Deleted])
+        );
+
+        let reply =
+            handle_client_message(r#"{"type":"unsubscribe","id":"sub-1"}"#, &mut subscriptions);
+        assert!(matches!(reply, ServerMessage::Unsubscribed { .. }));
+        assert!(subscriptions.is_empty());
+    }
+
+    #[test]
+    fn test_handle_ping_and_invalid_messages() {
+        let mut subscriptions = HashMap::new();
+
+        let reply = handle_client_message(r#"{"type":"ping"}"#, &mut subscriptions);
+        assert_eq!(serde_json::to_string(&reply).unwrap(), r#"{"type":"pong"}"#);
+
+        let reply = handle_client_message("not json", &mut subscriptions);
+        assert!(matches!(reply, ServerMessage::Error { .. }));
+    }
+
+    #[test]
+    fn test_subscription_limit() {
+        let mut subscriptions = HashMap::new();
+        for index in 0..MAX_SUBSCRIPTIONS {
+            subscriptions.insert(format!("sub-{}", index), SubscriptionFilter::default());
+        }
+
+        let reply = handle_client_message(
+            r#"{"type":"subscribe","id":"one-more"}"#,
+            &mut subscriptions,
+        );
+        assert!(matches!(reply, ServerMessage::Error { .. }));
+        assert_eq!(subscriptions.len(), MAX_SUBSCRIPTIONS);
+    }
+
+    #[tokio::test]
+    async fn test_socket_delivers_matching_events() {
+        let events = Arc::new(UserEventBus::new(10));
+        let route = warp::path("ws")
+            .and(warp::ws())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::<WsQuery>())
+            .and(warp::any().map(WsAuthConfig::anonymous))
+            .and(warp::any().map({
+                let events = events.clone();
+                move || events.clone()
+            }))
+            .and_then(user_updates_socket);
+
+        let mut client = warp::test::ws().path("/ws").handshake(route).await.unwrap();
+
+        client
+            .send_text(r#"{"type":"subscribe","id":"mine","user_ids":["abc"]}"#)
+            .await;
+        let reply = client.recv().await.unwrap();
+        assert!(reply.to_str().unwrap().contains("subscribed"));
+
+        events.publish(UserEventKind::Created, "other".to_string(), None);
+        events.publish(UserEventKind::Created, "abc".to_string(), None);
+
+        let message = client.recv().await.unwrap();
+        let text = message.to_str().unwrap();
+        assert!(text.contains("\"subscription\":\"mine\""));
+        assert!(text.contains("\"user_id\":\"abc\""));
+    }
+
+    #[tokio::test]
+    async fn test_socket_rejects_missing_token() {
+        let route = warp::path("ws")
+            .and(warp::ws())
+            .and(warp::header::optional::<String>("authorization"))
+            .and(warp::query::<WsQuery>())
+            .and(warp::any().map(|| WsAuthConfig::new(vec!["secret".to_string()])))
+            .and(warp::any().map(|| Arc::new(UserEventBus::new(10))))
+            .and_then(user_updates_socket);
+
+        let result = warp::test::ws().path("/ws").handshake(route).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,429 @@
+//! Outgoing email
+//!
+//! The `Mailer` named by `MAILER` delivers the messages the API sends, such as
+//! email verification links: `smtp` hands them to an SMTP relay, `file` drops
+//! each one as an `.eml` file into a directory, and `memory` keeps them for
+//! tests to inspect.
+
+use chrono::Utc;
+use futures::future::BoxFuture;
+use rand::RngCore;
+use std::env;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+
+/// Mailer used when `MAILER` is not set
+const DEFAULT_MAILER: &str = "file";
+
+/// Default directory the `file` mailer writes to
+const DEFAULT_MAIL_DIR: &str = "mail";
+
+/// Default address of the SMTP relay used by the `smtp` mailer
+const DEFAULT_SMTP_ADDRESS: &str = "127.0.0.1:25";
+
+/// Default sender address
+const DEFAULT_MAIL_FROM: &str = "no-reply@localhost";
+
+/// Name the `smtp` mailer greets the relay with
+const SMTP_HELLO_NAME: &str = "rust-simple-api";
+
+/// Longest an SMTP conversation may take
+const SMTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// A plain-text message to one recipient
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Email {
+    pub to: String,
+    pub subject: String,
+    pub body: String,
+}
+
+impl Email {
+    /// The message in RFC 5322 form, with CRLF line endings
+    pub fn to_message(&self, from: &str) -> Result<String, String> {
+        for (name, value) in [("From", from), ("To", &self.to), ("Subject", &self.subject)] {
+            if value.contains(['\r', '\n']) {
+                return Err(format!("{} header must be a single line", name));
+            }
+        }
+
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {}\r\nDate: {}\r\nMessage-ID: <{}@{}>\r\n\
+             MIME-Version: 1.0\r\nContent-Type: text/plain; charset=utf-8\r\n\
+             Content-Transfer-Encoding: 8bit\r\n\r\n",
+            from,
+            self.to,
+            self.subject,
+            Utc::now().to_rfc2822(),
+            random_hex(16),
+            SMTP_HELLO_NAME
+        );
+        for line in self.body.lines() {
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        Ok(message)
+    }
+}
+
+/// Delivers outgoing email
+pub trait Mailer: Send + Sync {
+    /// Name for logs
+    fn name(&self) -> &str;
+
+    /// Deliver one message, returning an error when it could not be handed on
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// Hand messages to an SMTP relay, one connection per message.
+///
+/// The relay must accept mail from this host without authentication or TLS,
+/// as a local MTA or a development server such as Mailpit does.
+pub struct SmtpMailer {
+    address: String,
+    from: String,
+}
+
+impl SmtpMailer {
+    pub fn new(address: String, from: String) -> Self {
+        SmtpMailer { address, from }
+    }
+
+    async fn deliver(&self, email: &Email) -> Result<(), String> {
+        let message = email.to_message(&self.from)?;
+        let stream = TcpStream::connect(&self.address)
+            .await
+            .map_err(|e| format!("Failed to connect to SMTP at {}: {}", self.address, e))?;
+        let mut connection = BufReader::new(stream);
+
+        expect_reply(&mut connection, &[220]).await?;
+        command(
+            &mut connection,
+            &format!("EHLO {}", SMTP_HELLO_NAME),
+            &[250],
+        )
+        .await?;
+        command(
+            &mut connection,
+            &format!("MAIL FROM:<{}>", self.from),
+            &[250],
+        )
+        .await?;
+        command(
+            &mut connection,
+            &format!("RCPT TO:<{}>", email.to),
+            &[250, 251],
+        )
+        .await?;
+        command(&mut connection, "DATA", &[354]).await?;
+
+        // Lines starting with a dot are doubled so none ends the message early
+        let mut data = String::with_capacity(message.len() + 5);
+        for line in message.split_inclusive("\r\n") {
+            if line.starts_with('.') {
+                data.push('.');
+            }
+            data.push_str(line);
+        }
+        data.push_str(".\r\n");
+        connection
+            .get_mut()
+            .write_all(data.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+        expect_reply(&mut connection, &[250]).await?;
+
+        // The message is accepted; a failed goodbye does not change that
+        let _ = command(&mut connection, "QUIT", &[221]).await;
+        Ok(())
+    }
+}
+
+impl Mailer for SmtpMailer {
+    fn name(&self) -> &str {
+        "smtp"
+    }
+
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            tokio::time::timeout(SMTP_TIMEOUT, self.deliver(email))
+                .await
+                .map_err(|_| format!("SMTP at {} timed out", self.address))?
+        })
+    }
+}
+
+/// Send one SMTP command and check the reply code
+async fn command(
+    connection: &mut BufReader<TcpStream>,
+    line: &str,
+    expected: &[u16],
+) -> Result<(), String> {
+    connection
+        .get_mut()
+        .write_all(format!("{}\r\n", line).as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    expect_reply(connection, expected).await
+}
+
+/// Read a possibly multi-line SMTP reply and check its code
+async fn expect_reply(
+    connection: &mut BufReader<TcpStream>,
+    expected: &[u16],
+) -> Result<(), String> {
+    loop {
+        let mut line = String::new();
+        match connection.read_line(&mut line).await {
+            Ok(0) => return Err("SMTP connection closed".to_string()),
+            Ok(_) => {}
+            Err(e) => return Err(e.to_string()),
+        }
+        let line = line.trim_end();
+
+        // `250-...` continues the reply, `250 ...` ends it
+        if line.as_bytes().get(3) == Some(&b'-') {
+            continue;
+        }
+        let code = line.get(..3).and_then(|code| code.parse::<u16>().ok());
+        return match code {
+            Some(code) if expected.contains(&code) => Ok(()),
+            _ => Err(format!("SMTP relay answered: {}", line)),
+        };
+    }
+}
+
+/// Write each message as an `.eml` file into a directory, for a local pickup or a person to open
+pub struct FileMailer {
+    dir: PathBuf,
+    from: String,
+}
+
+impl FileMailer {
+    pub fn new(dir: PathBuf, from: String) -> Self {
+        FileMailer { dir, from }
+    }
+}
+
+impl Mailer for FileMailer {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let message = email.to_message(&self.from)?;
+            tokio::fs::create_dir_all(&self.dir)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            // Timestamped so a directory listing is in sending order
+            let path = self.dir.join(format!(
+                "{}-{}.eml",
+                Utc::now().format("%Y%m%dT%H%M%S%.3fZ"),
+                random_hex(4)
+            ));
+            tokio::fs::write(&path, message)
+                .await
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+        })
+    }
+}
+
+/// Keep messages in memory instead of sending them
+#[derive(Default)]
+pub struct MemoryMailer {
+    sent: Mutex<Vec<Email>>,
+}
+
+impl MemoryMailer {
+    /// The messages sent so far, oldest first
+    #[allow(dead_code)]
+    pub fn sent(&self) -> Vec<Email> {
+        self.sent
+            .lock()
+            .map(|sent| sent.clone())
+            .unwrap_or_default()
+    }
+}
+
+impl Mailer for MemoryMailer {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn send<'a>(&'a self, email: &'a Email) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            self.sent
+                .lock()
+                .map_err(|_| "Mailbox is poisoned".to_string())?
+                .push(email.clone());
+            Ok(())
+        })
+    }
+}
+
+/// Build the mailer named by `MAILER` (`smtp`, `file` or `memory`; default `file`)
+pub fn mailer_from_env() -> Arc<dyn Mailer> {
+    let from = env::var("MAIL_FROM").unwrap_or_else(|_| DEFAULT_MAIL_FROM.to_string());
+    let name = env::var("MAILER").unwrap_or_else(|_| DEFAULT_MAILER.to_string());
+
+    let mailer: Arc<dyn Mailer> = match name.trim() {
+        "smtp" => Arc::new(SmtpMailer::new(
+            env::var("SMTP_ADDRESS").unwrap_or_else(|_| DEFAULT_SMTP_ADDRESS.to_string()),
+            from,
+        )),
+        "memory" => Arc::new(MemoryMailer::default()),
+        other => {
+            if other != "file" {
+                eprintln!("Unknown mailer '{}', writing mail to files instead", other);
+            }
+            Arc::new(FileMailer::new(
+                PathBuf::from(
+                    env::var("MAIL_DIR").unwrap_or_else(|_| DEFAULT_MAIL_DIR.to_string()),
+                ),
+                from,
+            ))
+        }
+    };
+    println!("Sending email with the {} mailer", mailer.name());
+
+    mailer
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut random = vec![0u8; bytes];
+    rand::thread_rng().fill_bytes(&mut random);
+    hex::encode(random)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    fn email() -> Email {
+        Email {
+            to: "ada@example.com".to_string(),
+            subject: "Hello".to_string(),
+            body: "First line\n.hidden dot\nLast line".to_string(),
+        }
+    }
+
+    /// A minimal SMTP relay that reports the commands and data it received
+    async fn start_smtp_stand_in() -> (String, tokio::task::JoinHandle<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+
+        let transcript = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = BufReader::new(stream);
+            let mut transcript = String::new();
+            connection
+                .get_mut()
+                .write_all(b"220 stand-in ready\r\n")
+                .await
+                .unwrap();
+
+            let mut in_data = false;
+            let mut line = String::new();
+            while connection.read_line(&mut line).await.unwrap_or(0) > 0 {
+                transcript.push_str(&line);
+                let reply: &[u8] = if in_data {
+                    if line == ".\r\n" {
+                        in_data = false;
+                        b"250 queued\r\n"
+                    } else {
+                        b""
+                    }
+                } else if line.starts_with("EHLO") {
+                    b"250-stand-in\r\n250 8BITMIME\r\n"
+                } else if line.starts_with("DATA") {
+                    in_data = true;
+                    b"354 go ahead\r\n"
+                } else if line.starts_with("QUIT") {
+                    connection
+                        .get_mut()
+                        .write_all(b"221 bye\r\n")
+                        .await
+                        .unwrap();
+                    break;
+                } else {
+                    b"250 ok\r\n"
+                };
+                connection.get_mut().write_all(reply).await.unwrap();
+                line.clear();
+            }
+
+            let mut rest = Vec::new();
+            let _ = connection.read_to_end(&mut rest).await;
+            transcript
+        });
+
+        (address, transcript)
+    }
+
+    #[test]
+    fn test_message_headers_and_line_endings() {
+        let message = email().to_message("no-reply@example.com").unwrap();
+        assert!(message.starts_with("From: no-reply@example.com\r\nTo: ada@example.com\r\n"));
+        assert!(message.contains("\r\nSubject: Hello\r\n"));
+        assert!(message.ends_with("\r\n\r\nFirst line\r\n.hidden dot\r\nLast line\r\n"));
+
+        let injected = Email {
+            subject: "Hello\r\nBcc: everyone@example.com".to_string(),
+            ..email()
+        };
+        assert!(injected.to_message("no-reply@example.com").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_smtp_conversation() {
+        let (address, transcript) = start_smtp_stand_in().await;
+        let mailer = SmtpMailer::new(address, "no-reply@example.com".to_string());
+
+        mailer.send(&email()).await.unwrap();
+        let transcript = transcript.await.unwrap();
+
+        assert!(transcript.starts_with("EHLO rust-simple-api\r\nMAIL FROM:<no-reply@example.com>\r\nRCPT TO:<ada@example.com>\r\nDATA\r\n"));
+        // The dot starting a body line is doubled, and a lone dot ends the data
+        assert!(transcript.contains("\r\n..hidden dot\r\nLast line\r\n.\r\nQUIT\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_smtp_rejection_is_an_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"554 no service here\r\n").await.unwrap();
+        });
+
+        let mailer = SmtpMailer::new(address, "no-reply@example.com".to_string());
+        let error = mailer.send(&email()).await.unwrap_err();
+        assert_eq!(error, "SMTP relay answered: 554 no service here");
+    }
+
+    #[tokio::test]
+    async fn test_file_and_memory_mailers() {
+        let dir = env::temp_dir().join(format!("mailer-test-{}", random_hex(4)));
+        let mailer = FileMailer::new(dir.clone(), "no-reply@example.com".to_string());
+        mailer.send(&email()).await.unwrap();
+
+        let mut files = std::fs::read_dir(&dir).unwrap();
+        let file = files.next().unwrap().unwrap().path();
+        assert_eq!(file.extension().unwrap(), "eml");
+        assert!(std::fs::read_to_string(&file)
+            .unwrap()
+            .contains("To: ada@example.com\r\n"));
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mailer = MemoryMailer::default();
+        mailer.send(&email()).await.unwrap();
+        assert_eq!(mailer.sent(), vec![email()]);
+    }
+}
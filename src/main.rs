@@ -1,6 +1,14 @@
 mod db;
+mod events;
+mod graphql;
+mod grpc;
 mod handlers;
+mod mailer;
 mod models;
+mod openapi;
+mod outbox;
+mod tls;
+mod webhooks;
 
 use dotenv::dotenv;
 use serde_json::json;
@@ -28,9 +36,39 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     println!("Rust Simple API started!");
 
     // Initialize database connection
-    let database = Arc::new(db::connect_to_database().await?);
+    let client = db::connect_to_client().await?;
+    let database = Arc::new(db::database_for_client(&client));
     println!("Database connection established successfully!");
 
+    // Expire stored idempotent responses after the configured TTL
+    if let Err(e) = db::ensure_idempotency_indexes(&database).await {
+        eprintln!("Error creating idempotency indexes: {}", e);
+    }
+    if let Err(e) = db::ensure_audit_indexes(&database).await {
+        eprintln!("Error creating audit log indexes: {}", e);
+    }
+    if let Err(e) = db::ensure_webhook_indexes(&database).await {
+        eprintln!("Error creating webhook indexes: {}", e);
+    }
+    if let Err(e) = db::ensure_outbox_indexes(&database).await {
+        eprintln!("Error creating outbox indexes: {}", e);
+    }
+    if let Err(e) = db::ensure_user_search_indexes(&database).await {
+        eprintln!("Error creating user search index: {}", e);
+    }
+    if let Err(e) = db::ensure_user_status(&database).await {
+        eprintln!("Error backfilling user statuses: {}", e);
+    }
+    if let Err(e) = db::ensure_avatar_indexes(&database).await {
+        eprintln!("Error creating avatar indexes: {}", e);
+    }
+    if let Err(e) = db::ensure_auth_indexes(&database).await {
+        eprintln!("Error creating session and password reset indexes: {}", e);
+    }
+
+    // User changes and their outbox entries are written in one transaction
+    let user_outbox = Arc::new(db::Outbox::connect(client).await?);
+
     // Check if we should seed data on startup (via environment variable)
     if env::var("SEED_ON_STARTUP").unwrap_or_default() == "true" {
         println!("Seeding data on startup...");
@@ -44,6 +82,26 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     }
 
+    // Feed user change events from a change stream when MongoDB runs as a replica set
+    let user_events = Arc::new(events::UserEventBus::from_env());
+    events::start_change_stream_feed(database.clone(), user_events.clone()).await;
+
+    // Verification links are mailed and avatars removed by their own outbox sinks,
+    // so every way of creating or deleting a user is covered
+    let mailer = mailer::mailer_from_env();
+    let email_verifier = Arc::new(handlers::EmailVerifier::from_env(mailer.clone()));
+    let mut sinks = outbox::sinks_from_env(database.clone());
+    sinks.push(Arc::new(outbox::VerificationEmailSink::new(
+        database.clone(),
+        email_verifier.clone(),
+    )));
+    sinks.push(Arc::new(outbox::AvatarCleanupSink::new(database.clone())));
+
+    // Relay outbox entries to the configured sinks and deliver queued webhooks
+    outbox::start_outbox_relay(database.clone(), sinks);
+    let webhook_config = webhooks::WebhookConfig::from_env();
+    webhooks::start_webhook_worker(database.clone(), webhook_config.clone());
+
     // Get server port from environment variable or use default
     let port: u16 = env::var("PORT")
         .unwrap_or_else(|_| DEFAULT_PORT.to_string())
@@ -55,39 +113,595 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         .and(warp::get())
         .and_then(handlers::health_check_with_status);
 
+    // API description and its reference page
+    let openapi_spec = warp::path("openapi.json")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and_then(handlers::openapi_spec);
+
+    let docs_page = warp::path("docs")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and_then(handlers::docs);
+
+    // Request bodies larger than these are refused with 413
+    let body_limits = handlers::BodyLimits::from_env();
+
     // User routes with database access
     let db = database.clone();
     let users_get_all = warp::path("users")
         .and(warp::get())
         .and(warp::path::end())
+        .and(warp::query::<handlers::UserListQuery>())
+        .and(warp::query::<handlers::FieldsQuery>())
+        .and(handlers::with_format(true))
         .and(warp::any().map(move || db.clone()))
         .and_then(handlers::get_all_users);
 
+    let db = database.clone();
+    let events = user_events.clone();
+    let users_events = warp::path!("users" / "events")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("last-event-id"))
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::user_events);
+
+    // WebSocket subscriptions to user changes, authenticated on the upgrade request
+    let ws_auth = handlers::WsAuthConfig::from_env();
+    if ws_auth.allows_anonymous() {
+        println!("WS_ALLOW_ANONYMOUS is set, /ws accepts unauthenticated connections");
+    } else if !ws_auth.accepts_connections() {
+        println!("WS_AUTH_TOKENS is not set, /ws refuses every connection");
+    }
+    let events = user_events.clone();
+    let users_ws = warp::path("ws")
+        .and(warp::path::end())
+        .and(warp::ws())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::query::<handlers::WsQuery>())
+        .and(warp::any().map(move || ws_auth.clone()))
+        .and(warp::any().map(move || events.clone()))
+        .and_then(handlers::user_updates_socket);
+
     let db = database.clone();
     let users_get_by_id = warp::path!("users" / String)
         .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::query::<handlers::FieldsQuery>())
+        .and(handlers::with_format(false))
         .and(warp::any().map(move || db.clone()))
         .and_then(handlers::get_user_by_id);
 
     let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_update = warp::path!("users" / String)
+        .and(warp::patch())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::negotiated_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::update_user);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_delete = warp::path!("users" / String)
+        .and(warp::delete())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::delete_user);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
     let users_create = warp::path("users")
         .and(warp::post())
-        .and(warp::body::json())
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(handlers::negotiated_body_with_bytes(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::create_user_idempotent);
+
+    // Status transitions
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_activate = warp::path!("users" / String / "activate")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::optional_json_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::activate_user);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_suspend = warp::path!("users" / String / "suspend")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::optional_json_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::suspend_user);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_archive = warp::path!("users" / String / "archive")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::optional_json_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::archive_user);
+
+    // Version 2 of the user routes, answering with v2 representations
+    let db = database.clone();
+    let users_get_all_v2 = warp::path("users")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::query::<handlers::UserListQuery>())
+        .and(warp::query::<handlers::FieldsQuery>())
+        .and(handlers::with_format(true))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_all_users_v2);
+
+    let db = database.clone();
+    let users_get_by_id_v2 = warp::path!("users" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::query::<handlers::FieldsQuery>())
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_user_by_id_v2);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_update_v2 = warp::path!("users" / String)
+        .and(warp::patch())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::negotiated_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::update_user_v2);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_delete_v2 = warp::path!("users" / String)
+        .and(warp::delete())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::delete_user_v2);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_create_v2 = warp::path("users")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(handlers::negotiated_body_with_bytes(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::create_user_v2);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_activate_v2 = warp::path!("users" / String / "activate")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::optional_json_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::activate_user_v2);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_suspend_v2 = warp::path!("users" / String / "suspend")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::optional_json_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::suspend_user_v2);
+
+    let db = database.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let users_archive_v2 = warp::path!("users" / String / "archive")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("if-match"))
+        .and(handlers::optional_json_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::archive_user_v2);
+
+    let db = database.clone();
+    let users_search = warp::path!("users" / "search")
+        .and(warp::get())
+        .and(warp::query::<handlers::SearchQuery>())
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::search_users);
+
+    let db = database.clone();
+    let users_search_v2 = warp::path!("users" / "search")
+        .and(warp::get())
+        .and(warp::query::<handlers::SearchQuery>())
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::search_users_v2);
+
+    let db = database.clone();
+    let stats_cache = handlers::StatsCache::from_env();
+    let cache = stats_cache.clone();
+    let users_stats = warp::path!("users" / "stats")
+        .and(warp::get())
+        .and(warp::query::<handlers::StatsQuery>())
+        .and(warp::any().map(move || cache.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_user_stats);
+
+    let db = database.clone();
+    let users_stats_v2 = warp::path!("users" / "stats")
+        .and(warp::get())
+        .and(warp::query::<handlers::StatsQuery>())
+        .and(warp::any().map(move || stats_cache.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_user_stats_v2);
+
+    let db = database.clone();
+    let users_history = warp::path!("users" / String / "history")
+        .and(warp::get())
+        .and(warp::query::<handlers::HistoryQuery>())
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_user_history);
+
+    let db = database.clone();
+    let audit_log = warp::path("audit")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::query::<handlers::AuditQuery>())
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_audit_log);
+
+    let db = database.clone();
+    let webhooks_create = warp::path("webhooks")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(handlers::json_body_with_bytes(body_limits.webhooks))
+        .and(warp::any().map(move || webhook_config.targets.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::create_webhook);
+
+    let db = database.clone();
+    let webhooks_list = warp::path("webhooks")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::list_webhooks);
+
+    let db = database.clone();
+    let webhooks_dead_letters = warp::path!("webhooks" / "dead-letters")
+        .and(warp::get())
+        .and(warp::query::<handlers::DeliveryQuery>())
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_dead_letters);
+
+    let db = database.clone();
+    let webhooks_retry = warp::path!("webhooks" / "deliveries" / String / "retry")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::retry_webhook_delivery);
+
+    let db = database.clone();
+    let webhooks_delete = warp::path!("webhooks" / String)
+        .and(warp::delete())
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::delete_webhook);
+
+    let db = database.clone();
+    let webhooks_deliveries = warp::path!("webhooks" / String / "deliveries")
+        .and(warp::get())
+        .and(warp::query::<handlers::DeliveryQuery>())
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_webhook_deliveries);
+
+    // GraphQL over the same users, with the GraphiQL playground for development
+    let schema = graphql::build_schema(database.clone(), user_events.clone(), user_outbox.clone());
+    let graphql_route = warp::path("graphql")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(handlers::limited_body(body_limits.graphql))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || schema.clone()))
+        .and_then(handlers::graphql_request);
+
+    let graphiql_enabled = env::var("GRAPHIQL_ENABLED").unwrap_or_default() == "true";
+    let graphiql_route = warp::path("graphql")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::any().map(move || graphiql_enabled))
+        .and_then(handlers::graphiql);
+
+    // gRPC for internal services, on its own port
+    grpc::start_grpc_server(
+        grpc::UserGrpcService::new(database.clone(), user_events.clone(), user_outbox.clone()),
+        grpc::grpc_port(),
+    );
+
+    // Cross-origin policy and security headers, configured per environment
+    let cors = handlers::CorsConfig::from_env();
+    if cors.origins.is_none() {
+        println!("CORS_ALLOWED_ORIGINS is not set, any origin may call the API");
+    }
+    let security_headers = handlers::SecurityHeaders::from_env();
+
+    // Custom error recovery handler to convert all errors to JSON responses,
+    // then compression of whatever goes out by Accept-Encoding
+    let db = database.clone();
+    let verifier = email_verifier.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let verify_email = warp::path("verify-email")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::query::<handlers::VerifyEmailQuery>())
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || verifier.clone()))
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::verify_email);
+
+    let db = database.clone();
+    let verifier = email_verifier.clone();
+    let events = user_events.clone();
+    let outbox = user_outbox.clone();
+    let verify_email_v2 = warp::path("verify-email")
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(warp::query::<handlers::VerifyEmailQuery>())
+        .and(handlers::with_format(false))
+        .and(handlers::with_audit_context())
+        .and(warp::any().map(move || verifier.clone()))
+        .and(warp::any().map(move || events.clone()))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::verify_email_v2);
+
+    let db = database.clone();
+    let verifier = email_verifier.clone();
+    let users_verify_email_resend = warp::path!("users" / String / "verify-email" / "resend")
+        .and(warp::post())
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || verifier.clone()))
         .and(warp::any().map(move || db.clone()))
-        .and_then(handlers::create_user);
+        .and_then(handlers::resend_verification_email);
 
-    // Custom error recovery handler to convert all errors to JSON responses
-    let routes = health_route
+    // Password sign-in, sessions and password resets mailed with the same mailer
+    let auth_config = Arc::new(handlers::AuthConfig::from_env(mailer));
+    let db = database.clone();
+    let config = auth_config.clone();
+    let auth_login = warp::path!("auth" / "login")
+        .and(warp::post())
+        .and(handlers::json_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || config.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::login);
+
+    let db = database.clone();
+    let config = auth_config.clone();
+    let auth_login_v2 = warp::path!("auth" / "login")
+        .and(warp::post())
+        .and(handlers::json_body(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || config.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::login_v2);
+
+    let db = database.clone();
+    let auth_session = warp::path!("auth" / "session")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_session);
+
+    let db = database.clone();
+    let auth_session_v2 = warp::path!("auth" / "session")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_session_v2);
+
+    let db = database.clone();
+    let config = auth_config.clone();
+    let auth_password_reset = warp::path!("auth" / "password-reset")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(handlers::json_body_with_bytes(body_limits.users))
+        .and(warp::any().map(move || config.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::request_password_reset);
+
+    let db = database.clone();
+    let outbox = user_outbox.clone();
+    let auth_password_reset_confirm = warp::path!("auth" / "password-reset" / "confirm")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("idempotency-key"))
+        .and(handlers::json_body_with_bytes(body_limits.users))
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || outbox.clone()))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::confirm_password_reset);
+
+    // Avatars, uploaded as multipart form data and served as images
+    let db = database.clone();
+    let avatars_body_limit = body_limits.avatars;
+    let users_avatar_upload = warp::path!("users" / String / "avatar")
+        .and(warp::put())
+        .and(warp::multipart::form().max_length(None))
+        .and(warp::any().map(move || avatars_body_limit))
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::upload_avatar);
+
+    let db = database.clone();
+    let users_avatar_upload_v2 = warp::path!("users" / String / "avatar")
+        .and(warp::put())
+        .and(warp::multipart::form().max_length(None))
+        .and(warp::any().map(move || avatars_body_limit))
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::upload_avatar_v2);
+
+    let db = database.clone();
+    let avatar_config = handlers::AvatarConfig::from_env();
+    let users_avatar = warp::path!("users" / String / "avatar")
+        .and(warp::get())
+        .and(warp::query::<handlers::AvatarQuery>())
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(handlers::with_format(false))
+        .and(warp::any().map(move || avatar_config))
+        .and(warp::any().map(move || db.clone()))
+        .and_then(handlers::get_avatar);
+
+    // Routes whose responses are the same in both versions
+    let shared_routes = health_route
+        .or(openapi_spec)
+        .or(docs_page)
+        .or(users_events)
+        .or(users_ws)
+        .or(users_history)
+        .or(users_verify_email_resend)
+        .or(users_avatar)
+        .or(auth_password_reset)
+        .or(auth_password_reset_confirm)
+        .or(audit_log)
+        .or(webhooks_create)
+        .or(webhooks_list)
+        .or(webhooks_dead_letters)
+        .or(webhooks_retry)
+        .or(webhooks_delete)
+        .or(webhooks_deliveries)
+        .or(graphql_route)
+        .or(graphiql_route)
+        // Boxed so the types of the versioned route trees stay within the compiler's limits
+        .boxed();
+
+    // v1 is deprecated, and says so on the responses of routes that have a
+    // v2 version. Search and stats come before the `users/{id}` routes.
+    let v1_deprecation = handlers::Deprecation::v1_from_env();
+    let users_v1 = users_search
+        .or(users_stats)
         .or(users_get_all)
         .or(users_get_by_id)
         .or(users_create)
-        .recover(custom_reject)
-        .with(warp::cors().allow_any_origin());
+        .or(users_update)
+        .or(users_delete)
+        .or(users_activate)
+        .or(users_suspend)
+        .or(users_archive)
+        .or(users_avatar_upload)
+        .or(verify_email)
+        .or(auth_login)
+        .or(auth_session)
+        .map(move |reply| v1_deprecation.apply(reply));
+    let users_v2 = users_search_v2
+        .or(users_stats_v2)
+        .or(users_get_all_v2)
+        .or(users_get_by_id_v2)
+        .or(users_create_v2)
+        .or(users_update_v2)
+        .or(users_delete_v2)
+        .or(users_activate_v2)
+        .or(users_suspend_v2)
+        .or(users_archive_v2)
+        .or(users_avatar_upload_v2)
+        .or(verify_email_v2)
+        .or(auth_login_v2)
+        .or(auth_session_v2);
+    let api_v1 = shared_routes.clone().or(users_v1);
+    let api_v2 = shared_routes.or(users_v2);
 
-    println!("Starting server on port {}", port);
+    // /v1 and /v2 prefixes, or unprefixed paths with the version in API-Version
+    let routes = warp::path("v1")
+        .and(api_v1.clone())
+        .or(warp::path("v2").and(api_v2.clone()))
+        .or(handlers::api_version_header(handlers::ApiVersion::V1).and(api_v1))
+        .or(handlers::api_version_header(handlers::ApiVersion::V2).and(api_v2))
+        .recover(custom_reject);
+    let routes = warp::header::optional::<String>("accept-encoding")
+        .and(routes)
+        .and_then(handlers::compress_reply)
+        .map(move |reply| security_headers.apply(reply))
+        .with(cors.build());
 
-    // Start the web server
-    warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+    // Start the web server, over TLS when a certificate is configured
+    match tls::TlsConfig::from_env() {
+        Some(tls_config) => {
+            println!("Starting HTTPS server on port {}", port);
+            if tls_config.client_ca_path.is_some() {
+                println!("Client certificates are required");
+            }
+            tls::run_tls(routes, port, tls_config).await?;
+        }
+        None => {
+            println!("Starting server on port {}", port);
+            warp::serve(routes).run(([0, 0, 0, 0], port)).await;
+        }
+    }
 
     Ok(())
 }
@@ -141,6 +755,42 @@ async fn custom_reject(err: warp::Rejection) -> Result<impl warp::Reply, std::co
         code = StatusCode::BAD_REQUEST;
         error_type = "validation_error";
         message = "Invalid JSON format".to_string();
+    } else if err.find::<handlers::PayloadTooLarge>().is_some() {
+        code = StatusCode::PAYLOAD_TOO_LARGE;
+        error_type = "payload_too_large";
+        message = "Request body is too large".to_string();
+    } else if err.find::<handlers::UnsupportedContentEncoding>().is_some() {
+        code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        error_type = "unsupported_media_type";
+        message = "Content-Encoding must be gzip, br or zstd".to_string();
+    } else if err.find::<handlers::InvalidContentEncoding>().is_some() {
+        code = StatusCode::BAD_REQUEST;
+        error_type = "validation_error";
+        message = "Request body does not match its Content-Encoding".to_string();
+    } else if err.find::<handlers::UnsupportedMediaType>().is_some() {
+        code = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+        error_type = "unsupported_media_type";
+        message = "Content-Type must be JSON, MessagePack, CBOR or XML".to_string();
+    } else if let Some(handlers::InvalidBody(format)) = err.find::<handlers::InvalidBody>() {
+        code = StatusCode::BAD_REQUEST;
+        error_type = "validation_error";
+        message = match format {
+            handlers::Format::Json => "Invalid JSON format".to_string(),
+            _ => "Request body does not match its Content-Type".to_string(),
+        };
+    } else if err.find::<handlers::NotAcceptable>().is_some() {
+        code = StatusCode::NOT_ACCEPTABLE;
+        error_type = "not_acceptable";
+        message =
+            "Supported formats are JSON, MessagePack, CBOR, XML and CSV for lists".to_string();
+    } else if let Some(graphql_err) = err.find::<async_graphql_warp::GraphQLBadRequest>() {
+        code = graphql_err.status();
+        error_type = "bad_request";
+        message = graphql_err.to_string();
+    } else if err.find::<handlers::UnsupportedApiVersion>().is_some() {
+        code = StatusCode::BAD_REQUEST;
+        error_type = "unsupported_api_version";
+        message = "API-Version must be 1 or 2".to_string();
     } else if err.find::<warp::reject::MethodNotAllowed>().is_some() {
         code = StatusCode::METHOD_NOT_ALLOWED;
         error_type = "method_not_allowed";
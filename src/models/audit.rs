@@ -0,0 +1,152 @@
+use mongodb::bson::{self, oid::ObjectId, DateTime, Document};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::models::User;
+
+/// Fields that change on every write and are left out of `changed_fields`
+const BOOKKEEPING_FIELDS: [&str; 3] = ["_id", "updated_at", "version"];
+
+/// The kind of mutation recorded by an audit entry
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditAction {
+    Create,
+    Update,
+    Delete,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Create => "create",
+            AuditAction::Update => "update",
+            AuditAction::Delete => "delete",
+        }
+    }
+}
+
+/// An immutable record of a single mutation of a user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// The authenticated caller, or `anonymous`
+    pub actor: String,
+    /// Who the caller said it acted for; unverified
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub claimed_actor: Option<String>,
+    pub request_id: String,
+    pub action: AuditAction,
+    pub resource_id: String,
+    pub before: Option<Document>,
+    pub after: Option<Document>,
+    pub changed_fields: Vec<String>,
+    pub timestamp: DateTime,
+}
+
+impl AuditEntry {
+    /// Build an entry from the user as it was before and after the mutation
+    pub fn for_user(
+        actor: String,
+        request_id: String,
+        action: AuditAction,
+        before: Option<&User>,
+        after: Option<&User>,
+    ) -> Self {
+        let resource_id = after
+            .or(before)
+            .and_then(|user| user.id)
+            .map(|id| id.to_hex())
+            .unwrap_or_default();
+        let before = before.and_then(|user| bson::to_document(user).ok());
+        let after = after.and_then(|user| bson::to_document(user).ok());
+        let changed_fields = changed_fields(before.as_ref(), after.as_ref());
+
+        AuditEntry {
+            id: None,
+            actor,
+            claimed_actor: None,
+            request_id,
+            action,
+            resource_id,
+            before,
+            after,
+            changed_fields,
+            timestamp: DateTime::now(),
+        }
+    }
+}
+
+/// List the fields whose values differ between two snapshots, ignoring bookkeeping fields
+pub fn changed_fields(before: Option<&Document>, after: Option<&Document>) -> Vec<String> {
+    let empty = Document::new();
+    let before = before.unwrap_or(&empty);
+    let after = after.unwrap_or(&empty);
+
+    let mut fields: Vec<String> = before
+        .keys()
+        .chain(after.keys())
+        .filter(|key| !BOOKKEEPING_FIELDS.contains(&key.as_str()))
+        .filter(|key| before.get(key.as_str()) != after.get(key.as_str()))
+        .cloned()
+        .collect();
+
+    fields.sort();
+    fields.dedup();
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::doc;
+
+    #[test]
+    fn test_changed_fields_on_update() {
+        let before = doc! { "name": "Old", "email": "same@example.com", "version": 1 };
+        let after = doc! { "name": "New", "email": "same@example.com", "version": 2 };
+
+        assert_eq!(changed_fields(Some(&before), Some(&after)), vec!["name"]);
+    }
+
+    #[test]
+    fn test_changed_fields_on_create_and_delete() {
+        let user = doc! { "_id": ObjectId::new(), "name": "User", "email": "user@example.com" };
+
+        assert_eq!(changed_fields(None, Some(&user)), vec!["email", "name"]);
+        assert_eq!(changed_fields(Some(&user), None), vec!["email", "name"]);
+    }
+
+    #[test]
+    fn test_audit_entry_for_user() {
+        let user = User::with_id(
+            ObjectId::new(),
+            "Audit User".to_string(),
+            "audit@example.com".to_string(),
+            chrono::Utc::now(),
+        );
+
+        let entry = AuditEntry::for_user(
+            "admin".to_string(),
+            "req-1".to_string(),
+            AuditAction::Create,
+            None,
+            Some(&user),
+        );
+
+        assert_eq!(entry.resource_id, user.id.unwrap().to_hex());
+        assert_eq!(entry.action, AuditAction::Create);
+        assert!(entry.before.is_none());
+        assert_eq!(entry.after.unwrap().get_str("name").unwrap(), "Audit User");
+    }
+
+    #[test]
+    fn test_audit_action_serialization() {
+        assert_eq!(
+            serde_json::to_string(&AuditAction::Delete).unwrap(),
+            "\"delete\""
+        );
+        assert_eq!(AuditAction::Update.as_str(), "update");
+    }
+}
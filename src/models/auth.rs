@@ -0,0 +1,97 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// A user's password hash.
+///
+/// Kept out of the user document so it never reaches API responses, events or
+/// the before and after snapshots of the audit log.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Credential {
+    #[serde(rename = "_id")]
+    pub user_id: ObjectId,
+    /// Argon2id in PHC string format
+    pub password_hash: String,
+    pub updated_at: DateTime,
+}
+
+/// A signed-in session, stored under the SHA-256 hash of its bearer token
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Session {
+    #[serde(rename = "_id")]
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    pub created_at: DateTime,
+    /// BSON date so the TTL index can expire the session
+    pub expires_at: DateTime,
+}
+
+impl Session {
+    /// A session for `user_id` starting now and lasting `ttl`
+    pub fn new(token_hash: String, user_id: ObjectId, ttl: Duration) -> Self {
+        let now = DateTime::now();
+        Session {
+            token_hash,
+            user_id,
+            created_at: now,
+            expires_at: expires_after(now, ttl),
+        }
+    }
+}
+
+/// A single-use password reset token, stored under the SHA-256 hash of the mailed value
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PasswordResetToken {
+    #[serde(rename = "_id")]
+    pub token_hash: String,
+    pub user_id: ObjectId,
+    pub created_at: DateTime,
+    /// BSON date so the TTL index can expire the token
+    pub expires_at: DateTime,
+    /// Set when the token is redeemed, or when another token for the user is
+    #[serde(default)]
+    pub used_at: Option<DateTime>,
+}
+
+impl PasswordResetToken {
+    /// An unused token for `user_id` starting now and lasting `ttl`
+    pub fn new(token_hash: String, user_id: ObjectId, ttl: Duration) -> Self {
+        let now = DateTime::now();
+        PasswordResetToken {
+            token_hash,
+            user_id,
+            created_at: now,
+            expires_at: expires_after(now, ttl),
+            used_at: None,
+        }
+    }
+}
+
+fn expires_after(now: DateTime, ttl: Duration) -> DateTime {
+    DateTime::from_millis(
+        now.timestamp_millis()
+            .saturating_add(ttl.as_millis() as i64),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tokens_expire_after_their_ttl() {
+        let user_id = ObjectId::new();
+        let session = Session::new("abc".to_string(), user_id, Duration::from_secs(60));
+        let token = PasswordResetToken::new("def".to_string(), user_id, Duration::from_secs(3600));
+
+        assert_eq!(
+            session.expires_at.timestamp_millis() - session.created_at.timestamp_millis(),
+            60_000
+        );
+        assert_eq!(
+            token.expires_at.timestamp_millis() - token.created_at.timestamp_millis(),
+            3_600_000
+        );
+        assert!(token.used_at.is_none());
+    }
+}
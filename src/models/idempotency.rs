@@ -0,0 +1,70 @@
+use mongodb::bson::{Binary, DateTime};
+use serde::{Deserialize, Serialize};
+
+/// A stored `Idempotency-Key` together with the response it produced.
+///
+/// The record is inserted as a placeholder (no status code) before the
+/// handler runs, and completed with the response once the handler returns.
+/// A placeholder whose claim is older than the lease can be claimed again, so a
+/// crash between the two steps does not block retries until the record expires.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct IdempotencyRecord {
+    /// Scope and key combined, e.g. `POST /users:3f1c...`
+    #[serde(rename = "_id")]
+    pub id: String,
+    /// Hash of the request method, path and body
+    pub fingerprint: String,
+    pub status_code: Option<u16>,
+    pub content_type: Option<String>,
+    pub body: Option<String>,
+    /// Set instead of `body` when the response is not text, such as MessagePack or CBOR
+    #[serde(default)]
+    pub binary_body: Option<Binary>,
+    /// BSON date so the TTL index can expire the record
+    pub created_at: DateTime,
+    /// When the request currently processing this key claimed it
+    #[serde(default)]
+    pub claimed_at: Option<DateTime>,
+}
+
+impl IdempotencyRecord {
+    /// Create a placeholder record for a request that is still being processed
+    pub fn pending(id: String, fingerprint: String) -> Self {
+        let now = DateTime::now();
+        IdempotencyRecord {
+            id,
+            fingerprint,
+            status_code: None,
+            content_type: None,
+            body: None,
+            binary_body: None,
+            created_at: now,
+            claimed_at: Some(now),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending_record() {
+        let record = IdempotencyRecord::pending("POST /users:abc".to_string(), "hash".to_string());
+
+        assert_eq!(record.id, "POST /users:abc");
+        assert_eq!(record.fingerprint, "hash");
+        assert!(record.status_code.is_none());
+        assert!(record.body.is_none());
+        assert_eq!(record.claimed_at, Some(record.created_at));
+    }
+
+    #[test]
+    fn test_record_serialization_uses_id_field() {
+        let record = IdempotencyRecord::pending("POST /users:abc".to_string(), "hash".to_string());
+
+        let json_str = serde_json::to_string(&record).unwrap();
+        assert!(json_str.contains("\"_id\""));
+        assert!(json_str.contains("fingerprint"));
+    }
+}
@@ -1,7 +1,17 @@
+pub mod audit;
+pub mod auth;
+pub mod idempotency;
+pub mod outbox;
 pub mod user;
+pub mod webhook;
 
-// Re-export the User model for easier access
-pub use user::User;
+// Re-export the models for easier access
+pub use audit::{AuditAction, AuditEntry};
+pub use auth::{Credential, PasswordResetToken, Session};
+pub use idempotency::IdempotencyRecord;
+pub use outbox::OutboxEntry;
+pub use user::{User, UserStatus};
+pub use webhook::{DeliveryAttempt, DeliveryStatus, WebhookDelivery, WebhookSubscription};
 
 // Common model functionality can be added here
 // For example, traits that multiple models might implement
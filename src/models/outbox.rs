@@ -0,0 +1,78 @@
+use mongodb::bson::{oid::ObjectId, DateTime, Document};
+use serde::{Deserialize, Serialize};
+
+/// An event waiting to be published, written in the same transaction as the change it describes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OutboxEntry {
+    /// Also the event ID sinks can use to deduplicate
+    #[serde(rename = "_id")]
+    pub id: ObjectId,
+    /// `user.created`, `user.updated` or `user.deleted`
+    pub event_type: String,
+    pub aggregate_id: String,
+    /// The JSON event body handed to every sink
+    pub payload: String,
+    pub occurred_at: DateTime,
+    /// Names of the sinks that have published this entry
+    #[serde(default)]
+    pub published_to: Vec<String>,
+    /// When the last of the relay's sinks published this entry; the retention starts here
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<DateTime>,
+    /// Per-sink claim expiry, so only one relay publishes an entry to a sink at a time
+    #[serde(default)]
+    pub leases: Document,
+}
+
+impl OutboxEntry {
+    pub fn new(event_type: String, aggregate_id: String, payload: String) -> Self {
+        OutboxEntry {
+            id: ObjectId::new(),
+            event_type,
+            aggregate_id,
+            payload,
+            occurred_at: DateTime::now(),
+            published_to: Vec::new(),
+            published_at: None,
+            leases: Document::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson;
+
+    #[test]
+    fn test_outbox_entry_serialization() {
+        let entry = OutboxEntry::new(
+            "user.created".to_string(),
+            "abc".to_string(),
+            "{}".to_string(),
+        );
+
+        let document = bson::to_document(&entry).unwrap();
+        assert_eq!(document.get_object_id("_id").unwrap(), entry.id);
+        assert_eq!(document.get_str("event_type").unwrap(), "user.created");
+        assert!(document.get_array("published_to").unwrap().is_empty());
+        // Without the field the TTL index leaves the entry alone
+        assert!(!document.contains_key("published_at"));
+    }
+
+    #[test]
+    fn test_outbox_entry_defaults_bookkeeping_fields() {
+        let document = bson::doc! {
+            "_id": ObjectId::new(),
+            "event_type": "user.deleted",
+            "aggregate_id": "abc",
+            "payload": "{}",
+            "occurred_at": DateTime::now(),
+        };
+
+        let entry: OutboxEntry = bson::from_document(document).unwrap();
+        assert!(entry.published_to.is_empty());
+        assert!(entry.published_at.is_none());
+        assert!(entry.leases.is_empty());
+    }
+}
@@ -1,6 +1,72 @@
 use chrono::{DateTime, Utc};
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use utoipa::ToSchema;
+
+/// Where a user is in its lifecycle.
+///
+/// ```text
+/// pending ──activate──▶ active ◀──activate── suspended
+///    │                    │  └────suspend─────▶  │
+///    └──────archive───────┴──────▶ archived ◀────┘
+/// ```
+///
+/// Archived is final.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum UserStatus {
+    /// Created, waiting to be activated
+    Pending,
+    /// Records stored before statuses read as active
+    #[default]
+    Active,
+    Suspended,
+    Archived,
+}
+
+impl UserStatus {
+    pub const ALL: [UserStatus; 4] = [
+        UserStatus::Pending,
+        UserStatus::Active,
+        UserStatus::Suspended,
+        UserStatus::Archived,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UserStatus::Pending => "pending",
+            UserStatus::Active => "active",
+            UserStatus::Suspended => "suspended",
+            UserStatus::Archived => "archived",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<UserStatus> {
+        UserStatus::ALL
+            .into_iter()
+            .find(|status| status.as_str() == value)
+    }
+
+    /// The statuses a user may move to `target` from
+    pub fn sources(target: UserStatus) -> &'static [UserStatus] {
+        match target {
+            UserStatus::Pending => &[],
+            UserStatus::Active => &[UserStatus::Pending, UserStatus::Suspended],
+            UserStatus::Suspended => &[UserStatus::Active],
+            UserStatus::Archived => &[
+                UserStatus::Pending,
+                UserStatus::Active,
+                UserStatus::Suspended,
+            ],
+        }
+    }
+
+    /// Whether a user in this status may move to `target`
+    pub fn can_become(&self, target: UserStatus) -> bool {
+        UserStatus::sources(target).contains(self)
+    }
+}
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct User {
@@ -12,6 +78,40 @@ pub struct User {
     pub created_at: DateTime<Utc>,
     #[serde(rename = "updated_at", default)]
     pub updated_at: Option<DateTime<Utc>>,
+    /// Incremented on every change; records created before versioning read as 0
+    #[serde(default)]
+    pub version: i64,
+    /// Phone number as entered, e.g. `+44 20 7946 0958`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub phone: Option<String>,
+    /// BCP 47 language tag, e.g. `en-GB`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub locale: Option<String>,
+    /// IANA time zone name, e.g. `Europe/London`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timezone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avatar_url: Option<String>,
+    /// Free-form string values keyed by name, e.g. per-tenant identifiers
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub metadata: BTreeMap<String, String>,
+    #[serde(default)]
+    pub status: UserStatus,
+    /// Why the status last changed, as given with the transition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_reason: Option<String>,
+    /// When the status last changed; `None` until the first transition
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub status_changed_at: Option<DateTime<Utc>>,
+    /// When the owner of `email` confirmed it; cleared when the email changes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_verified_at: Option<DateTime<Utc>>,
+    /// When a verification link was last resent on request, to space resends out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification_resent_at: Option<DateTime<Utc>>,
+    /// When a password reset link was last mailed, to space reset emails out
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password_reset_requested_at: Option<DateTime<Utc>>,
 }
 
 impl User {
@@ -24,6 +124,18 @@ impl User {
             email,
             created_at: now,
             updated_at: Some(now),
+            version: 1,
+            phone: None,
+            locale: None,
+            timezone: None,
+            avatar_url: None,
+            metadata: BTreeMap::new(),
+            status: UserStatus::Pending,
+            status_reason: None,
+            status_changed_at: None,
+            email_verified_at: None,
+            verification_resent_at: None,
+            password_reset_requested_at: None,
         }
     }
 
@@ -36,6 +148,18 @@ impl User {
             email,
             created_at,
             updated_at: Some(created_at),
+            version: 1,
+            phone: None,
+            locale: None,
+            timezone: None,
+            avatar_url: None,
+            metadata: BTreeMap::new(),
+            status: UserStatus::Pending,
+            status_reason: None,
+            status_changed_at: None,
+            email_verified_at: None,
+            verification_resent_at: None,
+            password_reset_requested_at: None,
         }
     }
 }
@@ -54,6 +178,7 @@ mod tests {
         assert!(user.id.is_none());
         assert!(user.updated_at.is_some());
         assert!(user.created_at <= Utc::now());
+        assert_eq!(user.version, 1);
     }
 
     #[test]
@@ -155,6 +280,62 @@ mod tests {
         assert_eq!(user.name, "Test User");
         assert_eq!(user.email, "test@example.com");
         assert!(user.id.is_none());
+        // Records stored before versioning default to version 0
+        assert_eq!(user.version, 0);
+        // and records stored before profiles have none
+        assert!(user.phone.is_none());
+        assert!(user.metadata.is_empty());
+        // and those stored before statuses are active
+        assert_eq!(user.status, UserStatus::Active);
+    }
+
+    #[test]
+    fn test_status_transitions() {
+        use UserStatus::*;
+
+        assert_eq!(
+            User::new_user("New".to_string(), "new@example.com".to_string()).status,
+            Pending
+        );
+
+        let allowed = [
+            (Pending, Active),
+            (Pending, Archived),
+            (Active, Suspended),
+            (Active, Archived),
+            (Suspended, Active),
+            (Suspended, Archived),
+        ];
+        for from in UserStatus::ALL {
+            for to in UserStatus::ALL {
+                assert_eq!(
+                    from.can_become(to),
+                    allowed.contains(&(from, to)),
+                    "{:?} -> {:?}",
+                    from,
+                    to
+                );
+            }
+        }
+
+        assert_eq!(UserStatus::parse("suspended"), Some(Suspended));
+        assert_eq!(UserStatus::parse("deleted"), None);
+        assert_eq!(serde_json::to_string(&Archived).unwrap(), r#""archived""#);
+    }
+
+    #[test]
+    fn test_empty_profile_is_not_stored() {
+        let mut user = User::new_user("Test User".to_string(), "test@example.com".to_string());
+        let json_str = serde_json::to_string(&user).unwrap();
+        assert!(!json_str.contains("phone"));
+        assert!(!json_str.contains("metadata"));
+
+        user.locale = Some("en-GB".to_string());
+        user.metadata
+            .insert("tenant".to_string(), "acme".to_string());
+        let json_str = serde_json::to_string(&user).unwrap();
+        assert!(json_str.contains(r#""locale":"en-GB""#));
+        assert!(json_str.contains(r#""metadata":{"tenant":"acme"}"#));
     }
 
     #[test]
@@ -0,0 +1,149 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+/// Event types a webhook subscription can ask for
+pub const WEBHOOK_EVENT_TYPES: [&str; 3] = ["user.created", "user.updated", "user.deleted"];
+
+/// A downstream endpoint that receives signed user lifecycle events
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookSubscription {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub url: String,
+    /// Shared secret used to sign every delivery
+    pub secret: String,
+    pub events: Vec<String>,
+    pub active: bool,
+    pub created_at: DateTime,
+}
+
+impl WebhookSubscription {
+    pub fn new(url: String, secret: String, events: Vec<String>) -> Self {
+        WebhookSubscription {
+            id: None,
+            url,
+            secret,
+            events,
+            active: true,
+            created_at: DateTime::now(),
+        }
+    }
+
+    /// Check whether this subscription wants events of the given type
+    pub fn wants(&self, event_type: &str) -> bool {
+        self.active && self.events.iter().any(|event| event == event_type)
+    }
+}
+
+/// Where a delivery is in its lifecycle
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    /// Waiting for its first attempt or a retry
+    Pending,
+    Succeeded,
+    /// Gave up after the maximum number of attempts
+    Dead,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DeliveryStatus::Pending => "pending",
+            DeliveryStatus::Succeeded => "succeeded",
+            DeliveryStatus::Dead => "dead",
+        }
+    }
+}
+
+/// The outcome of a single HTTP attempt
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DeliveryAttempt {
+    pub attempted_at: DateTime,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub duration_ms: i64,
+}
+
+/// One event queued for one subscription, with its attempt history
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct WebhookDelivery {
+    /// Derived from the subscription and event so the same event is never queued twice
+    #[serde(rename = "_id")]
+    pub id: String,
+    pub subscription_id: ObjectId,
+    pub event_id: String,
+    pub event_type: String,
+    /// The exact JSON body that is signed and sent
+    pub payload: String,
+    pub status: DeliveryStatus,
+    pub attempts: i32,
+    pub next_attempt_at: DateTime,
+    #[serde(default)]
+    pub attempt_log: Vec<DeliveryAttempt>,
+    pub created_at: DateTime,
+    pub updated_at: DateTime,
+}
+
+impl WebhookDelivery {
+    pub fn pending(
+        id: String,
+        subscription_id: ObjectId,
+        event_id: String,
+        event_type: String,
+        payload: String,
+    ) -> Self {
+        let now = DateTime::now();
+        WebhookDelivery {
+            id,
+            subscription_id,
+            event_id,
+            event_type,
+            payload,
+            status: DeliveryStatus::Pending,
+            attempts: 0,
+            next_attempt_at: now,
+            attempt_log: Vec::new(),
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson;
+
+    #[test]
+    fn test_subscription_wants_subscribed_events() {
+        let mut subscription = WebhookSubscription::new(
+            "http://localhost:9000/hook".to_string(),
+            "secret".to_string(),
+            vec!["user.created".to_string()],
+        );
+
+        assert!(subscription.wants("user.created"));
+        assert!(!subscription.wants("user.deleted"));
+
+        subscription.active = false;
+        assert!(!subscription.wants("user.created"));
+    }
+
+    #[test]
+    fn test_delivery_status_serialization() {
+        let delivery = WebhookDelivery::pending(
+            "delivery".to_string(),
+            ObjectId::new(),
+            "event".to_string(),
+            "user.created".to_string(),
+            "{}".to_string(),
+        );
+
+        let document = bson::to_document(&delivery).unwrap();
+        assert_eq!(document.get_str("status").unwrap(), "pending");
+        assert_eq!(document.get_str("_id").unwrap(), "delivery");
+        assert_eq!(document.get_i32("attempts").unwrap(), 0);
+    }
+}
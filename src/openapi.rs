@@ -0,0 +1,238 @@
+//! OpenAPI document generated from the handler annotations and model types
+
+use crate::handlers;
+use utoipa::OpenApi;
+
+/// The API description served at `/openapi.json`
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "Rust Simple API",
+        description = "User management API with audit history, change feeds and webhooks"
+    ),
+    paths(
+        handlers::health::health_check_with_status,
+        handlers::users::get_all_users,
+        handlers::users::get_user_by_id,
+        handlers::users::create_user_idempotent,
+        handlers::users::update_user,
+        handlers::users::delete_user,
+        handlers::search::search_users,
+        handlers::stats::get_user_stats,
+        handlers::status::activate_user,
+        handlers::status::suspend_user,
+        handlers::status::archive_user,
+        handlers::verification::verify_email,
+        handlers::verification::resend_verification_email,
+        handlers::auth::login,
+        handlers::auth::get_session,
+        handlers::auth::request_password_reset,
+        handlers::auth::confirm_password_reset,
+        handlers::avatars::upload_avatar,
+        handlers::avatars::get_avatar,
+        handlers::users_v2::get_all_users_v2,
+        handlers::users_v2::get_user_by_id_v2,
+        handlers::users_v2::create_user_v2,
+        handlers::users_v2::update_user_v2,
+        handlers::users_v2::delete_user_v2,
+        handlers::status::activate_user_v2,
+        handlers::status::suspend_user_v2,
+        handlers::status::archive_user_v2,
+        handlers::search::search_users_v2,
+        handlers::stats::get_user_stats_v2,
+        handlers::verification::verify_email_v2,
+        handlers::auth::login_v2,
+        handlers::auth::get_session_v2,
+        handlers::avatars::upload_avatar_v2,
+        handlers::events::user_events,
+        handlers::ws::user_updates_socket,
+        handlers::audit::get_user_history,
+        handlers::audit::get_audit_log,
+        handlers::webhooks::create_webhook,
+        handlers::webhooks::list_webhooks,
+        handlers::webhooks::get_dead_letters,
+        handlers::webhooks::retry_webhook_delivery,
+        handlers::webhooks::delete_webhook,
+        handlers::webhooks::get_webhook_deliveries,
+    ),
+    components(schemas(
+        handlers::UserResponse,
+        handlers::CreateUserRequest,
+        handlers::UpdateUserRequest,
+        handlers::ErrorResponse,
+        handlers::UserSearchHit,
+        handlers::SearchHighlights,
+        handlers::MatchKind,
+        handlers::UserStats,
+        handlers::UserTotals,
+        handlers::SignupBucket,
+        handlers::DomainCount,
+        handlers::StatsInterval,
+        handlers::StatusChangeRequest,
+        handlers::EmailVerification,
+        handlers::LoginRequest,
+        handlers::SessionResponse,
+        handlers::CurrentSession,
+        handlers::PasswordResetRequest,
+        handlers::PasswordResetConfirmRequest,
+        handlers::Avatar,
+        handlers::AvatarUpload,
+        crate::models::UserStatus,
+        handlers::UserV2Response,
+        handlers::UserListV2Response,
+        handlers::CreateUserV2Request,
+        handlers::UpdateUserV2Request,
+        handlers::UserSearchV2Response,
+        handlers::UserSearchHitV2,
+        handlers::UserStatsV2,
+        handlers::EmailVerificationV2,
+        handlers::SessionV2Response,
+        handlers::CurrentSessionV2,
+        handlers::AvatarV2,
+    )),
+    tags(
+        (name = "health", description = "Service status"),
+        (name = "users", description = "User management"),
+        (name = "auth", description = "Passwords, sessions and password resets"),
+        (name = "events", description = "Live user change notifications"),
+        (name = "audit", description = "Audit log of user mutations"),
+        (name = "webhooks", description = "Webhook subscriptions and deliveries"),
+    )
+)]
+pub struct ApiDoc;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    /// Routes that describe the API rather than being part of it, and GraphQL,
+    /// which describes itself through introspection
+    const UNDOCUMENTED_PATHS: [&str; 3] = ["/openapi.json", "/docs", "/graphql"];
+
+    /// Prefixes that select an API version rather than being routes themselves
+    const VERSION_PREFIXES: [&str; 2] = ["/v1", "/v2"];
+
+    /// Collect `(method, path)` for every `let name = warp::path...;` route in `main.rs`,
+    /// with path parameters written as `{}`. Unprefixed paths are v1; routes
+    /// named `*_v2` only exist under `/v2`.
+    fn routes_in_main() -> BTreeSet<(String, String)> {
+        let source = include_str!("main.rs");
+        let mut routes = BTreeSet::new();
+
+        for statement in source.split("let ").skip(1) {
+            let Some((name, definition)) = statement.split_once(" = ") else {
+                continue;
+            };
+            if !definition.starts_with("warp::path") {
+                continue;
+            }
+            let definition = definition.split(';').next().unwrap_or_default();
+
+            let path = route_path(definition);
+            if UNDOCUMENTED_PATHS.contains(&path.as_str())
+                || VERSION_PREFIXES.contains(&path.as_str())
+            {
+                continue;
+            }
+            let path = if name.ends_with("_v2") {
+                format!("/v2{}", path)
+            } else {
+                path
+            };
+
+            let method = ["get", "post", "put", "patch", "delete"]
+                .into_iter()
+                .find(|method| definition.contains(&format!("warp::{}()", method)))
+                .or_else(|| definition.contains("warp::ws()").then_some("get"))
+                .unwrap_or_else(|| panic!("route without a method: {}", definition));
+
+            routes.insert((method.to_string(), path));
+        }
+
+        routes
+    }
+
+    /// Turn `warp::path("a")` or `warp::path!("a" / String / "b")` into `/a` or `/a/{}/b`
+    fn route_path(definition: &str) -> String {
+        let open = definition.find('(').unwrap();
+        let close = definition[open..].find(')').unwrap() + open;
+
+        definition[open + 1..close]
+            .split('/')
+            .map(|segment| {
+                let segment = segment.trim();
+                match segment.strip_prefix('"') {
+                    Some(literal) => format!("/{}", literal.trim_end_matches('"')),
+                    None => "/{}".to_string(),
+                }
+            })
+            .collect()
+    }
+
+    /// Collect `(method, path)` for every operation in the spec, with parameters written as `{}`
+    fn operations_in_spec() -> BTreeSet<(String, String)> {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let mut operations = BTreeSet::new();
+
+        for (path, item) in spec["paths"].as_object().unwrap() {
+            let normalized: String = path
+                .split('/')
+                .skip(1)
+                .map(|segment| {
+                    if segment.starts_with('{') {
+                        "/{}".to_string()
+                    } else {
+                        format!("/{}", segment)
+                    }
+                })
+                .collect();
+
+            for method in item.as_object().unwrap().keys() {
+                operations.insert((method.clone(), normalized.clone()));
+            }
+        }
+
+        operations
+    }
+
+    #[test]
+    fn test_route_path_parsing() {
+        assert_eq!(route_path("warp::path(\"health\")"), "/health");
+        assert_eq!(
+            route_path("warp::path!(\"webhooks\" / \"deliveries\" / String / \"retry\")"),
+            "/webhooks/deliveries/{}/retry"
+        );
+    }
+
+    #[test]
+    fn test_spec_matches_routes_in_main() {
+        let routes = routes_in_main();
+        let operations = operations_in_spec();
+        assert!(routes.contains(&("patch".to_string(), "/users/{}".to_string())));
+        assert!(routes.contains(&("patch".to_string(), "/v2/users/{}".to_string())));
+
+        let undocumented: Vec<_> = routes.difference(&operations).collect();
+        let unrouted: Vec<_> = operations.difference(&routes).collect();
+
+        assert!(
+            undocumented.is_empty(),
+            "routes missing from the OpenAPI spec: {:?}",
+            undocumented
+        );
+        assert!(
+            unrouted.is_empty(),
+            "spec operations without a route in main.rs: {:?}",
+            unrouted
+        );
+    }
+
+    #[test]
+    fn test_spec_is_openapi_3_1() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+
+        assert!(spec["openapi"].as_str().unwrap().starts_with("3.1"));
+        assert!(spec["components"]["schemas"]["UserResponse"].is_object());
+        assert!(spec["components"]["schemas"]["ErrorResponse"].is_object());
+    }
+}
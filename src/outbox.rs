@@ -0,0 +1,651 @@
+//! Relay of outbox entries to publishing sinks
+//!
+//! User changes write an outbox entry in the same transaction as the change
+//! itself (see `db::Outbox`), so an event cannot be lost between the write
+//! and its publication. Entries expire only after every sink has published
+//! them. A relay loop per sink claims unpublished entries in order, publishes
+//! them and records the sink in the entry's `published_to` list, so every sink
+//! sees each entry once. A crash after publishing but
+//! before recording can repeat an entry; the entry ID travels with every
+//! message so sinks can deduplicate (the webhook and NATS sinks do).
+
+use futures::future::BoxFuture;
+use mongodb::Database;
+use serde::Serialize;
+use std::env;
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+use crate::db::{claim_outbox_entry, delete_avatars, mark_outbox_published, postpone_outbox_entry};
+use crate::events::UserEventKind;
+use crate::handlers::users::{find_user, UserResponse};
+use crate::handlers::verification::EmailVerifier;
+use crate::models::{OutboxEntry, User};
+use crate::webhooks::queue_webhook_deliveries;
+
+/// Sinks used when `OUTBOX_SINKS` is not set
+const DEFAULT_SINKS: &str = "webhook";
+
+/// Default file written by the `file` sink
+const DEFAULT_OUTBOX_FILE: &str = "outbox.ndjson";
+
+/// Default address of the NATS server used by the `nats` sink
+const DEFAULT_NATS_ADDRESS: &str = "127.0.0.1:4222";
+
+/// Default subject prefix for the `nats` sink, followed by the event kind
+const DEFAULT_NATS_SUBJECT_PREFIX: &str = "users";
+
+/// Longest the `nats` sink waits on the server to connect or confirm a publish
+const NATS_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How often an idle relay checks for new entries
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How long a claimed entry is reserved for the relay publishing it
+const CLAIM_LEASE: Duration = Duration::from_secs(30);
+
+/// How long a sink waits before retrying an entry it failed to publish
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The published name of a user event kind
+pub fn event_type(kind: UserEventKind) -> &'static str {
+    match kind {
+        UserEventKind::Created => "user.created",
+        UserEventKind::Updated => "user.updated",
+        UserEventKind::Deleted => "user.deleted",
+    }
+}
+
+/// Event body handed to every sink
+#[derive(Serialize, Debug)]
+struct EventEnvelope<'a> {
+    /// The outbox entry ID
+    id: String,
+    #[serde(rename = "type")]
+    event_type: &'a str,
+    created_at: String,
+    data: EventData,
+}
+
+#[derive(Serialize, Debug)]
+struct EventData {
+    user_id: String,
+    /// The user after the change, or before it for deletions
+    user: UserResponse,
+}
+
+/// Build the outbox entry for a change to a user
+pub fn user_outbox_entry(kind: UserEventKind, user: &User) -> OutboxEntry {
+    let user_id = user.id.map(|id| id.to_hex()).unwrap_or_default();
+    let mut entry = OutboxEntry::new(event_type(kind).to_string(), user_id.clone(), String::new());
+
+    entry.payload = serde_json::to_string(&EventEnvelope {
+        id: entry.id.to_hex(),
+        event_type: event_type(kind),
+        created_at: entry
+            .occurred_at
+            .try_to_rfc3339_string()
+            .unwrap_or_default(),
+        data: EventData {
+            user_id,
+            user: UserResponse::from(user.clone()),
+        },
+    })
+    .unwrap_or_default();
+
+    entry
+}
+
+/// A destination for outbox entries
+pub trait OutboxSink: Send + Sync {
+    /// Name recorded in `published_to`; must not contain `.` or start with `$`
+    fn name(&self) -> &str;
+
+    /// Publish one entry, returning an error to have it retried later
+    fn publish<'a>(&'a self, entry: &'a OutboxEntry) -> BoxFuture<'a, Result<(), String>>;
+}
+
+/// Queue webhook deliveries for the entry's subscribers
+pub struct WebhookSink {
+    db: Arc<Database>,
+}
+
+impl WebhookSink {
+    pub fn new(db: Arc<Database>) -> Self {
+        WebhookSink { db }
+    }
+}
+
+impl OutboxSink for WebhookSink {
+    fn name(&self) -> &str {
+        "webhook"
+    }
+
+    fn publish<'a>(&'a self, entry: &'a OutboxEntry) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            queue_webhook_deliveries(&self.db, entry)
+                .await
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Print each event as a line of JSON on stdout
+pub struct StdoutSink;
+
+impl OutboxSink for StdoutSink {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn publish<'a>(&'a self, entry: &'a OutboxEntry) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            println!("{}", entry.payload);
+            Ok(())
+        })
+    }
+}
+
+/// Append each event as a line of JSON to a file
+pub struct FileSink {
+    path: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileSink {
+    pub fn new(path: PathBuf) -> Self {
+        FileSink {
+            path,
+            lock: Mutex::new(()),
+        }
+    }
+}
+
+impl OutboxSink for FileSink {
+    fn name(&self) -> &str {
+        "file"
+    }
+
+    fn publish<'a>(&'a self, entry: &'a OutboxEntry) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let _guard = self.lock.lock().await;
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.path)
+                .await
+                .map_err(|e| e.to_string())?;
+
+            file.write_all(format!("{}\n", entry.payload).as_bytes())
+                .await
+                .map_err(|e| e.to_string())?;
+            file.flush().await.map_err(|e| e.to_string())
+        })
+    }
+}
+
+/// Publish to a NATS server (or anything speaking its text protocol) on `<prefix>.<kind>`.
+///
+/// Messages carry a `Nats-Msg-Id` header set to the entry ID, which JetStream
+/// uses to drop duplicates. Each publish is confirmed with a PING/PONG round trip,
+/// and a server that does not answer in time fails the publish so the relay retries.
+pub struct NatsSink {
+    address: String,
+    subject_prefix: String,
+    timeout: Duration,
+    connection: Mutex<Option<BufReader<TcpStream>>>,
+}
+
+impl NatsSink {
+    pub fn new(address: String, subject_prefix: String) -> Self {
+        NatsSink {
+            address,
+            subject_prefix,
+            timeout: NATS_TIMEOUT,
+            connection: Mutex::new(None),
+        }
+    }
+
+    /// Fail an exchange with the server that takes longer than the timeout
+    async fn within_timeout<T>(
+        &self,
+        exchange: impl Future<Output = Result<T, String>>,
+    ) -> Result<T, String> {
+        tokio::time::timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| {
+                format!(
+                    "NATS at {} did not answer within {:?}",
+                    self.address, self.timeout
+                )
+            })?
+    }
+
+    fn subject(&self, entry: &OutboxEntry) -> String {
+        let kind = entry
+            .event_type
+            .strip_prefix("user.")
+            .unwrap_or(&entry.event_type);
+        format!("{}.{}", self.subject_prefix, kind)
+    }
+
+    async fn connect(&self) -> Result<BufReader<TcpStream>, String> {
+        let stream = TcpStream::connect(&self.address)
+            .await
+            .map_err(|e| format!("Failed to connect to NATS at {}: {}", self.address, e))?;
+        let mut connection = BufReader::new(stream);
+
+        let info = read_line(&mut connection).await?;
+        if !info.starts_with("INFO") {
+            return Err(format!("Unexpected NATS greeting: {}", info));
+        }
+
+        connection
+            .get_mut()
+            .write_all(
+                b"CONNECT {\"verbose\":false,\"pedantic\":false,\"headers\":true,\"name\":\"rust-simple-api\"}\r\n",
+            )
+            .await
+            .map_err(|e| e.to_string())?;
+
+        Ok(connection)
+    }
+
+    async fn send(
+        &self,
+        connection: &mut BufReader<TcpStream>,
+        entry: &OutboxEntry,
+    ) -> Result<(), String> {
+        let headers = format!("NATS/1.0\r\nNats-Msg-Id: {}\r\n\r\n", entry.id.to_hex());
+        let frame = format!(
+            "HPUB {} {} {}\r\n{}{}\r\nPING\r\n",
+            self.subject(entry),
+            headers.len(),
+            headers.len() + entry.payload.len(),
+            headers,
+            entry.payload
+        );
+
+        connection
+            .get_mut()
+            .write_all(frame.as_bytes())
+            .await
+            .map_err(|e| e.to_string())?;
+
+        // The server handles commands in order, so PONG confirms the publish
+        loop {
+            let line = read_line(connection).await?;
+            match line.as_str() {
+                "PONG" => return Ok(()),
+                "PING" => connection
+                    .get_mut()
+                    .write_all(b"PONG\r\n")
+                    .await
+                    .map_err(|e| e.to_string())?,
+                line if line.starts_with("-ERR") => {
+                    return Err(format!("NATS rejected publish: {}", line))
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl OutboxSink for NatsSink {
+    fn name(&self) -> &str {
+        "nats"
+    }
+
+    fn publish<'a>(&'a self, entry: &'a OutboxEntry) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let mut connection = self.connection.lock().await;
+            if connection.is_none() {
+                *connection = Some(self.within_timeout(self.connect()).await?);
+            }
+
+            let result = match connection.as_mut() {
+                Some(stream) => self.within_timeout(self.send(stream, entry)).await,
+                None => Err("NATS connection unavailable".to_string()),
+            };
+            if result.is_err() {
+                // Reconnect on the next attempt
+                *connection = None;
+            }
+            result
+        })
+    }
+}
+
+/// Mail a verification link to every user created.
+///
+/// Entries older than a link's lifetime are skipped, so enabling verification
+/// does not mail everyone still in the outbox, and users who are gone or
+/// already verified get nothing.
+pub struct VerificationEmailSink {
+    db: Arc<Database>,
+    verifier: Arc<EmailVerifier>,
+}
+
+impl VerificationEmailSink {
+    pub fn new(db: Arc<Database>, verifier: Arc<EmailVerifier>) -> Self {
+        VerificationEmailSink { db, verifier }
+    }
+}
+
+impl OutboxSink for VerificationEmailSink {
+    fn name(&self) -> &str {
+        "verification-email"
+    }
+
+    fn publish<'a>(&'a self, entry: &'a OutboxEntry) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            let age = chrono::Duration::milliseconds(
+                chrono::Utc::now().timestamp_millis() - entry.occurred_at.timestamp_millis(),
+            );
+            if entry.event_type != event_type(UserEventKind::Created)
+                || age > self.verifier.link_lifetime()
+            {
+                return Ok(());
+            }
+            let Ok(user_id) = mongodb::bson::oid::ObjectId::parse_str(&entry.aggregate_id) else {
+                return Ok(());
+            };
+
+            match find_user(&self.db, user_id).await {
+                Ok(Some(user)) if user.email_verified_at.is_none() => {
+                    self.verifier.send_link(&user).await
+                }
+                Ok(_) => Ok(()),
+                Err(e) => Err(e.message().to_string()),
+            }
+        })
+    }
+}
+
+/// Remove the avatar of every user deleted
+pub struct AvatarCleanupSink {
+    db: Arc<Database>,
+}
+
+impl AvatarCleanupSink {
+    pub fn new(db: Arc<Database>) -> Self {
+        AvatarCleanupSink { db }
+    }
+}
+
+impl OutboxSink for AvatarCleanupSink {
+    fn name(&self) -> &str {
+        "avatar-cleanup"
+    }
+
+    fn publish<'a>(&'a self, entry: &'a OutboxEntry) -> BoxFuture<'a, Result<(), String>> {
+        Box::pin(async move {
+            if entry.event_type != event_type(UserEventKind::Deleted) {
+                return Ok(());
+            }
+            let Ok(user_id) = mongodb::bson::oid::ObjectId::parse_str(&entry.aggregate_id) else {
+                return Ok(());
+            };
+
+            delete_avatars(&self.db, user_id)
+                .await
+                .map(|_| ())
+                .map_err(|e| e.to_string())
+        })
+    }
+}
+
+async fn read_line(connection: &mut BufReader<TcpStream>) -> Result<String, String> {
+    let mut line = String::new();
+    match connection.read_line(&mut line).await {
+        Ok(0) => Err("NATS connection closed".to_string()),
+        Ok(_) => Ok(line.trim_end().to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Build the sinks named in the comma-separated `OUTBOX_SINKS` (default `webhook`)
+pub fn sinks_from_env(db: Arc<Database>) -> Vec<Arc<dyn OutboxSink>> {
+    let names = env::var("OUTBOX_SINKS").unwrap_or_else(|_| DEFAULT_SINKS.to_string());
+    let mut sinks: Vec<Arc<dyn OutboxSink>> = Vec::new();
+
+    for name in names
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        match name {
+            "webhook" => sinks.push(Arc::new(WebhookSink::new(db.clone()))),
+            "stdout" => sinks.push(Arc::new(StdoutSink)),
+            "file" => sinks.push(Arc::new(FileSink::new(PathBuf::from(
+                env::var("OUTBOX_FILE_PATH").unwrap_or_else(|_| DEFAULT_OUTBOX_FILE.to_string()),
+            )))),
+            "nats" => sinks.push(Arc::new(NatsSink::new(
+                env::var("NATS_ADDRESS").unwrap_or_else(|_| DEFAULT_NATS_ADDRESS.to_string()),
+                env::var("NATS_SUBJECT_PREFIX")
+                    .unwrap_or_else(|_| DEFAULT_NATS_SUBJECT_PREFIX.to_string()),
+            ))),
+            other => eprintln!("Ignoring unknown outbox sink '{}'", other),
+        }
+    }
+
+    sinks
+}
+
+/// Run one relay loop per sink so a failing sink does not hold back the others
+pub fn start_outbox_relay(db: Arc<Database>, sinks: Vec<Arc<dyn OutboxSink>>) {
+    // An entry is done, and starts its retention, once all of these have published it
+    let names: Arc<Vec<String>> =
+        Arc::new(sinks.iter().map(|sink| sink.name().to_string()).collect());
+
+    for sink in sinks {
+        let db = db.clone();
+        let names = names.clone();
+        println!("Relaying outbox entries to the {} sink", sink.name());
+
+        tokio::spawn(async move {
+            loop {
+                if !relay_next(&db, sink.as_ref(), &names).await {
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        });
+    }
+}
+
+/// Publish the next unpublished entry to a sink, returning false when there was none
+async fn relay_next(db: &Database, sink: &dyn OutboxSink, sinks: &[String]) -> bool {
+    let entry = match claim_outbox_entry(db, sink.name(), CLAIM_LEASE).await {
+        Ok(Some(entry)) => entry,
+        Ok(None) => return false,
+        Err(e) => {
+            eprintln!("Failed to claim outbox entry for {}: {}", sink.name(), e);
+            return false;
+        }
+    };
+
+    match sink.publish(&entry).await {
+        Ok(()) => {
+            if let Err(e) = mark_outbox_published(db, entry.id, sink.name(), sinks).await {
+                eprintln!(
+                    "Failed to mark outbox entry {} as published to {}: {}",
+                    entry.id,
+                    sink.name(),
+                    e
+                );
+            }
+            true
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to publish outbox entry {} to {}: {}",
+                entry.id,
+                sink.name(),
+                e
+            );
+            if let Err(e) = postpone_outbox_entry(db, entry.id, sink.name(), RETRY_DELAY).await {
+                eprintln!("Failed to postpone outbox entry {}: {}", entry.id, e);
+            }
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson::oid::ObjectId;
+    use tokio::net::TcpListener;
+
+    fn test_user() -> User {
+        User::with_id(
+            ObjectId::new(),
+            "Outbox User".to_string(),
+            "outbox@example.com".to_string(),
+            chrono::Utc::now(),
+        )
+    }
+
+    /// A minimal NATS stand-in that reports every published message
+    async fn start_nats_stand_in() -> (String, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut connection = BufReader::new(stream);
+            connection
+                .get_mut()
+                .write_all(b"INFO {\"headers\":true}\r\n")
+                .await
+                .unwrap();
+
+            let mut line = String::new();
+            while connection.read_line(&mut line).await.unwrap_or(0) > 0 {
+                if line.starts_with("HPUB") {
+                    let total: usize = line.split_whitespace().nth(3).unwrap().parse().unwrap();
+                    let mut message = vec![0; total + 2];
+                    tokio::io::AsyncReadExt::read_exact(&mut connection, &mut message)
+                        .await
+                        .unwrap();
+                    let message = String::from_utf8(message).unwrap();
+                    let _ = sender.send(format!("{}{}", line, message));
+                } else if line.starts_with("PING") {
+                    connection.get_mut().write_all(b"PONG\r\n").await.unwrap();
+                }
+                line.clear();
+            }
+        });
+
+        (address, receiver)
+    }
+
+    #[test]
+    fn test_user_outbox_entry() {
+        let user = test_user();
+        let entry = user_outbox_entry(UserEventKind::Updated, &user);
+
+        assert_eq!(entry.event_type, "user.updated");
+        assert_eq!(entry.aggregate_id, user.id.unwrap().to_hex());
+
+        let payload: serde_json::Value = serde_json::from_str(&entry.payload).unwrap();
+        assert_eq!(payload["id"], entry.id.to_hex());
+        assert_eq!(payload["type"], "user.updated");
+        assert_eq!(payload["data"]["user"]["name"], "Outbox User");
+    }
+
+    #[tokio::test]
+    async fn test_sinks_from_env() {
+        // Creating a client does not connect, so no server is needed
+        let db = Arc::new(
+            mongodb::Client::with_uri_str("mongodb://localhost:27017")
+                .await
+                .unwrap()
+                .database("test"),
+        );
+
+        unsafe {
+            env::set_var("OUTBOX_SINKS", "stdout, file,unknown");
+        }
+        let names: Vec<String> = sinks_from_env(db.clone())
+            .iter()
+            .map(|sink| sink.name().to_string())
+            .collect();
+        assert_eq!(names, vec!["stdout", "file"]);
+
+        unsafe {
+            env::remove_var("OUTBOX_SINKS");
+        }
+        let names: Vec<String> = sinks_from_env(db)
+            .iter()
+            .map(|sink| sink.name().to_string())
+            .collect();
+        assert_eq!(names, vec!["webhook"]);
+    }
+
+    #[tokio::test]
+    async fn test_file_sink_appends_lines() {
+        let path = env::temp_dir().join(format!("outbox-{}.ndjson", ObjectId::new().to_hex()));
+        let sink = FileSink::new(path.clone());
+        let first = user_outbox_entry(UserEventKind::Created, &test_user());
+        let second = user_outbox_entry(UserEventKind::Deleted, &test_user());
+
+        sink.publish(&first).await.unwrap();
+        sink.publish(&second).await.unwrap();
+
+        let contents = tokio::fs::read_to_string(&path).await.unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines, vec![first.payload.as_str(), second.payload.as_str()]);
+
+        let _ = tokio::fs::remove_file(path).await;
+    }
+
+    #[tokio::test]
+    async fn test_nats_sink_publishes_with_message_id() {
+        let (address, mut received) = start_nats_stand_in().await;
+        let sink = NatsSink::new(address, "users".to_string());
+        let entry = user_outbox_entry(UserEventKind::Created, &test_user());
+
+        sink.publish(&entry).await.unwrap();
+
+        let message = received.recv().await.unwrap();
+        assert!(message.starts_with("HPUB users.created "));
+        assert!(message.contains(&format!("Nats-Msg-Id: {}", entry.id.to_hex())));
+        assert!(message.contains(&entry.payload));
+    }
+
+    #[tokio::test]
+    async fn test_nats_sink_times_out_on_a_silent_server() {
+        // Greets, then never answers the PING that confirms a publish
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"INFO {}\r\n").await.unwrap();
+            tokio::time::sleep(Duration::from_secs(60)).await;
+        });
+
+        let mut sink = NatsSink::new(address, "users".to_string());
+        sink.timeout = Duration::from_millis(200);
+        let entry = user_outbox_entry(UserEventKind::Created, &test_user());
+
+        let error = sink.publish(&entry).await.unwrap_err();
+        assert!(error.contains("did not answer"), "{}", error);
+        // The stalled connection is dropped, so the next attempt reconnects
+        assert!(sink.connection.lock().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_nats_sink_reports_unreachable_server() {
+        let sink = NatsSink::new("127.0.0.1:1".to_string(), "users".to_string());
+        let entry = user_outbox_entry(UserEventKind::Created, &test_user());
+
+        assert!(sink.publish(&entry).await.is_err());
+    }
+}
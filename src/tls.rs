@@ -0,0 +1,478 @@
+//! HTTPS serving without a fronting proxy
+//!
+//! When `TLS_CERT_PATH` and `TLS_KEY_PATH` are set the API is served over TLS,
+//! offering HTTP/2 and HTTP/1.1 through ALPN. The certificate files are polled
+//! and swapped in when they change, so renewed certificates need no restart.
+//! With `TLS_CLIENT_CA_PATH`, clients must also present a certificate signed
+//! by that CA, and its subject is handed to the routes as a `ClientIdentity`.
+
+use rustls::server::{AllowAnyAuthenticatedClient, ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use rustls::{Certificate, PrivateKey, RootCertStore, ServerConfig};
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use warp::hyper::server::conn::Http;
+use warp::hyper::service::{service_fn, Service};
+use warp::hyper::{Body, Request};
+use warp::{Filter, Rejection, Reply};
+
+/// Default interval between checks of the certificate files
+const DEFAULT_RELOAD_SECONDS: u64 = 30;
+
+/// Where to find the certificates and how often to look for new ones
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+    /// CA that client certificates must chain to; `None` leaves clients unauthenticated
+    pub client_ca_path: Option<PathBuf>,
+    pub reload_interval: Duration,
+}
+
+/// The subject of the certificate a client authenticated with, e.g. `CN=billing`.
+///
+/// Set as a request extension on connections made with a client certificate,
+/// so routes can read it with `warp::ext::optional`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClientIdentity(pub String);
+
+impl ClientIdentity {
+    /// The subject of the leaf certificate, when the client presented one
+    pub fn from_certificates(certificates: Option<&[Certificate]>) -> Option<Self> {
+        let leaf = certificates?.first()?;
+        let (_, certificate) = x509_parser::parse_x509_certificate(&leaf.0).ok()?;
+        Some(ClientIdentity(certificate.subject().to_string()))
+    }
+}
+
+impl TlsConfig {
+    /// Read `TLS_CERT_PATH`, `TLS_KEY_PATH`, `TLS_CLIENT_CA_PATH` and `TLS_RELOAD_SECONDS`.
+    ///
+    /// `None` when no certificate is configured, meaning plain HTTP.
+    pub fn from_env() -> Option<Self> {
+        let path = |name| {
+            env::var(name)
+                .ok()
+                .filter(|value| !value.trim().is_empty())
+                .map(PathBuf::from)
+        };
+
+        Some(TlsConfig {
+            cert_path: path("TLS_CERT_PATH")?,
+            key_path: path("TLS_KEY_PATH")?,
+            client_ca_path: path("TLS_CLIENT_CA_PATH"),
+            reload_interval: Duration::from_secs(
+                env::var("TLS_RELOAD_SECONDS")
+                    .ok()
+                    .and_then(|value| value.parse().ok())
+                    .unwrap_or(DEFAULT_RELOAD_SECONDS)
+                    .max(1),
+            ),
+        })
+    }
+}
+
+fn invalid_data(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Read every certificate in a PEM file
+fn load_certificates(path: &Path) -> io::Result<Vec<Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certificates: Vec<Certificate> = rustls_pemfile::certs(&mut reader)?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+
+    if certificates.is_empty() {
+        return Err(invalid_data(format!(
+            "no certificates in {}",
+            path.display()
+        )));
+    }
+    Ok(certificates)
+}
+
+/// Read the first PKCS#8, PKCS#1 or SEC1 private key in a PEM file
+fn load_private_key(path: &Path) -> io::Result<PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+
+    while let Some(item) = rustls_pemfile::read_one(&mut reader)? {
+        match item {
+            rustls_pemfile::Item::PKCS8Key(key)
+            | rustls_pemfile::Item::RSAKey(key)
+            | rustls_pemfile::Item::ECKey(key) => return Ok(PrivateKey(key)),
+            _ => continue,
+        }
+    }
+
+    Err(invalid_data(format!(
+        "no private key in {}",
+        path.display()
+    )))
+}
+
+/// Load the certificate chain and its key, checking that the key can sign
+pub fn load_certified_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+    let certificates = load_certificates(cert_path)?;
+    let key = rustls::sign::any_supported_type(&load_private_key(key_path)?)
+        .map_err(|e| invalid_data(format!("unsupported private key: {}", e)))?;
+
+    Ok(CertifiedKey::new(certificates, key))
+}
+
+/// Serves whichever certificate was loaded last
+pub struct ReloadingCertResolver {
+    current: RwLock<Arc<CertifiedKey>>,
+}
+
+impl ReloadingCertResolver {
+    pub fn new(key: CertifiedKey) -> Self {
+        ReloadingCertResolver {
+            current: RwLock::new(Arc::new(key)),
+        }
+    }
+
+    pub fn replace(&self, key: CertifiedKey) {
+        *self.current.write().unwrap() = Arc::new(key);
+    }
+}
+
+impl ResolvesServerCert for ReloadingCertResolver {
+    fn resolve(&self, _client_hello: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.current.read().unwrap().clone())
+    }
+}
+
+/// Latest modification time of the certificate and key files
+fn modified(config: &TlsConfig) -> Option<SystemTime> {
+    let time = |path: &Path| path.metadata().and_then(|meta| meta.modified()).ok();
+    time(&config.cert_path).max(time(&config.key_path))
+}
+
+/// Reload the certificate whenever its files change, keeping the old one if the new one is broken
+pub fn start_certificate_reload(resolver: Arc<ReloadingCertResolver>, config: TlsConfig) {
+    tokio::spawn(async move {
+        let mut last_modified = modified(&config);
+        let mut interval = tokio::time::interval(config.reload_interval);
+
+        loop {
+            interval.tick().await;
+
+            let current = modified(&config);
+            if current == last_modified {
+                continue;
+            }
+
+            match load_certified_key(&config.cert_path, &config.key_path) {
+                Ok(key) => {
+                    resolver.replace(key);
+                    last_modified = current;
+                    println!(
+                        "Reloaded TLS certificate from {}",
+                        config.cert_path.display()
+                    );
+                }
+                // Files are often written one after the other, so try again next tick
+                Err(e) => eprintln!("Error reloading TLS certificate: {}", e),
+            }
+        }
+    });
+}
+
+/// Build the rustls configuration, requiring client certificates when a client CA is set
+pub fn server_config(
+    config: &TlsConfig,
+    resolver: Arc<ReloadingCertResolver>,
+) -> io::Result<ServerConfig> {
+    let builder = ServerConfig::builder().with_safe_defaults();
+
+    let builder = match &config.client_ca_path {
+        Some(client_ca_path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in load_certificates(client_ca_path)? {
+                roots
+                    .add(&certificate)
+                    .map_err(|e| invalid_data(format!("invalid client CA: {}", e)))?;
+            }
+            builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut server_config = builder.with_cert_resolver(resolver);
+    server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+    Ok(server_config)
+}
+
+/// Serve `filter` over TLS on `listener` until the process stops
+pub async fn serve_tls<F>(filter: F, listener: TcpListener, acceptor: TlsAcceptor)
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let service = warp::service(filter);
+
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                eprintln!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+
+        let acceptor = acceptor.clone();
+        let service = service.clone();
+        tokio::spawn(async move {
+            let stream = match acceptor.accept(stream).await {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("TLS handshake with {} failed: {}", peer, e);
+                    return;
+                }
+            };
+
+            // Every request on the connection carries the verified client certificate
+            let identity =
+                ClientIdentity::from_certificates(stream.get_ref().1.peer_certificates());
+            let service = service_fn(move |mut request: Request<Body>| {
+                if let Some(identity) = &identity {
+                    request.extensions_mut().insert(identity.clone());
+                }
+                service.clone().call(request)
+            });
+
+            // Speaks HTTP/2 or HTTP/1.1, whichever ALPN settled on
+            if let Err(e) = Http::new()
+                .serve_connection(stream, service)
+                .with_upgrades()
+                .await
+            {
+                eprintln!("Connection with {} ended: {}", peer, e);
+            }
+        });
+    }
+}
+
+/// Load the certificates, start watching them and serve `filter` over TLS on `port`
+pub async fn run_tls<F>(filter: F, port: u16, config: TlsConfig) -> io::Result<()>
+where
+    F: Filter<Error = Rejection> + Clone + Send + Sync + 'static,
+    F::Extract: Reply,
+{
+    let resolver = Arc::new(ReloadingCertResolver::new(load_certified_key(
+        &config.cert_path,
+        &config.key_path,
+    )?));
+    let acceptor = TlsAcceptor::from(Arc::new(server_config(&config, resolver.clone())?));
+    start_certificate_reload(resolver, config);
+
+    let listener = TcpListener::bind(SocketAddr::from(([0, 0, 0, 0], port))).await?;
+    serve_tls(filter, listener, acceptor).await;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rustls::{ClientConfig, ServerName};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::TlsConnector;
+
+    fn fixture(name: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("tests/fixtures/tls")
+            .join(name)
+    }
+
+    fn test_config(client_ca: bool) -> TlsConfig {
+        TlsConfig {
+            cert_path: fixture("server.pem"),
+            key_path: fixture("server.key"),
+            client_ca_path: client_ca.then(|| fixture("ca.pem")),
+            reload_interval: Duration::from_secs(1),
+        }
+    }
+
+    /// Serve a route answering "ok" over TLS on an ephemeral port
+    async fn start_server(config: TlsConfig) -> SocketAddr {
+        let resolver = Arc::new(ReloadingCertResolver::new(
+            load_certified_key(&config.cert_path, &config.key_path).unwrap(),
+        ));
+        let acceptor = TlsAcceptor::from(Arc::new(server_config(&config, resolver).unwrap()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let routes = warp::path("health").map(|| "ok").or(warp::path("whoami")
+            .and(warp::ext::optional::<ClientIdentity>())
+            .map(|identity: Option<ClientIdentity>| {
+                identity.map_or("anonymous".to_string(), |identity| identity.0)
+            }));
+        tokio::spawn(serve_tls(routes, listener, acceptor));
+        address
+    }
+
+    fn client_config(with_certificate: bool) -> ClientConfig {
+        let mut roots = RootCertStore::empty();
+        for certificate in load_certificates(&fixture("ca.pem")).unwrap() {
+            roots.add(&certificate).unwrap();
+        }
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+
+        let mut config = if with_certificate {
+            builder
+                .with_client_auth_cert(
+                    load_certificates(&fixture("client.pem")).unwrap(),
+                    load_private_key(&fixture("client.key")).unwrap(),
+                )
+                .unwrap()
+        } else {
+            builder.with_no_client_auth()
+        };
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+        config
+    }
+
+    /// Send a plain HTTP/1.1 request over TLS, forcing HTTP/1.1 through ALPN
+    async fn get_health(address: SocketAddr, with_certificate: bool) -> io::Result<String> {
+        get(address, "/health", with_certificate).await
+    }
+
+    async fn get(address: SocketAddr, path: &str, with_certificate: bool) -> io::Result<String> {
+        let mut config = client_config(with_certificate);
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let stream = tokio::net::TcpStream::connect(address).await?;
+        let mut stream = TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await?;
+
+        stream
+            .write_all(
+                format!(
+                    "GET {} HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n",
+                    path
+                )
+                .as_bytes(),
+            )
+            .await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok(response)
+    }
+
+    #[test]
+    fn test_tls_config_from_env() {
+        unsafe {
+            env::remove_var("TLS_CERT_PATH");
+            env::set_var("TLS_KEY_PATH", "key.pem");
+        }
+        assert!(TlsConfig::from_env().is_none());
+
+        unsafe {
+            env::set_var("TLS_CERT_PATH", "cert.pem");
+            env::set_var("TLS_RELOAD_SECONDS", "5");
+        }
+        let config = TlsConfig::from_env().unwrap();
+        assert_eq!(config.cert_path, PathBuf::from("cert.pem"));
+        assert!(config.client_ca_path.is_none());
+        assert_eq!(config.reload_interval, Duration::from_secs(5));
+
+        // Clean up
+        unsafe {
+            env::remove_var("TLS_CERT_PATH");
+            env::remove_var("TLS_KEY_PATH");
+            env::remove_var("TLS_RELOAD_SECONDS");
+        }
+    }
+
+    #[test]
+    fn test_load_certified_key() {
+        let key = load_certified_key(&fixture("server.pem"), &fixture("server.key")).unwrap();
+        assert_eq!(key.cert.len(), 1);
+
+        let error = load_certified_key(&fixture("server.key"), &fixture("server.key"))
+            .err()
+            .unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_resolver_serves_replaced_certificate() {
+        let resolver = ReloadingCertResolver::new(
+            load_certified_key(&fixture("server.pem"), &fixture("server.key")).unwrap(),
+        );
+        let before = resolver.current.read().unwrap().clone();
+
+        resolver
+            .replace(load_certified_key(&fixture("client.pem"), &fixture("client.key")).unwrap());
+        let after = resolver.current.read().unwrap().clone();
+        assert_ne!(before.cert, after.cert);
+    }
+
+    #[tokio::test]
+    async fn test_serves_https() {
+        let address = start_server(test_config(false)).await;
+
+        let response = get_health(address, false).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+        assert!(response.ends_with("ok"));
+    }
+
+    #[tokio::test]
+    async fn test_offers_http2() {
+        let address = start_server(test_config(false)).await;
+
+        let stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let stream = TlsConnector::from(Arc::new(client_config(false)))
+            .connect(ServerName::try_from("localhost").unwrap(), stream)
+            .await
+            .unwrap();
+        assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    }
+
+    #[tokio::test]
+    async fn test_mutual_tls_requires_client_certificate() {
+        let address = start_server(test_config(true)).await;
+
+        let response = get_health(address, true).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"));
+
+        // TLS 1.3 reports the rejected client certificate on the first read
+        assert!(get_health(address, false).await.is_err());
+    }
+
+    #[test]
+    fn test_client_identity_from_certificates() {
+        let certificates = load_certificates(&fixture("client.pem")).unwrap();
+
+        assert_eq!(
+            ClientIdentity::from_certificates(Some(&certificates)),
+            Some(ClientIdentity("CN=client".to_string()))
+        );
+        assert_eq!(ClientIdentity::from_certificates(None), None);
+    }
+
+    #[tokio::test]
+    async fn test_requests_carry_client_identity() {
+        let address = start_server(test_config(true)).await;
+        let response = get(address, "/whoami", true).await.unwrap();
+        assert!(response.ends_with("CN=client"));
+
+        let address = start_server(test_config(false)).await;
+        let response = get(address, "/whoami", false).await.unwrap();
+        assert!(response.ends_with("anonymous"));
+    }
+}
@@ -0,0 +1,849 @@
+//! Outbound webhooks for user lifecycle events
+//!
+//! The outbox relay turns every user event into one delivery per interested
+//! subscription and stores it in MongoDB. A background worker sends due
+//! deliveries as signed JSON POST requests, retries failures with exponential
+//! backoff and moves a delivery to the dead-letter list once it runs out of
+//! attempts.
+//!
+//! Delivery IDs are derived from the subscription and outbox entry, so an
+//! entry relayed twice still queues each delivery only once.
+//!
+//! Webhook URLs must resolve to public addresses unless the host or address is
+//! listed in `WEBHOOK_ALLOWED_TARGETS`. This is checked when a subscription is
+//! registered and again on every connection the worker opens, so a host that
+//! later resolves to an internal address is still refused.
+
+use futures::future::join_all;
+use hmac::{Hmac, Mac};
+use ipnet::IpNet;
+use mongodb::bson::{doc, DateTime};
+use mongodb::error::Error as MongoError;
+use mongodb::{Collection, Database};
+use reqwest::dns::{Addrs, Resolve, Resolving};
+use reqwest::Url;
+use sha2::Sha256;
+use std::env;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use warp::hyper::client::connect::dns::Name;
+
+use crate::db::{
+    claim_due_webhook_delivery, enqueue_webhook_delivery, find_webhook_subscribers,
+    record_webhook_attempt, WEBHOOK_SUBSCRIPTIONS_COLLECTION,
+};
+use crate::models::{
+    DeliveryAttempt, DeliveryStatus, OutboxEntry, WebhookDelivery, WebhookSubscription,
+};
+
+/// Header carrying the `t=<unix seconds>,v1=<hex HMAC-SHA256>` signature
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+
+/// Default number of attempts before a delivery is dead-lettered
+const DEFAULT_MAX_ATTEMPTS: i32 = 8;
+
+/// Default delay before the first retry; each further retry doubles it
+const DEFAULT_RETRY_BASE_SECONDS: u64 = 30;
+
+/// Default time allowed for the receiver to respond
+const DEFAULT_TIMEOUT_SECONDS: u64 = 10;
+
+/// Longest delay between two attempts
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(3600);
+
+/// How often the worker looks for due deliveries
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Maximum number of deliveries sent concurrently
+const MAX_CONCURRENT_DELIVERIES: usize = 8;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Retry, timeout and target settings for the delivery worker
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    pub max_attempts: i32,
+    pub retry_base: Duration,
+    pub timeout: Duration,
+    pub targets: WebhookTargets,
+}
+
+impl Default for WebhookConfig {
+    fn default() -> Self {
+        WebhookConfig {
+            max_attempts: DEFAULT_MAX_ATTEMPTS,
+            retry_base: Duration::from_secs(DEFAULT_RETRY_BASE_SECONDS),
+            timeout: Duration::from_secs(DEFAULT_TIMEOUT_SECONDS),
+            targets: WebhookTargets::default(),
+        }
+    }
+}
+
+impl WebhookConfig {
+    /// Read `WEBHOOK_MAX_ATTEMPTS`, `WEBHOOK_RETRY_BASE_SECONDS`, `WEBHOOK_TIMEOUT_SECONDS`
+    /// and `WEBHOOK_ALLOWED_TARGETS`
+    pub fn from_env() -> Self {
+        fn env_or<T: std::str::FromStr>(name: &str, default: T) -> T {
+            env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        }
+
+        WebhookConfig {
+            max_attempts: env_or("WEBHOOK_MAX_ATTEMPTS", DEFAULT_MAX_ATTEMPTS).max(1),
+            retry_base: Duration::from_secs(env_or(
+                "WEBHOOK_RETRY_BASE_SECONDS",
+                DEFAULT_RETRY_BASE_SECONDS,
+            )),
+            timeout: Duration::from_secs(env_or(
+                "WEBHOOK_TIMEOUT_SECONDS",
+                DEFAULT_TIMEOUT_SECONDS,
+            )),
+            targets: WebhookTargets::from_env(),
+        }
+    }
+
+    /// Delay before the next attempt after `attempts` failed ones
+    pub fn retry_delay(&self, attempts: i32) -> Duration {
+        let exponent = (attempts.max(1) - 1).min(20) as u32;
+        self.retry_base
+            .saturating_mul(2u32.saturating_pow(exponent))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+/// Hosts and addresses webhooks may be sent to besides public addresses
+#[derive(Debug, Clone, Default)]
+pub struct WebhookTargets {
+    hosts: Vec<String>,
+    networks: Vec<IpNet>,
+}
+
+impl WebhookTargets {
+    /// Allow each entry, a host name, an IP address or a CIDR range such as `10.0.0.0/8`
+    pub fn new<S: AsRef<str>>(allowed: &[S]) -> Self {
+        let mut targets = WebhookTargets::default();
+        for entry in allowed {
+            let entry = entry.as_ref().trim();
+            if entry.is_empty() {
+                continue;
+            }
+            if let Ok(network) = entry.parse::<IpNet>() {
+                targets.networks.push(network);
+            } else if let Ok(ip) = entry.parse::<IpAddr>() {
+                targets.networks.push(IpNet::from(ip));
+            } else {
+                targets.hosts.push(entry.to_ascii_lowercase());
+            }
+        }
+        targets
+    }
+
+    /// Read the comma-separated `WEBHOOK_ALLOWED_TARGETS`; unset allows public addresses only
+    pub fn from_env() -> Self {
+        let allowed = env::var("WEBHOOK_ALLOWED_TARGETS").unwrap_or_default();
+        WebhookTargets::new(&allowed.split(',').collect::<Vec<_>>())
+    }
+
+    /// Whether `host`, resolved to `ip`, may receive webhooks
+    pub fn allows(&self, host: &str, ip: IpAddr) -> bool {
+        is_public_address(ip)
+            || self
+                .hosts
+                .iter()
+                .any(|allowed| allowed.eq_ignore_ascii_case(host))
+            || self.networks.iter().any(|network| network.contains(&ip))
+    }
+
+    /// Check that every address the URL's host resolves to may receive webhooks
+    pub async fn check_url(&self, url: &str) -> Result<(), String> {
+        let url = Url::parse(url).map_err(|_| "url must be an absolute URL".to_string())?;
+        let host = url
+            .host_str()
+            .ok_or_else(|| "url must have a host".to_string())?
+            .trim_start_matches('[')
+            .trim_end_matches(']');
+
+        match host.parse::<IpAddr>() {
+            Ok(ip) if self.allows(host, ip) => Ok(()),
+            Ok(ip) => Err(format!("url points to non-public address {}", ip)),
+            Err(_) => self
+                .resolve(host, url.port_or_known_default().unwrap_or(0))
+                .await
+                .map(|_| ()),
+        }
+    }
+
+    /// Resolve `host`, refusing it when any of its addresses may not receive webhooks
+    async fn resolve(&self, host: &str, port: u16) -> Result<Vec<SocketAddr>, String> {
+        let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+            .await
+            .map_err(|_| format!("url host {} could not be resolved", host))?
+            .collect();
+
+        if addrs.is_empty() {
+            return Err(format!("url host {} could not be resolved", host));
+        }
+        if let Some(addr) = addrs.iter().find(|addr| !self.allows(host, addr.ip())) {
+            return Err(format!(
+                "url host {} resolves to non-public address {}",
+                host,
+                addr.ip()
+            ));
+        }
+
+        Ok(addrs)
+    }
+}
+
+/// Resolves hosts for the delivery client, refusing those that may not receive webhooks
+struct TargetResolver {
+    targets: WebhookTargets,
+}
+
+impl Resolve for TargetResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let targets = self.targets.clone();
+        Box::pin(async move {
+            let addrs = targets.resolve(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// Whether an address is reachable on the public internet, so not loopback, private,
+/// link-local, shared, reserved or otherwise special
+pub fn is_public_address(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => {
+            if let Some(mapped) = ip.to_ipv4_mapped() {
+                return is_public_ipv4(mapped);
+            }
+            let segments = ip.segments();
+            // NAT64 addresses embed an IPv4 address in their last 32 bits
+            if segments[..6] == [0x64, 0xff9b, 0, 0, 0, 0] {
+                let [a, b] = segments[6].to_be_bytes();
+                let [c, d] = segments[7].to_be_bytes();
+                return is_public_ipv4(Ipv4Addr::new(a, b, c, d));
+            }
+            !(ip.is_unspecified()
+                || ip.is_loopback()
+                || ip.is_multicast()
+                // Unique local fc00::/7 and link-local fe80::/10
+                || (segments[0] & 0xfe00) == 0xfc00
+                || (segments[0] & 0xffc0) == 0xfe80
+                // Documentation 2001:db8::/32
+                || (segments[0] == 0x2001 && segments[1] == 0x0db8))
+        }
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [a, b, c, _] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // "This network" 0.0.0.0/8 and reserved 240.0.0.0/4
+        || a == 0
+        || a >= 240
+        // Shared address space 100.64.0.0/10
+        || (a == 100 && (b & 0xc0) == 64)
+        // IETF protocol assignments 192.0.0.0/24
+        || (a == 192 && b == 0 && c == 0)
+        // Benchmarking 198.18.0.0/15
+        || (a == 198 && (b & 0xfe) == 18))
+}
+
+/// Build the client the worker delivers with: connections only go to allowed
+/// addresses and redirects are not followed
+pub fn delivery_client(config: &WebhookConfig) -> reqwest::Client {
+    reqwest::Client::builder()
+        .timeout(config.timeout)
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(TargetResolver {
+            targets: config.targets.clone(),
+        }))
+        .build()
+        .unwrap_or_default()
+}
+
+/// Build one pending delivery per subscription interested in the entry
+pub fn build_deliveries(
+    entry: &OutboxEntry,
+    subscriptions: &[WebhookSubscription],
+) -> Vec<WebhookDelivery> {
+    let event_id = entry.id.to_hex();
+
+    subscriptions
+        .iter()
+        .filter(|subscription| subscription.wants(&entry.event_type))
+        .filter_map(|subscription| {
+            let subscription_id = subscription.id?;
+            Some(WebhookDelivery::pending(
+                format!("{}:{}", subscription_id.to_hex(), event_id),
+                subscription_id,
+                event_id.clone(),
+                entry.event_type.clone(),
+                entry.payload.clone(),
+            ))
+        })
+        .collect()
+}
+
+/// Queue deliveries of an outbox entry for every interested subscription
+pub async fn queue_webhook_deliveries(
+    db: &Database,
+    entry: &OutboxEntry,
+) -> Result<(), MongoError> {
+    let subscriptions = find_webhook_subscribers(db, &entry.event_type).await?;
+
+    for delivery in build_deliveries(entry, &subscriptions) {
+        // Already queued when the entry was relayed before
+        enqueue_webhook_delivery(db, &delivery).await?;
+    }
+
+    Ok(())
+}
+
+/// Sign `"<timestamp>.<payload>"` with the subscription secret
+pub fn sign_payload(secret: &str, timestamp: i64, payload: &str) -> String {
+    let mut mac =
+        HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload.as_bytes());
+
+    format!(
+        "t={},v1={}",
+        timestamp,
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+/// Send a delivery once and report how it went; any 2xx response counts as success.
+///
+/// The URL is checked against `targets` first, which covers hosts given as IP
+/// addresses; host names are checked again by the client's resolver when it connects.
+pub async fn send_delivery(
+    client: &reqwest::Client,
+    targets: &WebhookTargets,
+    subscription: &WebhookSubscription,
+    delivery: &WebhookDelivery,
+) -> DeliveryAttempt {
+    let attempted_at = DateTime::now();
+    let started = Instant::now();
+
+    if let Err(error) = targets.check_url(&subscription.url).await {
+        return DeliveryAttempt {
+            attempted_at,
+            status_code: None,
+            error: Some(error),
+            duration_ms: started.elapsed().as_millis() as i64,
+        };
+    }
+    let signature = sign_payload(
+        &subscription.secret,
+        attempted_at.timestamp_millis() / 1000,
+        &delivery.payload,
+    );
+
+    let result = client
+        .post(&subscription.url)
+        .header("content-type", "application/json")
+        .header(SIGNATURE_HEADER, signature)
+        .header("x-webhook-id", &delivery.event_id)
+        .header("x-webhook-event", &delivery.event_type)
+        .header("x-webhook-delivery", &delivery.id)
+        .body(delivery.payload.clone())
+        .send()
+        .await;
+
+    let (status_code, error) = match result {
+        Ok(response) if response.status().is_success() => {
+            (Some(response.status().as_u16() as i32), None)
+        }
+        Ok(response) => (
+            Some(response.status().as_u16() as i32),
+            Some(format!("Receiver responded with {}", response.status())),
+        ),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    DeliveryAttempt {
+        attempted_at,
+        status_code,
+        error,
+        duration_ms: started.elapsed().as_millis() as i64,
+    }
+}
+
+/// Decide what happens to a delivery after an attempt
+pub fn next_delivery_state(
+    config: &WebhookConfig,
+    attempts_before: i32,
+    attempt: &DeliveryAttempt,
+) -> (DeliveryStatus, DateTime) {
+    let now = DateTime::now();
+    let attempts = attempts_before + 1;
+
+    if attempt.error.is_none() {
+        (DeliveryStatus::Succeeded, now)
+    } else if attempts >= config.max_attempts {
+        (DeliveryStatus::Dead, now)
+    } else {
+        let delay = config.retry_delay(attempts);
+        (
+            DeliveryStatus::Pending,
+            DateTime::from_millis(now.timestamp_millis() + delay.as_millis() as i64),
+        )
+    }
+}
+
+/// Send queued deliveries in the background
+pub fn start_webhook_worker(db: Arc<Database>, config: WebhookConfig) {
+    tokio::spawn(async move {
+        let client = delivery_client(&config);
+
+        loop {
+            while run_due_deliveries(&db, &client, &config).await > 0 {}
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+    });
+}
+
+/// Claim and send a batch of due deliveries, returning how many were processed
+async fn run_due_deliveries(
+    db: &Database,
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+) -> usize {
+    // The lease outlives the request timeout so an in-flight delivery is not claimed twice
+    let lease = config.timeout * 2;
+    let mut claimed = Vec::new();
+
+    while claimed.len() < MAX_CONCURRENT_DELIVERIES {
+        match claim_due_webhook_delivery(db, lease).await {
+            Ok(Some(delivery)) => claimed.push(delivery),
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Failed to claim webhook deliveries: {}", e);
+                break;
+            }
+        }
+    }
+
+    let count = claimed.len();
+    join_all(
+        claimed
+            .into_iter()
+            .map(|delivery| process_delivery(db, client, config, delivery)),
+    )
+    .await;
+
+    count
+}
+
+async fn process_delivery(
+    db: &Database,
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+    delivery: WebhookDelivery,
+) {
+    let subscriptions: Collection<WebhookSubscription> =
+        db.collection(WEBHOOK_SUBSCRIPTIONS_COLLECTION);
+
+    let attempt = match subscriptions
+        .find_one(doc! { "_id": delivery.subscription_id }, None)
+        .await
+    {
+        Ok(Some(subscription)) if subscription.active => {
+            send_delivery(client, &config.targets, &subscription, &delivery).await
+        }
+        Ok(_) => {
+            // Nothing left to deliver to, so dead-letter straight away
+            let attempt = DeliveryAttempt {
+                attempted_at: DateTime::now(),
+                status_code: None,
+                error: Some("Subscription was deleted or deactivated".to_string()),
+                duration_ms: 0,
+            };
+            record_outcome(
+                db,
+                &delivery,
+                &attempt,
+                DeliveryStatus::Dead,
+                DateTime::now(),
+            )
+            .await;
+            return;
+        }
+        Err(e) => {
+            eprintln!(
+                "Failed to load webhook subscription {}: {}",
+                delivery.subscription_id, e
+            );
+            return;
+        }
+    };
+
+    let (status, next_attempt_at) = next_delivery_state(config, delivery.attempts, &attempt);
+    record_outcome(db, &delivery, &attempt, status, next_attempt_at).await;
+}
+
+async fn record_outcome(
+    db: &Database,
+    delivery: &WebhookDelivery,
+    attempt: &DeliveryAttempt,
+    status: DeliveryStatus,
+    next_attempt_at: DateTime,
+) {
+    if let Err(e) = record_webhook_attempt(db, &delivery.id, attempt, status, next_attempt_at).await
+    {
+        eprintln!(
+            "Failed to record webhook attempt for {}: {}",
+            delivery.id, e
+        );
+    }
+}
+
+/// Generate a random signing secret for a new subscription
+pub fn generate_secret() -> String {
+    format!("whsec_{}", hex::encode(rand::random::<[u8; 32]>()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::UserEventKind;
+    use crate::models::User;
+    use crate::outbox::user_outbox_entry;
+    use mongodb::bson::oid::ObjectId;
+    use tokio::sync::mpsc;
+    use warp::http::HeaderMap;
+    use warp::Filter;
+
+    /// Check a signature header against the payload, as a receiver would
+    fn verify_signature(secret: &str, header: &str, payload: &str) -> bool {
+        let mut timestamp = None;
+        let mut signature = None;
+        for part in header.split(',') {
+            match part.trim().split_once('=') {
+                Some(("t", value)) => timestamp = value.parse::<i64>().ok(),
+                Some(("v1", value)) => signature = hex::decode(value).ok(),
+                _ => {}
+            }
+        }
+
+        let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+            return false;
+        };
+
+        let mut mac =
+            HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+        mac.update(timestamp.to_string().as_bytes());
+        mac.update(b".");
+        mac.update(payload.as_bytes());
+        mac.verify_slice(&signature).is_ok()
+    }
+
+    fn test_subscription(url: String, events: &[&str]) -> WebhookSubscription {
+        let mut subscription = WebhookSubscription::new(
+            url,
+            "test-secret".to_string(),
+            events.iter().map(|event| event.to_string()).collect(),
+        );
+        subscription.id = Some(ObjectId::new());
+        subscription
+    }
+
+    fn test_entry(kind: UserEventKind) -> OutboxEntry {
+        let user = User::with_id(
+            ObjectId::new(),
+            "Hook User".to_string(),
+            "hook@example.com".to_string(),
+            chrono::Utc::now(),
+        );
+        user_outbox_entry(kind, &user)
+    }
+
+    /// Start a local receiver that answers with `status` and forwards what it received
+    async fn start_receiver(status: u16) -> (String, mpsc::UnboundedReceiver<(HeaderMap, String)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let route = warp::post()
+            .and(warp::header::headers_cloned())
+            .and(warp::body::bytes())
+            .map(move |headers: HeaderMap, body: warp::hyper::body::Bytes| {
+                let _ = sender.send((headers, String::from_utf8_lossy(&body).to_string()));
+                warp::reply::with_status("", warp::http::StatusCode::from_u16(status).unwrap())
+            });
+
+        let (address, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{}/hook", address), receiver)
+    }
+
+    /// The test receivers listen on loopback, which is only reachable when allowed
+    fn local_targets() -> WebhookTargets {
+        WebhookTargets::new(&["127.0.0.0/8"])
+    }
+
+    fn local_client() -> reqwest::Client {
+        delivery_client(&WebhookConfig {
+            targets: local_targets(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_sign_and_verify_payload() {
+        let signature = sign_payload("secret", 1_700_000_000, "{\"a\":1}");
+
+        assert!(signature.starts_with("t=1700000000,v1="));
+        assert!(verify_signature("secret", &signature, "{\"a\":1}"));
+        assert!(!verify_signature("other", &signature, "{\"a\":1}"));
+        assert!(!verify_signature("secret", &signature, "{\"a\":2}"));
+        assert!(!verify_signature("secret", "garbage", "{\"a\":1}"));
+    }
+
+    #[test]
+    fn test_retry_delay_backs_off_exponentially() {
+        let config = WebhookConfig {
+            retry_base: Duration::from_secs(10),
+            ..Default::default()
+        };
+
+        assert_eq!(config.retry_delay(1), Duration::from_secs(10));
+        assert_eq!(config.retry_delay(2), Duration::from_secs(20));
+        assert_eq!(config.retry_delay(4), Duration::from_secs(80));
+        assert_eq!(config.retry_delay(30), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn test_next_delivery_state() {
+        let config = WebhookConfig {
+            max_attempts: 3,
+            ..Default::default()
+        };
+        let success = DeliveryAttempt {
+            attempted_at: DateTime::now(),
+            status_code: Some(200),
+            error: None,
+            duration_ms: 5,
+        };
+        let failure = DeliveryAttempt {
+            error: Some("Receiver responded with 500".to_string()),
+            status_code: Some(500),
+            ..success.clone()
+        };
+
+        assert_eq!(
+            next_delivery_state(&config, 0, &success).0,
+            DeliveryStatus::Succeeded
+        );
+
+        let (status, next_attempt_at) = next_delivery_state(&config, 0, &failure);
+        assert_eq!(status, DeliveryStatus::Pending);
+        assert!(next_attempt_at > DateTime::now());
+
+        assert_eq!(
+            next_delivery_state(&config, 2, &failure).0,
+            DeliveryStatus::Dead
+        );
+    }
+
+    #[test]
+    fn test_build_deliveries_only_for_interested_subscriptions() {
+        let entry = test_entry(UserEventKind::Updated);
+        let subscriptions = vec![
+            test_subscription("http://a".to_string(), &["user.updated"]),
+            test_subscription("http://b".to_string(), &["user.created"]),
+        ];
+
+        let deliveries = build_deliveries(&entry, &subscriptions);
+        assert_eq!(deliveries.len(), 1);
+        assert_eq!(deliveries[0].subscription_id, subscriptions[0].id.unwrap());
+        assert_eq!(deliveries[0].event_type, "user.updated");
+        assert_eq!(deliveries[0].event_id, entry.id.to_hex());
+
+        let payload: serde_json::Value = serde_json::from_str(&deliveries[0].payload).unwrap();
+        assert_eq!(payload["type"], "user.updated");
+        assert_eq!(payload["data"]["user"]["name"], "Hook User");
+    }
+
+    #[test]
+    fn test_delivery_ids_are_stable_per_entry() {
+        // Relaying the same entry again must not queue new deliveries
+        let entry = test_entry(UserEventKind::Created);
+        let subscriptions = vec![test_subscription("http://a".to_string(), &["user.created"])];
+
+        assert_eq!(
+            build_deliveries(&entry, &subscriptions)[0].id,
+            build_deliveries(&entry, &subscriptions)[0].id
+        );
+    }
+
+    #[tokio::test]
+    async fn test_send_delivery_to_local_receiver() {
+        let (url, mut received) = start_receiver(204).await;
+        let subscription = test_subscription(url, &["user.created"]);
+        let delivery = build_deliveries(
+            &test_entry(UserEventKind::Created),
+            std::slice::from_ref(&subscription),
+        )
+        .remove(0);
+
+        let attempt =
+            send_delivery(&local_client(), &local_targets(), &subscription, &delivery).await;
+        assert_eq!(attempt.status_code, Some(204));
+        assert!(attempt.error.is_none());
+
+        let (headers, body) = received.recv().await.unwrap();
+        assert_eq!(body, delivery.payload);
+        assert_eq!(headers.get("x-webhook-event").unwrap(), "user.created");
+        assert_eq!(
+            headers.get("x-webhook-id").unwrap().to_str().unwrap(),
+            delivery.event_id
+        );
+
+        let signature = headers.get(SIGNATURE_HEADER).unwrap().to_str().unwrap();
+        assert!(verify_signature("test-secret", signature, &body));
+    }
+
+    #[tokio::test]
+    async fn test_send_delivery_reports_receiver_errors() {
+        let (url, _received) = start_receiver(503).await;
+        let subscription = test_subscription(url, &["user.created"]);
+        let delivery = build_deliveries(
+            &test_entry(UserEventKind::Created),
+            std::slice::from_ref(&subscription),
+        )
+        .remove(0);
+
+        let attempt =
+            send_delivery(&local_client(), &local_targets(), &subscription, &delivery).await;
+        assert_eq!(attempt.status_code, Some(503));
+        assert!(attempt.error.unwrap().contains("503"));
+
+        // Nothing listening on the port at all
+        let unreachable =
+            test_subscription("http://127.0.0.1:1/hook".to_string(), &["user.created"]);
+        let attempt =
+            send_delivery(&local_client(), &local_targets(), &unreachable, &delivery).await;
+        assert!(attempt.status_code.is_none());
+        assert!(attempt.error.is_some());
+    }
+
+    #[test]
+    fn test_is_public_address() {
+        for public in ["93.184.216.34", "8.8.8.8", "2606:4700::1111"] {
+            assert!(is_public_address(public.parse().unwrap()), "{}", public);
+        }
+        for internal in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "255.255.255.255",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+            "64:ff9b::a9fe:a9fe",
+        ] {
+            assert!(
+                !is_public_address(internal.parse().unwrap()),
+                "{}",
+                internal
+            );
+        }
+    }
+
+    #[test]
+    fn test_webhook_targets_allowlist() {
+        let targets = WebhookTargets::new(&["10.0.0.0/8", "192.168.1.5", "Hooks.Internal", " "]);
+        let internal: IpAddr = "10.20.30.40".parse().unwrap();
+        let other: IpAddr = "172.16.0.1".parse().unwrap();
+
+        assert!(targets.allows("anything", internal));
+        assert!(targets.allows("anything", "192.168.1.5".parse().unwrap()));
+        assert!(!targets.allows("anything", "192.168.1.6".parse().unwrap()));
+        assert!(targets.allows("hooks.internal", other));
+        assert!(!targets.allows("other.internal", other));
+        assert!(targets.allows("crm.example.com", "93.184.216.34".parse().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_check_url_rejects_internal_addresses() {
+        let targets = WebhookTargets::default();
+
+        assert!(targets.check_url("http://93.184.216.34/hook").await.is_ok());
+        assert!(targets
+            .check_url("http://127.0.0.1:9000/hook")
+            .await
+            .is_err());
+        assert!(targets
+            .check_url("http://169.254.169.254/latest/meta-data")
+            .await
+            .is_err());
+        assert!(targets.check_url("http://[::1]/hook").await.is_err());
+
+        let allowed = WebhookTargets::new(&["127.0.0.1"]);
+        assert!(allowed
+            .check_url("http://127.0.0.1:9000/hook")
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_delivery_refuses_internal_targets() {
+        let (url, _received) = start_receiver(204).await;
+        let subscription = test_subscription(url, &["user.created"]);
+        let delivery = build_deliveries(
+            &test_entry(UserEventKind::Created),
+            std::slice::from_ref(&subscription),
+        )
+        .remove(0);
+
+        let attempt = send_delivery(
+            &local_client(),
+            &WebhookTargets::default(),
+            &subscription,
+            &delivery,
+        )
+        .await;
+        assert!(attempt.status_code.is_none());
+        assert!(attempt.error.unwrap().contains("non-public"));
+    }
+
+    #[tokio::test]
+    async fn test_delivery_client_resolver_refuses_internal_hosts() {
+        // Even when the URL check is passed, connecting re-resolves the host
+        let client = delivery_client(&WebhookConfig::default());
+        let error = client
+            .post("http://localhost:1/hook")
+            .send()
+            .await
+            .unwrap_err();
+        assert!(format!("{:?}", error).contains("non-public"));
+    }
+
+    #[test]
+    fn test_generate_secret() {
+        let secret = generate_secret();
+
+        assert!(secret.starts_with("whsec_"));
+        assert_eq!(secret.len(), "whsec_".len() + 64);
+        assert_ne!(secret, generate_secret());
+    }
+}
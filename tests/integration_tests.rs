@@ -500,3 +500,1268 @@ async fn test_database_operations_end_to_end() -> Result<(), Box<dyn std::error:
     // Cleanup will happen automatically when guard goes out of scope
     Ok(())
 }
+
+#[tokio::test]
+async fn test_create_user_idempotency_key() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+    let idempotency_key = format!(
+        "integration-{}",
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_nanos()
+    );
+
+    let user_data = json!({
+        "name": "Idempotent Test User",
+        "email": "idempotent@test.com"
+    });
+
+    // 1. First request creates the user
+    let first_response = client
+        .post(format!("{}/users", base_url))
+        .header("Idempotency-Key", &idempotency_key)
+        .json(&user_data)
+        .send()
+        .await?;
+
+    assert_eq!(first_response.status(), 201);
+    let first_user: Value = first_response.json().await?;
+    let user_id = first_user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 2. Retrying with the same key replays the original response
+    let retry_response = client
+        .post(format!("{}/users", base_url))
+        .header("Idempotency-Key", &idempotency_key)
+        .json(&user_data)
+        .send()
+        .await?;
+
+    assert_eq!(retry_response.status(), 201);
+    assert_eq!(
+        retry_response
+            .headers()
+            .get("idempotent-replayed")
+            .and_then(|value| value.to_str().ok()),
+        Some("true")
+    );
+    let retry_user: Value = retry_response.json().await?;
+    assert_eq!(retry_user["id"], user_id);
+
+    // 3. Reusing the key with a different body is rejected
+    let conflicting_data = json!({
+        "name": "Different User",
+        "email": "different@test.com"
+    });
+
+    let conflict_response = client
+        .post(format!("{}/users", base_url))
+        .header("Idempotency-Key", &idempotency_key)
+        .json(&conflicting_data)
+        .send()
+        .await?;
+
+    assert_eq!(conflict_response.status(), 422);
+    let body: Value = conflict_response.json().await?;
+    assert_eq!(body["error"], "idempotency_key_reused");
+
+    // Cleanup will happen automatically when guard goes out of scope
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_update_and_delete_user_with_etag() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. Create a user
+    let user_data = json!({
+        "name": "ETag Test User",
+        "email": "etag@test.com"
+    });
+
+    let create_response = client
+        .post(format!("{}/users", base_url))
+        .json(&user_data)
+        .send()
+        .await?;
+
+    assert_eq!(create_response.status(), 201);
+    let created_user: Value = create_response.json().await?;
+    let user_id = created_user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 2. Read it and keep the ETag
+    let get_response = client
+        .get(format!("{}/users/{}", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(get_response.status(), 200);
+    let etag = get_response
+        .headers()
+        .get("etag")
+        .and_then(|value| value.to_str().ok())
+        .unwrap()
+        .to_string();
+
+    // 3. Unchanged reads answer 304
+    let not_modified_response = client
+        .get(format!("{}/users/{}", base_url, user_id))
+        .header("If-None-Match", &etag)
+        .send()
+        .await?;
+    assert_eq!(not_modified_response.status(), 304);
+
+    // 4. Update with the current ETag succeeds
+    let update_response = client
+        .patch(format!("{}/users/{}", base_url, user_id))
+        .header("If-Match", &etag)
+        .json(&json!({ "name": "ETag Renamed User" }))
+        .send()
+        .await?;
+    assert_eq!(update_response.status(), 200);
+    let updated_user: Value = update_response.json().await?;
+    assert_eq!(updated_user["name"], "ETag Renamed User");
+
+    // 5. Deleting with the stale ETag fails
+    let stale_delete_response = client
+        .delete(format!("{}/users/{}", base_url, user_id))
+        .header("If-Match", &etag)
+        .send()
+        .await?;
+    assert_eq!(stale_delete_response.status(), 412);
+
+    let body: Value = stale_delete_response.json().await?;
+    assert_eq!(body["error"], "precondition_failed");
+
+    // 6. Deleting without a precondition succeeds
+    let delete_response = client
+        .delete(format!("{}/users/{}", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(delete_response.status(), 204);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_history_and_audit_log() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. Create and update a user as a named actor
+    let create_response = client
+        .post(format!("{}/users", base_url))
+        .header("X-Actor", "integration-auditor")
+        .json(&json!({
+            "name": "Audit Test User",
+            "email": "audit@test.com"
+        }))
+        .send()
+        .await?;
+
+    assert_eq!(create_response.status(), 201);
+    let created_user: Value = create_response.json().await?;
+    let user_id = created_user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    let update_response = client
+        .patch(format!("{}/users/{}", base_url, user_id))
+        .header("X-Actor", "integration-auditor")
+        .json(&json!({ "email": "audit-updated@test.com" }))
+        .send()
+        .await?;
+    assert_eq!(update_response.status(), 200);
+
+    // 2. History lists both changes, newest first
+    let history_response = reqwest::get(&format!("{}/users/{}/history", base_url, user_id)).await?;
+    assert_eq!(history_response.status(), 200);
+
+    let history: Value = history_response.json().await?;
+    assert_eq!(history["total"], 2);
+    assert_eq!(history["items"][0]["action"], "update");
+    assert_eq!(history["items"][0]["changed_fields"], json!(["email"]));
+    assert_eq!(history["items"][1]["action"], "create");
+
+    // 3. The audit log can be filtered by actor
+    let audit_response = reqwest::get(&format!(
+        "{}/audit?actor=integration-auditor&resource_id={}",
+        base_url, user_id
+    ))
+    .await?;
+    assert_eq!(audit_response.status(), 200);
+
+    let audit: Value = audit_response.json().await?;
+    assert_eq!(audit["total"], 2);
+
+    // 4. Invalid filters are rejected
+    let invalid_response = reqwest::get(&format!("{}/audit?action=rename", base_url)).await?;
+    assert_eq!(invalid_response.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_events_stream() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. Subscribe before making a change
+    let mut events_response = client
+        .get(format!("{}/users/events", base_url))
+        .send()
+        .await?;
+    assert_eq!(events_response.status(), 200);
+    assert_eq!(
+        events_response
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok()),
+        Some("text/event-stream")
+    );
+
+    // 2. Create a user
+    let create_response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({
+            "name": "Events Test User",
+            "email": "events@test.com"
+        }))
+        .send()
+        .await?;
+    assert_eq!(create_response.status(), 201);
+    let created_user: Value = create_response.json().await?;
+    let user_id = created_user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 3. The created event arrives on the stream
+    let mut received = String::new();
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+    while !received.contains(&user_id) {
+        let chunk = tokio::time::timeout_at(deadline, events_response.chunk()).await??;
+        match chunk {
+            Some(bytes) => received.push_str(&String::from_utf8_lossy(&bytes)),
+            None => break,
+        }
+    }
+
+    assert!(received.contains("event:created"));
+    assert!(received.contains(&user_id));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_webhook_delivery_to_local_receiver() -> Result<(), Box<dyn std::error::Error>> {
+    use warp::Filter;
+
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. Start a local receiver that forwards every request body
+    let (sender, mut received) = tokio::sync::mpsc::unbounded_channel();
+    let receiver = warp::post()
+        .and(warp::header::optional::<String>("x-webhook-signature"))
+        .and(warp::body::json())
+        .map(move |signature: Option<String>, body: Value| {
+            let _ = sender.send((signature, body));
+            warp::reply()
+        });
+    let (address, server) = warp::serve(receiver).bind_ephemeral(([127, 0, 0, 1], 0));
+    tokio::spawn(server);
+
+    // 2. Subscribe to user creations
+    let webhook_response = client
+        .post(format!("{}/webhooks", base_url))
+        .json(&json!({
+            "url": format!("http://{}/hook", address),
+            "events": ["user.created"]
+        }))
+        .send()
+        .await?;
+    assert_eq!(webhook_response.status(), 201);
+    let webhook: Value = webhook_response.json().await?;
+    let webhook_id = webhook["id"].as_str().unwrap().to_string();
+    assert!(webhook["secret"].as_str().unwrap().starts_with("whsec_"));
+
+    // 3. Create a user
+    let create_response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({
+            "name": "Webhook Test User",
+            "email": "webhook@test.com"
+        }))
+        .send()
+        .await?;
+    assert_eq!(create_response.status(), 201);
+    let created_user: Value = create_response.json().await?;
+    let user_id = created_user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 4. The signed event reaches the receiver
+    let (signature, body) = tokio::time::timeout(Duration::from_secs(15), received.recv())
+        .await?
+        .unwrap();
+    assert!(signature.unwrap().contains("v1="));
+    assert_eq!(body["type"], "user.created");
+    assert_eq!(body["data"]["user_id"], user_id.as_str());
+
+    // 5. The delivery log records the successful attempt
+    sleep(Duration::from_millis(500)).await;
+    let deliveries_response = client
+        .get(format!("{}/webhooks/{}/deliveries", base_url, webhook_id))
+        .send()
+        .await?;
+    assert_eq!(deliveries_response.status(), 200);
+    let deliveries: Value = deliveries_response.json().await?;
+    assert_eq!(deliveries["items"][0]["status"], "succeeded");
+    assert_eq!(deliveries["items"][0]["attempt_log"][0]["status_code"], 200);
+
+    // 6. Remove the subscription
+    let delete_response = client
+        .delete(format!("{}/webhooks/{}", base_url, webhook_id))
+        .send()
+        .await?;
+    assert_eq!(delete_response.status(), 204);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_content_negotiation() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. XML request body, MessagePack response
+    let response = client
+        .post(format!("{}/users", base_url))
+        .header("Content-Type", "application/xml")
+        .header("Accept", "application/msgpack")
+        .body("<user><name>Negotiated User</name><email>negotiated@test.com</email></user>")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 201);
+    assert_eq!(
+        response
+            .headers()
+            .get("content-type")
+            .and_then(|value| value.to_str().ok()),
+        Some("application/msgpack")
+    );
+    let user: Value = rmp_serde::from_slice(&response.bytes().await?)?;
+    assert_eq!(user["email"], "negotiated@test.com");
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 2. Lists can be downloaded as CSV
+    let response = client
+        .get(format!("{}/users", base_url))
+        .header("Accept", "text/csv")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 200);
+    let csv = response.text().await?;
+    assert!(csv.starts_with("id,name,email,created_at,version"));
+    assert!(csv.contains(&user_id));
+
+    // 3. A single user cannot be CSV
+    let response = client
+        .get(format!("{}/users/{}", base_url, user_id))
+        .header("Accept", "text/csv")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 406);
+
+    // 4. Unknown request body formats are rejected
+    let response = client
+        .post(format!("{}/users", base_url))
+        .header("Content-Type", "text/plain")
+        .body("name=Nobody")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 415);
+    let body: Value = response.json().await?;
+    assert_eq!(body["error"], "unsupported_media_type");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_compression_and_body_limits() -> Result<(), Box<dyn std::error::Error>> {
+    use std::io::{Read, Write};
+
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. A gzip-compressed request body is accepted
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(
+        json!({ "name": "Compressed User", "email": "compressed@test.com" })
+            .to_string()
+            .as_bytes(),
+    )?;
+    let response = client
+        .post(format!("{}/users", base_url))
+        .header("Content-Type", "application/json")
+        .header("Content-Encoding", "gzip")
+        .body(encoder.finish()?)
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    guard.add_user_id(user["id"].as_str().unwrap().to_string());
+
+    // Enough users for the list to be worth compressing
+    for i in 0..10 {
+        let response = client
+            .post(format!("{}/users", base_url))
+            .json(&json!({
+                "name": format!("Compression Filler {}", i),
+                "email": format!("compression.filler{}@test.com", i)
+            }))
+            .send()
+            .await?;
+        let user: Value = response.json().await?;
+        guard.add_user_id(user["id"].as_str().unwrap().to_string());
+    }
+
+    // 2. Large responses are compressed as the client asks
+    let response = client
+        .get(format!("{}/users", base_url))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(
+        response
+            .headers()
+            .get("content-encoding")
+            .and_then(|value| value.to_str().ok()),
+        Some("gzip")
+    );
+    let mut body = String::new();
+    flate2::read::GzDecoder::new(&response.bytes().await?[..]).read_to_string(&mut body)?;
+    let users: Value = serde_json::from_str(&body)?;
+    assert!(users.as_array().unwrap().len() >= 11);
+
+    // 3. Bodies over the limit are refused
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "x".repeat(64 * 1024), "email": "large@test.com" }))
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 413);
+    let body: Value = response.json().await?;
+    assert_eq!(body["error"], "payload_too_large");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cors_preflight_and_security_headers() -> Result<(), Box<dyn std::error::Error>> {
+    let _guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. Browsers may send conditional PATCH requests cross-origin
+    let response = client
+        .request(reqwest::Method::OPTIONS, format!("{}/users/abc", base_url))
+        .header("Origin", "http://localhost:5173")
+        .header("Access-Control-Request-Method", "PATCH")
+        .header("Access-Control-Request-Headers", "content-type, if-match")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 200);
+    let allowed_methods = response
+        .headers()
+        .get("access-control-allow-methods")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default()
+        .to_string();
+    assert!(allowed_methods.contains("PATCH"));
+    assert!(allowed_methods.contains("DELETE"));
+
+    // 2. Every response carries the security headers
+    let response = client.get(format!("{}/health", base_url)).send().await?;
+
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["x-content-type-options"], "nosniff");
+    assert!(response.headers().contains_key("content-security-policy"));
+    assert!(response.headers().contains_key("referrer-policy"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_api_versions() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. v2 answers with camelCase fields
+    let response = client
+        .post(format!("{}/v2/users", base_url))
+        .json(&json!({ "name": "Versioned User", "email": "versioned@test.com" }))
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 201);
+    assert!(response.headers().get("deprecation").is_none());
+    let user: Value = response.json().await?;
+    assert!(user["createdAt"].is_string());
+    assert!(user["updatedAt"].is_string());
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 2. v2 refuses fields it does not know
+    let response = client
+        .post(format!("{}/v2/users", base_url))
+        .json(&json!({ "name": "Extra", "email": "extra@test.com", "role": "admin" }))
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 400);
+
+    // 3. v2 lists come in an envelope, selected by header on unprefixed paths
+    let response = client
+        .get(format!("{}/users", base_url))
+        .header("API-Version", "2")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 200);
+    let users: Value = response.json().await?;
+    assert!(users["count"].as_u64().unwrap() >= 1);
+    assert!(users["data"].is_array());
+
+    // 4. v1, prefixed or not, is the old shape and announces its retirement
+    for path in ["/v1/users", "/users"] {
+        let response = client
+            .get(format!("{}{}/{}", base_url, path, user_id))
+            .send()
+            .await?;
+
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().contains_key("deprecation"));
+        assert!(response.headers().contains_key("sunset"));
+        let user: Value = response.json().await?;
+        assert!(user["created_at"].is_string());
+    }
+
+    // 5. Search answers in the version's shape too
+    let response = client
+        .get(format!("{}/v2/users/search?q=Versioned", base_url))
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().get("deprecation").is_none());
+    let page: Value = response.json().await?;
+    assert!(page["perPage"].is_u64());
+    assert!(page["data"][0]["user"]["createdAt"].is_string());
+    assert!(page["data"][0]["matchedBy"].is_string());
+
+    // 6. Routes that are the same in both versions are not deprecated
+    for path in ["/v1/health", "/health"] {
+        let response = client.get(format!("{}{}", base_url, path)).send().await?;
+
+        assert!(response.headers().get("deprecation").is_none());
+    }
+
+    // 7. Unknown versions are refused
+    let response = client
+        .get(format!("{}/users", base_url))
+        .header("API-Version", "9")
+        .send()
+        .await?;
+
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await?;
+    assert_eq!(body["error"], "unsupported_api_version");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_search_users() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "Søren Kierkegaard-Search", "email": "soren.search@test.com" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 1. Whole words match through the text index, whatever the case and accents
+    let response = client
+        .get(format!("{}/users/search", base_url))
+        .query(&[("q", "SOREN KIERKEGAARD"), ("per_page", "100")])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let results: Value = response.json().await?;
+    let hit = results["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|hit| hit["user"]["id"] == user_id.as_str())
+        .expect("created user is found");
+    assert_eq!(hit["matched_by"], "text");
+    assert!(hit["score"].as_f64().unwrap() > 0.0);
+
+    // 2. Prefixes with a typo fall back to fuzzy matching, with highlights
+    let response = client
+        .get(format!("{}/users/search", base_url))
+        .query(&[("q", "kierkegard"), ("per_page", "100")])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let results: Value = response.json().await?;
+    let hit = results["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|hit| hit["user"]["id"] == user_id.as_str())
+        .expect("created user is found despite the typo");
+    assert_eq!(hit["matched_by"], "fuzzy");
+    assert!(hit["highlights"]["name"]
+        .as_str()
+        .unwrap()
+        .contains("<em>Kierkegaard</em>"));
+
+    // 3. A query is required
+    let response = client
+        .get(format!("{}/users/search", base_url))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_filter_users() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "Filter User", "email": "filter.user@acme-filter.test" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 1. Matching users only, in both API versions
+    let filter = "created_at>=2024-01-01 and email ends_with \"@ACME-FILTER.test\"";
+    let response = client
+        .get(format!("{}/users", base_url))
+        .query(&[("filter", filter)])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let users: Value = response.json().await?;
+    let users = users.as_array().unwrap();
+    assert!(users.iter().any(|user| user["id"] == user_id.as_str()));
+    assert!(users
+        .iter()
+        .all(|user| user["email"].as_str().unwrap().ends_with("@acme-filter.test")));
+
+    let response = client
+        .get(format!("{}/v2/users", base_url))
+        .query(&[("filter", format!("id = {}", user_id))])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let users: Value = response.json().await?;
+    assert_eq!(users["count"], 1);
+
+    // 2. Invalid filters point at the offending token
+    let response = client
+        .get(format!("{}/users", base_url))
+        .query(&[("filter", "name = x and password = y")])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await?;
+    assert_eq!(body["error"], "invalid_filter");
+    assert!(body["message"]
+        .as_str()
+        .unwrap()
+        .starts_with("Unknown field 'password'"));
+    assert!(body["message"].as_str().unwrap().ends_with("at position 14"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_sparse_fieldsets() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "Sparse User", "email": "sparse@test.com" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 1. Only the requested fields come back, and the ETag still does
+    let response = client
+        .get(format!("{}/users/{}?fields=name,id", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert!(response.headers().contains_key("etag"));
+    let user: Value = response.json().await?;
+    assert_eq!(user, json!({ "id": user_id, "name": "Sparse User" }));
+
+    // 2. Lists and v2 field names work the same way
+    let response = client
+        .get(format!("{}/v2/users?fields=createdAt", base_url))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let users: Value = response.json().await?;
+    let first = users["data"][0].as_object().unwrap();
+    assert_eq!(first.keys().collect::<Vec<_>>(), vec!["createdAt"]);
+
+    // 3. Unknown fields and expansions are refused
+    let response = client
+        .get(format!("{}/users?fields=id,password", base_url))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await?;
+    assert_eq!(body["error"], "invalid_fields");
+
+    let response = client
+        .get(format!("{}/users/{}?expand=history", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+    let body: Value = response.json().await?;
+    assert_eq!(body["error"], "invalid_expand");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_streamed_user_lists() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "Streamed User", "email": "streamed@test.com" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    guard.add_user_id(user["id"].as_str().unwrap().to_string());
+
+    // 1. NDJSON is one user per line
+    let response = client
+        .get(format!("{}/users?fields=id,email", base_url))
+        .header("Accept", "application/x-ndjson")
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "application/x-ndjson");
+    let body = response.text().await?;
+    let users: Vec<Value> = body
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?;
+    assert!(users.iter().any(|user| user["email"] == "streamed@test.com"));
+
+    // 2. Streamed JSON is the same document as the collected one
+    let collected: Value = client
+        .get(format!("{}/v2/users", base_url))
+        .send()
+        .await?
+        .json()
+        .await?;
+    let streamed: Value = client
+        .get(format!("{}/v2/users?stream=true", base_url))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(streamed["count"], collected["count"]);
+    assert_eq!(streamed["data"], collected["data"]);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_stats() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // A range starting just now is a query nobody has cached yet
+    let from = (chrono::Utc::now() - chrono::Duration::minutes(1))
+        .format("%Y-%m-%dT%H:%M:%SZ")
+        .to_string();
+
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "Stats User", "email": "stats.user@Stats-Test.com" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    guard.add_user_id(user["id"].as_str().unwrap().to_string());
+
+    // 1. The new user counts as a signup in the range, and its domain is lowercased
+    let response = client
+        .get(format!("{}/users/stats", base_url))
+        .query(&[("from", from.as_str()), ("top", "100")])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let stats: Value = response.json().await?;
+    assert!(stats["signups_in_range"].as_u64().unwrap() >= 1);
+    assert!(stats["totals"]["active"].as_u64().unwrap() >= 1);
+    assert_eq!(
+        stats["totals"]["total"].as_u64().unwrap(),
+        stats["totals"]["active"].as_u64().unwrap() + stats["totals"]["deleted"].as_u64().unwrap()
+    );
+    assert!(stats["top_domains"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .any(|domain| domain["domain"] == "stats-test.com"));
+
+    // 2. Months are zero-filled across the range
+    let response = client
+        .get(format!("{}/users/stats", base_url))
+        .query(&[
+            ("from", "2020-01-01"),
+            ("to", "2020-03-31"),
+            ("interval", "month"),
+        ])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let stats: Value = response.json().await?;
+    let periods: Vec<&str> = stats["signups"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|bucket| bucket["period"].as_str().unwrap())
+        .collect();
+    assert_eq!(periods, vec!["2020-01", "2020-02", "2020-03"]);
+
+    // 3. Invalid parameters are refused
+    let response = client
+        .get(format!("{}/users/stats", base_url))
+        .query(&[("interval", "year")])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+    let error: Value = response.json().await?;
+    assert_eq!(error["error"], "validation_error");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_profile_and_metadata() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. Profile fields and metadata are stored and returned
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({
+            "name": "Profile User",
+            "email": "profile.user@test.com",
+            "phone": "+44 20 7946 0958",
+            "locale": "en-GB",
+            "timezone": "Europe/London",
+            "metadata": { "plan": "profile-test", "tenant": "acme" }
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+    assert_eq!(user["locale"], "en-GB");
+    assert_eq!(user["metadata"]["tenant"], "acme");
+    assert!(user["avatar_url"].is_null());
+
+    // 2. Users can be filtered by metadata
+    let response = client
+        .get(format!("{}/users", base_url))
+        .query(&[("filter", "metadata.plan = profile-test and metadata.tenant exists")])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let users: Value = response.json().await?;
+    let users = users.as_array().unwrap();
+    assert_eq!(users.len(), 1);
+    assert_eq!(users[0]["id"], user_id.as_str());
+
+    // 3. v2 updates in camelCase; blank fields and empty metadata are removed
+    let response = client
+        .patch(format!("{}/v2/users/{}", base_url, user_id))
+        .json(&json!({
+            "avatarUrl": "https://cdn.example.com/profile.png",
+            "phone": "",
+            "metadata": {}
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let user: Value = response.json().await?;
+    assert_eq!(user["avatarUrl"], "https://cdn.example.com/profile.png");
+    assert!(user["phone"].is_null());
+    assert_eq!(user["metadata"], json!({}));
+
+    // 4. Invalid profiles are refused
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({
+            "name": "Invalid Profile",
+            "email": "invalid.profile@test.com",
+            "metadata": { "$where": "1" }
+        }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+    let error: Value = response.json().await?;
+    assert_eq!(error["error"], "validation_error");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_status_lifecycle() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. New users start pending
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "Status User", "email": "status.user@test.com" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+    assert_eq!(user["status"], "pending");
+
+    // 2. Pending users cannot be suspended
+    let response = client
+        .post(format!("{}/users/{}/suspend", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 409);
+    let error: Value = response.json().await?;
+    assert_eq!(error["error"], "invalid_transition");
+
+    // 3. Activate without a body, then suspend with a reason
+    let response = client
+        .post(format!("{}/users/{}/activate", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let user: Value = response.json().await?;
+    assert_eq!(user["status"], "active");
+    assert!(user["status_changed_at"].is_string());
+
+    let response = client
+        .post(format!("{}/v2/users/{}/suspend", base_url, user_id))
+        .json(&json!({ "reason": "Chargeback" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let user: Value = response.json().await?;
+    assert_eq!(user["status"], "suspended");
+    assert_eq!(user["statusReason"], "Chargeback");
+
+    // 4. Users can be filtered by status
+    let response = client
+        .get(format!("{}/users", base_url))
+        .query(&[("filter", "status = suspended and email = \"status.user@test.com\"")])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let users: Value = response.json().await?;
+    assert_eq!(users.as_array().unwrap().len(), 1);
+
+    // 5. Archived is final
+    let response = client
+        .post(format!("{}/users/{}/archive", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let response = client
+        .post(format!("{}/users/{}/activate", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 409);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_v1_suspend_with_reason() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "V1 Status User", "email": "v1.status.user@test.com" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    let response = client
+        .post(format!("{}/v1/users/{}/activate", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+
+    // Unprefixed paths are v1, and the reason comes back in snake_case
+    let response = client
+        .post(format!("{}/users/{}/suspend", base_url, user_id))
+        .json(&json!({ "reason": "Chargeback" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let user: Value = response.json().await?;
+    assert_eq!(user["id"], user_id.as_str());
+    assert_eq!(user["status"], "suspended");
+    assert_eq!(user["status_reason"], "Chargeback");
+
+    // A create-shaped body is refused, not taken as a new user
+    let response = client
+        .post(format!("{}/users/{}/archive", base_url, user_id))
+        .json(&json!({ "name": "Not Created", "email": "not.created@test.com" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+    let response = client
+        .get(format!("{}/users", base_url))
+        .query(&[("filter", "email = \"not.created@test.com\"")])
+        .send()
+        .await?;
+    let users: Value = response.json().await?;
+    assert_eq!(users.as_array().unwrap().len(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_email_verification()-> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    // 1. New users are unverified
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "Verify User", "email": "verify.user@test.com" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+    assert!(user["email_verified_at"].is_null());
+
+    // 2. A link can be resent, but not twice in a row
+    let response = client
+        .post(format!("{}/users/{}/verify-email/resend", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 202);
+
+    let response = client
+        .post(format!("{}/users/{}/verify-email/resend", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 429);
+    assert!(response.headers().contains_key("retry-after"));
+
+    // 3. Forged or missing tokens are rejected
+    let response = client
+        .get(format!("{}/verify-email", base_url))
+        .query(&[("token", format!("{}.4102444800.00", user_id))])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+    let error: Value = response.json().await?;
+    assert_eq!(error["error"], "invalid_token");
+
+    let response = client.get(format!("{}/verify-email", base_url)).send().await?;
+    assert_eq!(response.status(), 400);
+
+    // 4. The user is still unverified
+    let response = client
+        .get(format!("{}/users/{}", base_url, user_id))
+        .send()
+        .await?;
+    let user: Value = response.json().await?;
+    assert!(user["email_verified_at"].is_null());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_user_avatar() -> Result<(), Box<dyn std::error::Error>> {
+    let mut guard = setup_test_environment().await?;
+
+    let client = reqwest::Client::new();
+    let base_url = get_api_base_url();
+
+    let response = client
+        .post(format!("{}/users", base_url))
+        .json(&json!({ "name": "Avatar User", "email": "avatar.user@test.com" }))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 201);
+    let user: Value = response.json().await?;
+    let user_id = user["id"].as_str().unwrap().to_string();
+    guard.add_user_id(user_id.clone());
+
+    // 1. No avatar yet
+    let response = client
+        .get(format!("{}/users/{}/avatar", base_url, user_id))
+        .send()
+        .await?;
+    assert_eq!(response.status(), 404);
+
+    // 2. Upload a 1x1 PNG, whatever its declared type
+    let png: Vec<u8> = vec![
+        0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x48, 0x44,
+        0x52, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x01, 0x08, 0x06, 0x00, 0x00, 0x00, 0x1f,
+        0x15, 0xc4, 0x89, 0x00, 0x00, 0x00, 0x0d, 0x49, 0x44, 0x41, 0x54, 0x78, 0x9c, 0x63, 0xf8,
+        0xcf, 0xc0, 0xf0, 0x1f, 0x00, 0x05, 0x00, 0x01, 0xff, 0x89, 0x99, 0x3d, 0x1d, 0x00, 0x00,
+        0x00, 0x00, 0x49, 0x45, 0x4e, 0x44, 0xae, 0x42, 0x60, 0x82,
+    ];
+    let boundary = "avatar-test-boundary";
+    let mut body = format!(
+        "--{}\r\ncontent-disposition: form-data; name=\"avatar\"; filename=\"me.txt\"\r\ncontent-type: text/plain\r\n\r\n",
+        boundary
+    )
+    .into_bytes();
+    body.extend_from_slice(&png);
+    body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+    let response = client
+        .put(format!("{}/users/{}/avatar", base_url, user_id))
+        .header("content-type", format!("multipart/form-data; boundary={}", boundary))
+        .body(body)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    let avatar: Value = response.json().await?;
+    assert_eq!(avatar["content_type"], "image/png");
+    assert_eq!(avatar["sizes"], json!([64, 128, 256, 512]));
+
+    // 3. Served with caching headers, and revalidated with the ETag
+    let response = client
+        .get(format!("{}/users/{}/avatar", base_url, user_id))
+        .query(&[("size", "64")])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 200);
+    assert_eq!(response.headers()["content-type"], "image/png");
+    assert!(response.headers().contains_key("last-modified"));
+    assert!(response.headers()["cache-control"]
+        .to_str()?
+        .starts_with("public, max-age="));
+    let etag = response.headers()["etag"].to_str()?.to_string();
+    let image = response.bytes().await?;
+    assert!(image.starts_with(&png[..8]));
+
+    let response = client
+        .get(format!("{}/users/{}/avatar", base_url, user_id))
+        .query(&[("size", "64")])
+        .header("if-none-match", &etag)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 304);
+
+    let response = client
+        .get(format!("{}/users/{}/avatar", base_url, user_id))
+        .query(&[("size", "100")])
+        .send()
+        .await?;
+    assert_eq!(response.status(), 400);
+
+    // 4. Text is not an image
+    let body = format!(
+        "--{b}\r\ncontent-disposition: form-data; name=\"avatar\"; filename=\"me.png\"\r\ncontent-type: image/png\r\n\r\nhello\r\n--{b}--\r\n",
+        b = boundary
+    );
+    let response = client
+        .put(format!("{}/users/{}/avatar", base_url, user_id))
+        .header("content-type", format!("multipart/form-data; boundary={}", boundary))
+        .body(body)
+        .send()
+        .await?;
+    assert_eq!(response.status(), 415);
+
+    Ok(())
+}